@@ -0,0 +1,68 @@
+//! Diagnosis of a stream: counts the frames and groups and reports the groups
+//! that cannot be parsed.
+
+use pitinfo_parser::{parse_group, Message};
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Report {
+    pub frames: usize,
+    pub groups: usize,
+    /// Groups the parser does not handle
+    pub ignored: usize,
+    pub errors: usize,
+}
+
+impl Report {
+    /// Adds a group to the report, returning the parse error if any.
+    pub fn add(&mut self, group: &str) -> Option<String> {
+        if group.is_empty() {
+            return None;
+        }
+        match parse_group(group) {
+            Ok(Some(message)) => {
+                if matches!(message, Message::MeterAddress(_)) {
+                    self.frames += 1;
+                }
+                self.groups += 1;
+                None
+            }
+            Ok(None) => {
+                self.ignored += 1;
+                None
+            }
+            Err(e) => {
+                self.errors += 1;
+                Some(e.to_string())
+            }
+        }
+    }
+
+    /// Adds a line that could not be read, e.g. because of line noise.
+    pub fn add_error(&mut self) {
+        self.errors += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = Report::default();
+        for group in ["PAPP 00803 ,", "ADCO 031762120110 /", "PAPP 00803 ,", ""] {
+            assert_eq!(report.add(group), None);
+        }
+        assert_eq!(report.add("MOTDETAT 000000 B"), None);
+        assert!(report.add("PTEC HCXX '").is_some());
+        assert_eq!(
+            report,
+            Report {
+                frames: 1,
+                groups: 4,
+                ignored: 0,
+                errors: 1,
+            }
+        );
+    }
+}
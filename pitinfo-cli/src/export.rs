@@ -0,0 +1,79 @@
+//! Conversion of a capture to CSV, with one row per frame and one column per
+//! label, in the order the meter sends them.
+
+use crate::source::split_group;
+use pitinfo_parser::parse_group;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+#[derive(Default)]
+pub struct Frames {
+    labels: Vec<String>,
+    frames: Vec<HashMap<String, String>>,
+}
+
+impl Frames {
+    /// Adds a group to the current frame. Groups before the first frame and
+    /// invalid groups are dropped.
+    pub fn add(&mut self, group: &str) {
+        let Some((label, data)) = split_group(group) else {
+            return;
+        };
+        if parse_group(group).is_err() {
+            return;
+        }
+        // Frames start with ADCO
+        if label == "ADCO" {
+            self.frames.push(HashMap::new());
+        }
+        let Some(frame) = self.frames.last_mut() else {
+            return;
+        };
+        if !self.labels.iter().any(|known| known == label) {
+            self.labels.push(String::from(label));
+        }
+        frame.insert(String::from(label), String::from(data));
+    }
+
+    /// Writes the frames, returning their number.
+    pub fn write_csv<W: Write>(&self, mut output: W) -> Result<usize, io::Error> {
+        writeln!(output, "{}", self.labels.join(","))?;
+        for frame in &self.frames {
+            let row: Vec<&str> = self
+                .labels
+                .iter()
+                .map(|label| frame.get(label).map(String::as_str).unwrap_or(""))
+                .collect();
+            writeln!(output, "{}", row.join(","))?;
+        }
+        output.flush()?;
+        Ok(self.frames.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows() {
+        let mut frames = Frames::default();
+        for group in [
+            "PAPP 00750 -",
+            "ADCO 031762120110 /",
+            "PAPP 00803 ,",
+            "PTEC HCXX '",
+            "ADCO 031762120110 /",
+            "PTEC HCJB C",
+            "PAPP 00810 *",
+        ] {
+            frames.add(group);
+        }
+        let mut output = Vec::new();
+        assert_eq!(frames.write_csv(&mut output).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "ADCO,PAPP,PTEC\n031762120110,00803,\n031762120110,00810,HCJB\n"
+        );
+    }
+}
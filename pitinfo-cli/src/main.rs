@@ -0,0 +1,235 @@
+//! Companion of the pitinfo-iot daemon for interactive and diagnostic use.
+
+mod check;
+mod export;
+mod replay;
+mod source;
+
+use clap::{Parser, Subcommand};
+use pitinfo_parser::parse_group;
+use source::{Input, DEFAULT_BAUD_RATE, DEFAULT_PORT, STDIO, ZSTD_LEVEL};
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Frames recorded before being compressed together, about a minute.
+const COMPRESSED_FRAMES: usize = 32;
+
+#[derive(Parser)]
+#[command(name = "pitinfo", version, about = "Teleinformation toolbox")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the message parsed from each group
+    Parse {
+        #[command(flatten)]
+        input: Input,
+    },
+    /// Prints the groups of each frame sent by the meter
+    Watch {
+        #[arg(default_value = DEFAULT_PORT)]
+        port: String,
+        #[arg(long, default_value_t = DEFAULT_BAUD_RATE)]
+        baud_rate: u32,
+    },
+    /// Appends the raw stream of the meter to a capture file, compressed
+    /// when its extension is `.zst`
+    Record {
+        output: PathBuf,
+        #[arg(long, default_value = DEFAULT_PORT)]
+        port: String,
+        #[arg(long, default_value_t = DEFAULT_BAUD_RATE)]
+        baud_rate: u32,
+    },
+    /// Plays a capture back at the pace of the meter
+    Replay {
+        input: PathBuf,
+        /// Serial port, `-` for the standard output
+        #[arg(long, default_value = STDIO)]
+        output: String,
+        #[arg(long, default_value_t = DEFAULT_BAUD_RATE)]
+        baud_rate: u32,
+        /// Speed factor, e.g. 10 to play 10 times faster than the meter
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Converts frames to CSV, one row per frame
+    Export {
+        #[command(flatten)]
+        input: Input,
+        /// CSV file, compressed when its extension is `.zst`, the standard
+        /// output by default
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Reports the groups that cannot be parsed
+    Check {
+        #[command(flatten)]
+        input: Input,
+        /// Stops after this number of frames
+        #[arg(long)]
+        frames: Option<usize>,
+    },
+}
+
+fn main() {
+    let result = match Cli::parse().command {
+        Command::Parse { input } => parse(&input),
+        Command::Watch { port, baud_rate } => watch(&port, baud_rate),
+        Command::Record {
+            output,
+            port,
+            baud_rate,
+        } => record(&port, baud_rate, &output),
+        Command::Replay {
+            input,
+            output,
+            baud_rate,
+            speed,
+        } => replay(&input, &output, baud_rate, speed),
+        Command::Export { input, output } => export(&input, output),
+        Command::Check { input, frames } => check(&input, frames),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn parse(input: &Input) -> Result<(), Box<dyn Error>> {
+    for group in source::groups(input.open()?) {
+        let group = group?;
+        match parse_group(&group) {
+            Ok(Some(message)) => println!("{:<20} -> {:?}", group, message),
+            Ok(None) => println!("{:<20} -> Ignored", group),
+            Err(e) => eprintln!("{:<20} -> {}", group, e),
+        }
+    }
+    Ok(())
+}
+
+fn watch(port: &str, baud_rate: u32) -> Result<(), Box<dyn Error>> {
+    let input = Input {
+        input: String::from(port),
+        serial: true,
+        baud_rate,
+    };
+    let mut frame: Vec<String> = Vec::new();
+    for group in source::groups(input.open()?) {
+        let group = group?;
+        let Some((label, data)) = source::split_group(&group) else {
+            continue;
+        };
+        // Frames start with ADCO
+        if label == "ADCO" && !frame.is_empty() {
+            println!("{}", frame.join(" "));
+            frame.clear();
+        }
+        frame.push(format!("{}={}", label, data));
+    }
+    Ok(())
+}
+
+fn record(port: &str, baud_rate: u32, output: &Path) -> Result<(), Box<dyn Error>> {
+    let mut port = source::open_serial(port, baud_rate)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(output)?;
+    let compressed = source::is_compressed(output);
+    // Raw stream not compressed yet
+    let mut pending = Vec::new();
+    let mut pending_frames = 0;
+    let mut buffer = [0u8; 256];
+    let mut frames = 0;
+    loop {
+        let length = match port.read(&mut buffer) {
+            Ok(length) => length,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let started = buffer[..length]
+            .iter()
+            .filter(|byte| **byte == 0x02)
+            .count();
+        if compressed {
+            // Compressed by chunks, each a complete zstd frame, so that
+            // stopping with Ctrl-C only loses the last chunk
+            pending.extend_from_slice(&buffer[..length]);
+            pending_frames += started;
+            if pending_frames >= COMPRESSED_FRAMES {
+                file.write_all(&zstd::encode_all(&pending[..], ZSTD_LEVEL)?)?;
+                pending.clear();
+                pending_frames = 0;
+            }
+        } else {
+            // Written as it comes, so that stopping with Ctrl-C loses nothing
+            file.write_all(&buffer[..length])?;
+        }
+        if started > 0 {
+            frames += started;
+            eprint!("\r{} frames recorded", frames);
+        }
+    }
+}
+
+fn replay(input: &Path, output: &str, baud_rate: u32, speed: f64) -> Result<(), Box<dyn Error>> {
+    if speed <= 0.0 {
+        return Err("the speed must be positive".into());
+    }
+    let input = source::open_file(input)?;
+    let written = if output == STDIO {
+        replay::replay(input, io::stdout().lock(), baud_rate, speed)?
+    } else {
+        let port = source::open_serial(output, baud_rate)?;
+        replay::replay(input, port, baud_rate, speed)?
+    };
+    eprintln!("{} bytes replayed", written);
+    Ok(())
+}
+
+fn export(input: &Input, output: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let mut frames = export::Frames::default();
+    for group in source::groups(input.open()?) {
+        frames.add(&group?);
+    }
+    let count = match output {
+        Some(output) => frames.write_csv(source::create_file(&output)?)?,
+        None => frames.write_csv(io::stdout().lock())?,
+    };
+    eprintln!("{} frames exported", count);
+    Ok(())
+}
+
+fn check(input: &Input, frames: Option<usize>) -> Result<(), Box<dyn Error>> {
+    let mut report = check::Report::default();
+    for (line, group) in source::groups(input.open()?).enumerate() {
+        match group {
+            Ok(group) => {
+                // Frames start with ADCO
+                let complete = frames.is_some_and(|frames| report.frames >= frames);
+                if complete && group.starts_with("ADCO") {
+                    break;
+                }
+                if let Some(error) = report.add(&group) {
+                    println!("Line {}: {}", line + 1, error);
+                }
+            }
+            Err(e) => {
+                report.add_error();
+                println!("Line {}: {}", line + 1, e);
+            }
+        }
+    }
+    println!(
+        "{} frames, {} groups, {} ignored groups, {} errors",
+        report.frames, report.groups, report.ignored, report.errors
+    );
+    if report.errors > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
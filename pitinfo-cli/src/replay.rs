@@ -0,0 +1,47 @@
+//! Playback of a capture at the pace of the meter, e.g. to feed the daemon
+//! through its standard input.
+
+use std::io::{self, BufRead, Write};
+use std::thread;
+use std::time::Duration;
+
+/// Bits sent per character: start bit, 7 data bits, parity and stop bit.
+const BITS_PER_CHARACTER: u32 = 10;
+
+/// Time the meter takes to send `length` characters, divided by `speed`.
+pub fn duration(length: usize, baud_rate: u32, speed: f64) -> Duration {
+    Duration::from_secs_f64(length as f64 * BITS_PER_CHARACTER as f64 / baud_rate as f64 / speed)
+}
+
+/// Writes the capture line by line, returning the number of bytes written.
+pub fn replay<R: BufRead, W: Write>(
+    mut input: R,
+    mut output: W,
+    baud_rate: u32,
+    speed: f64,
+) -> Result<u64, io::Error> {
+    let mut written = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(written);
+        }
+        output.write_all(&line)?;
+        output.flush()?;
+        written += line.len() as u64;
+        thread::sleep(duration(line.len(), baud_rate, speed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_pace() {
+        assert_eq!(duration(120, 1200, 1.0), Duration::from_secs(1));
+        assert_eq!(duration(120, 1200, 10.0), Duration::from_millis(100));
+        assert_eq!(duration(960, 9600, 1.0), Duration::from_secs(1));
+    }
+}
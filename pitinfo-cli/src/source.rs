@@ -0,0 +1,137 @@
+//! Streams read and written by the commands: capture files, the standard
+//! input and output, and serial ports.
+//!
+//! Files with the `.zst` extension are compressed with zstd, transparently.
+
+use clap::Args;
+use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Name of the standard input or output.
+pub const STDIO: &str = "-";
+pub const DEFAULT_PORT: &str = "/dev/ttyAMA0";
+pub const DEFAULT_BAUD_RATE: u32 = 1200;
+/// Compression level of the files written, the zstd default.
+pub const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Args)]
+pub struct Input {
+    /// Capture file, `-` for the standard input, or serial port with --serial
+    #[arg(default_value = STDIO)]
+    pub input: String,
+    /// Reads the meter on the serial port given as input
+    #[arg(long)]
+    pub serial: bool,
+    #[arg(long, default_value_t = DEFAULT_BAUD_RATE)]
+    pub baud_rate: u32,
+}
+
+impl Input {
+    pub fn open(&self) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+        if self.serial {
+            let mut reader = BufReader::new(open_serial(&self.input, self.baud_rate)?);
+            // The first line is usually incomplete
+            reader.read_until(b'\n', &mut Vec::new())?;
+            Ok(Box::new(reader))
+        } else if self.input == STDIO {
+            Ok(Box::new(io::stdin().lock()))
+        } else {
+            Ok(open_file(Path::new(&self.input))?)
+        }
+    }
+}
+
+/// Whether a file is compressed with zstd, from its extension.
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "zst")
+}
+
+/// Opens a file, decompressed on the fly when compressed.
+pub fn open_file(path: &Path) -> Result<Box<dyn BufRead>, io::Error> {
+    let file = File::open(path)?;
+    if is_compressed(path) {
+        Ok(Box::new(BufReader::new(zstd::Decoder::new(file)?)))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Creates a file, compressed on the fly when its extension is `.zst`.
+pub fn create_file(path: &Path) -> Result<Box<dyn Write>, io::Error> {
+    let file = File::create(path)?;
+    if is_compressed(path) {
+        Ok(Box::new(
+            zstd::Encoder::new(file, ZSTD_LEVEL)?.auto_finish(),
+        ))
+    } else {
+        Ok(Box::new(BufWriter::new(file)))
+    }
+}
+
+/// Opens a serial port with the teleinformation settings, 7E1.
+pub fn open_serial(port: &str, baud_rate: u32) -> Result<Box<dyn SerialPort>, serialport::Error> {
+    serialport::new(port, baud_rate)
+        .parity(Parity::Even)
+        .data_bits(DataBits::Seven)
+        .flow_control(FlowControl::None)
+        .stop_bits(StopBits::One)
+        .timeout(Duration::from_millis(1000))
+        .open()
+}
+
+/// Groups of a stream, without the frame control characters and the empty
+/// lines around frames.
+pub fn groups(reader: Box<dyn BufRead>) -> impl Iterator<Item = Result<String, io::Error>> {
+    reader
+        .lines()
+        // Serial ports time out between frames
+        .filter(|line| !matches!(line, Err(e) if e.kind() == io::ErrorKind::TimedOut))
+        .map(|line| line.map(|line| String::from(trim_group(&line))))
+        .filter(|group| !matches!(group, Ok(group) if group.is_empty()))
+}
+
+/// Removes the control characters around a group: the last group of a frame
+/// is followed by \x03 (end of frame) and \x02 (start of frame).
+pub fn trim_group(line: &str) -> &str {
+    line.trim_matches(&['\x03', '\x02', '\r', '\n'] as &[_])
+}
+
+/// Label and data of a group, without its checksum.
+pub fn split_group(group: &str) -> Option<(&str, &str)> {
+    let (label, rest) = group.split_once([' ', '\t'])?;
+    let data = rest.get(..rest.len().checked_sub(2)?)?;
+    Some((label, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_parts() {
+        assert_eq!(trim_group("PPOT 00 #\r\x03\x02"), "PPOT 00 #");
+        assert_eq!(split_group("PAPP 00803 ."), Some(("PAPP", "00803")));
+        assert_eq!(split_group("PTEC\tHCJB\tS"), Some(("PTEC", "HCJB")));
+        assert_eq!(split_group("PAPP"), None);
+        assert_eq!(split_group("PAPP "), None);
+    }
+
+    #[test]
+    fn compressed_files() {
+        let path = std::env::temp_dir().join(format!("pitinfo-{}.tic.zst", std::process::id()));
+        // Appended by chunks, as recorded
+        let mut capture = zstd::encode_all(&b"ADCO 031762120110 @\r\n"[..], ZSTD_LEVEL).unwrap();
+        capture.extend(zstd::encode_all(&b"PAPP 00803 .\r\n"[..], ZSTD_LEVEL).unwrap());
+        std::fs::write(&path, capture).unwrap();
+
+        let groups: Vec<String> = groups(open_file(&path).unwrap())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(groups, vec!["ADCO 031762120110 @", "PAPP 00803 ."]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
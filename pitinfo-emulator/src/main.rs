@@ -0,0 +1,117 @@
+//! Teleinformation emulator, to exercise the daemon without a meter.
+//!
+//! Frames are written to a pseudo-terminal created for the occasion, whose
+//! path is printed on start, to the standard output or to a serial port.
+
+mod meter;
+
+use chrono::{Duration as ChronoDuration, Local};
+use clap::Parser;
+use meter::{Meter, TariffOption};
+use std::error::Error;
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bits sent per character: start bit, 7 data bits, parity and stop bit.
+const BITS_PER_CHARACTER: u32 = 10;
+
+#[derive(Parser)]
+#[command(name = "pitinfo-emulator", version, about = "Teleinformation emulator")]
+struct Args {
+    #[arg(long, value_enum, default_value = "tempo")]
+    option: TariffOption,
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=3))]
+    phases: u8,
+    /// Speed factor, e.g. 60 to simulate an hour per minute
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    #[arg(long, default_value_t = 1200)]
+    baud_rate: u32,
+    /// Share of the groups corrupted like by line noise, from 0 to 1
+    #[arg(long, default_value_t = 0.0)]
+    error_rate: f64,
+    /// Serial port or `-` for the standard output, a new pseudo-terminal by
+    /// default
+    #[arg(long)]
+    output: Option<String>,
+    /// Seed of the simulation, to get the same frames again
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.speed <= 0.0 {
+        return Err("the speed must be positive".into());
+    }
+    let seed = match args.seed {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    };
+    let mut output = open_output(&args)?;
+    let mut meter = Meter::new(args.option, args.phases, seed);
+    let mut now = Local::now().naive_local();
+    let mut elapsed = Duration::ZERO;
+
+    loop {
+        let groups: Vec<String> = meter
+            .frame(now, elapsed)
+            .into_iter()
+            .map(|group| {
+                if meter.random() < args.error_rate {
+                    meter.corrupt(&group)
+                } else {
+                    group
+                }
+            })
+            .collect();
+        let frame = meter::encode(&groups);
+        output.write_all(&frame)?;
+        output.flush()?;
+
+        elapsed = Duration::from_secs_f64(
+            frame.len() as f64 * BITS_PER_CHARACTER as f64 / args.baud_rate as f64,
+        );
+        now += ChronoDuration::from_std(elapsed)?;
+        thread::sleep(elapsed.div_f64(args.speed));
+    }
+}
+
+fn open_output(args: &Args) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    match args.output.as_deref() {
+        Some("-") => Ok(Box::new(io::stdout())),
+        Some(port) => Ok(Box::new(
+            serialport::new(port, args.baud_rate)
+                .parity(serialport::Parity::Even)
+                .data_bits(serialport::DataBits::Seven)
+                .stop_bits(serialport::StopBits::One)
+                .open()?,
+        )),
+        None => open_pty(),
+    }
+}
+
+#[cfg(unix)]
+fn open_pty() -> Result<Box<dyn Write>, Box<dyn Error>> {
+    use serialport::{SerialPort, TTYPort};
+
+    let (master, slave) = TTYPort::pair()?;
+    let name = slave.name().ok_or("pseudo-terminal without a name")?;
+    eprintln!("Emulating the meter on {}", name);
+    // Writes fail once no one has the pseudo-terminal open
+    std::mem::forget(slave);
+    Ok(Box::new(master))
+}
+
+#[cfg(not(unix))]
+fn open_pty() -> Result<Box<dyn Write>, Box<dyn Error>> {
+    Err("pseudo-terminals are only available on Unix, use --output".into())
+}
@@ -0,0 +1,277 @@
+//! Simulated meter sending historic mode frames.
+//!
+//! The consumption follows a base load with noise and an appliance switching
+//! on and off now and then. Indexes grow with the consumption, and the tariff
+//! periods follow the clock: off-peak hours from 22:00 to 6:00, and, for
+//! Tempo, a day color known the evening before.
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use clap::ValueEnum;
+use std::iter;
+use std::time::Duration;
+
+/// Start of text, sent before the first group of a frame.
+const STX: u8 = 0x02;
+/// End of text, sent after the last group of a frame.
+const ETX: u8 = 0x03;
+const VOLTAGE: f64 = 230.0;
+const BASE_LOAD: f64 = 350.0;
+const APPLIANCE_LOAD: f64 = 2000.0;
+/// Chance for the appliance to switch on or off at each frame.
+const APPLIANCE_SWITCH: f64 = 0.005;
+/// Hour the meter announces tomorrow's Tempo color.
+const TOMORROW_ANNOUNCE: u32 = 20;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TariffOption {
+    Base,
+    Hc,
+    Tempo,
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    Blue,
+    White,
+    Red,
+}
+
+pub struct Meter {
+    option: TariffOption,
+    phases: u8,
+    /// Indexes in Wh, by label
+    indexes: Vec<(&'static str, f64)>,
+    appliance: bool,
+    power: f64,
+    random: Random,
+}
+
+impl Meter {
+    pub fn new(option: TariffOption, phases: u8, seed: u64) -> Meter {
+        let labels: &[&'static str] = match option {
+            TariffOption::Base => &["BASE"],
+            TariffOption::Hc => &["HCHC", "HCHP"],
+            TariffOption::Tempo => &[
+                "BBRHCJB", "BBRHPJB", "BBRHCJW", "BBRHPJW", "BBRHCJR", "BBRHPJR",
+            ],
+        };
+        let mut random = Random::new(seed);
+        let indexes = labels
+            .iter()
+            .map(|label| (*label, (random.next_f64() * 10_000_000.0).floor()))
+            .collect();
+        Meter {
+            option,
+            phases,
+            indexes,
+            appliance: false,
+            power: BASE_LOAD,
+            random,
+        }
+    }
+
+    /// Groups of the frame sent at `now`, `elapsed` after the previous one.
+    pub fn frame(&mut self, now: NaiveDateTime, elapsed: Duration) -> Vec<String> {
+        if self.random.next_f64() < APPLIANCE_SWITCH {
+            self.appliance = !self.appliance;
+        }
+        let noise = (self.random.next_f64() - 0.5) * 60.0;
+        self.power = BASE_LOAD + noise + if self.appliance { APPLIANCE_LOAD } else { 0.0 };
+
+        let off_peak = !(6..22).contains(&now.hour());
+        let period = self.period(now, off_peak);
+        let energy = self.power * elapsed.as_secs_f64() / 3600.0;
+        let single = self.indexes.len() == 1;
+        if let Some(index) = self
+            .indexes
+            .iter_mut()
+            .find(|(label, _)| single || label.ends_with(&period))
+        {
+            index.1 += energy;
+        }
+
+        let mut groups = vec![group("ADCO", "031762120110")];
+        groups.push(group(
+            "OPTARIF",
+            match self.option {
+                TariffOption::Base => "BASE",
+                TariffOption::Hc => "HC..",
+                TariffOption::Tempo => "BBR(",
+            },
+        ));
+        groups.push(group("ISOUSC", "30"));
+        for (label, index) in &self.indexes {
+            groups.push(group(label, &format!("{:09}", *index as u64)));
+        }
+        groups.push(group("PTEC", &format!("{:.<4}", period)));
+        if let TariffOption::Tempo = self.option {
+            let tomorrow = if now.hour() >= TOMORROW_ANNOUNCE {
+                match color(tempo_day(now) + 1) {
+                    Color::Blue => "BLEU",
+                    Color::White => "BLAN",
+                    Color::Red => "ROUG",
+                }
+            } else {
+                "----"
+            };
+            groups.push(group("DEMAIN", tomorrow));
+        }
+        let current = (self.power / VOLTAGE / self.phases as f64).round() as u32;
+        if self.phases == 1 {
+            groups.push(group("IINST", &format!("{:03}", current)));
+            groups.push(group("IMAX", "090"));
+        } else {
+            for phase in 1..=self.phases {
+                groups.push(group(
+                    &format!("IINST{}", phase),
+                    &format!("{:03}", current),
+                ));
+            }
+            for phase in 1..=self.phases {
+                groups.push(group(&format!("IMAX{}", phase), "060"));
+            }
+            groups.push(group("PMAX", "06871"));
+        }
+        groups.push(group("PAPP", &format!("{:05}", self.power as u32)));
+        groups.push(group("HHPHC", "A"));
+        groups.push(group("MOTDETAT", "000000"));
+        if self.phases > 1 {
+            groups.push(group("PPOT", "00"));
+        }
+        groups
+    }
+
+    /// Current tariff period, as the suffix of the index labels.
+    fn period(&self, now: NaiveDateTime, off_peak: bool) -> String {
+        let hour = if off_peak { "HC" } else { "HP" };
+        match self.option {
+            TariffOption::Base => String::from("TH"),
+            TariffOption::Hc => String::from(hour),
+            TariffOption::Tempo => {
+                let color = match color(tempo_day(now)) {
+                    Color::Blue => "B",
+                    Color::White => "W",
+                    Color::Red => "R",
+                };
+                format!("{}J{}", hour, color)
+            }
+        }
+    }
+
+    /// Corrupts a character of a group, like line noise does.
+    pub fn corrupt(&mut self, group: &str) -> String {
+        let mut bytes = group.as_bytes().to_vec();
+        let position = self.random.below(bytes.len() as u64) as usize;
+        bytes[position] = b'!' + self.random.below(94) as u8;
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    pub fn random(&mut self) -> f64 {
+        self.random.next_f64()
+    }
+}
+
+/// Number of the Tempo day, which starts at 6:00.
+fn tempo_day(now: NaiveDateTime) -> i64 {
+    let day = if now.hour() < 6 {
+        now.date().pred_opt().unwrap_or(now.date())
+    } else {
+        now.date()
+    };
+    day.num_days_from_ce() as i64
+}
+
+/// About 300 blue, 43 white and 22 red days a year.
+fn color(day: i64) -> Color {
+    match day.rem_euclid(365) % 17 {
+        0 => Color::Red,
+        1 | 9 => Color::White,
+        _ => Color::Blue,
+    }
+}
+
+/// Group with its checksum: the sum of the label, separator and data
+/// characters, on 6 bits, shifted to a printable character.
+pub fn group(label: &str, data: &str) -> String {
+    let sum: u32 = label
+        .bytes()
+        .chain(iter::once(b' '))
+        .chain(data.bytes())
+        .map(u32::from)
+        .sum();
+    format!("{} {} {}", label, data, ((sum & 0x3F) + 0x20) as u8 as char)
+}
+
+/// Frame as sent on the line: each group between LF and CR, the whole frame
+/// between STX and ETX.
+pub fn encode(groups: &[String]) -> Vec<u8> {
+    let mut frame = vec![STX];
+    for group in groups {
+        frame.push(b'\n');
+        frame.extend_from_slice(group.as_bytes());
+        frame.push(b'\r');
+    }
+    frame.push(ETX);
+    frame
+}
+
+/// Xorshift generator, enough for a simulation.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use pitinfo_parser::{parse_group, Message};
+
+    #[test]
+    fn checksums() {
+        assert_eq!(group("BBRHCJB", "023916830"), "BBRHCJB 023916830 =");
+        assert_eq!(group("ADCO", "020830022493"), "ADCO 020830022493 8");
+        assert_eq!(group("PPOT", "00"), "PPOT 00 #");
+    }
+
+    #[test]
+    fn tempo_frames() {
+        let mut meter = Meter::new(TariffOption::Tempo, 3, 42);
+        let now = NaiveDate::from_ymd_opt(2024, 1, 16)
+            .unwrap()
+            .and_hms_opt(21, 0, 0)
+            .unwrap();
+        let groups = meter.frame(now, Duration::from_secs(2));
+        assert_eq!(groups.len(), 22);
+        for group in &groups {
+            assert!(parse_group(group).is_ok(), "{}", group);
+        }
+        assert_eq!(
+            parse_group(&groups[0]),
+            Ok(Some(Message::MeterAddress(String::from("031762120110"))))
+        );
+        assert!(!groups.iter().any(|group| group.starts_with("DEMAIN ----")));
+
+        let frame = encode(&groups);
+        assert_eq!(frame.first(), Some(&STX));
+        assert_eq!(frame.last(), Some(&ETX));
+    }
+}
@@ -0,0 +1,280 @@
+//! Consumption anomaly detection over the apparent power.
+//!
+//! Four rules are checked:
+//!
+//! - the always-on load, measured as the median power between 1:00 and 5:00,
+//!   is compared every morning with the median of the previous nights, which
+//!   reveals a forgotten heater or a failing appliance;
+//! - the power staying above a threshold for too long;
+//! - the loss of a phase, and its return, from PPOT on three-phase meters;
+//! - a fault reported by the meter in its status word, MOTDETAT.
+//!
+//! Anomalies are logged and run the configured command, with the description
+//! of the anomaly in the `PITINFO_ALERT` environment variable, and are sent
+//! by email when configured.
+
+use crate::config::AnomalyConfig;
+use crate::email::Mailer;
+use crate::hooks;
+use crate::pipeline::{self, Sink};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use pitinfo_parser::{Message, MeterStatus};
+use std::collections::VecDeque;
+use std::io;
+
+const NIGHT_START_HOUR: u32 = 1;
+const NIGHT_END_HOUR: u32 = 5;
+/// Number of nights the usual always-on load is learnt from.
+const LEARNING_NIGHTS: usize = 14;
+/// Nights needed before reporting always-on load anomalies.
+const MIN_NIGHTS: usize = 3;
+/// Smallest always-on load increase reported, in VA, so that low loads do
+/// not raise alerts for a few watts.
+const MIN_BASELINE_INCREASE: f64 = 50.0;
+
+/// Starts the detector. Messages sent to the returned sink are checked for
+/// anomalies.
+pub fn spawn(
+    config: &AnomalyConfig,
+    timezone: Tz,
+    mailer: Option<Mailer>,
+) -> Result<Sink, io::Error> {
+    let mut detector = Detector::new(config.clone());
+    Ok(pipeline::spawn_sink("anomaly", |mut receiver| async move {
+        while let Some(message) = receiver.recv_message().await {
+            let alerts = match message {
+                Message::ApparentPower { value } => {
+                    let now = Utc::now().with_timezone(&timezone).naive_local();
+                    detector.update(value, now)
+                }
+                Message::PhasePotential {
+                    phase1,
+                    phase2,
+                    phase3,
+                } => detector.phases([phase1, phase2, phase3]),
+                Message::MeterStatus(status) => detector.status(status).into_iter().collect(),
+                _ => continue,
+            };
+            for alert in alerts {
+                println!("Anomaly: {}", alert);
+                if let Some(command) = &detector.config.command {
+                    hooks::run(command, &[("PITINFO_ALERT", &alert)]).await;
+                }
+                if let Some(mailer) = &mailer {
+                    mailer.alert(&alert).await;
+                }
+            }
+        }
+    }))
+}
+
+pub struct Detector {
+    config: AnomalyConfig,
+    /// Samples of the current night
+    night: Vec<u16>,
+    night_day: Option<NaiveDate>,
+    /// Always-on load of the previous nights, in VA
+    baselines: VecDeque<f64>,
+    high_since: Option<NaiveDateTime>,
+    high_reported: bool,
+    /// Presence of the phases in the last PPOT
+    phases: [bool; 3],
+    /// Status word of the meter in the last MOTDETAT
+    status: MeterStatus,
+}
+
+impl Detector {
+    pub fn new(config: AnomalyConfig) -> Detector {
+        Detector {
+            config,
+            night: Vec::new(),
+            night_day: None,
+            baselines: VecDeque::new(),
+            high_since: None,
+            high_reported: false,
+            phases: [true; 3],
+            status: MeterStatus::empty(),
+        }
+    }
+
+    /// Adds an apparent power sample, returning the anomalies detected.
+    pub fn update(&mut self, power: u16, now: NaiveDateTime) -> Vec<String> {
+        let mut alerts = Vec::new();
+
+        let hour = now.hour();
+        if (NIGHT_START_HOUR..NIGHT_END_HOUR).contains(&hour) {
+            if self.night_day != Some(now.date()) {
+                self.night.clear();
+                self.night_day = Some(now.date());
+            }
+            self.night.push(power);
+        } else if !self.night.is_empty() {
+            let baseline = median(self.night.iter().map(|p| *p as f64).collect());
+            self.night.clear();
+            alerts.extend(self.check_baseline(baseline));
+        }
+
+        match self.config.high_power {
+            Some(high_power) if power >= high_power => {
+                let since = *self.high_since.get_or_insert(now);
+                let duration = Duration::minutes(self.config.high_power_minutes as i64);
+                if !self.high_reported && now - since >= duration {
+                    self.high_reported = true;
+                    alerts.push(format!(
+                        "power above {} VA for {} minutes",
+                        high_power, self.config.high_power_minutes
+                    ));
+                }
+            }
+            _ => {
+                self.high_since = None;
+                self.high_reported = false;
+            }
+        }
+        alerts
+    }
+
+    /// Updates the presence of the phases, returning the phases lost and
+    /// back.
+    pub fn phases(&mut self, present: [bool; 3]) -> Vec<String> {
+        let alerts = (0..3)
+            .filter(|phase| present[*phase] != self.phases[*phase])
+            .map(|phase| match present[phase] {
+                true => format!("voltage back on phase {}", phase + 1),
+                false => format!("voltage lost on phase {}", phase + 1),
+            })
+            .collect();
+        self.phases = present;
+        alerts
+    }
+
+    /// Updates the status word of the meter, returning the change of fault,
+    /// if any.
+    pub fn status(&mut self, status: MeterStatus) -> Option<String> {
+        if status == self.status {
+            return None;
+        }
+        self.status = status;
+        Some(match status.is_nominal() {
+            true => String::from("meter status back to normal"),
+            false => format!("meter status {} reports a fault", status),
+        })
+    }
+
+    fn check_baseline(&mut self, baseline: f64) -> Option<String> {
+        let alert = if self.baselines.len() >= MIN_NIGHTS {
+            let usual = median(self.baselines.iter().copied().collect());
+            let increase = baseline - usual;
+            if increase >= MIN_BASELINE_INCREASE
+                && increase > usual * self.config.baseline_increase / 100.0
+            {
+                Some(format!(
+                    "always-on load of {:.0} VA last night, usually {:.0} VA",
+                    baseline, usual
+                ))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        self.baselines.push_back(baseline);
+        if self.baselines.len() > LEARNING_NIGHTS {
+            self.baselines.pop_front();
+        }
+        alert
+    }
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AnomalyConfig {
+        AnomalyConfig {
+            baseline_increase: 50.0,
+            high_power: Some(6000),
+            high_power_minutes: 60,
+            command: None,
+        }
+    }
+
+    #[test]
+    fn always_on_load() {
+        let mut detector = Detector::new(config());
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let mut alerts = Vec::new();
+        for (night, load) in [300, 320, 280, 310, 600].iter().enumerate() {
+            for minute in 0..6 * 60 {
+                let now = start + Duration::days(night as i64) + Duration::minutes(minute);
+                alerts.extend(detector.update(*load, now));
+            }
+        }
+        assert_eq!(
+            alerts,
+            vec!["always-on load of 600 VA last night, usually 305 VA"]
+        );
+    }
+
+    #[test]
+    fn high_power() {
+        let mut detector = Detector::new(config());
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let mut alerts = Vec::new();
+        for minute in 0..120 {
+            alerts.extend(detector.update(7000, start + Duration::minutes(minute)));
+        }
+        assert_eq!(alerts, vec!["power above 6000 VA for 60 minutes"]);
+        assert!(detector
+            .update(1000, start + Duration::minutes(121))
+            .is_empty());
+    }
+
+    #[test]
+    fn phase_loss() {
+        let mut detector = Detector::new(config());
+        assert!(detector.phases([true, true, true]).is_empty());
+        assert_eq!(
+            detector.phases([true, false, true]),
+            vec!["voltage lost on phase 2"]
+        );
+        assert!(detector.phases([true, false, true]).is_empty());
+        assert_eq!(
+            detector.phases([true, true, true]),
+            vec!["voltage back on phase 2"]
+        );
+    }
+
+    #[test]
+    fn meter_status() {
+        let mut detector = Detector::new(config());
+        assert_eq!(detector.status(MeterStatus::empty()), None);
+        let fault = MeterStatus::from_bits_retain(0x100);
+        assert_eq!(
+            detector.status(fault).as_deref(),
+            Some("meter status 000100 reports a fault")
+        );
+        assert_eq!(detector.status(fault), None);
+        assert_eq!(
+            detector.status(MeterStatus::empty()).as_deref(),
+            Some("meter status back to normal")
+        );
+    }
+}
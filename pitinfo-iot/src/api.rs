@@ -0,0 +1,442 @@
+//! HTTP API reading and changing the settings at runtime.
+//!
+//! An admin interface or a Home Assistant `rest_command` can tune the alert
+//! thresholds, the load-shedding rules or the publish intervals without
+//! editing the configuration file over SSH:
+//!
+//! - `GET /api/v1/settings` returns the configuration as JSON, the file with
+//!   the environment variables and the settings changed remotely applied,
+//!   without the defaults and the secrets;
+//! - `GET /api/v1/settings/<key>` returns a setting by its dotted key, e.g.
+//!   `anomaly.high_power`, or 404 when it is left to its default;
+//! - `PUT /api/v1/settings/<key>`, when the API is `writable`, with a JSON
+//!   value checks the configuration with it, writes it to the configuration
+//!   file, keeping its comments, and has the daemon apply it like the `set`
+//!   command, which restarts the sinks. Only the thresholds, rules and
+//!   intervals of `config::check_remote` can be changed;
+//! - `GET /api/v1/history` returns the currents and the apparent power of the
+//!   recent frames, kept by `history::History`, optionally of a `label` and
+//!   read after `since`, in Unix seconds;
+//! - `GET /api/v1/openapi.json` returns the OpenAPI document of the routes,
+//!   written by hand in `openapi.json`.
+//!
+//! Requests need the configured token, if any, as a bearer token, a token
+//! being required beyond the loopback interface, along with TLS, served with
+//! the `api-tls` feature or by a reverse proxy. The server runs on a thread
+//! of its own, requests being rare and quick.
+
+use crate::command::Command;
+use crate::config::{self, ApiConfig, Config};
+use crate::history::History;
+use chrono::DateTime;
+use serde_json::{json, Value as Json};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use toml::{Table, Value};
+
+/// Settings changed remotely, kept over the configuration file.
+pub type Settings = watch::Receiver<Vec<(String, Value)>>;
+
+/// Starts serving the API.
+pub fn spawn(
+    config: &ApiConfig,
+    path: Option<PathBuf>,
+    settings: Settings,
+    commands: Sender<Command>,
+    history: Arc<Mutex<History>>,
+) -> Result<(), io::Error> {
+    let server = listen(config).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("unable to listen on {}: {}", config.listen, e),
+        )
+    })?;
+    let api = Api {
+        token: config.token.clone(),
+        writable: config.writable,
+        path,
+        settings,
+        commands,
+        history,
+    };
+    thread::Builder::new()
+        .name(String::from("api"))
+        .spawn(move || {
+            for request in server.incoming_requests() {
+                api.handle(request);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(feature = "api-tls")]
+fn listen(config: &ApiConfig) -> Result<Server, Box<dyn std::error::Error + Send + Sync>> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let ssl = tiny_http::SslConfig {
+                certificate: std::fs::read(cert)?,
+                private_key: std::fs::read(key)?,
+            };
+            Server::https(&config.listen, ssl)
+        }
+        _ => Server::http(&config.listen),
+    }
+}
+
+#[cfg(not(feature = "api-tls"))]
+fn listen(config: &ApiConfig) -> Result<Server, Box<dyn std::error::Error + Send + Sync>> {
+    Server::http(&config.listen)
+}
+
+struct Api {
+    token: Option<String>,
+    writable: bool,
+    /// Configuration file, where the settings changed are written
+    path: Option<PathBuf>,
+    settings: Settings,
+    commands: Sender<Command>,
+    /// Recent currents and apparent power, recorded by the daemon
+    history: Arc<Mutex<History>>,
+}
+
+impl Api {
+    fn handle(&self, mut request: Request) {
+        let (status, body) = self.answer(&mut request);
+        let content_type =
+            Header::from_bytes("Content-Type", "application/json").expect("valid header");
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type);
+        if let Err(e) = request.respond(response) {
+            eprintln!("Unable to answer the API request: {}", e);
+        }
+    }
+
+    fn answer(&self, request: &mut Request) -> (u16, Json) {
+        if let Some(token) = &self.token {
+            let expected = format!("Bearer {}", token);
+            let authorized = request.headers().iter().any(|header| {
+                header.field.equiv("Authorization")
+                    && same(header.value.as_bytes(), expected.as_bytes())
+            });
+            if !authorized {
+                return error(401, "missing or invalid token");
+            }
+        }
+        let route = match route(request.url()) {
+            Some(route) => route,
+            None => return error(404, "not found"),
+        };
+        match (request.method(), route) {
+            (Method::Get, Route::Settings(None)) => self.show(),
+            (Method::Get, Route::Settings(Some(key))) => self.get(&key),
+            (Method::Get, Route::History { label, since }) => self.history(label, since),
+            (Method::Get, Route::OpenApi) => (200, openapi()),
+            (Method::Put, Route::Settings(Some(key))) => match read_body(request.as_reader()) {
+                Ok(body) => self.put(key, &body),
+                Err(error) => error,
+            },
+            _ => error(405, "method not allowed"),
+        }
+    }
+
+    fn history(&self, label: Option<String>, since: Option<String>) -> (u16, Json) {
+        let since = match since {
+            Some(since) => match since
+                .parse()
+                .ok()
+                .and_then(|since| DateTime::from_timestamp(since, 0))
+            {
+                Some(since) => Some(since),
+                None => return error(400, "since is not a Unix time in seconds"),
+            },
+            None => None,
+        };
+        let history = self.history.lock().unwrap();
+        (200, json!(history.since(label.as_deref(), since)))
+    }
+
+    fn table(&self) -> Result<Table, (u16, Json)> {
+        let settings = self.settings.borrow().clone();
+        Config::table(self.path.as_deref(), &settings).map_err(|e| error(500, &e.to_string()))
+    }
+
+    fn show(&self) -> (u16, Json) {
+        match self.table() {
+            Ok(mut table) => {
+                without_secrets(&mut table);
+                (200, json!(table))
+            }
+            Err(error) => error,
+        }
+    }
+
+    fn get(&self, key: &str) -> (u16, Json) {
+        if config::is_secret(key) {
+            return error(403, "secret setting");
+        }
+        let table = match self.table() {
+            Ok(table) => table,
+            Err(error) => return error,
+        };
+        let value = key
+            .split('.')
+            .try_fold(&Value::Table(table.clone()), |value, name| value.get(name))
+            .cloned();
+        match value {
+            Some(value) => (200, json!({ "key": key, "value": value })),
+            None => error(404, "not set, left to its default"),
+        }
+    }
+
+    fn put(&self, key: String, body: &str) -> (u16, Json) {
+        if !self.writable {
+            return error(403, "read-only API");
+        }
+        if let Err(e) = config::check_remote(&key) {
+            return error(403, &e.to_string());
+        }
+        let value: Value = match serde_json::from_str(body) {
+            Ok(value) => value,
+            Err(e) => return error(400, &format!("invalid value: {}", e)),
+        };
+        let mut settings = self.settings.borrow().clone();
+        settings.retain(|(name, _)| *name != key);
+        settings.push((key.clone(), value.clone()));
+        if let Err(e) = Config::load_with(self.path.as_deref(), &settings) {
+            return error(400, &e.to_string());
+        }
+        if let Some(path) = &self.path {
+            if let Err(e) = config::persist(path, &key, &value) {
+                return error(500, &format!("unable to write {}: {}", path.display(), e));
+            }
+        }
+        let command = Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        if self.commands.blocking_send(command).is_err() {
+            return error(503, "the daemon is stopping");
+        }
+        let persisted = self.path.is_some();
+        (
+            200,
+            json!({ "key": key, "value": value, "persisted": persisted }),
+        )
+    }
+}
+
+/// Largest body of a request, far above any setting.
+const MAX_BODY: u64 = 4096;
+
+/// Body of a request, refused with 413 beyond `MAX_BODY` rather than read
+/// whole in memory.
+fn read_body(reader: impl Read) -> Result<String, (u16, Json)> {
+    let mut body = String::new();
+    match reader.take(MAX_BODY + 1).read_to_string(&mut body) {
+        Ok(read) if read as u64 > MAX_BODY => Err(error(413, "body too large")),
+        Ok(_) => Ok(body),
+        Err(e) => Err(error(400, &e.to_string())),
+    }
+}
+
+/// Prefix of the routes, the version changing with incompatible changes.
+const BASE: &str = "/api/v1";
+
+enum Route {
+    /// The configuration, or one of its settings
+    Settings(Option<String>),
+    History {
+        label: Option<String>,
+        since: Option<String>,
+    },
+    OpenApi,
+}
+
+fn route(url: &str) -> Option<Route> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let parameter = |name: &str| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .map(String::from)
+    };
+    match path.strip_prefix(BASE)? {
+        "/settings" | "/settings/" => Some(Route::Settings(None)),
+        "/history" => Some(Route::History {
+            label: parameter("label"),
+            since: parameter("since"),
+        }),
+        "/openapi.json" => Some(Route::OpenApi),
+        path => path
+            .strip_prefix("/settings/")
+            .filter(|key| !key.is_empty())
+            .map(|key| Route::Settings(Some(key.to_string()))),
+    }
+}
+
+fn openapi() -> Json {
+    serde_json::from_str(include_str!("openapi.json")).expect("valid OpenAPI document")
+}
+
+fn error(status: u16, message: &str) -> (u16, Json) {
+    (status, json!({ "error": message }))
+}
+
+/// Whether two byte strings are equal, in a time that does not depend on
+/// where they differ.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn without_secrets(table: &mut Table) {
+    table.retain(|name, _| !config::is_secret(name));
+    for (_, value) in table.iter_mut() {
+        match value {
+            Value::Table(table) => without_secrets(table),
+            Value::Array(values) => {
+                for value in values {
+                    if let Value::Table(table) = value {
+                        without_secrets(table);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets() {
+        let mut table: Table = "[mqtt]\nhost = \"broker\"\npassword = \"secret\"\n\
+                                [[schedule]]\njob = \"command\"\ntoken = \"secret\"\n"
+            .parse()
+            .unwrap();
+        without_secrets(&mut table);
+        assert_eq!(
+            table.to_string(),
+            "[mqtt]\nhost = \"broker\"\n\n[[schedule]]\njob = \"command\"\n"
+        );
+    }
+
+    #[test]
+    fn settings_changed() {
+        let (_, settings) = watch::channel(Vec::new());
+        let (commands, mut received) = tokio::sync::mpsc::channel(1);
+        let mut api = Api {
+            token: None,
+            writable: false,
+            path: None,
+            settings,
+            commands,
+            history: Arc::new(Mutex::new(History::new(10))),
+        };
+        let (status, _) = api.put(String::from("anomaly.high_power"), "6000");
+        assert_eq!(status, 403);
+
+        api.writable = true;
+        for key in ["serial.gap_command", "influxdb.token", "anomaly.command"] {
+            let (status, _) = api.put(String::from(key), "\"touch /tmp/owned\"");
+            assert_eq!(status, 403, "{}", key);
+        }
+        assert!(received.try_recv().is_err());
+        let (status, _) = api.put(String::from("anomaly.high_power"), "6000");
+        assert_eq!(status, 200);
+        assert_eq!(
+            received.try_recv(),
+            Ok(Command::Set {
+                key: String::from("anomaly.high_power"),
+                value: Value::Integer(6000)
+            })
+        );
+    }
+
+    #[test]
+    fn openapi_paths() {
+        let document = openapi();
+        assert_eq!(document["servers"][0]["url"], BASE);
+        let paths = document["paths"].as_object().unwrap();
+        assert_eq!(paths.len(), 4);
+        for (path, methods) in paths {
+            let url = format!("{}{}", BASE, path.replace("{key}", "anomaly.high_power"));
+            let route = route(&url).unwrap_or_else(|| panic!("no route for {}", path));
+            let expected: &[&str] = match route {
+                Route::Settings(None) | Route::History { .. } | Route::OpenApi => &["get"],
+                Route::Settings(Some(_)) => &["get", "parameters", "put"],
+            };
+            let methods: Vec<&str> = methods
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(|m| m.as_str())
+                .collect();
+            assert_eq!(methods, expected, "{}", path);
+        }
+        assert!(route("/api/v1/settings/anomaly.high_power?pretty").is_some());
+        assert!(route("/settings").is_none());
+        assert!(route("/api/v1/statistics").is_none());
+    }
+
+    #[test]
+    fn recent_values() {
+        let (_, settings) = watch::channel(Vec::new());
+        let (commands, _) = tokio::sync::mpsc::channel(1);
+        let api = Api {
+            token: None,
+            writable: false,
+            path: None,
+            settings,
+            commands,
+            history: Arc::new(Mutex::new(History::new(10))),
+        };
+        let at = |second: i64| DateTime::from_timestamp(1_700_000_000 + second, 0).unwrap();
+        for (second, value) in [(0, 803), (1, 1250)] {
+            let message = pitinfo_parser::Message::ApparentPower { value };
+            api.history.lock().unwrap().record(&message, at(second));
+        }
+        let message = pitinfo_parser::Message::InstantaneousPower { phase: 1, value: 4 };
+        api.history.lock().unwrap().record(&message, at(1));
+
+        let Some(Route::History { label, since }) =
+            route("/api/v1/history?label=PAPP&since=1700000000")
+        else {
+            panic!("no history route");
+        };
+        assert_eq!(
+            api.history(label, since),
+            (
+                200,
+                json!({ "PAPP": [{ "time": "2023-11-14T22:13:21Z", "value": 1250 }] })
+            )
+        );
+        assert_eq!(
+            api.history(None, None).1["IINST1"],
+            json!([{ "time": "2023-11-14T22:13:21Z", "value": 4 }])
+        );
+        assert_eq!(api.history(None, Some(String::from("yesterday"))).0, 400);
+    }
+
+    #[test]
+    fn body_size() {
+        let body = "1".repeat(MAX_BODY as usize);
+        assert_eq!(read_body(body.as_bytes()), Ok(body.clone()));
+        let body = body + "0";
+        assert_eq!(read_body(body.as_bytes()).unwrap_err().0, 413);
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(same(b"Bearer change-me", b"Bearer change-me"));
+        assert!(!same(b"Bearer change-me", b"Bearer change-it"));
+        assert!(!same(b"Bearer change-me", b"Bearer change"));
+    }
+}
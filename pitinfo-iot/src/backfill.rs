@@ -0,0 +1,119 @@
+//! Replay of the stored history into a sink added after the data collection
+//! started.
+//!
+//! Days still having raw readings are replayed at full resolution, older days
+//! from the last value of each minute aggregate. Points are timestamped, so
+//! an interrupted backfill can simply be run again.
+
+use crate::influxdb::Client;
+use crate::state::Value;
+use crate::storage::{Aggregate, Reading, Resolution, Store};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Number of points sent per request.
+const BATCH_SIZE: usize = 5000;
+
+/// Replays the days from `from`, or the whole history, returning the number
+/// of points written.
+pub fn backfill(
+    store: &Store,
+    client: &Client,
+    from: Option<NaiveDate>,
+) -> Result<usize, Box<dyn Error>> {
+    let mut days = store.history_days()?;
+    days.retain(|day| from.is_none_or(|from| *day >= from));
+    let mut total = 0;
+    for (done, day) in days.iter().enumerate() {
+        let readings = store.day_readings(*day)?;
+        let points = if readings.is_empty() {
+            minute_points(&store.aggregates(
+                Resolution::Minute,
+                store.day_start(*day).timestamp_millis(),
+                day_end(store, *day).timestamp_millis(),
+            )?)
+        } else {
+            points(&readings)
+        };
+        let lines: Vec<String> = points
+            .iter()
+            .map(|(timestamp, values)| client.line(*timestamp, values))
+            .collect();
+        for batch in lines.chunks(BATCH_SIZE) {
+            client.write(batch)?;
+        }
+        total += lines.len();
+        println!(
+            "{}: {} points ({}/{} days)",
+            day,
+            lines.len(),
+            done + 1,
+            days.len()
+        );
+    }
+    Ok(total)
+}
+
+fn day_end(store: &Store, day: NaiveDate) -> DateTime<Utc> {
+    store.day_start(day.succ_opt().unwrap_or(day))
+}
+
+/// Groups the readings stored together.
+fn points(readings: &[Reading]) -> BTreeMap<DateTime<Utc>, Vec<(String, Value)>> {
+    let mut points: BTreeMap<DateTime<Utc>, Vec<(String, Value)>> = BTreeMap::new();
+    for reading in readings {
+        points
+            .entry(reading.timestamp)
+            .or_default()
+            .push((reading.label.clone(), reading.value.clone()));
+    }
+    points
+}
+
+fn minute_points(
+    aggregates: &[(DateTime<Utc>, String, Aggregate)],
+) -> BTreeMap<DateTime<Utc>, Vec<(String, Value)>> {
+    let mut points: BTreeMap<DateTime<Utc>, Vec<(String, Value)>> = BTreeMap::new();
+    for (timestamp, label, aggregate) in aggregates {
+        let value = match (aggregate.last, &aggregate.text) {
+            (Some(last), _) => Value::Integer(last as u64),
+            (None, Some(text)) => Value::Text(text.clone()),
+            (None, None) => continue,
+        };
+        points
+            .entry(*timestamp)
+            .or_default()
+            .push((label.clone(), value));
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn grouped_points() {
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 16, 12, 0, 0).unwrap();
+        let reading = |seconds, label: &str, value| Reading {
+            timestamp: timestamp + Duration::seconds(seconds),
+            label: String::from(label),
+            value: Value::Integer(value),
+        };
+        let points = points(&[
+            reading(0, "PAPP", 800),
+            reading(0, "IINST1", 4),
+            reading(10, "PAPP", 1000),
+        ]);
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            points[&timestamp],
+            vec![
+                (String::from("PAPP"), Value::Integer(800)),
+                (String::from("IINST1"), Value::Integer(4)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Raw teleinformation received over MQTT.
+//!
+//! ESP based dongles, like the Denky or LiXee ones in passthrough mode,
+//! publish the raw TIC text on a topic instead of decoding it. Their
+//! messages are handed to the parser like the bytes of the serial port, so
+//! that the same sinks and alerts serve every meter.
+
+use crate::config::MqttConfig;
+use crate::mqtt;
+use crate::pipeline::{Chunk, Chunks};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::io;
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time;
+
+/// Messages waiting to be parsed.
+const CHUNK_CAPACITY: usize = 100;
+
+/// Subscribes to `topic` on the broker of the MQTT configuration, reading
+/// its messages in a dedicated task.
+pub fn spawn_reader(config: &MqttConfig, topic: &str) -> Result<Chunks, io::Error> {
+    let options = mqtt::options(config, &format!("{}-source", config.client_id))?;
+    let (client, mut event_loop) = AsyncClient::new(options, mqtt::REQUEST_CAPACITY);
+    let topic = topic.to_string();
+    let (sender, receiver) = mpsc::channel(CHUNK_CAPACITY);
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    if let Err(e) = client.try_subscribe(&topic, QoS::AtMostOnce) {
+                        eprintln!("Unable to subscribe to {}: {}", topic, e);
+                    }
+                }
+                // Retained messages are stale groups
+                Ok(Event::Incoming(Packet::Publish(publish))) if !publish.retain => {
+                    let chunk = Chunk {
+                        received: Instant::now(),
+                        bytes: framed(&publish.payload),
+                        start: None,
+                    };
+                    match sender.try_send(chunk) {
+                        Ok(()) => (),
+                        Err(TrySendError::Full(chunk)) => eprintln!(
+                            "Parsing is behind, dropping '{}'",
+                            String::from_utf8_lossy(&chunk.bytes)
+                        ),
+                        Err(TrySendError::Closed(_)) => return,
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("MQTT source connection error: {}", e);
+                    time::sleep(mqtt::RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Bytes of a message as read from a serial port: messages holding whole
+/// frames are kept as they are, and groups without frame delimiters are
+/// framed on their own.
+fn framed(payload: &[u8]) -> Vec<u8> {
+    if payload.iter().any(|byte| matches!(byte, b'\x02' | b'\x03')) {
+        return payload.to_vec();
+    }
+    let mut bytes = b"\x02\n".to_vec();
+    bytes.extend(payload);
+    bytes.extend(b"\n\x03");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_frames() {
+        assert_eq!(framed(b"PAPP 00803 ."), b"\x02\nPAPP 00803 .\n\x03");
+        let frame = b"\x02\nADCO 031762120110 @\r\nPAPP 00803 .\r\x03";
+        assert_eq!(framed(frame), frame);
+    }
+}
@@ -0,0 +1,143 @@
+//! Sanity of the system clock before timestamping readings.
+//!
+//! The Pi has no real-time clock: until NTP synchronizes it at boot, the time
+//! is the one saved at the last shutdown, possibly days or years off. Sinks
+//! persisting timestamps hold their values back until the clock is
+//! synchronized, then stamp them from the monotonic clock, which is right
+//! from the start.
+
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Values held back while the clock is not synchronized, a day of one per
+/// minute.
+const PENDING_CAPACITY: usize = 1440;
+/// Earlier times are surely wrong, 2024-01-01.
+const MIN_VALID_TIMESTAMP: i64 = 1_704_067_200;
+
+/// Timestamps values once the clock is synchronized.
+#[derive(Debug)]
+pub struct Stamper<T> {
+    wait_for_sync: bool,
+    synchronized: bool,
+    /// Values held back, with the instant they were taken
+    pending: VecDeque<(Instant, T)>,
+    dropped: u64,
+}
+
+impl<T> Stamper<T> {
+    pub fn new(wait_for_sync: bool) -> Stamper<T> {
+        Stamper {
+            wait_for_sync,
+            synchronized: false,
+            pending: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Whether the system time can be trusted. Once synchronized, NTP only
+    /// slews the clock, so it is not checked anymore.
+    pub fn synchronized(&mut self) -> bool {
+        if !self.synchronized {
+            self.synchronized = !self.wait_for_sync
+                || (Utc::now().timestamp() >= MIN_VALID_TIMESTAMP && system_synchronized());
+        }
+        self.synchronized
+    }
+
+    /// Whether values are held back.
+    pub fn holding(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Timestamps a value taken now. Until the clock is synchronized, values
+    /// are held back and nothing is returned, then they are all returned with
+    /// the time they were taken at.
+    pub fn stamp(&mut self, value: T) -> Vec<(DateTime<Utc>, T)> {
+        let synchronized = self.synchronized();
+        self.push(value, synchronized, Instant::now(), Utc::now())
+    }
+
+    /// Timestamps a value taken at `instant`, `now` in system time.
+    fn push(
+        &mut self,
+        value: T,
+        synchronized: bool,
+        instant: Instant,
+        now: DateTime<Utc>,
+    ) -> Vec<(DateTime<Utc>, T)> {
+        if !synchronized {
+            if self.pending.is_empty() {
+                println!("The clock is not synchronized yet, holding values back");
+            }
+            if self.pending.len() >= PENDING_CAPACITY {
+                self.pending.pop_front();
+                self.dropped += 1;
+            }
+            self.pending.push_back((instant, value));
+            return Vec::new();
+        }
+        if !self.pending.is_empty() {
+            println!(
+                "The clock is synchronized, timestamping {} values held back, {} dropped",
+                self.pending.len(),
+                self.dropped
+            );
+            self.dropped = 0;
+        }
+        self.pending
+            .drain(..)
+            .chain([(instant, value)])
+            .map(|(taken, value)| {
+                let age = chrono::Duration::from_std(instant - taken).unwrap_or_default();
+                (now - age, value)
+            })
+            .collect()
+    }
+}
+
+/// Whether the kernel considers the clock synchronized, as shown by
+/// `timedatectl`.
+#[cfg(target_os = "linux")]
+fn system_synchronized() -> bool {
+    // Without any mode set, adjtimex only reads the state of the clock
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    unsafe { libc::adjtimex(&mut timex) != libc::TIME_ERROR }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn system_synchronized() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::Duration;
+
+    #[test]
+    fn held_back_values() {
+        let mut stamper = Stamper::new(true);
+        let start = Instant::now();
+        // Restored from the last shutdown
+        let stale = Utc.with_ymd_and_hms(2024, 1, 16, 12, 0, 0).unwrap();
+        for (seconds, value) in [(0, 803), (2, 1250)] {
+            let instant = start + Duration::from_secs(seconds);
+            assert!(stamper.push(value, false, instant, stale).is_empty());
+        }
+
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 8, 0, 0).unwrap();
+        let stamped = stamper.push(2430, true, start + Duration::from_secs(5), now);
+        assert_eq!(
+            stamped,
+            vec![
+                (now - chrono::Duration::seconds(5), 803),
+                (now - chrono::Duration::seconds(3), 1250),
+                (now, 2430)
+            ]
+        );
+        assert!(stamper.pending.is_empty());
+    }
+}
@@ -0,0 +1,486 @@
+//! CoAP server exposing the latest values to constrained devices.
+//!
+//! Every group is a resource named after its label in lower case, e.g.
+//! `/papp` or `/ptec`, with the indexes grouped under `/index`, e.g.
+//! `/index/bbrhcjb`. Values are sent as plain text. Clients can observe a
+//! resource (RFC 7641) to be notified when its value changes instead of
+//! polling, and `/.well-known/core` lists the resources received so far.
+
+use crate::config::CoapConfig;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{is_index, MeterState, Update};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+const VERSION: u8 = 1;
+
+const CONFIRMABLE: u8 = 0;
+const NON_CONFIRMABLE: u8 = 1;
+const ACKNOWLEDGEMENT: u8 = 2;
+const RESET: u8 = 3;
+
+const EMPTY: u8 = 0x00;
+const GET: u8 = 0x01;
+const CONTENT: u8 = 0x45;
+const NOT_FOUND: u8 = 0x84;
+const METHOD_NOT_ALLOWED: u8 = 0x85;
+
+const OBSERVE: u16 = 6;
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+
+const TEXT_PLAIN: u32 = 0;
+const LINK_FORMAT: u32 = 40;
+
+/// Observe value of a registration, any other value deregisters.
+const REGISTER: u32 = 0;
+
+const PAYLOAD_MARKER: u8 = 0xFF;
+/// Largest datagram expected, as recommended when the path MTU is unknown.
+const MAX_DATAGRAM: usize = 1152;
+/// Observers kept at most, further clients get a single response.
+const MAX_OBSERVERS: usize = 64;
+const DISCOVERY_PATH: &str = ".well-known/core";
+
+/// Starts the server. Messages sent to the returned sink update the
+/// resources and notify their observers.
+pub fn spawn(config: &CoapConfig) -> Result<Sink, io::Error> {
+    let socket = std::net::UdpSocket::bind(&config.listen)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    Ok(pipeline::spawn_sink("coap", |receiver| {
+        run(socket, receiver)
+    }))
+}
+
+async fn run(socket: UdpSocket, mut receiver: Inbox<Update>) {
+    let mut server = Server::default();
+    let mut buffer = [0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            item = receiver.recv() => {
+                let Some(item) = item else {
+                    return;
+                };
+                for (address, notification) in server.update(&item) {
+                    send(&socket, &notification, address).await;
+                }
+            }
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok((length, address)) => {
+                    let response = Packet::decode(&buffer[..length])
+                        .and_then(|request| server.handle(&request, address));
+                    if let Some(response) = response {
+                        send(&socket, &response, address).await;
+                    }
+                }
+                Err(e) => eprintln!("CoAP receive error: {}", e),
+            }
+        }
+    }
+}
+
+async fn send(socket: &UdpSocket, packet: &Packet, address: SocketAddr) {
+    if let Err(e) = socket.send_to(&packet.encode(), address).await {
+        eprintln!("Unable to send CoAP message to {}: {}", address, e);
+    }
+}
+
+/// Options by increasing number, with their value.
+type Options = Vec<(u16, Vec<u8>)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: u8,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    pub options: Options,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Parses a datagram, `None` when it is not a valid CoAP message.
+    pub fn decode(datagram: &[u8]) -> Option<Packet> {
+        let (&first, rest) = datagram.split_first()?;
+        if first >> 6 != VERSION {
+            return None;
+        }
+        let token_length = (first & 0x0F) as usize;
+        if token_length > 8 || rest.len() < 3 + token_length {
+            return None;
+        }
+        let code = rest[0];
+        let message_id = u16::from_be_bytes([rest[1], rest[2]]);
+        let token = rest[3..3 + token_length].to_vec();
+
+        let mut bytes = &rest[3 + token_length..];
+        let mut options = Vec::new();
+        let mut number = 0u16;
+        let mut payload = Vec::new();
+        while let Some((&header, rest)) = bytes.split_first() {
+            if header == PAYLOAD_MARKER {
+                if rest.is_empty() {
+                    return None;
+                }
+                payload = rest.to_vec();
+                break;
+            }
+            let (delta, rest) = option_field(header >> 4, rest)?;
+            let (length, rest) = option_field(header & 0x0F, rest)?;
+            number = number.checked_add(delta)?;
+            let value = rest.get(..length as usize)?;
+            options.push((number, value.to_vec()));
+            bytes = &rest[length as usize..];
+        }
+
+        Some(Packet {
+            kind: (first >> 4) & 0x03,
+            code,
+            message_id,
+            token,
+            options,
+            payload,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut datagram = vec![
+            VERSION << 6 | self.kind << 4 | self.token.len() as u8,
+            self.code,
+        ];
+        datagram.extend_from_slice(&self.message_id.to_be_bytes());
+        datagram.extend_from_slice(&self.token);
+        let mut number = 0;
+        for (option, value) in &self.options {
+            let (delta, delta_extension) = option_nibble(option - number);
+            let (length, length_extension) = option_nibble(value.len() as u16);
+            datagram.push(delta << 4 | length);
+            datagram.extend_from_slice(&delta_extension);
+            datagram.extend_from_slice(&length_extension);
+            datagram.extend_from_slice(value);
+            number = *option;
+        }
+        if !self.payload.is_empty() {
+            datagram.push(PAYLOAD_MARKER);
+            datagram.extend_from_slice(&self.payload);
+        }
+        datagram
+    }
+
+    fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == number)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Segments of the Uri-Path options joined with `/`.
+    fn path(&self) -> String {
+        let segments: Vec<String> = self
+            .options
+            .iter()
+            .filter(|(option, _)| *option == URI_PATH)
+            .map(|(_, segment)| String::from_utf8_lossy(segment).into_owned())
+            .collect();
+        segments.join("/")
+    }
+}
+
+/// Option delta or length, with the extended forms of 13 and 14.
+fn option_field(nibble: u8, bytes: &[u8]) -> Option<(u16, &[u8])> {
+    match nibble {
+        13 => Some((*bytes.first()? as u16 + 13, &bytes[1..])),
+        14 => {
+            let extension = bytes.get(..2)?;
+            let value = u16::from_be_bytes([extension[0], extension[1]]).checked_add(269)?;
+            Some((value, &bytes[2..]))
+        }
+        15 => None,
+        nibble => Some((nibble as u16, bytes)),
+    }
+}
+
+fn option_nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// Unsigned integer option value: big endian, without leading zeros.
+fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(4);
+    bytes[start..].to_vec()
+}
+
+fn decode_uint(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .take(4)
+        .fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+/// Path of the resource of a label, e.g. `papp` or `index/bbrhcjb`.
+fn resource_path(label: &str) -> String {
+    if is_index(label) {
+        format!("index/{}", label.to_lowercase())
+    } else {
+        label.to_lowercase()
+    }
+}
+
+struct Observer {
+    address: SocketAddr,
+    token: Vec<u8>,
+    path: String,
+    /// Value of the last notification
+    value: String,
+    /// Message id of the last notification, which the client resets to
+    /// cancel the observation
+    message_id: u16,
+}
+
+#[derive(Default)]
+struct Server {
+    state: MeterState,
+    observers: Vec<Observer>,
+    message_id: u16,
+    /// Observe sequence number, on 24 bits
+    sequence: u32,
+}
+
+impl Server {
+    fn value(&self, path: &str) -> Option<String> {
+        self.state
+            .values()
+            .into_iter()
+            .find(|(label, _)| resource_path(label) == path)
+            .map(|(_, value)| value.to_string())
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence = (self.sequence + 1) & 0xFF_FFFF;
+        self.sequence
+    }
+
+    /// Updates the resources, returns the notifications of the observers of
+    /// the values that changed.
+    fn update(&mut self, item: &Update) -> Vec<(SocketAddr, Packet)> {
+        if item.label_value().is_none() {
+            return Vec::new();
+        }
+        self.state.record(item);
+
+        let mut notifications = Vec::new();
+        for index in 0..self.observers.len() {
+            let value = match self.value(&self.observers[index].path) {
+                Some(value) if value != self.observers[index].value => value,
+                _ => continue,
+            };
+            let message_id = self.next_message_id();
+            let sequence = self.next_sequence();
+            let observer = &mut self.observers[index];
+            notifications.push((
+                observer.address,
+                Packet {
+                    kind: NON_CONFIRMABLE,
+                    code: CONTENT,
+                    message_id,
+                    token: observer.token.clone(),
+                    options: vec![
+                        (OBSERVE, encode_uint(sequence)),
+                        (CONTENT_FORMAT, encode_uint(TEXT_PLAIN)),
+                    ],
+                    payload: value.clone().into_bytes(),
+                },
+            ));
+            observer.value = value;
+            observer.message_id = message_id;
+        }
+        notifications
+    }
+
+    /// Answers a request, `None` for messages that need no answer.
+    fn handle(&mut self, request: &Packet, address: SocketAddr) -> Option<Packet> {
+        match (request.kind, request.code) {
+            (RESET, _) => {
+                // Cancels the observation the rejected notification belongs to
+                self.observers.retain(|observer| {
+                    observer.address != address || observer.message_id != request.message_id
+                });
+                None
+            }
+            // Ping
+            (CONFIRMABLE, EMPTY) => Some(Packet {
+                kind: RESET,
+                code: EMPTY,
+                message_id: request.message_id,
+                token: Vec::new(),
+                options: Vec::new(),
+                payload: Vec::new(),
+            }),
+            // Only requests, of class 0, are answered
+            (CONFIRMABLE | NON_CONFIRMABLE, code) if code >> 5 == 0 => {
+                let (code, options, payload) = self.respond(request, address);
+                let (kind, message_id) = if request.kind == CONFIRMABLE {
+                    (ACKNOWLEDGEMENT, request.message_id)
+                } else {
+                    (NON_CONFIRMABLE, self.next_message_id())
+                };
+                Some(Packet {
+                    kind,
+                    code,
+                    message_id,
+                    token: request.token.clone(),
+                    options,
+                    payload,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn respond(&mut self, request: &Packet, address: SocketAddr) -> (u8, Options, Vec<u8>) {
+        if request.code != GET {
+            return (METHOD_NOT_ALLOWED, Vec::new(), Vec::new());
+        }
+        let path = request.path();
+        if path == DISCOVERY_PATH {
+            let links: Vec<String> = self
+                .state
+                .values()
+                .iter()
+                .map(|(label, _)| format!("</{}>;ct=0;obs", resource_path(label)))
+                .collect();
+            return (
+                CONTENT,
+                vec![(CONTENT_FORMAT, encode_uint(LINK_FORMAT))],
+                links.join(",").into_bytes(),
+            );
+        }
+        let Some(value) = self.value(&path) else {
+            return (NOT_FOUND, Vec::new(), Vec::new());
+        };
+
+        // A new request of the same client replaces its observation, or
+        // cancels it without the Observe option
+        let mut options = Vec::new();
+        self.observers
+            .retain(|observer| observer.address != address || observer.token != request.token);
+        match request.option(OBSERVE).map(decode_uint) {
+            Some(REGISTER) if self.observers.len() < MAX_OBSERVERS => {
+                self.observers.push(Observer {
+                    address,
+                    token: request.token.clone(),
+                    path,
+                    value: value.clone(),
+                    message_id: request.message_id,
+                });
+                options.push((OBSERVE, encode_uint(self.next_sequence())));
+            }
+            Some(REGISTER) => eprintln!("Too many CoAP observers, ignoring {}", address),
+            _ => (),
+        }
+        options.push((CONTENT_FORMAT, encode_uint(TEXT_PLAIN)));
+        (CONTENT, options, value.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::Message;
+
+    fn get(message_id: u16, path: &str, observe: Option<u32>) -> Packet {
+        let mut options = Vec::new();
+        if let Some(observe) = observe {
+            options.push((OBSERVE, encode_uint(observe)));
+        }
+        for segment in path.split('/') {
+            options.push((URI_PATH, segment.as_bytes().to_vec()));
+        }
+        Packet {
+            kind: CONFIRMABLE,
+            code: GET,
+            message_id,
+            token: vec![0x71],
+            options,
+            payload: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let request = get(0x1234, "index/bbrhcjb", Some(REGISTER));
+        let datagram = [
+            &[0x41, 0x01, 0x12, 0x34, 0x71, 0x60, 0x55][..],
+            b"index",
+            &[0x07],
+            b"bbrhcjb",
+        ]
+        .concat();
+        assert_eq!(request.encode(), datagram);
+        assert_eq!(Packet::decode(&datagram), Some(request));
+
+        let response = Packet {
+            kind: ACKNOWLEDGEMENT,
+            code: CONTENT,
+            message_id: 0x1234,
+            token: Vec::new(),
+            options: vec![(OBSERVE, encode_uint(300)), (300, vec![0; 20])],
+            payload: b"803".to_vec(),
+        };
+        assert_eq!(Packet::decode(&response.encode()), Some(response));
+        assert_eq!(Packet::decode(&[0x41, 0x01, 0x12]), None);
+        assert_eq!(Packet::decode(&[0x40, 0x01, 0x12, 0x34, 0xFF]), None);
+    }
+
+    #[test]
+    fn observation() {
+        let client: SocketAddr = "192.168.1.30:5683".parse().unwrap();
+        let mut server = Server::default();
+        server.update(&Message::ApparentPower { value: 803 }.into());
+
+        let response = server
+            .handle(&get(1, "papp", Some(REGISTER)), client)
+            .unwrap();
+        assert_eq!((response.kind, response.code), (ACKNOWLEDGEMENT, CONTENT));
+        assert!(response.option(OBSERVE).is_some());
+        assert_eq!(response.payload, b"803");
+        let response = server.handle(&get(2, "index/bbrhcjb", None), client);
+        assert_eq!(response.unwrap().code, NOT_FOUND);
+
+        let notifications = server.update(&Message::ApparentPower { value: 1250 }.into());
+        assert_eq!(notifications.len(), 1);
+        let (address, notification) = &notifications[0];
+        assert_eq!(*address, client);
+        assert_eq!(notification.token, vec![0x71]);
+        assert_eq!(notification.payload, b"1250");
+        // Only changes are notified
+        assert!(server
+            .update(&Message::ApparentPower { value: 1250 }.into())
+            .is_empty());
+
+        let reset = Packet {
+            kind: RESET,
+            code: EMPTY,
+            message_id: notification.message_id,
+            token: Vec::new(),
+            options: Vec::new(),
+            payload: Vec::new(),
+        };
+        assert_eq!(server.handle(&reset, client), None);
+        assert!(server
+            .update(&Message::ApparentPower { value: 2430 }.into())
+            .is_empty());
+    }
+}
@@ -0,0 +1,54 @@
+//! Commands received remotely, on the MQTT command topic.
+//!
+//! Commands are JSON objects named by their `command` attribute:
+//!
+//! - `{"command": "republish"}` publishes the discovery messages and the
+//!   whole state again;
+//! - `{"command": "reload"}` reads the configuration file again and restarts
+//!   the sinks;
+//! - `{"command": "set", "key": "anomaly.high_power", "value": 6000}` changes
+//!   a threshold, rule or interval allowed by `config::check_remote`, kept
+//!   over the configuration file until the daemon stops, and restarts the
+//!   sinks;
+//! - `{"command": "sync"}` sends the stored history to the remote sinks, see
+//!   the `sync` module.
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Republish,
+    Reload,
+    Set { key: String, value: toml::Value },
+    Sync,
+}
+
+impl Command {
+    pub fn parse(payload: &[u8]) -> Result<Command, String> {
+        serde_json::from_slice(payload).map_err(|e| format!("invalid command: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            Command::parse(br#"{"command": "republish"}"#),
+            Ok(Command::Republish)
+        );
+        assert_eq!(
+            Command::parse(br#"{"command": "set", "key": "anomaly.high_power", "value": 6000}"#),
+            Ok(Command::Set {
+                key: String::from("anomaly.high_power"),
+                value: toml::Value::Integer(6000)
+            })
+        );
+        assert_eq!(Command::parse(br#"{"command": "sync"}"#), Ok(Command::Sync));
+        assert!(Command::parse(br#"{"command": "relay"}"#).is_err());
+        assert!(Command::parse(b"reload").is_err());
+    }
+}
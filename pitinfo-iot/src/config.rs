@@ -0,0 +1,1754 @@
+use crate::pipeline::DataClass;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tokio_serial::{Parity, SerialPortType};
+use toml::{Table, Value};
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub serial: SerialConfig,
+    pub clock: ClockConfig,
+    pub modbus_tcp: Option<ModbusTcpConfig>,
+    pub modbus_rtu: Option<ModbusRtuConfig>,
+    pub coap: Option<CoapConfig>,
+    pub knx: Option<KnxConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub tempo: Option<TempoConfig>,
+    pub enedis: Option<EnedisConfig>,
+    pub ecowatt: Option<EcowattConfig>,
+    pub storage: Option<StorageConfig>,
+    pub influxdb: Option<InfluxDbConfig>,
+    pub grafana_live: Option<GrafanaLiveConfig>,
+    pub home_assistant: Option<HomeAssistantConfig>,
+    pub pushgateway: Option<PushgatewayConfig>,
+    pub emoncms: Option<EmoncmsConfig>,
+    pub pvoutput: Option<PvOutputConfig>,
+    pub thingsboard: Option<ThingsBoardConfig>,
+    pub openhab: Option<OpenHabConfig>,
+    pub dbus: Option<DbusConfig>,
+    pub snmp: Option<SnmpConfig>,
+    pub anomaly: Option<AnomalyConfig>,
+    pub nilm: Option<NilmConfig>,
+    pub loki: Option<LokiConfig>,
+    pub imax: Option<ImaxConfig>,
+    pub forecast: Option<ForecastConfig>,
+    pub offpeak: Option<OffPeakConfig>,
+    pub production: Option<ProductionConfig>,
+    pub fleet: Option<FleetConfig>,
+    pub latency: Option<LatencyConfig>,
+    pub proxy: Option<ProxyConfig>,
+    pub api: Option<ApiConfig>,
+    pub email: Option<EmailConfig>,
+    /// Expressions of the derived metrics, by name
+    pub derived: BTreeMap<String, String>,
+    /// Periodic jobs run by the daemon
+    pub schedule: Vec<ScheduleConfig>,
+    /// Language of the sensor names and of the reports
+    pub language: Language,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    Fr,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SerialConfig {
+    pub port: String,
+    pub baud_rate: u32,
+    /// Address of the meter expected on the port, in ADCO
+    pub meter: Option<String>,
+    /// Seconds without a valid frame before reporting a gap, 0 disables it
+    pub gap_timeout: u64,
+    /// Shell command run when a gap starts and ends
+    pub gap_command: Option<String>,
+    /// Seconds without any line before opening the port again, 0 disables it
+    pub stall_timeout: u64,
+    /// Topic of the raw TIC published by a bridge, read on the broker of the
+    /// `[mqtt]` section instead of the port
+    pub mqtt_topic: Option<String>,
+    /// Percentage of groups in error beyond which other serial framings are
+    /// tried, 0 disables it
+    pub reframe_error_rate: u8,
+    /// Labels to parse, e.g. `PAPP`, the other groups are skipped; empty
+    /// parses them all
+    pub labels: Vec<String>,
+    /// Raw bytes dumped before and after the groups that cannot be parsed, 0
+    /// disables it
+    pub error_context: usize,
+    /// Whether groups with a wrong checksum are rejected, disabled for links
+    /// that alter it
+    pub checksum: bool,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            port: default_serial_port(),
+            baud_rate: 1200,
+            meter: None,
+            gap_timeout: 30,
+            gap_command: None,
+            stall_timeout: 15,
+            mqtt_topic: None,
+            reframe_error_rate: 50,
+            labels: Vec::new(),
+            error_context: 32,
+            checksum: true,
+        }
+    }
+}
+
+/// The PiTInfo hat sits on the UART of the Pi. Elsewhere, typically on a
+/// development machine, the first USB adapter found is used.
+fn default_serial_port() -> String {
+    if cfg!(target_os = "linux") {
+        return String::from("/dev/ttyAMA0");
+    }
+    tokio_serial::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        // macOS lists each adapter twice, the callout device does not wait
+        // for the carrier
+        .filter(|port| !port.port_name.starts_with("/dev/tty."))
+        .find(|port| matches!(port.port_type, SerialPortType::UsbPort(_)))
+        .map(|port| port.port_name)
+        .unwrap_or_else(|| {
+            String::from(if cfg!(windows) {
+                "COM3"
+            } else {
+                "/dev/cu.usbserial"
+            })
+        })
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ClockConfig {
+    /// Time zone of the day boundaries, e.g. of the daily aggregates and of
+    /// the Tempo days
+    pub timezone: Tz,
+    /// Whether to hold the values to store back until the system clock is
+    /// synchronized
+    pub wait_for_sync: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            timezone: chrono_tz::Europe::Paris,
+            wait_for_sync: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModbusTcpConfig {
+    #[serde(default = "default_modbus_tcp_listen")]
+    pub listen: String,
+}
+
+fn default_modbus_tcp_listen() -> String {
+    String::from("0.0.0.0:502")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProxyConfig {
+    #[serde(default = "default_proxy_listen")]
+    pub listen: String,
+}
+
+fn default_proxy_listen() -> String {
+    String::from("0.0.0.0:2001")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiConfig {
+    #[serde(default = "default_api_listen")]
+    pub listen: String,
+    /// Bearer token required by the requests, and to listen beyond the
+    /// loopback interface
+    pub token: Option<String>,
+    /// Whether settings can be changed, the API being read-only by default
+    #[serde(default)]
+    pub writable: bool,
+    /// Certificate chain and private key, in PEM, to serve over TLS with the
+    /// `api-tls` feature
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Values of the currents and of the apparent power kept for
+    /// `/api/v1/history`, per label
+    #[serde(default = "default_api_history")]
+    pub history: usize,
+}
+
+impl ApiConfig {
+    /// Whether the API only listens on the loopback interface.
+    fn is_local(&self) -> bool {
+        self.listen
+            .to_socket_addrs()
+            .map(|mut addresses| addresses.all(|address| address.ip().is_loopback()))
+            .unwrap_or(false)
+    }
+}
+
+fn default_api_listen() -> String {
+    String::from("127.0.0.1:8080")
+}
+
+/// About an hour of frames
+fn default_api_history() -> usize {
+    3600
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModbusRtuConfig {
+    pub port: String,
+    #[serde(default = "default_modbus_rtu_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub parity: ParityConfig,
+    #[serde(default = "default_modbus_rtu_unit_id")]
+    pub unit_id: u8,
+}
+
+fn default_modbus_rtu_baud_rate() -> u32 {
+    9600
+}
+
+fn default_modbus_rtu_unit_id() -> u8 {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CoapConfig {
+    #[serde(default = "default_coap_listen")]
+    pub listen: String,
+}
+
+fn default_coap_listen() -> String {
+    String::from("0.0.0.0:5683")
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ParityConfig {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+
+impl From<ParityConfig> for Parity {
+    fn from(parity: ParityConfig) -> Self {
+        match parity {
+            ParityConfig::None => Parity::None,
+            ParityConfig::Even => Parity::Even,
+            ParityConfig::Odd => Parity::Odd,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KnxConfig {
+    pub gateway: String,
+    pub apparent_power: Option<String>,
+    pub tariff_period: Option<String>,
+    pub day_color: Option<String>,
+    pub tomorrow_color: Option<String>,
+    /// Minimum number of seconds between two apparent power telegrams
+    #[serde(default = "default_knx_power_interval")]
+    pub power_interval: u64,
+}
+
+fn default_knx_power_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    #[serde(default = "default_mqtt_host")]
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub profile: MqttProfile,
+    /// Defaults to `labels` for the default profile, `json` for zigbee2mqtt
+    pub format: Option<MqttFormat>,
+    /// Topic template, with `{base_topic}`, `{device_name}` and, for the
+    /// `labels` format, `{label}` placeholders
+    pub topic: Option<String>,
+    #[serde(default = "default_mqtt_base_topic")]
+    pub base_topic: String,
+    /// Friendly name of the meter, used by the zigbee2mqtt profile
+    #[serde(default = "default_mqtt_device_name")]
+    pub device_name: String,
+    /// Overrides the topic reporting whether the daemon is online
+    pub availability_topic: Option<String>,
+    /// Overrides the topic of the device information
+    pub info_topic: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Keep alive interval in seconds
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u64,
+    pub tls: Option<MqttTlsConfig>,
+    /// Publishes Home Assistant discovery messages
+    #[serde(default)]
+    pub home_assistant: bool,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Publishes the smoothed apparent power and its rate of change
+    pub trend: Option<TrendConfig>,
+    /// Publishes the active power estimated from the apparent power
+    pub active_power: Option<ActivePowerConfig>,
+    /// Publishes the power factor measured in standard mode
+    pub power_factor: Option<PowerFactorConfig>,
+    /// Topic of the remote commands, disabled when not set
+    pub command_topic: Option<String>,
+    /// Scales of the values, by label or `PREFIX*`
+    #[serde(default)]
+    pub scale: BTreeMap<String, ScaleConfig>,
+    /// QoS level of the values, from 0 to 2
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+/// TLS settings, the system root certificates are used when no CA is given.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttTlsConfig {
+    pub ca_file: Option<PathBuf>,
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+}
+
+/// Scale of the values of a label, e.g. Wh to kWh.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScaleConfig {
+    #[serde(default = "default_scale_factor")]
+    pub factor: f64,
+    /// Decimals the scaled values are rounded to
+    pub decimals: Option<usize>,
+    /// Unit of the scaled values, announced where units are
+    pub unit: Option<String>,
+}
+
+fn default_scale_factor() -> f64 {
+    1.0
+}
+
+/// How a sink copes with a slow or unreachable destination.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    /// Messages waiting to be handled, a few minutes of frames by default
+    pub queue: Option<usize>,
+    /// Messages dropped once the queue is full
+    pub overflow: Overflow,
+    /// What becomes of the indexes, the apparent and instantaneous powers
+    /// and the notices of the meter once the queue is full, the other
+    /// messages being dropped
+    pub indexes: ClassPolicy,
+    pub samples: ClassPolicy,
+    pub alerts: ClassPolicy,
+    /// Attempts after a failed write
+    pub retries: u32,
+    /// Seconds before the first retry, doubled at each of the next ones
+    pub backoff: u64,
+    /// Most seconds between two retries
+    pub max_backoff: u64,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            queue: None,
+            overflow: Overflow::default(),
+            indexes: ClassPolicy::Coalesce,
+            samples: ClassPolicy::Drop,
+            alerts: ClassPolicy::Keep,
+            retries: 0,
+            backoff: 1,
+            max_backoff: 60,
+        }
+    }
+}
+
+impl DeliveryConfig {
+    /// Policy of a class of messages once the queue is full.
+    pub fn policy(&self, class: DataClass) -> ClassPolicy {
+        match class {
+            DataClass::Index => self.indexes,
+            DataClass::Sample => self.samples,
+            DataClass::Alert => self.alerts,
+            DataClass::Other => ClassPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Keeps the messages queued, the history stays complete up to the gap
+    #[default]
+    DropNewest,
+    /// Keeps the latest messages, for sinks showing the current state
+    DropOldest,
+}
+
+/// What becomes of a class of messages once the queue of a sink is full.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClassPolicy {
+    /// Queued beyond its size
+    Keep,
+    /// Queued beyond its size, replacing the previous value of the same
+    /// group still queued
+    Coalesce,
+    /// Dropped as set by the overflow
+    Drop,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TrendConfig {
+    /// Seconds of apparent power samples the trend is computed over
+    #[serde(default = "default_trend_window")]
+    pub window: u64,
+}
+
+fn default_trend_window() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PowerFactorConfig {
+    /// Seconds of energy the power factor is measured over
+    #[serde(default = "default_power_factor_interval")]
+    pub interval: u64,
+}
+
+fn default_power_factor_interval() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ActivePowerConfig {
+    /// Power factor when no level nor hours apply
+    #[serde(default = "default_power_factor")]
+    pub power_factor: f64,
+    /// Power factors from an apparent power on, the highest level reached
+    /// applies
+    #[serde(default)]
+    pub levels: Vec<LevelFactor>,
+    /// Power factors during hours of the day
+    #[serde(default)]
+    pub hours: Vec<HourFactor>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LevelFactor {
+    /// Apparent power, in VA
+    pub above: u16,
+    pub power_factor: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HourFactor {
+    /// First hour, local
+    pub start: u32,
+    /// Hour after the last one, possibly across midnight
+    pub end: u32,
+    pub power_factor: f64,
+}
+
+fn default_power_factor() -> f64 {
+    0.9
+}
+
+fn default_mqtt_host() -> String {
+    String::from("localhost")
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    String::from("pitinfo")
+}
+
+fn default_mqtt_base_topic() -> String {
+    String::from("pitinfo")
+}
+
+fn default_mqtt_device_name() -> String {
+    String::from("teleinfo")
+}
+
+fn default_mqtt_keep_alive() -> u64 {
+    30
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttProfile {
+    #[default]
+    Default,
+    Zigbee2mqtt,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttFormat {
+    /// One topic per label with the raw value as payload
+    Labels,
+    /// A single topic with the whole state as JSON, once per frame
+    Json,
+    /// A single topic with the whole state as a SenML (RFC 8428) pack, once
+    /// per frame
+    Senml,
+}
+
+/// Credentials of an RTE API application subscribed to the Tempo calendar
+#[derive(Deserialize, Debug, Clone)]
+pub struct TempoConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Seconds between two calendar requests
+    #[serde(default = "default_tempo_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default = "default_rte_token_url")]
+    pub token_url: String,
+    #[serde(default = "default_tempo_calendar_url")]
+    pub calendar_url: String,
+}
+
+fn default_tempo_poll_interval() -> u64 {
+    3600
+}
+
+fn default_rte_token_url() -> String {
+    String::from("https://digital.iservices.rte-france.com/token/oauth/")
+}
+
+fn default_tempo_calendar_url() -> String {
+    String::from(
+        "https://digital.iservices.rte-france.com/open_api/tempo_like_supply_contract/v1/tempo_like_calendars",
+    )
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnedisConfig {
+    /// Consent token of the Enedis data API
+    pub token: String,
+    /// Identifier of the delivery point (PRM)
+    pub prm: String,
+    /// Seconds between two reconciliations
+    #[serde(default = "default_enedis_check_interval")]
+    pub check_interval: u64,
+    /// Accepted difference between local and Enedis data, in percent
+    #[serde(default = "default_enedis_tolerance")]
+    pub tolerance: f64,
+    #[serde(default = "default_enedis_url")]
+    pub url: String,
+}
+
+fn default_enedis_check_interval() -> u64 {
+    3600
+}
+
+fn default_enedis_tolerance() -> f64 {
+    1.0
+}
+
+fn default_enedis_url() -> String {
+    String::from("https://ext.prod.api.enedis.fr/metering_data_dc/v5/daily_consumption")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EcowattConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Seconds between two signal requests, the API accepts one every 15 minutes
+    #[serde(default = "default_ecowatt_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default = "default_rte_token_url")]
+    pub token_url: String,
+    #[serde(default = "default_ecowatt_signals_url")]
+    pub signals_url: String,
+    #[serde(default)]
+    pub rules: Vec<EcowattRule>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EcowattRule {
+    pub name: String,
+    /// Lowest EcoWatt level triggering the rule
+    pub level: EcowattLevel,
+    /// Apparent power in VA above which the rule triggers, any power by default
+    pub max_power: Option<u16>,
+    /// Shell command run when the rule triggers
+    pub command: Option<String>,
+    /// Shell command run when the EcoWatt level drops below the rule level
+    pub release_command: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum EcowattLevel {
+    Green,
+    Orange,
+    Red,
+}
+
+fn default_ecowatt_poll_interval() -> u64 {
+    1800
+}
+
+fn default_ecowatt_signals_url() -> String {
+    String::from("https://digital.iservices.rte-france.com/open_api/ecowatt/v5/signals")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StorageConfig {
+    #[serde(default = "default_storage_path")]
+    pub path: PathBuf,
+    /// Minimum number of seconds between two stored snapshots
+    #[serde(default = "default_storage_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Seconds the snapshots are kept in memory to be written together, 0
+    /// writes each one right away
+    #[serde(default)]
+    pub flush_interval: u64,
+    #[serde(default)]
+    pub synchronous: StorageSynchronous,
+    /// Seconds between two copies to `path` of the database, then kept in
+    /// memory, written in place when not set
+    pub snapshot_interval: Option<u64>,
+    /// age secret key encrypting the copies of the database
+    pub identity: Option<String>,
+    /// File holding the age secret key, relative to the credentials
+    /// directory of systemd when run with `LoadCredential`
+    pub identity_file: Option<PathBuf>,
+}
+
+/// How hard SQLite makes sure that the writes reached the storage, as its
+/// `synchronous` pragma.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageSynchronous {
+    /// Leaves the writes to the operating system, a power cut may corrupt
+    /// the database
+    Off,
+    /// Syncs less often, a power cut may lose the last writes
+    Normal,
+    #[default]
+    Full,
+}
+
+/// Daily aggregates are kept forever.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Days during which raw readings are kept
+    pub raw_days: u32,
+    /// Months during which 1-minute aggregates are kept
+    pub minute_months: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            raw_days: 7,
+            minute_months: 6,
+        }
+    }
+}
+
+fn default_storage_path() -> PathBuf {
+    PathBuf::from("/var/lib/pitinfo/history.db")
+}
+
+fn default_storage_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InfluxDbConfig {
+    #[serde(default = "default_influxdb_url")]
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+    /// Minimum number of seconds between two points
+    #[serde(default = "default_influxdb_interval")]
+    pub interval: u64,
+    /// Scales of the values, by label or `PREFIX*`
+    #[serde(default)]
+    pub scale: BTreeMap<String, ScaleConfig>,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_influxdb_url() -> String {
+    String::from("http://localhost:8086")
+}
+
+fn default_influxdb_measurement() -> String {
+    String::from("teleinfo")
+}
+
+fn default_influxdb_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GrafanaLiveConfig {
+    #[serde(default = "default_grafana_url")]
+    pub url: String,
+    /// Service account token with the Editor role
+    pub token: String,
+    #[serde(default = "default_grafana_stream")]
+    pub stream: String,
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_grafana_url() -> String {
+    String::from("http://localhost:3000")
+}
+
+fn default_grafana_stream() -> String {
+    String::from("pitinfo")
+}
+
+#[derive(Deserialize, Debug)]
+#[cfg_attr(not(feature = "statistics"), allow(dead_code))]
+pub struct HomeAssistantConfig {
+    /// WebSocket API endpoint
+    #[serde(default = "default_home_assistant_url")]
+    pub url: String,
+    /// Long-lived access token of an administrator
+    pub token: String,
+    /// Name prefixing the statistic ids, as in `pitinfo:teleinfo_bbrhcjb`
+    #[serde(default = "default_mqtt_device_name")]
+    pub device_name: String,
+}
+
+fn default_home_assistant_url() -> String {
+    String::from("ws://homeassistant.local:8123/api/websocket")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushgatewayConfig {
+    #[serde(default = "default_pushgateway_url")]
+    pub url: String,
+    #[serde(default = "default_pushgateway_job")]
+    pub job: String,
+    pub instance: Option<String>,
+    /// Minimum number of seconds between two pushes
+    #[serde(default = "default_pushgateway_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_pushgateway_url() -> String {
+    String::from("http://localhost:9091")
+}
+
+fn default_pushgateway_job() -> String {
+    String::from("pitinfo")
+}
+
+fn default_pushgateway_interval() -> u64 {
+    15
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmoncmsConfig {
+    #[serde(default = "default_emoncms_url")]
+    pub url: String,
+    /// Read & Write API key of the account
+    pub apikey: String,
+    /// Node the inputs are created under
+    #[serde(default = "default_emoncms_node")]
+    pub node: String,
+    /// Minimum number of seconds between two posts
+    #[serde(default = "default_emoncms_interval")]
+    pub interval: u64,
+    /// Scales of the values, by label or `PREFIX*`
+    #[serde(default)]
+    pub scale: BTreeMap<String, ScaleConfig>,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_emoncms_url() -> String {
+    String::from("https://emoncms.org")
+}
+
+fn default_emoncms_node() -> String {
+    String::from("pitinfo")
+}
+
+fn default_emoncms_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PvOutputConfig {
+    #[serde(default = "default_pvoutput_url")]
+    pub url: String,
+    pub apikey: String,
+    pub system_id: String,
+    /// Minutes between two statuses, the status interval of the system
+    #[serde(default = "default_pvoutput_interval")]
+    pub interval: u32,
+    /// Labels posted as the extended parameters v7 to v12, in donation mode
+    #[serde(default)]
+    pub extended: BTreeMap<String, String>,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_pvoutput_url() -> String {
+    String::from("https://pvoutput.org")
+}
+
+fn default_pvoutput_interval() -> u32 {
+    5
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ThingsBoardConfig {
+    #[serde(default = "default_mqtt_host")]
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    /// Access token of the device
+    pub access_token: String,
+    pub tls: Option<MqttTlsConfig>,
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u64,
+    /// Minimum number of seconds between two telemetry messages
+    #[serde(default = "default_thingsboard_interval")]
+    pub interval: u64,
+    /// Scales of the values, by label or `PREFIX*`
+    #[serde(default)]
+    pub scale: BTreeMap<String, ScaleConfig>,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_thingsboard_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OpenHabConfig {
+    #[serde(default = "default_openhab_url")]
+    pub url: String,
+    /// API token of an administrator, to create the items
+    pub token: Option<String>,
+    /// Prefix of the item names, followed by the labels
+    #[serde(default = "default_openhab_prefix")]
+    pub prefix: String,
+    /// Creates the items missing, instead of expecting them
+    #[serde(default = "default_openhab_create_items")]
+    pub create_items: bool,
+    /// Minimum number of seconds between two updates
+    #[serde(default = "default_openhab_interval")]
+    pub interval: u64,
+    /// Scales of the values, by label or `PREFIX*`
+    #[serde(default)]
+    pub scale: BTreeMap<String, ScaleConfig>,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_openhab_url() -> String {
+    String::from("http://localhost:8080")
+}
+
+fn default_openhab_prefix() -> String {
+    String::from("Pitinfo_")
+}
+
+fn default_openhab_create_items() -> bool {
+    true
+}
+
+fn default_openhab_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub struct DbusConfig {
+    #[serde(default)]
+    pub bus: DbusBus,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DbusBus {
+    /// Requires a policy allowing the daemon to own the service name
+    #[default]
+    System,
+    /// Bus of the user session, e.g. for desktop widgets
+    Session,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnmpConfig {
+    /// Socket of the AgentX master agent: a Unix socket path, or host:port
+    #[serde(default = "default_snmp_master")]
+    pub master: String,
+    /// OID of the PITINFO-MIB subtree
+    #[serde(default = "default_snmp_base_oid")]
+    pub base_oid: String,
+}
+
+fn default_snmp_master() -> String {
+    String::from("/var/agentx/master")
+}
+
+fn default_snmp_base_oid() -> String {
+    String::from("1.3.6.1.4.1.8072.9999.9999.1")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnomalyConfig {
+    /// Increase of the always-on load over the usual one reported, in percent
+    #[serde(default = "default_anomaly_baseline_increase")]
+    pub baseline_increase: f64,
+    /// Apparent power in VA that should not last, disabled by default
+    pub high_power: Option<u16>,
+    #[serde(default = "default_anomaly_high_power_minutes")]
+    pub high_power_minutes: u32,
+    /// Shell command run for each anomaly
+    pub command: Option<String>,
+}
+
+fn default_anomaly_baseline_increase() -> f64 {
+    50.0
+}
+
+fn default_anomaly_high_power_minutes() -> u32 {
+    120
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NilmConfig {
+    #[serde(default = "default_nilm_directory")]
+    pub directory: PathBuf,
+}
+
+fn default_nilm_directory() -> PathBuf {
+    PathBuf::from("/var/lib/pitinfo/nilm")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LokiConfig {
+    #[serde(default = "default_loki_url")]
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Labels of the stream, e.g. the installation, along with `job`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Seconds between two pushes
+    #[serde(default = "default_loki_interval")]
+    pub interval: u64,
+}
+
+fn default_loki_url() -> String {
+    String::from("http://localhost:3100")
+}
+
+fn default_loki_interval() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImaxConfig {
+    #[serde(default = "default_imax_path")]
+    pub path: PathBuf,
+    #[serde(default = "default_imax_rolling_days")]
+    pub rolling_days: u32,
+}
+
+fn default_imax_path() -> PathBuf {
+    PathBuf::from("/var/lib/pitinfo/imax.json")
+}
+
+fn default_imax_rolling_days() -> u32 {
+    30
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForecastConfig {
+    /// Day of the month the billing periods start on, from 1 to 28
+    #[serde(default = "default_forecast_billing_day")]
+    pub billing_day: u32,
+    /// Price of a kWh, for the indexes without their own price
+    pub price: Option<f64>,
+    /// Price of a kWh per index label, e.g. `BBRHPJR`
+    #[serde(default)]
+    pub prices: BTreeMap<String, f64>,
+    /// Prices taking effect on later dates
+    #[serde(default)]
+    pub tariffs: Vec<TariffConfig>,
+    #[serde(default = "default_forecast_currency")]
+    pub currency: String,
+    #[serde(default = "default_forecast_path")]
+    pub path: PathBuf,
+    /// Shell command run with the forecast, once a day
+    pub command: Option<String>,
+    pub budget: Option<BudgetConfig>,
+}
+
+/// Prices of a kWh from a date on, replacing those before.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TariffConfig {
+    /// First day the prices apply, as `YYYY-MM-DD`
+    pub from: NaiveDate,
+    pub price: Option<f64>,
+    #[serde(default)]
+    pub prices: BTreeMap<String, f64>,
+}
+
+/// Budget of a billing period, in energy, cost or both.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BudgetConfig {
+    /// kWh
+    pub energy: Option<f64>,
+    pub cost: Option<f64>,
+    /// Shell command run when the forecast exceeds the budget, and when the
+    /// consumption reaches it
+    pub command: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OffPeakConfig {
+    /// Off-peak windows of the contract in local time, e.g. `22:30-06:30`,
+    /// decoded from the HHPHC schedule of the meter when left empty
+    #[serde(default)]
+    pub windows: Vec<String>,
+    /// Minutes of notice before off-peak hours start
+    #[serde(default = "default_offpeak_notice")]
+    pub notice: u64,
+    /// Shell command run when off-peak hours are about to start, start and end
+    pub command: Option<String>,
+}
+
+fn default_offpeak_notice() -> u64 {
+    15
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProductionConfig {
+    /// Serial port of the TIC of the production meter
+    pub port: String,
+    #[serde(default = "default_production_baud_rate")]
+    pub baud_rate: u32,
+}
+
+fn default_production_baud_rate() -> u32 {
+    1200
+}
+
+fn default_forecast_billing_day() -> u32 {
+    1
+}
+
+fn default_forecast_currency() -> String {
+    String::from("EUR")
+}
+
+fn default_forecast_path() -> PathBuf {
+    PathBuf::from("/var/lib/pitinfo/forecast.json")
+}
+
+/// Prefix of the environment variables overriding the configuration.
+const ENV_PREFIX: &str = "PITINFO_";
+/// Environment variable giving the configuration file.
+pub const CONFIG_VARIABLE: &str = "PITINFO_CONFIG";
+
+#[derive(Deserialize, Debug)]
+pub struct FleetConfig {
+    /// JSON state topic of each site, by site name
+    pub sites: BTreeMap<String, String>,
+    /// Seconds without a state from a site before reporting it
+    #[serde(default = "default_fleet_silence")]
+    pub silence: u64,
+    /// Total apparent power in VA reported, disabled by default
+    pub power: Option<u32>,
+    /// Shell command run for each alert
+    pub command: Option<String>,
+}
+
+fn default_fleet_silence() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LatencyConfig {
+    /// Seconds between two reports of the latencies
+    #[serde(default = "default_latency_interval")]
+    pub interval: u64,
+    /// Latency in milliseconds that 99% of the messages of a sink should
+    /// meet, warned about otherwise
+    pub budget: Option<u64>,
+}
+
+fn default_latency_interval() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleConfig {
+    /// Local time of the runs, as HH:MM
+    pub at: String,
+    pub every: Every,
+    /// Day of the month of monthly runs, from 1 to 28
+    #[serde(default = "default_schedule_day")]
+    pub day: u32,
+    pub job: Job,
+    /// File or directory of exports, `{date}` being replaced with the date
+    /// of the run
+    pub path: Option<String>,
+    /// Shell command of command jobs
+    pub command: Option<String>,
+}
+
+fn default_schedule_day() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Every {
+    Day,
+    Month,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Job {
+    /// Daily aggregates as Parquet, like the `export` subcommand
+    Export,
+    ExportAnonymized,
+    ExportHeatmap,
+    /// Consumption of the previous day or month, sent by email
+    EmailReport,
+    Command,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmailConfig {
+    /// SMTP server
+    pub host: String,
+    /// Port of the server, 587 with STARTTLS, 465 with TLS and 25 without
+    /// by default
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender, e.g. `Pitinfo <pitinfo@example.org>`
+    pub from: String,
+    pub to: Vec<String>,
+    /// Whether alerts are sent, besides the reports
+    #[serde(default = "default_email_alerts")]
+    pub alerts: bool,
+}
+
+fn default_email_alerts() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS
+    #[default]
+    Starttls,
+    /// TLS from the start
+    Tls,
+    /// No encryption, for a relay on the local network
+    None,
+}
+
+impl Config {
+    /// Loads the configuration file, when given, and applies the environment
+    /// variables on top of it.
+    pub fn load(path: Option<&Path>) -> Result<Config, io::Error> {
+        Config::load_with(path, &[])
+    }
+
+    /// Loads the configuration like `load`, then applies settings changed
+    /// remotely, keyed by their dotted path, e.g. `anomaly.high_power`.
+    ///
+    /// Unknown keys, invalid values and conflicting options are logged with
+    /// their position in the file, and fail the loading.
+    pub fn load_with(
+        path: Option<&Path>,
+        settings: &[(String, Value)],
+    ) -> Result<Config, io::Error> {
+        let (text, table) = read_table(path, settings)?;
+        let mut unknown = Vec::new();
+        let config: Result<Config, _> =
+            serde_path_to_error::deserialize(serde_ignored::Deserializer::new(
+                Value::Table(table),
+                &mut |ignored: serde_ignored::Path| {
+                    let mut keys = Vec::new();
+                    ignored_keys(&ignored, &mut keys);
+                    unknown.push(keys);
+                },
+            ));
+        let locate = |keys: &[String], message: String| {
+            let position = text.as_deref().and_then(|text| position(text, keys));
+            match (path, position) {
+                (Some(path), Some((line, column))) => {
+                    format!("{}:{}:{}: {}", path.display(), line, column, message)
+                }
+                _ => message,
+            }
+        };
+        let mut diagnostics: Vec<String> = unknown
+            .iter()
+            .map(|keys| locate(keys, format!("unknown key `{}`", keys.join("."))))
+            .collect();
+        match &config {
+            Ok(config) => {
+                for (keys, message) in config.conflicts() {
+                    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                    diagnostics.push(locate(&keys, message));
+                }
+            }
+            Err(e) => {
+                let keys: Vec<String> = e.path().iter().map(|key| key.to_string()).collect();
+                diagnostics.push(locate(
+                    &keys,
+                    format!("`{}`: {}", e.path(), e.inner().message()),
+                ));
+            }
+        }
+        match diagnostics.as_slice() {
+            [] => Ok(config.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?),
+            [diagnostic] => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                diagnostic.clone(),
+            )),
+            _ => {
+                for diagnostic in &diagnostics {
+                    eprintln!("{}", diagnostic);
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} errors in the configuration", diagnostics.len()),
+                ))
+            }
+        }
+    }
+
+    /// Configuration as loaded, before the defaults are applied, e.g. to
+    /// show it.
+    pub fn table(path: Option<&Path>, settings: &[(String, Value)]) -> Result<Table, io::Error> {
+        Ok(read_table(path, settings)?.1)
+    }
+
+    /// Options that cannot be used together, with the key to report them on.
+    fn conflicts(&self) -> Vec<(Vec<&'static str>, String)> {
+        let mut conflicts = Vec::new();
+        if self.serial.mqtt_topic.is_some() && self.mqtt.is_none() {
+            conflicts.push((
+                vec!["serial", "mqtt_topic"],
+                String::from("serial.mqtt_topic requires an [mqtt] section"),
+            ));
+        }
+        if let Some(api) = &self.api {
+            if api.token.is_none() && !api.is_local() {
+                conflicts.push((
+                    vec!["api", "listen"],
+                    format!(
+                        "api.listen {} beyond the loopback interface requires api.token",
+                        api.listen
+                    ),
+                ));
+            }
+            if api.tls_cert.is_none() && !api.is_local() {
+                conflicts.push((
+                    vec!["api", "listen"],
+                    format!(
+                        "api.listen {} beyond the loopback interface requires api.tls_cert, \
+                         the token being sent in clear otherwise, or a reverse proxy with TLS",
+                        api.listen
+                    ),
+                ));
+            }
+            if api.tls_cert.is_some() != api.tls_key.is_some() {
+                conflicts.push((
+                    vec!["api", "tls_cert"],
+                    String::from("api.tls_cert and api.tls_key go together"),
+                ));
+            }
+            if api.tls_cert.is_some() && !cfg!(feature = "api-tls") {
+                conflicts.push((
+                    vec!["api", "tls_cert"],
+                    without_feature("api.tls_cert", "api-tls"),
+                ));
+            }
+        }
+        if self.dbus.is_some() && !cfg!(feature = "dbus") {
+            conflicts.push((vec!["dbus"], without_feature("the [dbus] section", "dbus")));
+        }
+        if let Some(storage) = &self.storage {
+            if storage.identity.is_some() && storage.identity_file.is_some() {
+                conflicts.push((
+                    vec!["storage", "identity_file"],
+                    String::from("storage.identity and storage.identity_file are exclusive"),
+                ));
+            }
+        }
+        conflicts
+    }
+}
+
+/// Message for an option of a cargo feature left out of the build.
+pub fn without_feature(what: &str, feature: &str) -> String {
+    format!(
+        "{} not available, built without the `{}` feature",
+        what, feature
+    )
+}
+
+/// Keys of a path ignored by the deserialization, array items by position.
+fn ignored_keys(path: &serde_ignored::Path, keys: &mut Vec<String>) {
+    match path {
+        serde_ignored::Path::Root => (),
+        serde_ignored::Path::Seq { parent, index } => {
+            ignored_keys(parent, keys);
+            keys.push(index.to_string());
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            ignored_keys(parent, keys);
+            keys.push(key.clone());
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => ignored_keys(parent, keys),
+    }
+}
+
+/// Line and column of a key in the configuration file, or of the closest
+/// section found when it is not in the file.
+fn position(text: &str, keys: &[String]) -> Option<(usize, usize)> {
+    let document = toml_edit::ImDocument::parse(text).ok()?;
+    let span = table_span(document.as_table(), keys)?;
+    let before = text.get(..span.start)?;
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    Some((line, column))
+}
+
+fn table_span(table: &dyn toml_edit::TableLike, keys: &[String]) -> Option<Range<usize>> {
+    let (first, rest) = keys.split_first()?;
+    let (key, item) = table.get_key_value(first)?;
+    item_span(item, rest).or_else(|| key.span())
+}
+
+fn item_span(item: &toml_edit::Item, keys: &[String]) -> Option<Range<usize>> {
+    let (first, rest) = keys.split_first()?;
+    match item {
+        toml_edit::Item::Table(table) => table_span(table, keys),
+        toml_edit::Item::Value(toml_edit::Value::InlineTable(table)) => table_span(table, keys),
+        toml_edit::Item::ArrayOfTables(tables) => {
+            let table = tables.get(first.parse().ok()?)?;
+            table_span(table, rest).or_else(|| table.span())
+        }
+        toml_edit::Item::Value(toml_edit::Value::Array(values)) => {
+            let value = values.get(first.parse().ok()?)?;
+            match value.as_inline_table() {
+                Some(table) => table_span(table, rest),
+                None => None,
+            }
+            .or_else(|| value.span())
+        }
+        _ => None,
+    }
+}
+
+/// Sets the `PITINFO_<SECTION>__<KEY>` variables, e.g. `PITINFO_MQTT__HOST`
+/// for the `host` key of the `[mqtt]` section. Values are read as TOML
+/// values, falling back to strings.
+fn apply_env(
+    table: &mut Table,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), io::Error> {
+    for (name, value) in variables {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if path.contains("__") => path.to_lowercase(),
+            _ => continue,
+        };
+        let keys: Vec<&str> = path.split("__").collect();
+        set(table, &name, &keys, env_value(&value))?;
+    }
+    Ok(())
+}
+
+/// Text of the configuration file, and the file with the environment
+/// variables and the settings applied.
+fn read_table(
+    path: Option<&Path>,
+    settings: &[(String, Value)],
+) -> Result<(Option<String>, Table), io::Error> {
+    let text = path.map(fs::read_to_string).transpose()?;
+    let mut table = match (path, &text) {
+        (Some(path), Some(text)) => text.parse::<Table>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?,
+        _ => Table::new(),
+    };
+    apply_env(&mut table, env::vars())?;
+    for (key, value) in settings {
+        set(
+            &mut table,
+            key,
+            &key.split('.').collect::<Vec<_>>(),
+            value.clone(),
+        )?;
+    }
+    Ok((text, table))
+}
+
+/// Settings that can be changed remotely, by the `set` command and the HTTP
+/// API, `*` standing for any position in an array: thresholds, rules and
+/// intervals of the sinks restarted on a change. Shell commands, credentials
+/// and addresses are left out, whoever changes them running commands on the
+/// host or sending the readings elsewhere.
+const TUNABLE: &[&str] = &[
+    "anomaly.baseline_increase",
+    "anomaly.high_power",
+    "anomaly.high_power_minutes",
+    "ecowatt.poll_interval",
+    "ecowatt.rules.*.level",
+    "ecowatt.rules.*.max_power",
+    "emoncms.interval",
+    "enedis.check_interval",
+    "enedis.tolerance",
+    "forecast.budget.cost",
+    "forecast.budget.energy",
+    "influxdb.interval",
+    "knx.power_interval",
+    "offpeak.notice",
+    "offpeak.windows",
+    "openhab.interval",
+    "pushgateway.interval",
+    "pvoutput.interval",
+    "storage.interval",
+    "thingsboard.interval",
+];
+
+/// Keys of the secrets, never returned nor changed remotely.
+const SECRETS: &[&str] = &[
+    "access_token",
+    "apikey",
+    "client_secret",
+    "identity",
+    "password",
+    "token",
+];
+
+/// Whether a setting, keyed by its dotted path, is a secret.
+pub fn is_secret(key: &str) -> bool {
+    key.rsplit('.')
+        .next()
+        .is_some_and(|name| SECRETS.contains(&name))
+}
+
+/// Checks that a setting, keyed by its dotted path, can be changed remotely,
+/// see `TUNABLE`.
+pub fn check_remote(key: &str) -> Result<(), io::Error> {
+    let reason = if is_secret(key) {
+        "a secret"
+    } else if key.ends_with("command") {
+        "a shell command"
+    } else if TUNABLE.iter().any(|tunable| {
+        let mut names = key.split('.');
+        tunable.split('.').all(|pattern| {
+            names
+                .next()
+                .is_some_and(|name| pattern == "*" || name == pattern)
+        }) && names.next().is_none()
+    }) {
+        return Ok(());
+    } else {
+        "not a threshold, rule or interval"
+    };
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} cannot be changed remotely: {}", key, reason),
+    ))
+}
+
+/// Writes a setting, keyed by its dotted path, to the configuration file,
+/// keeping its comments and layout.
+pub fn persist(path: &Path, key: &str, value: &Value) -> Result<(), io::Error> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut document: toml_edit::DocumentMut = fs::read_to_string(path)?
+        .parse()
+        .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+    let mut value: toml_edit::Value = value
+        .to_string()
+        .parse()
+        .map_err(|e| invalid(format!("{}: {}", key, e)))?;
+    let keys: Vec<&str> = key.split('.').collect();
+    let (name, sections) = keys.split_last().unwrap_or((&"", &[]));
+    let mut table = document.as_table_mut();
+    let mut names = sections.iter();
+    while let Some(section) = names.next() {
+        let item = table
+            .entry(section)
+            .or_insert_with(|| toml_edit::Item::Table(toml_edit::Table::new()));
+        let item = match item {
+            toml_edit::Item::ArrayOfTables(tables) => names
+                .next()
+                .and_then(move |position| tables.get_mut(position.parse().ok()?)),
+            item => item.as_table_mut(),
+        };
+        table = item.ok_or_else(|| invalid(format!("{}: '{}' is not a section", key, section)))?;
+    }
+    if let Some(previous) = table.get(name).and_then(toml_edit::Item::as_value) {
+        *value.decor_mut() = previous.decor().clone();
+    }
+    table.insert(name, toml_edit::Item::Value(value));
+    fs::write(path, document.to_string())
+}
+
+/// Sets a key of a section, creating the sections missing. The tables of an
+/// array are given by their position, e.g. `ecowatt.rules.0.level`. `name` is
+/// the setting reported on errors.
+fn set(table: &mut Table, name: &str, keys: &[&str], value: Value) -> Result<(), io::Error> {
+    let (key, sections) = keys.split_last().unwrap_or((&"", &[]));
+    let mut section = table;
+    let mut names = sections.iter();
+    while let Some(section_name) = names.next() {
+        let entry = section
+            .entry(*section_name)
+            .or_insert_with(|| Value::Table(Table::new()));
+        let entry = match entry {
+            Value::Array(tables) => names
+                .next()
+                .and_then(move |position| tables.get_mut(position.parse::<usize>().ok()?)),
+            entry => Some(entry),
+        };
+        section = match entry {
+            Some(Value::Table(table)) => table,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: '{}' is not a section", name, section_name),
+                ))
+            }
+        };
+    }
+    section.insert(String::from(*key), value);
+    Ok(())
+}
+
+fn env_value(value: &str) -> Value {
+    format!("value = {}", value)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(String::from(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_variables() {
+        let mut table: Table = "[serial]\nport = \"/dev/ttyAMA0\"\nbaud_rate = 1200\n"
+            .parse()
+            .unwrap();
+        let variables = [
+            ("PITINFO_SERIAL__PORT", "-"),
+            ("PITINFO_MQTT__HOST", "broker.local"),
+            ("PITINFO_MQTT__PORT", "8883"),
+            ("PITINFO_MQTT__BASE_TOPIC", "home/teleinfo"),
+            ("PITINFO_MQTT__PASSWORD", "\"1234\""),
+            ("PITINFO_CLOCK__TIMEZONE", "America/Martinique"),
+            ("PITINFO_CONFIG", "/etc/pitinfo/pitinfo.toml"),
+            ("HOME", "/root"),
+        ];
+        apply_env(
+            &mut table,
+            variables
+                .iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value))),
+        )
+        .unwrap();
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!(config.serial.port, "-");
+        assert_eq!(config.serial.baud_rate, 1200);
+        assert_eq!(config.clock.timezone, chrono_tz::America::Martinique);
+        let mqtt = config.mqtt.unwrap();
+        assert_eq!(mqtt.host, "broker.local");
+        assert_eq!(mqtt.port, 8883);
+        assert_eq!(mqtt.base_topic, "home/teleinfo");
+        assert_eq!(mqtt.password.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn remote_settings() {
+        let settings = [(String::from("anomaly.high_power"), Value::Integer(6000))];
+        let config = Config::load_with(None, &settings).unwrap();
+        assert_eq!(config.anomaly.unwrap().high_power, Some(6000));
+
+        let settings = [(String::from("serial.port.name"), Value::Integer(1))];
+        assert!(Config::load_with(None, &settings).is_err());
+
+        let mut settings = vec![(
+            String::from("api.listen"),
+            Value::String(String::from("0.0.0.0:8080")),
+        )];
+        assert!(Config::load_with(None, &settings).is_err());
+        settings.push((
+            String::from("api.token"),
+            Value::String(String::from("change-me")),
+        ));
+        assert!(Config::load_with(None, &settings).is_err());
+        for (key, file) in [("api.tls_cert", "cert.pem"), ("api.tls_key", "key.pem")] {
+            settings.push((String::from(key), Value::String(String::from(file))));
+        }
+        assert_eq!(
+            Config::load_with(None, &settings).is_ok(),
+            cfg!(feature = "api-tls")
+        );
+    }
+
+    #[test]
+    fn remote_keys() {
+        assert!(check_remote("anomaly.high_power").is_ok());
+        assert!(check_remote("ecowatt.rules.2.level").is_ok());
+        for key in [
+            "serial.gap_command",
+            "ecowatt.rules.0.command",
+            "ecowatt.rules.0.release_command",
+            "anomaly.command",
+            "influxdb.token",
+            "mqtt.password",
+            "mqtt.host",
+            "anomaly",
+            "ecowatt.rules",
+            "anomaly.high_power.value",
+        ] {
+            let error = check_remote(key).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied, "{}", key);
+        }
+        assert!(is_secret("influxdb.token"));
+        assert!(!is_secret("anomaly.high_power"));
+    }
+
+    #[test]
+    fn diagnostics() {
+        let text = "[serial]\nport = \"-\"\nbaud_rte = 1200\n\n[[schedule]]\nevery = \"day\"\nat = \"07:00\"\njob = \"command\"\ncomand = \"true\"\n";
+        let keys = |path: &str| path.split('.').map(String::from).collect::<Vec<_>>();
+        assert_eq!(position(text, &keys("serial.baud_rte")), Some((3, 1)));
+        assert_eq!(position(text, &keys("schedule.0.comand")), Some((9, 1)));
+        // The closest section
+        assert_eq!(position(text, &keys("serial.parity")), Some((1, 2)));
+        assert_eq!(position(text, &keys("mqtt.host")), None);
+
+        let path = env::temp_dir().join(format!("pitinfo-config-{}.toml", std::process::id()));
+        fs::write(&path, text).unwrap();
+        let error = Config::load(Some(&path)).unwrap_err();
+        assert_eq!(error.to_string(), "2 errors in the configuration");
+        fs::write(&path, "[serial]\nport = \"-\"\n\n[mqtt]\nport = \"1883\"\n").unwrap();
+        let error = Config::load(Some(&path)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:5:1: `mqtt.port`: invalid type: string \"1883\", expected u16",
+                path.display()
+            )
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn persisted() {
+        let path = env::temp_dir().join(format!("pitinfo-persist-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "# Linky in the garage\n[serial]\nport = \"-\"\n\n[anomaly]\nhigh_power = 6000 # VA\n",
+        )
+        .unwrap();
+        persist(&path, "anomaly.high_power", &Value::Integer(7000)).unwrap();
+        persist(&path, "anomaly.high_power_minutes", &Value::Integer(20)).unwrap();
+        persist(&path, "trend.window", &Value::String(String::from("10m"))).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Linky in the garage\n[serial]\nport = \"-\"\n\n[anomaly]\nhigh_power = 7000 # VA\n\
+             high_power_minutes = 20\n\n[trend]\nwindow = \"10m\"\n"
+        );
+        assert!(persist(&path, "serial.port.name", &Value::Integer(1)).is_err());
+        fs::write(
+            &path,
+            "[[ecowatt.rules]]\nname = \"heater\"\nlevel = \"red\"\n",
+        )
+        .unwrap();
+        persist(&path, "ecowatt.rules.0.max_power", &Value::Integer(3000)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[[ecowatt.rules]]\nname = \"heater\"\nlevel = \"red\"\nmax_power = 3000\n"
+        );
+        assert!(persist(&path, "ecowatt.rules.1.max_power", &Value::Integer(3000)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Raw bytes around the groups that cannot be parsed.
+//!
+//! Intermittent corruption, e.g. bits flipped by an electromagnetic glitch
+//! or bytes lost by the UART, is easier to tell apart from the bytes around
+//! the group in error than from the group alone, once decoded. The reader
+//! keeps the last bytes read, and an error is held until the configured
+//! window of bytes after its group is read too, then reported with the
+//! bytes from the window before the group to the window after it, in
+//! hexadecimal.
+
+use crate::loki::ParseError;
+use crate::pipeline::{Raw, RawHistory};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bytes kept beyond the windows, for the lines read but not parsed yet.
+const SLACK: usize = 4096;
+/// Longest wait for the bytes after a group, e.g. when the stream stops.
+const MAX_HOLD: Duration = Duration::from_secs(5);
+
+struct Held {
+    /// Positions of the context in the stream
+    start: u64,
+    end: u64,
+    since: Instant,
+    error: ParseError,
+}
+
+/// Errors waiting for their context.
+pub struct ErrorContexts {
+    raw: Raw,
+    window: usize,
+    held: Vec<Held>,
+}
+
+impl ErrorContexts {
+    /// Contexts of `window` bytes on both sides of the groups, with the raw
+    /// history to give to the reader.
+    pub fn new(window: usize) -> ErrorContexts {
+        ErrorContexts {
+            raw: Arc::new(Mutex::new(RawHistory::new(2 * window + SLACK))),
+            window,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn raw(&self) -> Raw {
+        Arc::clone(&self.raw)
+    }
+
+    /// Holds the error of a group, ending at the `end` position of the
+    /// stream, until the bytes after it are read.
+    pub fn hold(&mut self, end: u64, error: ParseError, now: Instant) {
+        // With its carriage return
+        let length = error.line.len() as u64 + 1;
+        self.held.push(Held {
+            start: end.saturating_sub(length + self.window as u64),
+            end: end + 1 + self.window as u64,
+            since: now,
+            error,
+        });
+    }
+
+    /// Errors whose context is complete, or held for too long, or all of
+    /// them with `flush`, with their context.
+    pub fn ready(&mut self, now: Instant, flush: bool) -> Vec<ParseError> {
+        let raw = self.raw.lock().unwrap();
+        let (ready, held) = self.held.drain(..).partition(|held: &Held| {
+            flush || raw.read() >= held.end || now.duration_since(held.since) >= MAX_HOLD
+        });
+        self.held = held;
+        ready
+            .into_iter()
+            .map(|held| ParseError {
+                context: Some(hex(&raw.range(held.start, held.end))),
+                ..held.error
+            })
+            .collect()
+    }
+}
+
+/// Bytes in hexadecimal, separated by spaces.
+pub fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn contexts() {
+        let mut contexts = ErrorContexts::new(4);
+        let raw = contexts.raw();
+        let start = Instant::now();
+        let error = |line: &str| ParseError {
+            timestamp: Utc::now(),
+            line: String::from(line),
+            error: String::from("checksum error"),
+            context: None,
+        };
+        raw.lock()
+            .unwrap()
+            .record(b"\x02\nADCO 0 @\r\nPAPP 0\xb003 .\r\n");
+        // The line feed ending the PAPP group is at 24
+        contexts.hold(24, error("PAPP 0\u{fffd}03 ."), start);
+        assert!(contexts.ready(start, false).is_empty());
+
+        raw.lock().unwrap().record(b"IINST");
+        let ready = contexts.ready(start, false);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(
+            ready[0].context.as_deref(),
+            Some("20 30 20 40 0d 0a 50 41 50 50 20 30 b0 30 33 20 2e 0d 0a 49 49 4e 53")
+        );
+
+        // Reported without all the bytes after it once the stream stops
+        contexts.hold(29, error("IINST"), start);
+        assert!(contexts.ready(start, false).is_empty());
+        let ready = contexts.ready(start + MAX_HOLD, false);
+        assert_eq!(
+            ready[0].context.as_deref(),
+            Some("30 33 20 2e 0d 0a 49 49 4e 53 54")
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Daily consumption computed from the meter indexes.
+
+use crate::state::index_label;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use pitinfo_parser::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Number of days kept in memory.
+const KEPT_DAYS: usize = 31;
+/// Longest interruption of the readings for a day to still be complete.
+const MAX_GAP_MINUTES: i64 = 10;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DailyTracker {
+    indexes: HashMap<String, u32>,
+    last_reading: Option<NaiveDateTime>,
+    current_day: Option<NaiveDate>,
+    /// Sum of the indexes at the first reading of the current day
+    day_start: Option<u64>,
+    /// Whether the current day has been observed since its beginning
+    complete: bool,
+    consumption: BTreeMap<NaiveDate, u64>,
+}
+
+impl DailyTracker {
+    pub fn update(&mut self, message: &Message, now: NaiveDateTime) {
+        let (label, value) = match message {
+            Message::Index { period, value } => (index_label(period), *value),
+            _ => return,
+        };
+        self.indexes.insert(label, value);
+        let total: u64 = self.indexes.values().map(|value| *value as u64).sum();
+        let continuous = self
+            .last_reading
+            .is_some_and(|last| now - last <= Duration::minutes(MAX_GAP_MINUTES));
+        self.last_reading = Some(now);
+        if !continuous {
+            self.complete = false;
+        }
+
+        let today = now.date();
+        if self.current_day == Some(today) {
+            return;
+        }
+        if let (Some(day), Some(start), true) = (self.current_day, self.day_start, self.complete) {
+            if day.succ_opt() == Some(today) && total >= start {
+                self.consumption.insert(day, total - start);
+                while self.consumption.len() > KEPT_DAYS {
+                    let oldest = *self.consumption.keys().next().unwrap();
+                    self.consumption.remove(&oldest);
+                }
+            }
+        }
+        self.complete = continuous;
+        self.current_day = Some(today);
+        self.day_start = Some(total);
+    }
+
+    /// Consumption in Wh of the complete days observed.
+    pub fn consumption(&self) -> &BTreeMap<NaiveDate, u64> {
+        &self.consumption
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, TarifPeriod};
+
+    fn index(hour: HourlyTarifPeriod, value: u32) -> Message {
+        Message::Index {
+            period: TarifPeriod {
+                hour,
+                day_color: Some(DayColor::Blue),
+            },
+            value,
+        }
+    }
+
+    #[test]
+    fn daily_consumption() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        let at = |d, h| day(d).and_hms_opt(h, 0, 0).unwrap();
+        let mut tracker = DailyTracker::default();
+
+        // Readings every 5 minutes on January 1st and 2nd
+        tracker.update(&index(HourlyTarifPeriod::OffPeakHours, 100), at(1, 0));
+        for step in 0..2 * 24 * 12 {
+            let now = at(1, 0) + Duration::minutes(5 * step);
+            tracker.update(
+                &index(HourlyTarifPeriod::PeakHours, 1000 + step as u32),
+                now,
+            );
+        }
+        tracker.update(&index(HourlyTarifPeriod::PeakHours, 1576), at(3, 0));
+        // The first day is partial
+        assert_eq!(tracker.consumption().len(), 1);
+        assert_eq!(tracker.consumption().get(&day(2)), Some(&288));
+
+        // Days with interrupted readings are not complete
+        tracker.update(&index(HourlyTarifPeriod::PeakHours, 1600), at(3, 12));
+        tracker.update(&index(HourlyTarifPeriod::PeakHours, 1700), at(4, 0));
+        assert_eq!(tracker.consumption().len(), 1);
+    }
+}
@@ -0,0 +1,221 @@
+//! Meter values on D-Bus.
+//!
+//! Desktop widgets and other daemons of the same machine read the meter
+//! without going through the network: the `org.pitinfo.Meter` service
+//! exports the `/org/pitinfo/Meter` object, whose `org.pitinfo.Meter`
+//! interface has the values of the last frame as properties. Changes are
+//! signalled once per frame with `PropertiesChanged`, for the properties
+//! that changed only.
+//!
+//! D-Bus has no null: values not received yet are empty strings, zeros or
+//! empty collections.
+
+use crate::config::{DbusBus, DbusConfig};
+use crate::meter::MeterInfo;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{is_index, MeterState, Update, Value};
+use pitinfo_parser::Message;
+use std::collections::HashMap;
+use std::io;
+use tokio::sync::watch;
+use zbus::object_server::{InterfaceRef, SignalEmitter};
+
+const SERVICE: &str = "org.pitinfo.Meter";
+const PATH: &str = "/org/pitinfo/Meter";
+
+/// Values of a frame, as exported.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Snapshot {
+    meter: String,
+    tariff_option: String,
+    current_period: String,
+    tomorrow: String,
+    apparent_power: u32,
+    /// Indexes in Wh, by label
+    indexes: HashMap<String, u64>,
+    /// Instantaneous currents in A, by phase
+    currents: Vec<u32>,
+}
+
+impl Snapshot {
+    fn new(meter: Option<&str>, values: &[(String, Value)]) -> Snapshot {
+        let mut snapshot = Snapshot {
+            meter: meter.unwrap_or_default().to_string(),
+            ..Snapshot::default()
+        };
+        for (label, value) in values {
+            match (label.as_str(), value) {
+                ("OPTARIF", Value::Text(text)) => snapshot.tariff_option = text.clone(),
+                ("PTEC", Value::Text(text)) => snapshot.current_period = text.clone(),
+                ("DEMAIN", Value::Text(text)) => snapshot.tomorrow = text.clone(),
+                ("PAPP", Value::Integer(value)) => snapshot.apparent_power = *value as u32,
+                (label, Value::Integer(value)) if is_index(label) => {
+                    snapshot.indexes.insert(label.to_string(), *value);
+                }
+                (label, Value::Integer(value)) if label.starts_with("IINST") => {
+                    snapshot.currents.push(*value as u32)
+                }
+                _ => (),
+            }
+        }
+        snapshot
+    }
+}
+
+struct Meter {
+    snapshot: Snapshot,
+}
+
+#[zbus::interface(name = "org.pitinfo.Meter")]
+impl Meter {
+    /// Address of the meter, ADCO
+    #[zbus(property)]
+    fn meter(&self) -> String {
+        self.snapshot.meter.clone()
+    }
+
+    /// Tariff option as sent in OPTARIF, e.g. `HC..`
+    #[zbus(property)]
+    fn tariff_option(&self) -> String {
+        self.snapshot.tariff_option.clone()
+    }
+
+    /// Current tariff period as sent in PTEC, e.g. `HPJB`
+    #[zbus(property)]
+    fn current_period(&self) -> String {
+        self.snapshot.current_period.clone()
+    }
+
+    /// Color of tomorrow as sent in DEMAIN, Tempo only
+    #[zbus(property)]
+    fn tomorrow(&self) -> String {
+        self.snapshot.tomorrow.clone()
+    }
+
+    /// Apparent power in VA
+    #[zbus(property)]
+    fn apparent_power(&self) -> u32 {
+        self.snapshot.apparent_power
+    }
+
+    /// Indexes in Wh, by label
+    #[zbus(property)]
+    fn indexes(&self) -> HashMap<String, u64> {
+        self.snapshot.indexes.clone()
+    }
+
+    /// Instantaneous currents in A, by phase
+    #[zbus(property)]
+    fn currents(&self) -> Vec<u32> {
+        self.snapshot.currents.clone()
+    }
+}
+
+/// Starts the service. Messages sent to the returned sink update the
+/// properties once per frame.
+pub fn spawn(
+    config: &DbusConfig,
+    meter: watch::Receiver<Option<MeterInfo>>,
+) -> Result<Sink, io::Error> {
+    let bus = config.bus;
+    Ok(pipeline::spawn_sink("dbus", move |receiver| {
+        run(bus, meter, receiver)
+    }))
+}
+
+async fn run(bus: DbusBus, meter: watch::Receiver<Option<MeterInfo>>, mut receiver: Inbox<Update>) {
+    let interface = match connect(bus).await {
+        Ok(interface) => interface,
+        Err(e) => {
+            eprintln!("Unable to export {} on D-Bus: {}", SERVICE, e);
+            return;
+        }
+    };
+    let mut state = MeterState::default();
+    while let Some(item) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_))) {
+            let snapshot = Snapshot::new(
+                meter.borrow().as_ref().map(|meter| meter.address.as_str()),
+                &state.values(),
+            );
+            if let Err(e) = update(&interface, snapshot).await {
+                eprintln!("Unable to signal D-Bus changes: {}", e);
+            }
+        }
+        state.record(&item);
+    }
+}
+
+async fn connect(bus: DbusBus) -> zbus::Result<InterfaceRef<Meter>> {
+    let builder = match bus {
+        DbusBus::System => zbus::connection::Builder::system()?,
+        DbusBus::Session => zbus::connection::Builder::session()?,
+    };
+    let meter = Meter {
+        snapshot: Snapshot::default(),
+    };
+    let connection = builder
+        .name(SERVICE)?
+        .serve_at(PATH, meter)?
+        .build()
+        .await?;
+    connection.object_server().interface(PATH).await
+}
+
+/// Replaces the snapshot, signalling the properties that changed.
+async fn update(interface: &InterfaceRef<Meter>, snapshot: Snapshot) -> zbus::Result<()> {
+    let mut meter = interface.get_mut().await;
+    let previous = std::mem::replace(&mut meter.snapshot, snapshot);
+    let current = &meter.snapshot;
+    let emitter: &SignalEmitter = interface.signal_emitter();
+    if previous.meter != current.meter {
+        meter.meter_changed(emitter).await?;
+    }
+    if previous.tariff_option != current.tariff_option {
+        meter.tariff_option_changed(emitter).await?;
+    }
+    if previous.current_period != current.current_period {
+        meter.current_period_changed(emitter).await?;
+    }
+    if previous.tomorrow != current.tomorrow {
+        meter.tomorrow_changed(emitter).await?;
+    }
+    if previous.apparent_power != current.apparent_power {
+        meter.apparent_power_changed(emitter).await?;
+    }
+    if previous.indexes != current.indexes {
+        meter.indexes_changed(emitter).await?;
+    }
+    if previous.currents != current.currents {
+        meter.currents_changed(emitter).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots() {
+        let values = [
+            (String::from("OPTARIF"), Value::Text(String::from("BBR"))),
+            (String::from("BBRHCJB"), Value::Integer(23916830)),
+            (String::from("PTEC"), Value::Text(String::from("HCJB"))),
+            (String::from("IINST1"), Value::Integer(3)),
+            (String::from("IINST2"), Value::Integer(4)),
+            (String::from("PAPP"), Value::Integer(803)),
+        ];
+        let snapshot = Snapshot::new(Some("031762120110"), &values);
+        assert_eq!(snapshot.meter, "031762120110");
+        assert_eq!(snapshot.current_period, "HCJB");
+        assert_eq!(snapshot.apparent_power, 803);
+        assert_eq!(
+            snapshot.indexes,
+            HashMap::from([(String::from("BBRHCJB"), 23916830)])
+        );
+        assert_eq!(snapshot.currents, vec![3, 4]);
+        assert_eq!(Snapshot::new(None, &[]), Snapshot::default());
+    }
+}
@@ -0,0 +1,249 @@
+//! Metrics derived from the groups, configured as expressions.
+//!
+//! Each entry of the `[derived]` section is an arithmetic expression over the
+//! labels of the frame, e.g. `PAPP * 100 / (ISOUSC * 230 * phases)`, with
+//! `phases` the number of phases of the meter and `sum(BBRH*)` the sum of the
+//! labels starting with `BBRH`. Once a frame is complete, the metrics are
+//! computed and handed to the sinks like the groups, rounded to integers,
+//! under their name in upper case. A metric missing one of its labels is
+//! left out of the frame.
+
+use crate::state::{MeterState, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::iter::Peekable;
+use std::str::Chars;
+
+pub struct Metric {
+    pub label: String,
+    expression: Expression,
+}
+
+#[derive(Debug, PartialEq)]
+enum Expression {
+    Number(f64),
+    Label(String),
+    Phases,
+    /// Sum of the labels starting with a prefix
+    Sum(String),
+    Operation(Box<Expression>, char, Box<Expression>),
+}
+
+/// Metrics of the configuration, by name.
+pub fn metrics(config: &BTreeMap<String, String>) -> Result<Vec<Metric>, io::Error> {
+    config
+        .iter()
+        .map(|(name, expression)| {
+            let expression = parse(expression).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid derived metric {}: {}", name, e),
+                )
+            })?;
+            Ok(Metric {
+                label: name.to_uppercase(),
+                expression,
+            })
+        })
+        .collect()
+}
+
+impl Metric {
+    pub fn evaluate(&self, state: &MeterState) -> Option<i64> {
+        let values: HashMap<String, f64> = state
+            .values()
+            .into_iter()
+            .filter_map(|(label, value)| match value {
+                Value::Integer(value) => Some((label, value as f64)),
+                Value::Text(_) => None,
+            })
+            .collect();
+        let phases = state
+            .instantaneous_current
+            .iter()
+            .filter(|current| current.is_some())
+            .count()
+            .max(1) as f64;
+        let value = evaluate(&self.expression, &values, phases)?;
+        value.is_finite().then(|| value.round() as i64)
+    }
+}
+
+fn evaluate(expression: &Expression, values: &HashMap<String, f64>, phases: f64) -> Option<f64> {
+    match expression {
+        Expression::Number(number) => Some(*number),
+        Expression::Label(label) => values.get(label).copied(),
+        Expression::Phases => Some(phases),
+        Expression::Sum(prefix) => {
+            let mut matching = values
+                .iter()
+                .filter(|(label, _)| label.starts_with(prefix.as_str()))
+                .peekable();
+            matching.peek()?;
+            Some(matching.map(|(_, value)| value).sum())
+        }
+        Expression::Operation(left, operator, right) => {
+            let (left, right) = (
+                evaluate(left, values, phases)?,
+                evaluate(right, values, phases)?,
+            );
+            match operator {
+                '+' => Some(left + right),
+                '-' => Some(left - right),
+                '*' => Some(left * right),
+                _ if right == 0.0 => None,
+                _ => Some(left / right),
+            }
+        }
+    }
+}
+
+fn parse(expression: &str) -> Result<Expression, String> {
+    let mut chars = expression.chars().peekable();
+    let parsed = parse_sum(&mut chars)?;
+    skip_spaces(&mut chars);
+    match chars.next() {
+        None => Ok(parsed),
+        Some(c) => Err(format!("unexpected '{}'", c)),
+    }
+}
+
+/// Terms added or subtracted.
+fn parse_sum(chars: &mut Peekable<Chars>) -> Result<Expression, String> {
+    let mut expression = parse_product(chars)?;
+    loop {
+        skip_spaces(chars);
+        match chars.peek() {
+            Some(&operator) if operator == '+' || operator == '-' => {
+                chars.next();
+                let right = parse_product(chars)?;
+                expression = Expression::Operation(Box::new(expression), operator, Box::new(right));
+            }
+            _ => return Ok(expression),
+        }
+    }
+}
+
+/// Factors multiplied or divided.
+fn parse_product(chars: &mut Peekable<Chars>) -> Result<Expression, String> {
+    let mut expression = parse_factor(chars)?;
+    loop {
+        skip_spaces(chars);
+        match chars.peek() {
+            Some(&operator) if operator == '*' || operator == '/' => {
+                chars.next();
+                let right = parse_factor(chars)?;
+                expression = Expression::Operation(Box::new(expression), operator, Box::new(right));
+            }
+            _ => return Ok(expression),
+        }
+    }
+}
+
+fn parse_factor(chars: &mut Peekable<Chars>) -> Result<Expression, String> {
+    skip_spaces(chars);
+    match chars.peek() {
+        Some('(') => {
+            chars.next();
+            let expression = parse_sum(chars)?;
+            skip_spaces(chars);
+            match chars.next() {
+                Some(')') => Ok(expression),
+                _ => Err(String::from("missing ')'")),
+            }
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let number = take_while(chars, |c| c.is_ascii_digit() || c == '.');
+            number
+                .parse()
+                .map(Expression::Number)
+                .map_err(|_| format!("invalid number {}", number))
+        }
+        Some(c) if c.is_ascii_alphabetic() => {
+            let name = take_while(chars, |c| c.is_ascii_alphanumeric() || c == '_');
+            skip_spaces(chars);
+            match name.as_str() {
+                "phases" => Ok(Expression::Phases),
+                "sum" if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    skip_spaces(chars);
+                    let prefix = take_while(chars, |c| c.is_ascii_alphanumeric() || c == '_');
+                    skip_spaces(chars);
+                    match (chars.next(), chars.next()) {
+                        (Some('*'), Some(')')) if !prefix.is_empty() => Ok(Expression::Sum(prefix)),
+                        _ => Err(String::from("expected sum(PREFIX*)")),
+                    }
+                }
+                _ => Ok(Expression::Label(name)),
+            }
+        }
+        Some(c) => Err(format!("unexpected '{}'", c)),
+        None => Err(String::from("unexpected end")),
+    }
+}
+
+fn take_while(chars: &mut Peekable<Chars>, accept: impl Fn(char) -> bool) -> String {
+    let mut taken = String::new();
+    while let Some(&c) = chars.peek() {
+        if !accept(c) {
+            break;
+        }
+        taken.push(c);
+        chars.next();
+    }
+    taken
+}
+
+fn skip_spaces(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, Message, TarifPeriod};
+
+    #[test]
+    fn derived_metrics() {
+        let mut config = BTreeMap::new();
+        config.insert(
+            String::from("load_percent"),
+            String::from("PAPP * 100 / (30 * 230 * phases)"),
+        );
+        config.insert(String::from("total_index"), String::from("sum(BBRH*)"));
+        config.insert(String::from("off_peak"), String::from("HCHC - 1"));
+        let metrics = metrics(&config).unwrap();
+        assert_eq!(metrics[0].label, "LOAD_PERCENT");
+
+        let mut state = MeterState::default();
+        state.update(&Message::ApparentPower { value: 6900 });
+        for (phase, value) in [(1, 10), (2, 8), (3, 12)] {
+            state.update(&Message::InstantaneousPower { phase, value });
+        }
+        for (hour, value) in [
+            (HourlyTarifPeriod::OffPeakHours, 1000),
+            (HourlyTarifPeriod::PeakHours, 234),
+        ] {
+            state.update(&Message::Index {
+                period: TarifPeriod {
+                    hour,
+                    day_color: Some(DayColor::Blue),
+                },
+                value,
+            });
+        }
+        assert_eq!(metrics[0].evaluate(&state), Some(33));
+        // HCHC is not sent by Tempo meters
+        assert_eq!(metrics[1].label, "OFF_PEAK");
+        assert_eq!(metrics[1].evaluate(&state), None);
+        assert_eq!(metrics[2].evaluate(&state), Some(1234));
+
+        assert_eq!(parse("PAPP / 0").map(|_| ()), Ok(()));
+        assert!(parse("PAPP *").is_err());
+        assert!(parse("(PAPP").is_err());
+        assert!(parse("sum(BBR)").is_err());
+        assert!(parse("PAPP $ 2").is_err());
+    }
+}
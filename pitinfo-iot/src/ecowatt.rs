@@ -0,0 +1,211 @@
+//! Load shedding driven by the RTE EcoWatt signal.
+//!
+//! The hourly EcoWatt level of the grid is polled from the RTE API. Each rule
+//! triggers, at most once per EcoWatt window, when the level reaches the rule
+//! level while the apparent power is above its threshold, and is released
+//! when the level drops back. Triggering and releasing are logged and run the
+//! configured shell commands, e.g. to switch a relay off.
+
+use crate::config::{EcowattConfig, EcowattLevel, EcowattRule};
+use crate::hooks;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::rte;
+use crate::state::Update;
+use chrono::{DateTime, NaiveDate, Timelike, Utc};
+use chrono_tz::Europe::Paris;
+use chrono_tz::Tz;
+use pitinfo_parser::Message;
+use serde::Deserialize;
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{task, time};
+
+#[derive(Deserialize)]
+struct SignalsResponse {
+    signals: Vec<Signal>,
+}
+
+#[derive(Deserialize)]
+struct Signal {
+    jour: String,
+    values: Vec<HourlyValue>,
+}
+
+#[derive(Deserialize)]
+struct HourlyValue {
+    pas: u32,
+    hvalue: u8,
+}
+
+/// Starts polling the EcoWatt signal. Messages sent to the returned sink are
+/// checked against the rules.
+pub fn spawn(config: &EcowattConfig) -> Result<Sink, io::Error> {
+    let signals = Arc::new(Mutex::new(Vec::new()));
+
+    let poll_config = config.clone();
+    let poll_signals = Arc::clone(&signals);
+    tokio::spawn(async move {
+        loop {
+            let config = poll_config.clone();
+            // The HTTP client is blocking
+            let result =
+                task::spawn_blocking(move || fetch_signals(&config).map_err(|e| e.to_string()))
+                    .await;
+            match result {
+                Ok(Ok(signals)) => *poll_signals.lock().unwrap() = signals,
+                Ok(Err(e)) => eprintln!("Unable to fetch the EcoWatt signal: {}", e),
+                Err(e) => eprintln!("Unable to fetch the EcoWatt signal: {}", e),
+            }
+            time::sleep(Duration::from_secs(poll_config.poll_interval)).await;
+        }
+    });
+
+    let rules: Vec<RuleState> = config.rules.iter().cloned().map(RuleState::new).collect();
+    Ok(pipeline::spawn_sink("ecowatt", |receiver| {
+        run(rules, signals, receiver)
+    }))
+}
+
+async fn run(
+    mut rules: Vec<RuleState>,
+    signals: Arc<Mutex<Vec<Signal>>>,
+    mut receiver: Inbox<Update>,
+) {
+    while let Some(message) = receiver.recv_message().await {
+        let power = match message {
+            Message::ApparentPower { value } => value,
+            _ => continue,
+        };
+        // The signal covers mainland France, by hour of French time
+        let now = Utc::now().with_timezone(&Paris);
+        let level = current_level(&signals.lock().unwrap(), now);
+        for rule in rules.iter_mut() {
+            match rule.evaluate(level, power) {
+                Some(true) => {
+                    println!(
+                        "EcoWatt rule '{}' triggered: level {:?}, {} VA",
+                        rule.rule.name, level, power
+                    );
+                    run_command(&rule.rule.command).await;
+                }
+                Some(false) => {
+                    println!("EcoWatt rule '{}' released", rule.rule.name);
+                    run_command(&rule.rule.release_command).await;
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+struct RuleState {
+    rule: EcowattRule,
+    active: bool,
+}
+
+impl RuleState {
+    fn new(rule: EcowattRule) -> RuleState {
+        RuleState {
+            rule,
+            active: false,
+        }
+    }
+
+    /// Returns whether the rule has just been triggered or released.
+    fn evaluate(&mut self, level: Option<EcowattLevel>, power: u16) -> Option<bool> {
+        let alert = level.is_some_and(|level| level >= self.rule.level);
+        if !self.active && alert && self.rule.max_power.is_none_or(|max| power > max) {
+            self.active = true;
+            Some(true)
+        } else if self.active && !alert {
+            self.active = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+async fn run_command(command: &Option<String>) {
+    if let Some(command) = command {
+        hooks::run(command, &[]).await;
+    }
+}
+
+fn fetch_signals(config: &EcowattConfig) -> Result<Vec<Signal>, Box<dyn Error>> {
+    let token = rte::access_token(&config.token_url, &config.client_id, &config.client_secret)?;
+    let response = ureq::get(&config.signals_url)
+        .set("Authorization", &format!("Bearer {}", token))
+        .call()?
+        .into_string()?;
+    parse_signals(&response)
+}
+
+fn parse_signals(response: &str) -> Result<Vec<Signal>, Box<dyn Error>> {
+    let response: SignalsResponse = serde_json::from_str(response)?;
+    Ok(response.signals)
+}
+
+/// EcoWatt level of the current hour, when known.
+fn current_level(signals: &[Signal], now: DateTime<Tz>) -> Option<EcowattLevel> {
+    let day = now.date_naive();
+    signals
+        .iter()
+        .find(|signal| {
+            signal
+                .jour
+                .get(..10)
+                .and_then(|jour| NaiveDate::parse_from_str(jour, "%Y-%m-%d").ok())
+                == Some(day)
+        })?
+        .values
+        .iter()
+        .find(|value| value.pas == now.hour())
+        .and_then(|value| match value.hvalue {
+            // 0 is a green level with carbon free production
+            0 | 1 => Some(EcowattLevel::Green),
+            2 => Some(EcowattLevel::Orange),
+            3 => Some(EcowattLevel::Red),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn hourly_levels() {
+        let signals = parse_signals(
+            r#"{"signals":[{"GenerationFichier":"2024-01-16T00:00:00+01:00",
+            "jour":"2024-01-16T00:00:00+01:00","dvalue":2,"message":"Risque de coupures",
+            "values":[{"pas":7,"hvalue":1},{"pas":8,"hvalue":2},{"pas":9,"hvalue":3}]}]}"#,
+        )
+        .unwrap();
+        let at = |hour| Paris.with_ymd_and_hms(2024, 1, 16, hour, 30, 0).unwrap();
+        assert_eq!(current_level(&signals, at(7)), Some(EcowattLevel::Green));
+        assert_eq!(current_level(&signals, at(8)), Some(EcowattLevel::Orange));
+        assert_eq!(current_level(&signals, at(9)), Some(EcowattLevel::Red));
+        assert_eq!(current_level(&signals, at(10)), None);
+    }
+
+    #[test]
+    fn rules() {
+        let mut rule = RuleState::new(EcowattRule {
+            name: String::from("water heater"),
+            level: EcowattLevel::Orange,
+            max_power: Some(3000),
+            command: None,
+            release_command: None,
+        });
+        assert_eq!(rule.evaluate(Some(EcowattLevel::Red), 2000), None);
+        assert_eq!(rule.evaluate(Some(EcowattLevel::Green), 4000), None);
+        assert_eq!(rule.evaluate(Some(EcowattLevel::Orange), 4000), Some(true));
+        // Stays triggered once the load is shed
+        assert_eq!(rule.evaluate(Some(EcowattLevel::Red), 1000), None);
+        assert_eq!(rule.evaluate(None, 1000), Some(false));
+    }
+}
@@ -0,0 +1,197 @@
+//! Consumption reports and alerts sent by email.
+//!
+//! For households without a chat or home automation notifier, the
+//! `[email]` section gives an SMTP server the daemon sends mail through:
+//! alerts (anomalies, data gaps) as they are raised, and the consumption
+//! reports of the `email-report` scheduled jobs.
+
+use crate::config::{EmailConfig, Language, SmtpSecurity};
+use crate::locale;
+use crate::storage::Energy;
+use chrono::NaiveDate;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::io;
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    alerts: bool,
+}
+
+impl Mailer {
+    pub fn new(config: &EmailConfig) -> Result<Mailer, io::Error> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let mailbox = |address: &str| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|e| invalid(format!("invalid email address '{}': {}", address, e)))
+        };
+        let from = mailbox(&config.from)?;
+        let to = config
+            .to
+            .iter()
+            .map(|address| mailbox(address))
+            .collect::<Result<Vec<_>, _>>()?;
+        if to.is_empty() {
+            return Err(invalid(String::from("email.to requires an address")));
+        }
+        let mut builder = match config.security {
+            SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.host,
+            )),
+        }
+        .map_err(|e| invalid(format!("invalid SMTP host '{}': {}", config.host, e)))?;
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some(username) = &config.username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            ));
+        }
+        Ok(Mailer {
+            transport: builder.build(),
+            from,
+            to,
+            alerts: config.alerts,
+        })
+    }
+
+    /// Sends a mail to every recipient. Failures are logged.
+    pub async fn send(&self, subject: &str, body: String) {
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = match message.body(body) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Unable to build email '{}': {}", subject, e);
+                return;
+            }
+        };
+        if let Err(e) = self.transport.send(message).await {
+            eprintln!("Unable to send email '{}': {}", subject, e);
+        }
+    }
+
+    /// Sends an alert, unless alerts are disabled.
+    pub async fn alert(&self, alert: &str) {
+        if self.alerts {
+            self.send(&format!("Pitinfo alert: {}", alert), format!("{}\n", alert))
+                .await;
+        }
+    }
+}
+
+/// Subject and text of the report of the energy consumed from `first` to
+/// `last`, given in Wh per day color and period.
+pub fn report(
+    first: NaiveDate,
+    last: NaiveDate,
+    energy: &[Energy],
+    language: Language,
+) -> (String, String) {
+    let date = |day| locale::date(language, day);
+    let (consumption, colon, period) = match language {
+        Language::En if first == last => ("Consumption", ":", format!("of {}", date(first))),
+        Language::En => (
+            "Consumption",
+            ":",
+            format!("from {} to {}", date(first), date(last)),
+        ),
+        // With a space before colons
+        Language::Fr if first == last => ("Consommation", " :", format!("du {}", date(first))),
+        Language::Fr => (
+            "Consommation",
+            " :",
+            format!("du {} au {}", date(first), date(last)),
+        ),
+    };
+    let total: i64 = energy.iter().map(|(_, _, wh)| wh).sum();
+    let mut text = format!(
+        "{} {}{} {}\n",
+        consumption,
+        period,
+        colon,
+        locale::kwh(language, total)
+    );
+    if !energy.is_empty() {
+        text.push('\n');
+    }
+    for (color, period, wh) in energy {
+        text.push_str(&format!(
+            "{}{} {}\n",
+            locale::period_name(language, period, color.as_deref()),
+            colon,
+            locale::kwh(language, *wh)
+        ));
+    }
+    let subject = match language {
+        Language::En => format!("Pitinfo consumption {}", period),
+        Language::Fr => format!("Consommation Pitinfo {}", period),
+    };
+    (subject, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let day = |month, day| NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+        let (subject, text) = report(
+            day(1, 1),
+            day(1, 31),
+            &[
+                (Some(String::from("B")), String::from("HC"), 120_460),
+                (Some(String::from("R")), String::from("HP"), 30_000),
+            ],
+            Language::En,
+        );
+        assert_eq!(subject, "Pitinfo consumption from 2024-01-01 to 2024-01-31");
+        assert_eq!(
+            text,
+            "Consumption from 2024-01-01 to 2024-01-31: 150.5 kWh\n\n\
+             HC blue days: 120.5 kWh\n\
+             HP red days: 30.0 kWh\n"
+        );
+        let (subject, text) = report(day(1, 16), day(1, 16), &[], Language::En);
+        assert_eq!(subject, "Pitinfo consumption of 2024-01-16");
+        assert_eq!(text, "Consumption of 2024-01-16: 0.0 kWh\n");
+        let (subject, text) = report(
+            day(1, 16),
+            day(1, 16),
+            &[(Some(String::from("B")), String::from("HC"), 8_260)],
+            Language::Fr,
+        );
+        assert_eq!(subject, "Consommation Pitinfo du 16/01/2024");
+        assert_eq!(
+            text,
+            "Consommation du 16/01/2024 : 8,3 kWh\n\n\
+             Heures Creuses Jour Bleu : 8,3 kWh\n"
+        );
+
+        let config = EmailConfig {
+            host: String::from("smtp.example.org"),
+            port: None,
+            security: SmtpSecurity::Starttls,
+            username: None,
+            password: None,
+            from: String::from("Pitinfo <pitinfo@example.org>"),
+            to: vec![String::from("not an address")],
+            alerts: true,
+        };
+        assert!(Mailer::new(&config).is_err());
+    }
+}
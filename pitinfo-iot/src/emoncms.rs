@@ -0,0 +1,133 @@
+//! Posts meter values to Emoncms.
+//!
+//! OpenEnergyMonitor installations read the meter with the teleinfo
+//! interfacer of emonhub: the daemon can replace it and post the numeric
+//! values of a frame to the input API instead, at most once per configured
+//! interval, as inputs of a node named after the configuration. Emoncms
+//! inputs are numbers only, the text values, e.g. PTEC, are left out.
+
+use crate::config::{DeliveryConfig, EmoncmsConfig};
+use crate::pipeline::{self, Inbox, Sink};
+use crate::scale::Scales;
+use crate::state::{MeterState, Update, Value};
+use pitinfo_parser::Message;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Starts the poster. Messages sent to the returned sink are posted once per
+/// interval.
+pub fn spawn(config: &EmoncmsConfig) -> Result<Sink, io::Error> {
+    let scales = Scales::new(&config.scale)?;
+    let config = config.clone();
+    let delivery = config.delivery.clone();
+    Ok(pipeline::spawn_sink_with(
+        "emoncms",
+        &delivery,
+        move |receiver| run(config, scales, receiver),
+    ))
+}
+
+async fn run(config: EmoncmsConfig, scales: Scales, mut receiver: Inbox<Update>) {
+    let url = post_url(&config.url);
+    let interval = Duration::from_secs(config.interval);
+    let mut state = MeterState::default();
+    let mut last_post: Option<Instant> = None;
+    while let Some(item) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_)))
+            && last_post.is_none_or(|last| last.elapsed() >= interval)
+        {
+            let inputs = inputs(&state.values(), &scales);
+            if inputs != "{}" {
+                let url = url.clone();
+                let node = config.node.clone();
+                let apikey = config.apikey.clone();
+                let result = post(url, node, apikey, inputs, &config.delivery).await;
+                match result {
+                    Ok(()) => last_post = Some(Instant::now()),
+                    Err(e) => eprintln!("Unable to post to Emoncms: {}", e),
+                }
+            }
+        }
+        state.record(&item);
+    }
+}
+
+async fn post(
+    url: String,
+    node: String,
+    apikey: String,
+    inputs: String,
+    delivery: &DeliveryConfig,
+) -> Result<(), String> {
+    // The HTTP client is blocking
+    pipeline::deliver("emoncms", delivery, move || {
+        let response = ureq::post(&url)
+            .send_form(&[
+                ("node", node.as_str()),
+                ("fulljson", inputs.as_str()),
+                ("apikey", apikey.as_str()),
+            ])
+            .map_err(|e| e.to_string())?;
+        accepted(&response.into_string().map_err(|e| e.to_string())?)
+    })
+    .await
+}
+
+fn post_url(url: &str) -> String {
+    format!("{}/input/post", url.trim_end_matches('/'))
+}
+
+/// Inputs of a post, a JSON object of the numeric values.
+fn inputs(values: &[(String, Value)], scales: &Scales) -> String {
+    let inputs: serde_json::Map<String, serde_json::Value> = values
+        .iter()
+        .filter(|(_, value)| matches!(value, Value::Integer(_)))
+        .map(|(label, value)| (label.clone(), scales.json(label, value)))
+        .collect();
+    serde_json::Value::Object(inputs).to_string()
+}
+
+/// Whether Emoncms accepted a post, it answers errors with a success status
+/// too: `ok` or `{"success": true}` when accepted.
+fn accepted(body: &str) -> Result<(), String> {
+    let body = body.trim();
+    if body == "ok" {
+        return Ok(());
+    }
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(response) if response["success"] == true => Ok(()),
+        Ok(response) => Err(response["message"]
+            .as_str()
+            .map_or_else(|| body.to_string(), String::from)),
+        Err(_) => Err(body.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_posts() {
+        assert_eq!(
+            post_url("https://emoncms.org/"),
+            "https://emoncms.org/input/post"
+        );
+        let values = [
+            (String::from("BBRHCJB"), Value::Integer(23916830)),
+            (String::from("PAPP"), Value::Integer(800)),
+            (String::from("PTEC"), Value::Text(String::from("HPJB"))),
+        ];
+        assert_eq!(
+            inputs(&values, &Scales::default()),
+            r#"{"BBRHCJB":23916830,"PAPP":800}"#
+        );
+        assert_eq!(accepted("ok"), Ok(()));
+        assert_eq!(accepted(r#"{"success": true}"#), Ok(()));
+        assert_eq!(
+            accepted(r#"{"success": false, "message": "Username or password invalid"}"#),
+            Err(String::from("Username or password invalid"))
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Reconciliation of the local daily consumption with the Enedis data API.
+//!
+//! Enedis publishes the daily consumption measured by the meter the next
+//! day. Each complete day observed locally is compared with it once
+//! available, a divergence pointing at lost readings or a meter issue.
+
+use crate::config::EnedisConfig;
+use crate::daily::DailyTracker;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::Update;
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use tokio::task;
+use tokio::time::{self, MissedTickBehavior};
+
+#[derive(Deserialize)]
+struct ConsumptionResponse {
+    meter_reading: MeterReading,
+}
+
+#[derive(Deserialize)]
+struct MeterReading {
+    interval_reading: Vec<IntervalReading>,
+}
+
+#[derive(Deserialize)]
+struct IntervalReading {
+    value: String,
+    date: String,
+}
+
+/// Starts the reconciliation job. Messages sent to the returned sink feed the
+/// local daily consumption, over the days of `timezone`.
+pub fn spawn(config: &EnedisConfig, timezone: Tz) -> Result<Sink, io::Error> {
+    let config = config.clone();
+    Ok(pipeline::spawn_sink("enedis", move |receiver| {
+        run(config, timezone, receiver)
+    }))
+}
+
+async fn run(config: EnedisConfig, timezone: Tz, mut receiver: Inbox<Update>) {
+    let interval = Duration::from_secs(config.check_interval);
+    let mut checks = time::interval_at(time::Instant::now() + interval, interval);
+    checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut tracker = DailyTracker::default();
+    let mut reconciled = BTreeSet::new();
+
+    loop {
+        tokio::select! {
+            message = receiver.recv_message() => match message {
+                Some(message) => {
+                    tracker.update(&message, Utc::now().with_timezone(&timezone).naive_local());
+                    continue;
+                }
+                None => return,
+            },
+            _ = checks.tick() => (),
+        }
+
+        let local = tracker.consumption();
+        reconciled.retain(|day| local.contains_key(day));
+        let pending: Vec<NaiveDate> = local
+            .keys()
+            .filter(|day| !reconciled.contains(*day))
+            .copied()
+            .collect();
+        let (first, last) = match (pending.first(), pending.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => continue,
+        };
+        let fetch_config = config.clone();
+        // The HTTP client is blocking
+        let remote = task::spawn_blocking(move || {
+            fetch_consumption(&fetch_config, first, last + ChronoDuration::days(1))
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        let remote = match remote {
+            Ok(Ok(remote)) => remote,
+            Ok(Err(e)) => {
+                eprintln!("Unable to fetch the Enedis consumption: {}", e);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Unable to fetch the Enedis consumption: {}", e);
+                continue;
+            }
+        };
+        for day in pending {
+            // Not published yet, checked again later
+            let Some(remote) = remote.get(&day) else {
+                continue;
+            };
+            let local = local[&day];
+            if diverges(local, *remote, config.tolerance) {
+                eprintln!(
+                    "Consumption of {} differs from Enedis: {} Wh locally, {} Wh for Enedis",
+                    day, local, remote
+                );
+            }
+            reconciled.insert(day);
+        }
+    }
+}
+
+fn fetch_consumption(
+    config: &EnedisConfig,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<BTreeMap<NaiveDate, u64>, Box<dyn Error>> {
+    let response = ureq::get(&config.url)
+        .query("usage_point_id", &config.prm)
+        .query("start", &start.format("%Y-%m-%d").to_string())
+        .query("end", &end.format("%Y-%m-%d").to_string())
+        .set("Authorization", &format!("Bearer {}", config.token))
+        .set("Accept", "application/json")
+        .call()?
+        .into_string()?;
+    parse_consumption(&response)
+}
+
+/// Daily consumption in Wh by day.
+fn parse_consumption(response: &str) -> Result<BTreeMap<NaiveDate, u64>, Box<dyn Error>> {
+    let response: ConsumptionResponse = serde_json::from_str(response)?;
+    response
+        .meter_reading
+        .interval_reading
+        .iter()
+        .map(|reading| {
+            let day = NaiveDate::parse_from_str(&reading.date, "%Y-%m-%d")?;
+            Ok((day, reading.value.parse()?))
+        })
+        .collect()
+}
+
+fn diverges(local: u64, remote: u64, tolerance: f64) -> bool {
+    (local as f64 - remote as f64).abs() > remote as f64 * tolerance / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daily_consumption() {
+        let consumption = parse_consumption(
+            r#"{"meter_reading":{"usage_point_id":"16401220101758","start":"2024-01-15",
+            "end":"2024-01-17","quality":"BRUT","reading_type":{"measurement_kind":"energy",
+            "measuring_period":"P1D","unit":"Wh","aggregate":"sum"},"interval_reading":[
+            {"value":"12530","date":"2024-01-15"},{"value":"9870","date":"2024-01-16"}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            consumption.get(&NaiveDate::from_ymd_opt(2024, 1, 16).unwrap()),
+            Some(&9870)
+        );
+        assert_eq!(consumption.len(), 2);
+
+        assert!(!diverges(12600, 12530, 1.0));
+        assert!(diverges(12000, 12530, 1.0));
+    }
+}
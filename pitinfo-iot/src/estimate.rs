@@ -0,0 +1,135 @@
+//! Active power estimated from the apparent power.
+//!
+//! Historic mode only sends the apparent power, in VA, while dashboards
+//! usually want the active power, in W. It is estimated with a power factor
+//! configured per load level or hour of the day, and published under a label
+//! and a name saying it is an estimate, never taken for a measure.
+
+use crate::config::ActivePowerConfig;
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use std::io;
+
+pub const LABEL: &str = "PACT_EST";
+
+pub struct ActivePower {
+    config: ActivePowerConfig,
+    timezone: Tz,
+    estimate: Option<f64>,
+}
+
+impl ActivePower {
+    pub fn new(config: &ActivePowerConfig, timezone: Tz) -> Result<ActivePower, io::Error> {
+        let factors = config
+            .levels
+            .iter()
+            .map(|level| level.power_factor)
+            .chain(config.hours.iter().map(|hours| hours.power_factor))
+            .chain([config.power_factor]);
+        for factor in factors {
+            if !(factor > 0.0 && factor <= 1.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid power factor {}, expected in ]0, 1]", factor),
+                ));
+            }
+        }
+        if let Some(hours) = config.hours.iter().find(|h| h.start > 23 || h.end > 24) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid hours {}-{}", hours.start, hours.end),
+            ));
+        }
+        Ok(ActivePower {
+            config: config.clone(),
+            timezone,
+            estimate: None,
+        })
+    }
+
+    pub fn update(&mut self, apparent_power: u16, now: DateTime<Utc>) {
+        let hour = now.with_timezone(&self.timezone).hour();
+        let factor = self.power_factor(apparent_power, hour);
+        self.estimate = Some((apparent_power as f64 * factor).round());
+    }
+
+    /// Power factor of the highest load level reached, else of the hours,
+    /// else the default one.
+    fn power_factor(&self, apparent_power: u16, hour: u32) -> f64 {
+        let level = self
+            .config
+            .levels
+            .iter()
+            .filter(|level| apparent_power >= level.above)
+            .max_by_key(|level| level.above);
+        if let Some(level) = level {
+            return level.power_factor;
+        }
+        let hours = self.config.hours.iter().find(|hours| {
+            if hours.start <= hours.end {
+                (hours.start..hours.end).contains(&hour)
+            } else {
+                // Across midnight
+                hour >= hours.start || hour < hours.end
+            }
+        });
+        hours.map_or(self.config.power_factor, |hours| hours.power_factor)
+    }
+
+    /// Estimated active power, in W.
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        self.estimate
+            .map(|watts| (LABEL, watts))
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HourFactor, LevelFactor};
+    use chrono::TimeZone;
+
+    #[test]
+    fn power_factors() {
+        let config = ActivePowerConfig {
+            power_factor: 0.9,
+            levels: vec![
+                LevelFactor {
+                    above: 2000,
+                    power_factor: 0.95,
+                },
+                LevelFactor {
+                    above: 5000,
+                    power_factor: 0.99,
+                },
+            ],
+            hours: vec![HourFactor {
+                start: 22,
+                end: 6,
+                power_factor: 0.7,
+            }],
+        };
+        let mut active = ActivePower::new(&config, chrono_tz::Europe::Paris).unwrap();
+        assert_eq!(active.values(), vec![]);
+        // 12:00 in Paris
+        active.update(1000, Utc.with_ymd_and_hms(2024, 1, 16, 11, 0, 0).unwrap());
+        assert_eq!(active.values(), vec![(LABEL, 900.0)]);
+        // 23:00 in Paris
+        active.update(1000, Utc.with_ymd_and_hms(2024, 1, 16, 22, 0, 0).unwrap());
+        assert_eq!(active.values(), vec![(LABEL, 700.0)]);
+        active.update(6000, Utc.with_ymd_and_hms(2024, 1, 16, 22, 0, 0).unwrap());
+        assert_eq!(active.values(), vec![(LABEL, 5940.0)]);
+        assert_eq!(active.power_factor(3000, 12), 0.95);
+        assert_eq!(active.power_factor(1000, 5), 0.7);
+        assert_eq!(active.power_factor(1000, 6), 0.9);
+
+        let invalid = ActivePowerConfig {
+            power_factor: 1.2,
+            levels: Vec::new(),
+            hours: Vec::new(),
+        };
+        assert!(ActivePower::new(&invalid, chrono_tz::UTC).is_err());
+    }
+}
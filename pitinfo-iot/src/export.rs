@@ -0,0 +1,426 @@
+//! Export of the stored history to Parquet files.
+//!
+//! One file is written per local day, in Hive style partitions
+//! (`<directory>/date=2024-01-16/readings.parquet`), so that the archive can
+//! be loaded by pandas or DuckDB directly.
+//!
+//! The anonymized export, meant to be shared with research projects, writes
+//! the whole history to a single file of 15 minute aggregates instead. The
+//! meter address is never stored, and the indexes, which could be matched
+//! with the bills of the household, are replaced by the energy consumed in
+//! each period.
+//!
+//! The heatmap export sums the energy of those periods per local hour and
+//! day, one row per cell, to plot the hour of the day against the day of the
+//! month.
+
+use crate::state::{is_index, Value};
+use crate::storage::{Aggregate, Reading, Resolution, Store};
+use arrow_array::{
+    ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray, TimestampMillisecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, Datelike, DurationRound, NaiveDate, TimeDelta, Timelike, Utc};
+use chrono_tz::Tz;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Writes every stored day to `directory`, returning the number of files.
+pub fn export(store: &Store, directory: &Path) -> Result<usize, Box<dyn Error>> {
+    let mut files = 0;
+    for day in store.days()? {
+        let readings = store.day_readings(day)?;
+        if readings.is_empty() {
+            continue;
+        }
+        let partition = directory.join(format!("date={}", day.format("%Y-%m-%d")));
+        fs::create_dir_all(&partition)?;
+        write_parquet(&partition.join("readings.parquet"), &readings)?;
+        files += 1;
+    }
+    Ok(files)
+}
+
+/// Length of the periods of the anonymized export.
+const PERIOD_MINUTES: i64 = 15;
+
+/// Aggregates of a period of the anonymized export.
+#[derive(Debug, Default, PartialEq)]
+struct Period {
+    /// Energy consumed, in Wh, unknown for the first period
+    energy: Option<i64>,
+    power_sum: f64,
+    power_count: u32,
+    power_max: Option<i64>,
+    /// Last tariff period, e.g. `HCJB`
+    tariff_period: Option<String>,
+}
+
+/// Writes the anonymized history to the `path` file, returning the number of
+/// periods.
+pub fn export_anonymized(store: &Store, path: &Path) -> Result<usize, Box<dyn Error>> {
+    let periods = history_periods(store)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_periods(path, &periods)?;
+    Ok(periods.len())
+}
+
+/// Writes the energy consumed per local hour and day to the `path` file,
+/// returning the number of cells.
+pub fn export_heatmap(store: &Store, path: &Path, timezone: Tz) -> Result<usize, Box<dyn Error>> {
+    let cells = heatmap_cells(&history_periods(store)?, timezone);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_heatmap(path, &cells)?;
+    Ok(cells.len())
+}
+
+/// Periods of the whole history, from raw readings where still available.
+fn history_periods(store: &Store) -> Result<BTreeMap<DateTime<Utc>, Period>, Box<dyn Error>> {
+    // Last index of the previous periods, by label
+    let mut indexes = BTreeMap::new();
+    let mut periods = BTreeMap::new();
+    for day in store.history_days()? {
+        let readings = store.day_readings(day)?;
+        let aggregates = if readings.is_empty() {
+            let end = day.succ_opt().unwrap_or(day);
+            store.aggregates(
+                Resolution::Minute,
+                store.day_start(day).timestamp_millis(),
+                store.day_start(end).timestamp_millis(),
+            )?
+        } else {
+            readings.into_iter().map(reading_aggregate).collect()
+        };
+        periods.extend(anonymized_periods(&aggregates, &mut indexes));
+    }
+    Ok(periods)
+}
+
+/// A raw reading, as the aggregate of a single value.
+fn reading_aggregate(reading: Reading) -> (DateTime<Utc>, String, Aggregate) {
+    let aggregate = match reading.value {
+        Value::Integer(value) => Aggregate {
+            count: 1,
+            average: Some(value as f64),
+            minimum: Some(value as i64),
+            maximum: Some(value as i64),
+            last: Some(value as i64),
+            text: None,
+        },
+        Value::Text(text) => Aggregate {
+            count: 1,
+            text: Some(text),
+            ..Aggregate::default()
+        },
+    };
+    (reading.timestamp, reading.label, aggregate)
+}
+
+/// Groups aggregates in chronological order into periods. `indexes` holds
+/// the last index of each label, carried over to the next call.
+fn anonymized_periods(
+    aggregates: &[(DateTime<Utc>, String, Aggregate)],
+    indexes: &mut BTreeMap<String, i64>,
+) -> BTreeMap<DateTime<Utc>, Period> {
+    let mut periods: BTreeMap<DateTime<Utc>, Period> = BTreeMap::new();
+    let mut last_indexes: BTreeMap<DateTime<Utc>, BTreeMap<&str, i64>> = BTreeMap::new();
+    for (timestamp, label, aggregate) in aggregates {
+        let Ok(start) = timestamp.duration_trunc(TimeDelta::minutes(PERIOD_MINUTES)) else {
+            continue;
+        };
+        let period = periods.entry(start).or_default();
+        match (label.as_str(), aggregate) {
+            ("PAPP", aggregate) => {
+                if let Some(average) = aggregate.average {
+                    period.power_sum += average;
+                    period.power_count += 1;
+                }
+                period.power_max = period.power_max.max(aggregate.maximum);
+            }
+            ("PTEC", aggregate) => period.tariff_period = aggregate.text.clone(),
+            (label, aggregate) if is_index(label) => {
+                if let Some(last) = aggregate.last {
+                    last_indexes.entry(start).or_default().insert(label, last);
+                }
+            }
+            // Anything else could tell the household apart
+            _ => (),
+        }
+    }
+    for (start, last) in last_indexes {
+        let mut energy = None;
+        for (label, value) in last {
+            if let Some(previous) = indexes.insert(label.to_string(), value) {
+                // A decrease is a meter reset
+                *energy.get_or_insert(0) += (value - previous).max(0);
+            }
+        }
+        if let Some(period) = periods.get_mut(&start) {
+            period.energy = energy;
+        }
+    }
+    periods
+}
+
+fn write_periods(
+    path: &Path,
+    periods: &BTreeMap<DateTime<Utc>, Period>,
+) -> Result<(), Box<dyn Error>> {
+    let starts: TimestampMillisecondArray = periods
+        .keys()
+        .map(|start| Some(start.timestamp_millis()))
+        .collect();
+    let energies: Int64Array = periods.values().map(|period| period.energy).collect();
+    let averages: Float64Array = periods
+        .values()
+        .map(|period| {
+            (period.power_count > 0).then(|| period.power_sum / period.power_count as f64)
+        })
+        .collect();
+    let maximums: Int64Array = periods.values().map(|period| period.power_max).collect();
+    let tariff_periods: StringArray = periods
+        .values()
+        .map(|period| period.tariff_period.as_deref())
+        .collect();
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new(
+            "start",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("energy_wh", DataType::Int64, true),
+        Field::new("apparent_power_average", DataType::Float64, true),
+        Field::new("apparent_power_max", DataType::Int64, true),
+        Field::new("tariff_period", DataType::Utf8, true),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(starts.with_timezone("UTC")),
+        Arc::new(energies),
+        Arc::new(averages),
+        Arc::new(maximums),
+        Arc::new(tariff_periods),
+    ];
+    write_batch(path, schema, columns)
+}
+
+/// Energy in Wh per local day and hour of the day. The hour repeated when
+/// daylight saving time ends holds the energy of both.
+fn heatmap_cells(
+    periods: &BTreeMap<DateTime<Utc>, Period>,
+    timezone: Tz,
+) -> BTreeMap<(NaiveDate, u32), i64> {
+    let mut cells = BTreeMap::new();
+    for (start, period) in periods {
+        if let Some(energy) = period.energy {
+            let start = start.with_timezone(&timezone);
+            *cells.entry((start.date_naive(), start.hour())).or_default() += energy;
+        }
+    }
+    cells
+}
+
+fn write_heatmap(
+    path: &Path,
+    cells: &BTreeMap<(NaiveDate, u32), i64>,
+) -> Result<(), Box<dyn Error>> {
+    let months: StringArray = cells
+        .keys()
+        .map(|(day, _)| Some(day.format("%Y-%m").to_string()))
+        .collect();
+    let days: Int64Array = cells
+        .keys()
+        .map(|(day, _)| Some(day.day() as i64))
+        .collect();
+    let hours: Int64Array = cells.keys().map(|(_, hour)| Some(*hour as i64)).collect();
+    let energies: Float64Array = cells
+        .values()
+        .map(|energy| Some(*energy as f64 / 1000.0))
+        .collect();
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("month", DataType::Utf8, false),
+        Field::new("day", DataType::Int64, false),
+        Field::new("hour", DataType::Int64, false),
+        Field::new("energy_kwh", DataType::Float64, false),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(months),
+        Arc::new(days),
+        Arc::new(hours),
+        Arc::new(energies),
+    ];
+    write_batch(path, schema, columns)
+}
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("label", DataType::Utf8, false),
+        Field::new("value", DataType::Int64, true),
+        Field::new("text", DataType::Utf8, true),
+    ])
+}
+
+fn write_parquet(path: &Path, readings: &[Reading]) -> Result<(), Box<dyn Error>> {
+    let timestamps: TimestampMillisecondArray = readings
+        .iter()
+        .map(|reading| Some(reading.timestamp.timestamp_millis()))
+        .collect();
+    let labels: StringArray = readings
+        .iter()
+        .map(|reading| Some(reading.label.as_str()))
+        .collect();
+    let values: Int64Array = readings
+        .iter()
+        .map(|reading| match reading.value {
+            Value::Integer(value) => Some(value as i64),
+            Value::Text(_) => None,
+        })
+        .collect();
+    let texts: StringArray = readings
+        .iter()
+        .map(|reading| match &reading.value {
+            Value::Integer(_) => None,
+            Value::Text(text) => Some(text.as_str()),
+        })
+        .collect();
+
+    let schema = Arc::new(schema());
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(timestamps.with_timezone("UTC")),
+        Arc::new(labels),
+        Arc::new(values),
+        Arc::new(texts),
+    ];
+    write_batch(path, schema, columns)
+}
+
+fn write_batch(
+    path: &Path,
+    schema: Arc<Schema>,
+    columns: Vec<ArrayRef>,
+) -> Result<(), Box<dyn Error>> {
+    let batch = RecordBatch::try_new(Arc::clone(&schema), columns)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate, TimeZone};
+    use chrono_tz::Europe::Paris;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn daily_files() {
+        let mut store = Store::open(Path::new(":memory:"), Paris).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        for hour in [1, 2, 25] {
+            store
+                .insert_batch(&[(
+                    store.day_start(day) + Duration::hours(hour),
+                    vec![
+                        (String::from("PAPP"), Value::Integer(803)),
+                        (String::from("PTEC"), Value::Text(String::from("HCJB"))),
+                    ],
+                )])
+                .unwrap();
+        }
+
+        let directory = std::env::temp_dir().join(format!("pitinfo-export-{}", std::process::id()));
+        assert_eq!(export(&store, &directory).unwrap(), 2);
+        let file = File::open(directory.join("date=2024-01-16/readings.parquet")).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        assert!(directory.join("date=2024-01-17/readings.parquet").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn anonymized_export() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 16, 12, 0, 0).unwrap();
+        let reading = |minutes, label: &str, value| {
+            reading_aggregate(Reading {
+                timestamp: start + Duration::minutes(minutes),
+                label: String::from(label),
+                value,
+            })
+        };
+        let mut indexes = BTreeMap::new();
+        let periods = anonymized_periods(
+            &[
+                reading(0, "BBRHCJB", Value::Integer(1000)),
+                reading(0, "PAPP", Value::Integer(800)),
+                reading(0, "PTEC", Value::Text(String::from("HCJB"))),
+                reading(10, "PAPP", Value::Integer(1200)),
+                reading(10, "BBRHCJB", Value::Integer(1200)),
+                reading(20, "BBRHCJB", Value::Integer(1300)),
+                reading(20, "BBRHPJB", Value::Integer(50)),
+                reading(20, "ADCO", Value::Text(String::from("031762120110"))),
+            ],
+            &mut indexes,
+        );
+        assert_eq!(periods.len(), 2);
+        assert_eq!(
+            periods[&start],
+            Period {
+                energy: None,
+                power_sum: 2000.0,
+                power_count: 2,
+                power_max: Some(1200),
+                tariff_period: Some(String::from("HCJB")),
+            }
+        );
+        assert_eq!(periods[&(start + Duration::minutes(15))].energy, Some(100));
+
+        // Carried over to the next day
+        let periods = anonymized_periods(
+            &[reading(30, "BBRHCJB", Value::Integer(1250))],
+            &mut indexes,
+        );
+        assert_eq!(periods[&(start + Duration::minutes(30))].energy, Some(0));
+    }
+
+    #[test]
+    fn heatmap() {
+        // 23:30 and 23:45 in Paris
+        let start = Utc.with_ymd_and_hms(2024, 1, 16, 22, 30, 0).unwrap();
+        let period = |energy| Period {
+            energy,
+            ..Period::default()
+        };
+        let periods = BTreeMap::from([
+            (start, period(Some(100))),
+            (start + Duration::minutes(15), period(Some(150))),
+            (start + Duration::minutes(30), period(Some(40))),
+            (start + Duration::minutes(45), period(None)),
+        ]);
+        let cells = heatmap_cells(&periods, Paris);
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        assert_eq!(
+            cells.into_iter().collect::<Vec<_>>(),
+            vec![((day, 23), 250), ((day.succ_opt().unwrap(), 0), 40)]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Power factor measured in standard mode.
+//!
+//! Standard mode sends both the apparent power, in SINSTS, and the index of
+//! the active energy, in EAST: over an interval, the energy drawn gives the
+//! mean active power, and its ratio to the mean apparent power the power
+//! factor. A low factor points at heavy inductive loads, which is why the
+//! apparent power reaches the subscribed power earlier than the active one.
+//! EAST only counts whole Wh, so the factor is approximate, the more so over
+//! short intervals or at low power.
+
+use std::time::{Duration, Instant};
+
+pub const LABEL: &str = "PF_EST";
+
+/// Interval being measured.
+struct Interval {
+    start: Instant,
+    /// EAST at the start, in Wh
+    index: u32,
+    /// Apparent energy since the start, in VAh
+    apparent: f64,
+}
+
+pub struct PowerFactor {
+    interval: Duration,
+    current: Option<Interval>,
+    /// Latest apparent power, in VA, holding until the next one
+    power: Option<(u16, Instant)>,
+    factor: Option<f64>,
+}
+
+impl PowerFactor {
+    pub fn new(interval: Duration) -> PowerFactor {
+        PowerFactor {
+            interval,
+            current: None,
+            power: None,
+            factor: None,
+        }
+    }
+
+    /// Records the apparent power.
+    pub fn power(&mut self, power: u16, now: Instant) {
+        self.integrate(now);
+        self.power = Some((power, now));
+    }
+
+    /// Records the index of the active energy, ending the interval once it
+    /// lasted long enough.
+    pub fn index(&mut self, index: u32, now: Instant) {
+        self.integrate(now);
+        match &self.current {
+            // A lower index is that of another meter
+            Some(current) if index >= current.index => {
+                if now.duration_since(current.start) < self.interval {
+                    return;
+                }
+                let active = f64::from(index - current.index);
+                if current.apparent > 0.0 {
+                    self.factor = Some((active / current.apparent).min(1.0));
+                }
+            }
+            _ => self.factor = None,
+        }
+        self.current = Some(Interval {
+            start: now,
+            index,
+            apparent: 0.0,
+        });
+    }
+
+    /// Adds the apparent energy since the latest power to the interval.
+    fn integrate(&mut self, now: Instant) {
+        if let (Some(current), Some((power, last))) = (&mut self.current, self.power) {
+            let hours = now.saturating_duration_since(last).as_secs_f64() / 3600.0;
+            current.apparent += f64::from(power) * hours;
+        }
+        if let Some((power, _)) = self.power {
+            self.power = Some((power, now));
+        }
+    }
+
+    /// Power factor of the last interval, rounded for publishing.
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        self.factor
+            .map(|factor| (LABEL, (factor * 100.0).round() / 100.0))
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inductive_load() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut factor = PowerFactor::new(Duration::from_secs(300));
+        factor.power(2000, start);
+        factor.index(10_000, start);
+        assert_eq!(factor.values(), vec![]);
+
+        // 2000 VA for 6 minutes, 200 VAh, of which 160 Wh are active
+        for step in 1..=36 {
+            factor.power(2000, at(step * 10));
+            factor.index(10_000 + step as u32 * 160 / 36, at(step * 10));
+        }
+        assert_eq!(factor.values(), vec![(LABEL, 0.8)]);
+
+        // Another meter
+        factor.index(500, at(370));
+        assert_eq!(factor.values(), vec![]);
+    }
+}
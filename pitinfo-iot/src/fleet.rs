@@ -0,0 +1,392 @@
+//! Aggregation of the meters of several sites.
+//!
+//! In fleet mode the daemon reads no meter: it subscribes to the JSON state
+//! topics of the instances reading the meters of the sites, published with
+//! `format = "json"`, stores the history of each site in its own database
+//! and publishes the totals of the fleet. A site silent for too long and a
+//! total apparent power over the configured limit are reported.
+
+use crate::config::{Config, FleetConfig, MqttConfig, StorageConfig};
+use crate::hooks;
+use crate::mqtt;
+use crate::pipeline::Sink;
+use crate::state::Update;
+use crate::storage;
+use pitinfo_parser::{parse_group_unchecked, Message};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use serde_json::Value as Json;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Time between two checks of the fleet and publications of its totals.
+const CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// No state received from the site for the configured silence
+    Silent(String),
+    Back(String),
+    /// Total apparent power over the limit, in VA
+    HighPower(u32),
+    PowerBack(u32),
+}
+
+impl Alert {
+    /// Name given to the hook, in `PITINFO_FLEET`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alert::Silent(_) => "silent",
+            Alert::Back(_) => "back",
+            Alert::HighPower(_) => "high_power",
+            Alert::PowerBack(_) => "power_back",
+        }
+    }
+
+    fn site(&self) -> &str {
+        match self {
+            Alert::Silent(site) | Alert::Back(site) => site,
+            Alert::HighPower(_) | Alert::PowerBack(_) => "",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Site {
+    last_state: Instant,
+    apparent_power: Option<u16>,
+    silent: bool,
+}
+
+/// State of the sites of the fleet.
+#[derive(Debug)]
+pub struct Fleet {
+    sites: BTreeMap<String, Site>,
+    silence: Duration,
+    power_limit: Option<u32>,
+    high_power: bool,
+}
+
+impl Fleet {
+    /// Sites are given the silence from `now` to send their first state.
+    pub fn new<'a>(
+        names: impl IntoIterator<Item = &'a String>,
+        silence: Duration,
+        power_limit: Option<u32>,
+        now: Instant,
+    ) -> Fleet {
+        let site = || Site {
+            last_state: now,
+            apparent_power: None,
+            silent: false,
+        };
+        Fleet {
+            sites: names
+                .into_iter()
+                .map(|name| (name.clone(), site()))
+                .collect(),
+            silence,
+            power_limit,
+            high_power: false,
+        }
+    }
+
+    /// Records a state received from a site.
+    pub fn update(&mut self, name: &str, apparent_power: Option<u16>, now: Instant) -> Vec<Alert> {
+        let Some(site) = self.sites.get_mut(name) else {
+            return Vec::new();
+        };
+        site.last_state = now;
+        site.apparent_power = apparent_power.or(site.apparent_power);
+        if site.silent {
+            site.silent = false;
+            return vec![Alert::Back(name.to_string())];
+        }
+        Vec::new()
+    }
+
+    /// Checks for silent sites and for the total power.
+    pub fn check(&mut self, now: Instant) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for (name, site) in &mut self.sites {
+            if !site.silent && now.duration_since(site.last_state) >= self.silence {
+                site.silent = true;
+                alerts.push(Alert::Silent(name.clone()));
+            }
+        }
+        if let Some(limit) = self.power_limit {
+            let total = self.apparent_power();
+            if total > limit && !self.high_power {
+                alerts.push(Alert::HighPower(total));
+            } else if total <= limit && self.high_power {
+                alerts.push(Alert::PowerBack(total));
+            }
+            self.high_power = total > limit;
+        }
+        alerts
+    }
+
+    /// Total apparent power of the sites not silent, in VA.
+    pub fn apparent_power(&self) -> u32 {
+        self.sites
+            .values()
+            .filter(|site| !site.silent)
+            .filter_map(|site| site.apparent_power)
+            .map(u32::from)
+            .sum()
+    }
+
+    /// Totals of the fleet, by label.
+    pub fn values(&self) -> Vec<(&'static str, u32)> {
+        let online = self.sites.values().filter(|site| !site.silent).count();
+        vec![
+            ("PAPP", self.apparent_power()),
+            ("SITES_ONLINE", online as u32),
+        ]
+    }
+}
+
+/// Messages of a JSON state, as published with the `json` format. Computed
+/// values and unknown labels are skipped.
+pub fn state_messages(payload: &[u8]) -> Result<Vec<Message>, serde_json::Error> {
+    let state: BTreeMap<String, Json> = serde_json::from_slice(payload)?;
+    Ok(state
+        .iter()
+        .filter_map(|(label, value)| {
+            let data = match value {
+                Json::String(text) => text.clone(),
+                Json::Number(number) => number.to_string(),
+                _ => return None,
+            };
+            // Rebuilt without its checksum
+            parse_group_unchecked(&format!("{} {} _", label, data))
+                .ok()
+                .flatten()
+        })
+        .collect())
+}
+
+/// History of a site, next to the configured one, e.g.
+/// `/var/lib/pitinfo/history-home.db`.
+fn site_storage(storage: &StorageConfig, site: &str) -> StorageConfig {
+    let stem = storage
+        .path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut path = PathBuf::from(&storage.path);
+    path.set_file_name(match storage.path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, site, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, site),
+    });
+    StorageConfig {
+        path,
+        ..storage.clone()
+    }
+}
+
+/// Aggregates the sites until `shutdown` completes.
+pub async fn run(config: &Config, shutdown: impl Future<Output = ()>) -> Result<(), io::Error> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let fleet_config = config
+        .fleet
+        .as_ref()
+        .ok_or_else(|| invalid("the fleet mode requires a [fleet] section"))?;
+    let mqtt_config = config
+        .mqtt
+        .as_ref()
+        .ok_or_else(|| invalid("the fleet mode requires an [mqtt] section"))?;
+
+    let mut sinks = BTreeMap::new();
+    if let Some(storage) = &config.storage {
+        for site in fleet_config.sites.keys() {
+            let storage = site_storage(storage, site);
+            println!("Storing site {} in {}", site, storage.path.display());
+            sinks.insert(site.clone(), storage::spawn(&storage, &config.clock)?);
+        }
+    }
+    let sites: BTreeMap<&str, &str> = fleet_config
+        .sites
+        .iter()
+        .map(|(site, topic)| (topic.as_str(), site.as_str()))
+        .collect();
+    let mut fleet = Fleet::new(
+        fleet_config.sites.keys(),
+        Duration::from_secs(fleet_config.silence),
+        fleet_config.power,
+        Instant::now(),
+    );
+
+    let options = mqtt::options(mqtt_config, &format!("{}-fleet", mqtt_config.client_id))?;
+    let (client, mut event_loop) = AsyncClient::new(options, mqtt::REQUEST_CAPACITY);
+    let mut checks = time::interval(CHECK_PERIOD);
+    tokio::pin!(shutdown);
+    loop {
+        let alerts = tokio::select! {
+            event = event_loop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    for topic in sites.keys() {
+                        if let Err(e) = client.try_subscribe(*topic, QoS::AtLeastOnce) {
+                            eprintln!("Unable to subscribe to {}: {}", topic, e);
+                        }
+                    }
+                    Vec::new()
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let Some(site) = sites.get(publish.topic.as_str()) else {
+                        continue;
+                    };
+                    match state_messages(&publish.payload) {
+                        Ok(messages) => receive(site, &messages, &mut fleet, sinks.get_mut(*site)),
+                        Err(e) => {
+                            eprintln!("WARNING: invalid state from site {}: {}", site, e);
+                            Vec::new()
+                        }
+                    }
+                }
+                Ok(_) => Vec::new(),
+                Err(e) => {
+                    eprintln!("MQTT connection error: {}", e);
+                    time::sleep(mqtt::RECONNECT_DELAY).await;
+                    Vec::new()
+                }
+            },
+            _ = checks.tick() => {
+                let alerts = fleet.check(Instant::now());
+                publish_totals(&client, mqtt_config, &fleet);
+                alerts
+            }
+            _ = &mut shutdown => break,
+        };
+        for alert in alerts {
+            report(&alert, fleet_config).await;
+        }
+    }
+
+    for sink in sinks.into_values() {
+        sink.close().await;
+    }
+    if let Err(e) = client.try_disconnect() {
+        eprintln!("Unable to disconnect from MQTT: {}", e);
+    }
+    Ok(())
+}
+
+/// Forwards the messages of a site to its history, as a frame.
+fn receive(
+    site: &str,
+    messages: &[Message],
+    fleet: &mut Fleet,
+    sink: Option<&mut Sink>,
+) -> Vec<Alert> {
+    if let Some(sink) = sink {
+        for message in messages {
+            sink.send(&Update::Meter(message.clone()));
+        }
+        // Completes the frame, the address of the meter of the site is not
+        // published
+        sink.send(&Update::Meter(Message::MeterAddress(String::new())));
+    }
+    let apparent_power = messages.iter().find_map(|message| match message {
+        Message::ApparentPower { value } => Some(*value),
+        _ => None,
+    });
+    fleet.update(site, apparent_power, Instant::now())
+}
+
+/// Publishes the totals on `<base_topic>/fleet/<LABEL>`.
+fn publish_totals(client: &AsyncClient, config: &MqttConfig, fleet: &Fleet) {
+    for (label, value) in fleet.values() {
+        let topic = format!("{}/fleet/{}", config.base_topic, label);
+        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, false, value.to_string()) {
+            eprintln!("Unable to publish to MQTT: {}", e);
+        }
+    }
+}
+
+/// Logs an alert and runs the command with it.
+async fn report(alert: &Alert, config: &FleetConfig) {
+    let message = match alert {
+        Alert::Silent(site) => format!(
+            "no state received from site {} for {} s",
+            site, config.silence
+        ),
+        Alert::Back(site) => format!("site {} is back", site),
+        Alert::HighPower(total) => format!(
+            "the fleet draws {} VA, over {} VA",
+            total,
+            config.power.unwrap_or_default()
+        ),
+        Alert::PowerBack(total) => format!("the fleet draws {} VA again", total),
+    };
+    match alert {
+        Alert::Silent(_) | Alert::HighPower(_) => eprintln!("WARNING: {}", message),
+        Alert::Back(_) | Alert::PowerBack(_) => println!("{}", message),
+    }
+    if let Some(command) = &config.command {
+        hooks::run(
+            command,
+            &[
+                ("PITINFO_FLEET", alert.name()),
+                ("PITINFO_FLEET_SITE", alert.site()),
+                ("PITINFO_ALERT", &message),
+            ],
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn alerts() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let names = [String::from("home"), String::from("parents")];
+        let mut fleet = Fleet::new(&names, Duration::from_secs(300), Some(9000), start);
+        assert!(fleet.update("home", Some(6000), at(10)).is_empty());
+        assert!(fleet.update("parents", Some(2000), at(10)).is_empty());
+        assert!(fleet.check(at(20)).is_empty());
+        assert_eq!(fleet.update("parents", Some(4000), at(30)), vec![]);
+        assert_eq!(fleet.check(at(40)), vec![Alert::HighPower(10000)]);
+        // Reported once
+        assert!(fleet.check(at(50)).is_empty());
+
+        assert!(fleet.update("home", Some(6000), at(300)).is_empty());
+        assert_eq!(
+            fleet.check(at(330)),
+            vec![
+                Alert::Silent(String::from("parents")),
+                Alert::PowerBack(6000)
+            ]
+        );
+        assert_eq!(fleet.values(), vec![("PAPP", 6000), ("SITES_ONLINE", 1)]);
+        assert_eq!(
+            fleet.update("parents", None, at(340)),
+            vec![Alert::Back(String::from("parents"))]
+        );
+    }
+
+    #[test]
+    fn json_states() {
+        let messages =
+            state_messages(br#"{"PAPP":803,"PTEC":"HCJB","PAPP_AVG":790.5,"BBRHCJB":23916830}"#)
+                .unwrap();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.contains(&Message::ApparentPower { value: 803 }));
+        assert!(state_messages(b"803").is_err());
+
+        let storage: StorageConfig =
+            toml::from_str("path = '/var/lib/pitinfo/history.db'").unwrap();
+        assert_eq!(
+            site_storage(&storage, "home").path,
+            Path::new("/var/lib/pitinfo/history-home.db")
+        );
+    }
+}
@@ -0,0 +1,674 @@
+//! Forecast of the consumption and cost of the billing period.
+//!
+//! The consumption so far is the growth of the indexes since the start of the
+//! period. The hours left, and those before the first reading when the daemon
+//! started during the period, are estimated from the average consumption of
+//! the complete weekdays or weekend days of the last month, or from the
+//! average rate of the period while no complete day is known. The state is
+//! written to a JSON file so that it survives restarts of the daemon.
+//!
+//! Prices can change on a date, during a billing period: the energy consumed
+//! until then keeps the prices of the time, and the new prices only apply to
+//! what is consumed after.
+//!
+//! With a budget, an alert is raised once per period when the forecast
+//! exceeds it, and once more when the consumption reaches it.
+
+use crate::config::{BudgetConfig, ForecastConfig};
+use crate::daily::DailyTracker;
+use crate::hooks;
+use crate::pipeline::{self, Sink};
+use crate::state::index_label;
+use chrono::{Datelike, Months, NaiveDate, NaiveDateTime, Utc, Weekday};
+use chrono_tz::Tz;
+use pitinfo_parser::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task;
+
+/// Price of a kWh per index, `None` for all the others.
+type Prices = HashMap<Option<String>, f64>;
+
+pub const ENERGY_LABEL: &str = "FORECAST_KWH";
+pub const COST_LABEL: &str = "FORECAST_COST";
+
+/// Time between two computations of the forecast.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+/// Time between two saves of the state, also saved when a period starts.
+const SAVE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forecast {
+    /// First day of the billing period
+    pub start: NaiveDate,
+    /// First day of the next billing period
+    pub end: NaiveDate,
+    /// kWh consumed since the start of the period
+    pub consumed: f64,
+    /// kWh expected over the whole period
+    pub energy: f64,
+    /// Expected cost of the energy, when its prices are configured
+    pub cost: Option<f64>,
+    /// Cost of the energy consumed
+    pub spent: Option<f64>,
+}
+
+impl Forecast {
+    /// Values published along with the groups.
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        let mut values = vec![(ENERGY_LABEL, (self.energy * 10.0).round() / 10.0)];
+        if let Some(cost) = self.cost {
+            values.push((COST_LABEL, (cost * 100.0).round() / 100.0));
+        }
+        values
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAlert {
+    /// The forecast exceeds the budget
+    Projected,
+    /// The consumption reached the budget
+    Reached,
+}
+
+impl BudgetAlert {
+    /// Name given to the hook, in `PITINFO_BUDGET`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BudgetAlert::Projected => "projected",
+            BudgetAlert::Reached => "reached",
+        }
+    }
+}
+
+/// Latest forecast, shared with the sinks publishing it.
+#[derive(Clone)]
+pub struct LatestForecast {
+    /// Currency of the cost
+    pub currency: String,
+    forecast: Arc<Mutex<Option<Forecast>>>,
+}
+
+impl LatestForecast {
+    /// Values of the latest forecast, none while unknown.
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        self.forecast
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Forecast::values)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Forecaster {
+    /// First day of the billing period followed
+    start: Option<NaiveDate>,
+    /// First reading of the billing period
+    observed_from: Option<NaiveDateTime>,
+    /// Indexes at their first reading of the billing period
+    start_indexes: HashMap<String, u32>,
+    indexes: HashMap<String, u32>,
+    daily: DailyTracker,
+    /// Budget alerts raised in the billing period
+    #[serde(default)]
+    alerts: Vec<BudgetAlert>,
+    /// Indexes when the current prices took effect in the billing period
+    #[serde(default)]
+    settled_indexes: HashMap<String, u32>,
+    /// Cost of the energy consumed in the billing period before the current
+    /// prices took effect
+    #[serde(default = "no_cost")]
+    settled_cost: Option<f64>,
+    /// Date the current prices took effect, `None` for the first ones
+    #[serde(default)]
+    prices_from: Option<NaiveDate>,
+    #[serde(skip)]
+    billing_day: u32,
+    /// Prices of a kWh per index, `None` for all the others, by date they
+    /// take effect
+    #[serde(skip)]
+    tariffs: Vec<(Option<NaiveDate>, Prices)>,
+    #[serde(skip)]
+    budget: Option<BudgetConfig>,
+}
+
+impl Forecaster {
+    pub fn load(path: &Path, config: &ForecastConfig) -> Result<Forecaster, io::Error> {
+        let mut forecaster: Forecaster = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Forecaster::default(),
+            Err(e) => return Err(e),
+        };
+        forecaster.configure(config);
+        Ok(forecaster)
+    }
+
+    fn configure(&mut self, config: &ForecastConfig) {
+        self.billing_day = config.billing_day;
+        let prices = |price: Option<f64>, prices: &BTreeMap<String, f64>| -> Prices {
+            prices
+                .iter()
+                .map(|(label, price)| (Some(label.clone()), *price))
+                .chain(price.map(|price| (None, price)))
+                .collect()
+        };
+        self.tariffs = vec![(None, prices(config.price, &config.prices))];
+        self.tariffs.extend(
+            config
+                .tariffs
+                .iter()
+                .map(|tariff| (Some(tariff.from), prices(tariff.price, &tariff.prices))),
+        );
+        self.tariffs.sort_by_key(|(from, _)| *from);
+        self.budget = config.budget.clone();
+    }
+
+    /// Takes an index into account, returns whether a billing period started.
+    pub fn update(&mut self, message: &Message, now: NaiveDateTime) -> bool {
+        let Message::Index { period, value } = message else {
+            return false;
+        };
+        self.daily.update(message, now);
+        let start = period_start(now.date(), self.billing_day);
+        let started = self.start != Some(start);
+        let (effective, _) = self.prices(Some(now.date()));
+        if started {
+            self.start = Some(start);
+            self.observed_from = Some(now);
+            self.start_indexes.clear();
+            self.alerts.clear();
+            self.settled_indexes.clear();
+            self.settled_cost = Some(0.0);
+        } else if self.prices_from != effective {
+            // The energy so far keeps the prices it was consumed at
+            let (_, cost) = self.segment(self.prices(self.prices_from).1);
+            self.settled_cost = self
+                .settled_cost
+                .zip(cost)
+                .map(|(settled, cost)| settled + cost);
+            self.settled_indexes = self.indexes.clone();
+        }
+        self.prices_from = effective;
+        let label = index_label(period);
+        self.start_indexes.entry(label.clone()).or_insert(*value);
+        self.indexes.insert(label, *value);
+        started
+    }
+
+    /// Forecast of the current billing period, if enough is known.
+    pub fn forecast(&self, now: NaiveDateTime) -> Option<Forecast> {
+        let start = self
+            .start
+            .filter(|start| *start == period_start(now.date(), self.billing_day))?;
+        let observed_from = self.observed_from?;
+        let end = start.checked_add_months(Months::new(1))?;
+
+        let mut consumed = 0.0;
+        for (label, value) in &self.indexes {
+            consumed += value.saturating_sub(*self.start_indexes.get(label)?) as f64;
+        }
+        let (_, prices) = self.prices(Some(now.date()));
+        let (priced, priced_cost) = self.segment(prices);
+        let cost = self
+            .settled_cost
+            .zip(priced_cost)
+            .map(|(settled, cost)| settled + cost);
+        // Over an hour at least, for a meaningful rate
+        let days = (now - observed_from).num_minutes() as f64 / (24.0 * 60.0);
+        let rate = (days >= 1.0 / 24.0).then(|| consumed / days);
+        let expected = self.expected(midnight(start), observed_from, rate)?
+            + self.expected(now, midnight(end), rate)?;
+
+        // The energy left is priced at the average of the current prices so
+        // far
+        let price = if priced > 0.0 {
+            priced_cost.map(|cost| cost / priced * 1000.0)
+        } else {
+            prices.and_then(|prices| prices.get(&None)).copied()
+        };
+        Some(Forecast {
+            start,
+            end,
+            consumed: consumed / 1000.0,
+            energy: (consumed + expected) / 1000.0,
+            cost: cost
+                .zip(price)
+                .map(|(cost, price)| cost + expected / 1000.0 * price),
+            spent: cost,
+        })
+    }
+
+    /// Budget alerts of a forecast not raised yet in the billing period.
+    pub fn budget_alerts(&mut self, forecast: &Forecast) -> Vec<BudgetAlert> {
+        let Some(budget) = &self.budget else {
+            return Vec::new();
+        };
+        let over = |energy: f64, cost: Option<f64>| {
+            budget.energy.is_some_and(|limit| energy >= limit)
+                || budget
+                    .cost
+                    .zip(cost)
+                    .is_some_and(|(limit, cost)| cost >= limit)
+        };
+        let mut alerts = Vec::new();
+        if over(forecast.energy, forecast.cost) {
+            alerts.push(BudgetAlert::Projected);
+        }
+        if over(forecast.consumed, forecast.spent) {
+            alerts.push(BudgetAlert::Reached);
+        }
+        alerts.retain(|alert| !self.alerts.contains(alert));
+        self.alerts.extend(&alerts);
+        alerts
+    }
+
+    /// Prices in effect on a date, `None` for the first ones, with the date
+    /// they took effect.
+    fn prices(&self, date: Option<NaiveDate>) -> (Option<NaiveDate>, Option<&Prices>) {
+        self.tariffs
+            .iter()
+            .rev()
+            .find(|(from, _)| *from <= date)
+            .map_or((None, None), |(from, prices)| (*from, Some(prices)))
+    }
+
+    /// Wh consumed since the current prices took effect, and their cost.
+    fn segment(&self, prices: Option<&Prices>) -> (f64, Option<f64>) {
+        let mut consumed = 0.0;
+        let mut cost = Some(0.0);
+        for (label, value) in &self.indexes {
+            let start = self
+                .settled_indexes
+                .get(label)
+                .or_else(|| self.start_indexes.get(label));
+            let wh = value.saturating_sub(start.copied().unwrap_or(*value)) as f64;
+            consumed += wh;
+            if wh > 0.0 {
+                let price = prices.and_then(|prices| {
+                    prices
+                        .get(&Some(label.clone()))
+                        .or_else(|| prices.get(&None))
+                });
+                cost = cost
+                    .zip(price)
+                    .map(|(cost, price)| cost + wh / 1000.0 * price);
+            }
+        }
+        (consumed, cost)
+    }
+
+    /// Wh expected between two times, from the daily consumption of the same
+    /// kind of days, or from `rate`, in Wh per day.
+    fn expected(&self, from: NaiveDateTime, to: NaiveDateTime, rate: Option<f64>) -> Option<f64> {
+        let mut wh = 0.0;
+        let mut time = from;
+        while time < to {
+            let next = midnight(time.date().succ_opt()?).min(to);
+            let daily = self.daily_average(weekend(time.date())).or(rate)?;
+            wh += daily * (next - time).num_seconds() as f64 / 86_400.0;
+            time = next;
+        }
+        Some(wh)
+    }
+
+    /// Average Wh of the complete days observed of a kind, or of all of them.
+    fn daily_average(&self, weekend_day: bool) -> Option<f64> {
+        let consumption = self.daily.consumption();
+        let average = |days: Vec<u64>| {
+            (!days.is_empty()).then(|| days.iter().sum::<u64>() as f64 / days.len() as f64)
+        };
+        average(
+            consumption
+                .iter()
+                .filter(|(day, _)| weekend(**day) == weekend_day)
+                .map(|(_, wh)| *wh)
+                .collect(),
+        )
+        .or_else(|| average(consumption.values().copied().collect()))
+    }
+}
+
+fn no_cost() -> Option<f64> {
+    Some(0.0)
+}
+
+/// First day of the billing period of a day.
+fn period_start(day: NaiveDate, billing_day: u32) -> NaiveDate {
+    let start = day.with_day(billing_day).unwrap_or(day);
+    if start > day {
+        start.checked_sub_months(Months::new(1)).unwrap_or(start)
+    } else {
+        start
+    }
+}
+
+fn midnight(day: NaiveDate) -> NaiveDateTime {
+    day.and_hms_opt(0, 0, 0).unwrap()
+}
+
+fn weekend(day: NaiveDate) -> bool {
+    matches!(day.weekday(), Weekday::Sat | Weekday::Sun)
+}
+
+/// Starts forecasting the billing period over the days of `timezone`. Indexes
+/// sent to the returned sink update the forecast, shared with the sinks
+/// publishing it, and the state stored in the configured file. The forecast
+/// is logged, and given to the configured command, once a day, and so are
+/// the budget alerts.
+pub fn spawn(config: &ForecastConfig, timezone: Tz) -> Result<(Sink, LatestForecast), io::Error> {
+    if !(1..=28).contains(&config.billing_day) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the billing day must be between 1 and 28",
+        ));
+    }
+    let path = config.path.clone();
+    let command = config.command.clone();
+    let budget_command = config
+        .budget
+        .as_ref()
+        .and_then(|budget| budget.command.clone());
+    let mut forecaster = Forecaster::load(&path, config)?;
+    let latest = LatestForecast {
+        currency: config.currency.clone(),
+        forecast: Arc::default(),
+    };
+    let currency = latest.currency.clone();
+    let forecast = Arc::clone(&latest.forecast);
+    let sink = pipeline::spawn_sink("forecast", |mut receiver| async move {
+        let mut last_update: Option<Instant> = None;
+        let mut last_save = Instant::now();
+        let mut reported = None;
+        while let Some(message) = receiver.recv_message().await {
+            let now = Utc::now().with_timezone(&timezone).naive_local();
+            let started = forecaster.update(&message, now);
+            if started || last_save.elapsed() >= SAVE_INTERVAL {
+                save(&forecaster, &path).await;
+                last_save = Instant::now();
+            }
+            if last_update.is_some_and(|last| last.elapsed() < UPDATE_INTERVAL) {
+                continue;
+            }
+            last_update = Some(Instant::now());
+            let current = forecaster.forecast(now);
+            *forecast.lock().unwrap() = current.clone();
+            let Some(current) = current else {
+                continue;
+            };
+            if reported != Some(now.date()) {
+                reported = Some(now.date());
+                report(&current, &currency, command.as_deref()).await;
+            }
+            let alerts = forecaster.budget_alerts(&current);
+            for alert in &alerts {
+                alert_budget(*alert, &current, &currency, budget_command.as_deref()).await;
+            }
+            // Raised once per period, restarts included
+            if !alerts.is_empty() {
+                save(&forecaster, &path).await;
+            }
+        }
+        save(&forecaster, &path).await;
+    });
+    Ok((sink, latest))
+}
+
+async fn save(forecaster: &Forecaster, path: &Path) {
+    let content = serde_json::to_string_pretty(forecaster);
+    let path = path.to_path_buf();
+    let result = match content {
+        Ok(content) => task::spawn_blocking(move || {
+            // Written aside then renamed, so a power cut never leaves a
+            // truncated file
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, content).and_then(|()| fs::rename(temporary, path))
+        })
+        .await
+        .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match result {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => eprintln!("Unable to save the forecast: {}", e),
+        Err(e) => eprintln!("Unable to save the forecast: {}", e),
+    }
+}
+
+/// Logs the forecast and runs the command with it.
+async fn report(forecast: &Forecast, currency: &str, command: Option<&str>) {
+    let cost = forecast
+        .cost
+        .map(|cost| format!(", {:.2} {}", cost, currency))
+        .unwrap_or_default();
+    let message = format!(
+        "Billing period from {} to {}: {:.1} kWh consumed, {:.1} kWh expected{}",
+        forecast.start, forecast.end, forecast.consumed, forecast.energy, cost
+    );
+    println!("{}", message);
+    if let Some(command) = command {
+        let energy = format!("{:.1}", forecast.energy);
+        let cost = forecast
+            .cost
+            .map(|cost| format!("{:.2}", cost))
+            .unwrap_or_default();
+        hooks::run(
+            command,
+            &[
+                ("PITINFO_FORECAST_KWH", &energy),
+                ("PITINFO_FORECAST_COST", &cost),
+                ("PITINFO_ALERT", &message),
+            ],
+        )
+        .await;
+    }
+}
+
+/// Logs a budget alert and runs the command with it.
+async fn alert_budget(
+    alert: BudgetAlert,
+    forecast: &Forecast,
+    currency: &str,
+    command: Option<&str>,
+) {
+    let (energy, cost) = match alert {
+        BudgetAlert::Projected => (forecast.energy, forecast.cost),
+        BudgetAlert::Reached => (forecast.consumed, forecast.spent),
+    };
+    let cost = cost
+        .map(|cost| format!(", {:.2} {}", cost, currency))
+        .unwrap_or_default();
+    let message = match alert {
+        BudgetAlert::Projected => format!(
+            "The billing period ending on {} is expected over budget: {:.1} kWh{}",
+            forecast.end, energy, cost
+        ),
+        BudgetAlert::Reached => format!(
+            "The budget of the billing period ending on {} is reached: {:.1} kWh{}",
+            forecast.end, energy, cost
+        ),
+    };
+    eprintln!("WARNING: {}", message);
+    if let Some(command) = command {
+        let energy = format!("{:.1}", forecast.energy);
+        let cost = forecast
+            .cost
+            .map(|cost| format!("{:.2}", cost))
+            .unwrap_or_default();
+        hooks::run(
+            command,
+            &[
+                ("PITINFO_BUDGET", alert.name()),
+                ("PITINFO_FORECAST_KWH", &energy),
+                ("PITINFO_FORECAST_COST", &cost),
+                ("PITINFO_ALERT", &message),
+            ],
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TariffConfig;
+    use pitinfo_parser::{HourlyTarifPeriod, TarifPeriod};
+
+    fn index(hour: HourlyTarifPeriod, value: u32) -> Message {
+        Message::Index {
+            period: TarifPeriod {
+                hour,
+                day_color: None,
+            },
+            value,
+        }
+    }
+
+    #[test]
+    fn billing_periods() {
+        let day = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+        assert_eq!(period_start(day(1, 16), 1), day(1, 1));
+        let december = NaiveDate::from_ymd_opt(2023, 12, 20).unwrap();
+        assert_eq!(period_start(day(1, 16), 20), december);
+        assert_eq!(period_start(day(3, 20), 20), day(3, 20));
+    }
+
+    #[test]
+    fn forecasts() {
+        let config = ForecastConfig {
+            billing_day: 1,
+            price: Some(0.25),
+            prices: BTreeMap::from([(String::from("HCHC"), 0.2)]),
+            tariffs: Vec::new(),
+            path: Default::default(),
+            currency: String::from("EUR"),
+            command: None,
+            budget: None,
+        };
+        let mut forecaster = Forecaster::default();
+        forecaster.configure(&config);
+        // Started on Monday April 1st, 2024: 30 days, 8 weekend days
+        let start = midnight(NaiveDate::from_ymd_opt(2024, 4, 1).unwrap());
+        assert!(forecaster.update(&index(HourlyTarifPeriod::OffPeakHours, 1000), start));
+        // 1 kWh off-peak and 1 kWh peak per hour on weekdays, 3 + 3 on weekends
+        let mut off_peak = 1000;
+        let mut peak = 5000;
+        for minutes in (5..=9 * 24 * 60).step_by(5) {
+            let now = start + chrono::Duration::minutes(minutes);
+            let wh = if weekend(now.date()) { 250 } else { 250 / 3 };
+            off_peak += wh;
+            peak += wh;
+            forecaster.update(&index(HourlyTarifPeriod::OffPeakHours, off_peak), now);
+            forecaster.update(&index(HourlyTarifPeriod::PeakHours, peak), now);
+        }
+
+        let now = start + chrono::Duration::days(9);
+        let forecast = forecaster.forecast(now).unwrap();
+        assert_eq!(forecast.end, NaiveDate::from_ymd_opt(2024, 5, 1).unwrap());
+        let weekday = 2.0 * (250 / 3) as f64 * 12.0 * 24.0 / 1000.0;
+        let weekend_day = 2.0 * 250.0 * 12.0 * 24.0 / 1000.0;
+        // The first peak index reading comes 5 minutes late
+        assert!((forecast.consumed - (7.0 * weekday + 2.0 * weekend_day)).abs() < 0.1);
+        let expected = forecast.consumed + 15.0 * weekday + 6.0 * weekend_day;
+        assert!((forecast.energy - expected).abs() < 0.5);
+        // Half off-peak at 0.2, half peak at 0.25
+        assert!((forecast.cost.unwrap() - forecast.energy * 0.225).abs() < 0.5);
+
+        // Stale once the period is over
+        assert_eq!(forecaster.forecast(now + chrono::Duration::days(30)), None);
+    }
+
+    #[test]
+    fn price_changes() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 4, d).unwrap();
+        let config = ForecastConfig {
+            billing_day: 1,
+            price: Some(0.2),
+            prices: BTreeMap::new(),
+            tariffs: vec![TariffConfig {
+                from: day(10),
+                price: Some(0.3),
+                prices: BTreeMap::new(),
+            }],
+            path: Default::default(),
+            currency: String::from("EUR"),
+            command: None,
+            budget: None,
+        };
+        let mut forecaster = Forecaster::default();
+        forecaster.configure(&config);
+        let at = |d, hour| day(d).and_hms_opt(hour, 0, 0).unwrap();
+        for (time, value) in [(at(1, 0), 0), (at(9, 12), 10_000), (at(10, 12), 12_000)] {
+            forecaster.update(&index(HourlyTarifPeriod::PeakHours, value), time);
+        }
+        // 10 kWh at 0.2 then 2 kWh at 0.3
+        let spent = forecaster.forecast(at(10, 12)).unwrap().spent.unwrap();
+        assert!((spent - 2.6).abs() < 1e-9);
+
+        // Kept over restarts
+        let saved = serde_json::to_string(&forecaster).unwrap();
+        let mut restarted: Forecaster = serde_json::from_str(&saved).unwrap();
+        restarted.configure(&config);
+        restarted.update(&index(HourlyTarifPeriod::PeakHours, 13_000), at(11, 0));
+        let spent = restarted.forecast(at(11, 0)).unwrap().spent.unwrap();
+        assert!((spent - 2.9).abs() < 1e-9);
+
+        // States saved before the price changes
+        let mut old: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        for field in ["settled_indexes", "settled_cost", "prices_from"] {
+            old.as_object_mut().unwrap().remove(field);
+        }
+        let old: Forecaster = serde_json::from_value(old).unwrap();
+        assert_eq!(old.settled_cost, Some(0.0));
+    }
+
+    #[test]
+    fn budget_alerts() {
+        let mut forecaster = Forecaster {
+            budget: Some(BudgetConfig {
+                energy: None,
+                cost: Some(100.0),
+                command: None,
+            }),
+            ..Forecaster::default()
+        };
+        let day = |d| NaiveDate::from_ymd_opt(2024, 4, d).unwrap();
+        let mut forecast = Forecast {
+            start: day(1),
+            end: NaiveDate::from_ymd_opt(2024, 5, 1).unwrap(),
+            consumed: 200.0,
+            energy: 400.0,
+            cost: Some(90.0),
+            spent: Some(45.0),
+        };
+        assert!(forecaster.budget_alerts(&forecast).is_empty());
+        forecast.cost = Some(110.0);
+        assert_eq!(
+            forecaster.budget_alerts(&forecast),
+            vec![BudgetAlert::Projected]
+        );
+        // Raised once
+        assert!(forecaster.budget_alerts(&forecast).is_empty());
+        forecast.spent = Some(100.0);
+        assert_eq!(
+            forecaster.budget_alerts(&forecast),
+            vec![BudgetAlert::Reached]
+        );
+
+        // Raised again in the next period
+        forecaster.update(
+            &index(HourlyTarifPeriod::PeakHours, 1000),
+            midnight(forecast.end),
+        );
+        assert_eq!(forecaster.budget_alerts(&forecast).len(), 2);
+    }
+}
@@ -0,0 +1,255 @@
+//! Serial framings tried when the stream is mostly errors.
+//!
+//! A wrong installation, e.g. a meter in standard mode read at 1200 bauds or
+//! an adapter only able to do 8N1, gives a stream of garbled groups rather
+//! than no stream at all. When too many of the groups read fail to parse,
+//! the port is opened again with the next framing, until one gives clean
+//! data, which is reported so that it can be configured.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::Notify;
+use tokio_serial::{DataBits, Parity};
+
+/// Groups the error rate is measured over, a couple of frames.
+const WINDOW: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Framing {
+    pub baud_rate: u32,
+    /// 8 data bits without parity, the parity bit being masked, instead of
+    /// 7E1
+    pub masked: bool,
+}
+
+impl Framing {
+    pub fn data_bits(&self) -> DataBits {
+        if self.masked {
+            DataBits::Eight
+        } else {
+            DataBits::Seven
+        }
+    }
+
+    pub fn parity(&self) -> Parity {
+        if self.masked {
+            Parity::None
+        } else {
+            Parity::Even
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.masked {
+            write!(f, "{} bauds 8N1, parity bit masked", self.baud_rate)
+        } else {
+            write!(f, "{} bauds 7E1", self.baud_rate)
+        }
+    }
+}
+
+/// Framings to try, the configured one first, then the other speed of the
+/// TIC: 1200 bauds in historic mode, 9600 in standard mode.
+pub fn candidates(baud_rate: u32) -> Vec<Framing> {
+    let other = if baud_rate == 9600 { 1200 } else { 9600 };
+    vec![
+        Framing {
+            baud_rate,
+            masked: false,
+        },
+        Framing {
+            baud_rate,
+            masked: true,
+        },
+        Framing {
+            baud_rate: other,
+            masked: false,
+        },
+        Framing {
+            baud_rate: other,
+            masked: true,
+        },
+    ]
+}
+
+/// Error rate of the groups read, switching to the next framing when above
+/// the threshold.
+pub struct Trials {
+    candidates: Vec<Framing>,
+    /// Position of the framing the port is opened with, shared with the
+    /// opening of the port
+    current: Arc<AtomicUsize>,
+    /// Notified for the port to be opened again
+    reopen: Arc<Notify>,
+    /// Percentage of groups in error
+    threshold: u32,
+    groups: u32,
+    errors: u32,
+    /// Whether the current framing was reported clean
+    reported: bool,
+    /// Whether every framing was tried in vain
+    exhausted: bool,
+}
+
+impl Trials {
+    pub fn new(baud_rate: u32, threshold: u8) -> Trials {
+        Trials {
+            candidates: candidates(baud_rate),
+            current: Arc::default(),
+            reopen: Arc::new(Notify::new()),
+            threshold: threshold as u32,
+            groups: 0,
+            errors: 0,
+            reported: false,
+            exhausted: false,
+        }
+    }
+
+    /// Framing the port must be opened with.
+    pub fn opener(&self) -> impl Fn() -> Framing + Send + 'static {
+        let candidates = self.candidates.clone();
+        let current = Arc::clone(&self.current);
+        move || candidates[current.load(Ordering::Relaxed)]
+    }
+
+    pub fn reopen(&self) -> Arc<Notify> {
+        Arc::clone(&self.reopen)
+    }
+
+    /// Records a group read, whether it could be parsed or not.
+    pub fn record(&mut self, error: bool) {
+        if self.exhausted {
+            return;
+        }
+        self.groups += 1;
+        if error {
+            self.errors += 1;
+        }
+        if self.groups < WINDOW {
+            return;
+        }
+        let rate = self.errors * 100 / self.groups;
+        self.groups = 0;
+        self.errors = 0;
+        let index = self.current.load(Ordering::Relaxed);
+        if rate <= self.threshold {
+            if index > 0 && !self.reported {
+                eprintln!(
+                    "WARNING: clean data read with {}, configure it in the [serial] section",
+                    self.candidates[index]
+                );
+                self.reported = true;
+            }
+            return;
+        }
+        let next = if index + 1 < self.candidates.len() {
+            eprintln!(
+                "WARNING: {}% of the groups in error with {}, trying {}",
+                rate,
+                self.candidates[index],
+                self.candidates[index + 1]
+            );
+            index + 1
+        } else {
+            eprintln!(
+                "WARNING: no serial framing gave clean data, back to {}",
+                self.candidates[0]
+            );
+            self.exhausted = true;
+            0
+        };
+        self.current.store(next, Ordering::Relaxed);
+        self.reported = false;
+        self.reopen.notify_one();
+    }
+}
+
+/// Port clearing the parity bit of the bytes read, when 7E1 is read as 8N1.
+pub struct MaskParity<R> {
+    port: R,
+    mask: bool,
+}
+
+impl<R> MaskParity<R> {
+    pub fn new(port: R, mask: bool) -> MaskParity<R> {
+        MaskParity { port, mask }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MaskParity<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buffer.filled().len();
+        let poll = Pin::new(&mut self.port).poll_read(context, buffer);
+        if self.mask {
+            for byte in &mut buffer.filled_mut()[start..] {
+                *byte &= 0x7F;
+            }
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn framing_trials() {
+        let mut trials = Trials::new(1200, 50);
+        let framing = trials.opener();
+        assert_eq!(
+            framing(),
+            Framing {
+                baud_rate: 1200,
+                masked: false
+            }
+        );
+        for group in 0..WINDOW {
+            trials.record(group % 3 == 0);
+        }
+        assert_eq!(framing().baud_rate, 1200);
+        for _ in 0..WINDOW * 2 {
+            trials.record(true);
+        }
+        assert_eq!(
+            framing(),
+            Framing {
+                baud_rate: 9600,
+                masked: false
+            }
+        );
+        for _ in 0..WINDOW {
+            trials.record(false);
+        }
+        assert!(trials.reported);
+        for _ in 0..WINDOW * 2 {
+            trials.record(true);
+        }
+        // Back to the configured framing once they all failed
+        assert_eq!(framing().baud_rate, 1200);
+        assert!(trials.exhausted);
+    }
+
+    #[tokio::test]
+    async fn parity_mask() {
+        let bytes: &[u8] = &[0xC1, 0x44, 0x43, 0xCF];
+        let mut read = String::new();
+        MaskParity::new(bytes, true)
+            .read_to_string(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, "ADCO");
+    }
+}
@@ -0,0 +1,117 @@
+//! Detection of gaps in the teleinformation stream.
+//!
+//! A dead optocoupler or a loose wire stops the stream, and the integrations
+//! keep the last values, which downstream looks like a constant consumption.
+//! Without a valid frame for the configured timeout, a gap is reported, as a
+//! silent line when nothing was received at all, or as parse failures when
+//! lines kept coming without making a valid frame, e.g. with the wrong baud
+//! rate or a noisy line.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gap {
+    Silence,
+    ParseFailures,
+}
+
+impl Gap {
+    /// Name given to the hook, in `PITINFO_GAP`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gap::Silence => "silence",
+            Gap::ParseFailures => "parse_failures",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Started(Gap),
+    /// Frames came back after a gap lasting `duration`
+    Ended {
+        gap: Gap,
+        duration: Duration,
+    },
+}
+
+#[derive(Debug)]
+pub struct GapWatch {
+    timeout: Duration,
+    last_frame: Instant,
+    last_line: Option<Instant>,
+    /// Current gap and its start
+    gap: Option<(Gap, Instant)>,
+}
+
+impl GapWatch {
+    pub fn new(timeout: Duration, now: Instant) -> GapWatch {
+        GapWatch {
+            timeout,
+            last_frame: now,
+            last_line: None,
+            gap: None,
+        }
+    }
+
+    /// Records a line received, valid or not.
+    pub fn line(&mut self, now: Instant) {
+        self.last_line = Some(now);
+    }
+
+    /// Records a valid frame, ending the current gap.
+    pub fn frame(&mut self, now: Instant) -> Option<Event> {
+        self.last_frame = now;
+        self.line(now);
+        self.gap.take().map(|(gap, start)| Event::Ended {
+            gap,
+            duration: now.duration_since(start),
+        })
+    }
+
+    /// Checks for a new gap.
+    pub fn check(&mut self, now: Instant) -> Option<Event> {
+        if self.gap.is_some() || now.duration_since(self.last_frame) < self.timeout {
+            return None;
+        }
+        let gap = match self.last_line {
+            Some(line) if now.duration_since(line) < self.timeout => Gap::ParseFailures,
+            _ => Gap::Silence,
+        };
+        self.gap = Some((gap, self.last_frame + self.timeout));
+        Some(Event::Started(gap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut watch = GapWatch::new(Duration::from_secs(30), start);
+        assert_eq!(watch.frame(at(1)), None);
+        assert_eq!(watch.check(at(30)), None);
+        assert_eq!(watch.check(at(31)), Some(Event::Started(Gap::Silence)));
+        // Reported once
+        assert_eq!(watch.check(at(40)), None);
+        assert_eq!(
+            watch.frame(at(51)),
+            Some(Event::Ended {
+                gap: Gap::Silence,
+                duration: Duration::from_secs(20)
+            })
+        );
+
+        // Garbage keeps coming, without a valid frame
+        for seconds in 52..=81 {
+            watch.line(at(seconds));
+        }
+        assert_eq!(
+            watch.check(at(81)),
+            Some(Event::Started(Gap::ParseFailures))
+        );
+    }
+}
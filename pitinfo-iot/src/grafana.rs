@@ -0,0 +1,88 @@
+//! Streams meter values to Grafana Live.
+//!
+//! The state of each frame is pushed in InfluxDB line protocol to the push
+//! endpoint of a stream, and dashboards subscribed to the channel of the
+//! measurement update without waiting for a database.
+
+use crate::config::GrafanaLiveConfig;
+use crate::influxdb;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::scale::Scales;
+use crate::state::{MeterState, Update};
+use pitinfo_parser::Message;
+use std::io;
+
+/// Starts the publisher. Messages sent to the returned sink are pushed once
+/// per frame.
+pub fn spawn(config: &GrafanaLiveConfig) -> Result<Sink, io::Error> {
+    let config = config.clone();
+    eprintln!(
+        "Streaming to the Grafana Live channel {}",
+        channel(&config.stream, &config.measurement)
+    );
+    let delivery = config.delivery.clone();
+    Ok(pipeline::spawn_sink_with(
+        "grafana_live",
+        &delivery,
+        move |receiver| run(config, receiver),
+    ))
+}
+
+async fn run(config: GrafanaLiveConfig, mut receiver: Inbox<Update>) {
+    let url = push_url(&config.url, &config.stream);
+    let authorization = format!("Bearer {}", config.token);
+    let mut state = MeterState::default();
+    while let Some(item) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_))) {
+            let values = state.values();
+            if !values.is_empty() {
+                // Without timestamp, Grafana takes the time of reception
+                let line = format!(
+                    "{} {}",
+                    influxdb::escape(&config.measurement),
+                    influxdb::fields(&values, &Scales::default())
+                );
+                let url = url.clone();
+                let authorization = authorization.clone();
+                // The HTTP client is blocking
+                let result = pipeline::deliver("grafana_live", &config.delivery, move || {
+                    ureq::post(&url)
+                        .set("Authorization", &authorization)
+                        .send_string(&line)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .await;
+                if let Err(e) = result {
+                    eprintln!("Unable to push to Grafana Live: {}", e);
+                }
+            }
+        }
+        state.record(&item);
+    }
+}
+
+fn push_url(url: &str, stream: &str) -> String {
+    format!("{}/api/live/push/{}", url.trim_end_matches('/'), stream)
+}
+
+/// Channel dashboards subscribe to, Grafana names it after the stream and
+/// the measurement.
+pub fn channel(stream: &str, measurement: &str) -> String {
+    format!("stream/{}/{}", stream, measurement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(
+            push_url("http://grafana.local:3000/", "pitinfo"),
+            "http://grafana.local:3000/api/live/push/pitinfo"
+        );
+        assert_eq!(channel("pitinfo", "teleinfo"), "stream/pitinfo/teleinfo");
+    }
+}
@@ -0,0 +1,117 @@
+//! Recent currents and apparent power kept in memory.
+//!
+//! The current of each phase, IINST1 to IINST3, and the apparent power, PAPP,
+//! of the frames read are kept in a ring buffer per label holding their last
+//! `api.history` values, so the HTTP API can serve the last hour or so to
+//! draw charts without a database. Older values are dropped as new ones come.
+
+use crate::state;
+use chrono::{DateTime, Utc};
+use pitinfo_parser::Message;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Point {
+    pub time: DateTime<Utc>,
+    pub value: u64,
+}
+
+pub struct History {
+    /// Values kept per label
+    capacity: usize,
+    series: BTreeMap<String, VecDeque<Point>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            series: BTreeMap::new(),
+        }
+    }
+
+    /// Records the value of a message read at `time`, when it is a current
+    /// or the apparent power.
+    pub fn record(&mut self, message: &Message, time: DateTime<Utc>) {
+        if !matches!(
+            message,
+            Message::InstantaneousPower { .. } | Message::ApparentPower { .. }
+        ) || self.capacity == 0
+        {
+            return;
+        }
+        let Some((label, state::Value::Integer(value))) = state::label_value(message) else {
+            return;
+        };
+        let points = self.series.entry(label).or_default();
+        if points.len() == self.capacity {
+            points.pop_front();
+        }
+        points.push_back(Point { time, value });
+    }
+
+    /// Values recorded after `since`, of a label or of every one, oldest
+    /// first.
+    pub fn since(
+        &self,
+        label: Option<&str>,
+        since: Option<DateTime<Utc>>,
+    ) -> BTreeMap<&str, Vec<Point>> {
+        self.series
+            .iter()
+            .filter(|(name, _)| label.is_none_or(|label| label == name.as_str()))
+            .map(|(name, points)| {
+                let points = points
+                    .iter()
+                    .filter(|point| since.is_none_or(|since| point.time > since))
+                    .copied()
+                    .collect();
+                (name.as_str(), points)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn bounded() {
+        let mut history = History::new(3);
+        let at = |second| Utc.with_ymd_and_hms(2024, 1, 8, 12, 0, second).unwrap();
+        for second in 0..5 {
+            history.record(&Message::ApparentPower { value: second }, at(second as u32));
+            history.record(
+                &Message::InstantaneousPower { phase: 2, value: 1 },
+                at(second as u32),
+            );
+        }
+        history.record(&Message::SubscribedCurrent { value: 45 }, at(5));
+
+        let recent = history.since(None, None);
+        assert_eq!(
+            recent.keys().copied().collect::<Vec<_>>(),
+            ["IINST2", "PAPP"]
+        );
+        let values: Vec<u64> = recent["PAPP"].iter().map(|point| point.value).collect();
+        assert_eq!(values, [2, 3, 4]);
+        assert_eq!(recent["IINST2"].len(), 3);
+
+        let recent = history.since(Some("PAPP"), Some(at(3)));
+        assert_eq!(recent.len(), 1);
+        assert_eq!(
+            recent["PAPP"],
+            [Point {
+                time: at(4),
+                value: 4
+            }]
+        );
+        assert_eq!(
+            serde_json::to_string(&recent).unwrap(),
+            r#"{"PAPP":[{"time":"2024-01-08T12:00:04Z","value":4}]}"#
+        );
+    }
+}
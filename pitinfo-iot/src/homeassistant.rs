@@ -0,0 +1,591 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Indexes are announced as `total_increasing` energy sensors in Wh so that
+//! they can be used by the Energy dashboard and its long-term statistics.
+//!
+//! Sensors are only announced once the tariff option is known, with the
+//! indexes of that option alone. Those of the other options are removed, so
+//! that no Tempo indexes linger for a BASE contract.
+
+use crate::config::{Language, MqttFormat};
+use crate::estimate;
+use crate::factor;
+use crate::forecast;
+use crate::locale;
+use crate::mqtt::render_topic;
+use crate::offpeak;
+use crate::production;
+use crate::scale::Scales;
+use crate::trend;
+use pitinfo_parser::TariffOptionValue;
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Number of consecutive lower readings after which an index decrease is
+/// taken as a real meter reset rather than a transmission glitch.
+const RESET_CONFIRMATIONS: u8 = 3;
+
+struct Sensor {
+    label: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    /// Announced as a binary sensor, on when the value is positive
+    binary: bool,
+}
+
+const fn index(label: &'static str, name: &'static str) -> Sensor {
+    Sensor {
+        label,
+        name,
+        device_class: Some("energy"),
+        state_class: Some("total_increasing"),
+        unit: Some("Wh"),
+        binary: false,
+    }
+}
+
+const fn current(label: &'static str, name: &'static str) -> Sensor {
+    Sensor {
+        label,
+        name,
+        device_class: Some("current"),
+        state_class: Some("measurement"),
+        unit: Some("A"),
+        binary: false,
+    }
+}
+
+const fn text(label: &'static str, name: &'static str) -> Sensor {
+    Sensor {
+        label,
+        name,
+        device_class: None,
+        state_class: None,
+        unit: None,
+        binary: false,
+    }
+}
+
+const SENSORS: &[Sensor] = &[
+    Sensor {
+        label: "PAPP",
+        name: "Apparent power",
+        device_class: Some("apparent_power"),
+        state_class: Some("measurement"),
+        unit: Some("VA"),
+        binary: false,
+    },
+    current("IINST1", "Current phase 1"),
+    current("IINST2", "Current phase 2"),
+    current("IINST3", "Current phase 3"),
+    current("ISOUSC", "Subscribed current"),
+    Sensor {
+        label: "PMAX",
+        name: "Maximum power",
+        device_class: Some("power"),
+        state_class: Some("measurement"),
+        unit: Some("W"),
+        binary: false,
+    },
+    text("PTEC", "Current tariff period"),
+    text("OPTARIF", "Tariff option"),
+    text("HHPHC", "Off-peak schedule"),
+];
+
+pub const TARIFF_OPTIONS: [TariffOptionValue; 4] = [
+    TariffOptionValue::Base,
+    TariffOptionValue::OffPeakHours,
+    TariffOptionValue::EJP,
+    TariffOptionValue::Tempo,
+];
+
+const OFF_PEAK_SENSORS: &[Sensor] = &[
+    index("HCHC", "Off-peak hours index"),
+    index("HCHP", "Peak hours index"),
+];
+
+const EJP_SENSORS: &[Sensor] = &[Sensor {
+    label: "PEJP",
+    name: "EJP notice",
+    device_class: Some("duration"),
+    state_class: None,
+    unit: Some("min"),
+    binary: false,
+}];
+
+const TEMPO_SENSORS: &[Sensor] = &[
+    index("BBRHCJB", "Off-peak blue days index"),
+    index("BBRHPJB", "Peak blue days index"),
+    index("BBRHCJW", "Off-peak white days index"),
+    index("BBRHPJW", "Peak white days index"),
+    index("BBRHCJR", "Off-peak red days index"),
+    index("BBRHPJR", "Peak red days index"),
+    text("DEMAIN", "Tomorrow color"),
+];
+
+/// Sensors only sent by the meter with a tariff option.
+fn tariff_sensors(option: TariffOptionValue) -> &'static [Sensor] {
+    match option {
+        TariffOptionValue::Base => &[],
+        TariffOptionValue::OffPeakHours => OFF_PEAK_SENSORS,
+        TariffOptionValue::EJP => EJP_SENSORS,
+        TariffOptionValue::Tempo => TEMPO_SENSORS,
+    }
+}
+
+const TREND_SENSORS: &[Sensor] = &[
+    Sensor {
+        label: trend::AVERAGE_LABEL,
+        name: "Smoothed apparent power",
+        device_class: Some("apparent_power"),
+        state_class: Some("measurement"),
+        unit: Some("VA"),
+        binary: false,
+    },
+    Sensor {
+        label: trend::RATE_LABEL,
+        name: "Apparent power rate of change",
+        device_class: None,
+        state_class: Some("measurement"),
+        unit: Some("VA/min"),
+        binary: false,
+    },
+];
+
+const ACTIVE_POWER_SENSORS: &[Sensor] = &[Sensor {
+    label: estimate::LABEL,
+    name: "Estimated active power",
+    device_class: Some("power"),
+    state_class: Some("measurement"),
+    unit: Some("W"),
+    binary: false,
+}];
+
+const POWER_FACTOR_SENSORS: &[Sensor] = &[Sensor {
+    label: factor::LABEL,
+    name: "Measured power factor",
+    device_class: Some("power_factor"),
+    state_class: Some("measurement"),
+    unit: None,
+    binary: false,
+}];
+
+const FORECAST_SENSORS: &[Sensor] = &[
+    Sensor {
+        label: forecast::ENERGY_LABEL,
+        name: "Billing period energy forecast",
+        device_class: Some("energy"),
+        state_class: None,
+        unit: Some("kWh"),
+        binary: false,
+    },
+    // In the currency of the forecast
+    Sensor {
+        label: forecast::COST_LABEL,
+        name: "Billing period cost forecast",
+        device_class: Some("monetary"),
+        state_class: None,
+        unit: None,
+        binary: false,
+    },
+];
+
+const OFFPEAK_SENSORS: &[Sensor] = &[
+    Sensor {
+        label: offpeak::ACTIVE_LABEL,
+        name: "Off-peak hours",
+        device_class: None,
+        state_class: None,
+        unit: None,
+        binary: true,
+    },
+    Sensor {
+        label: offpeak::SOON_LABEL,
+        name: "Off-peak hours soon",
+        device_class: None,
+        state_class: None,
+        unit: None,
+        binary: true,
+    },
+    Sensor {
+        label: offpeak::MINUTES_LABEL,
+        name: "Minutes until off-peak hours",
+        device_class: Some("duration"),
+        state_class: Some("measurement"),
+        unit: Some("min"),
+        binary: false,
+    },
+];
+
+const PRODUCTION_SENSORS: &[Sensor] = &[
+    Sensor {
+        label: production::POWER_LABEL,
+        name: "Production apparent power",
+        device_class: Some("apparent_power"),
+        state_class: Some("measurement"),
+        unit: Some("VA"),
+        binary: false,
+    },
+    Sensor {
+        label: production::NET_POWER_LABEL,
+        name: "Net apparent power",
+        device_class: Some("apparent_power"),
+        state_class: Some("measurement"),
+        unit: Some("VA"),
+        binary: false,
+    },
+    // Integrated since the start of the daemon
+    Sensor {
+        label: production::ENERGY_LABEL,
+        name: "Production energy",
+        device_class: Some("energy"),
+        state_class: Some("total_increasing"),
+        unit: Some("Wh"),
+        binary: false,
+    },
+    Sensor {
+        label: production::IMPORT_LABEL,
+        name: "Net imported energy",
+        device_class: Some("energy"),
+        state_class: Some("total_increasing"),
+        unit: Some("Wh"),
+        binary: false,
+    },
+    Sensor {
+        label: production::EXPORT_LABEL,
+        name: "Net exported energy",
+        device_class: Some("energy"),
+        state_class: Some("total_increasing"),
+        unit: Some("Wh"),
+        binary: false,
+    },
+    Sensor {
+        label: production::SELF_CONSUMPTION_LABEL,
+        name: "Self-consumption ratio",
+        device_class: None,
+        state_class: Some("measurement"),
+        unit: Some("%"),
+        binary: false,
+    },
+];
+
+pub struct Discovery<'a> {
+    pub prefix: &'a str,
+    pub device_name: &'a str,
+    pub format: MqttFormat,
+    /// State topic, or topic template with the `{label}` placeholder left
+    pub topic: &'a str,
+    pub availability_topic: &'a str,
+    pub online: &'a str,
+    pub offline: &'a str,
+    /// Announces the apparent power trend sensors
+    pub trend: bool,
+    /// Announces the estimated active power sensor
+    pub active_power: bool,
+    /// Announces the measured power factor sensor
+    pub power_factor: bool,
+    /// Announces the forecast sensors, with the currency of the cost
+    pub forecast_currency: Option<&'a str>,
+    /// Announces the off-peak binary sensors
+    pub offpeak: bool,
+    /// Announces the sensors of the production meter balance
+    pub production: bool,
+    /// Units of the scaled labels, replacing those of the sensors
+    pub scales: &'a Scales,
+    /// Language of the names of the sensors
+    pub language: Language,
+}
+
+impl<'a> Discovery<'a> {
+    /// Retained configuration messages announcing every sensor of a tariff
+    /// option, and empty ones removing the sensors of the other options.
+    pub fn messages(&self, option: TariffOptionValue) -> Vec<(String, String)> {
+        let node_id = format!("pitinfo_{}", self.device_name);
+        let device = json!({
+            "identifiers": [node_id],
+            "name": self.device_name,
+            "manufacturer": "Enedis",
+            "model": "Teleinfo",
+        });
+
+        let removals = TARIFF_OPTIONS
+            .iter()
+            .filter(|other| **other != option)
+            .flat_map(|other| tariff_sensors(*other))
+            .map(|sensor| (self.config_topic(&node_id, sensor), String::new()));
+        let trend_sensors = if self.trend { TREND_SENSORS } else { &[] };
+        let active_power_sensors = if self.active_power {
+            ACTIVE_POWER_SENSORS
+        } else {
+            &[]
+        };
+        let power_factor_sensors = if self.power_factor {
+            POWER_FACTOR_SENSORS
+        } else {
+            &[]
+        };
+        let forecast_sensors = match self.forecast_currency {
+            Some(_) => FORECAST_SENSORS,
+            None => &[],
+        };
+        let offpeak_sensors = if self.offpeak { OFFPEAK_SENSORS } else { &[] };
+        let production_sensors = if self.production {
+            PRODUCTION_SENSORS
+        } else {
+            &[]
+        };
+        let announcements = SENSORS
+            .iter()
+            .chain(tariff_sensors(option))
+            .chain(trend_sensors)
+            .chain(active_power_sensors)
+            .chain(power_factor_sensors)
+            .chain(forecast_sensors)
+            .chain(offpeak_sensors)
+            .chain(production_sensors)
+            .map(|sensor| {
+                let object_id = sensor.label.to_lowercase();
+                let mut payload = json!({
+                    "name": locale::sensor_name(self.language, sensor.label, sensor.name),
+                    "unique_id": format!("{}_{}", node_id, object_id),
+                    "object_id": format!("{}_{}", self.device_name, object_id),
+                    "availability_topic": self.availability_topic,
+                    "payload_available": self.online,
+                    "payload_not_available": self.offline,
+                    "device": device,
+                });
+                // Value of the sensor in the state, in Jinja
+                let expression = match self.format {
+                    MqttFormat::Labels => {
+                        payload["state_topic"] =
+                            json!(render_topic(self.topic, &[("label", sensor.label)]));
+                        None
+                    }
+                    MqttFormat::Json => {
+                        payload["state_topic"] = json!(self.topic);
+                        Some(format!("value_json.{}", sensor.label))
+                    }
+                    MqttFormat::Senml => {
+                        payload["state_topic"] = json!(self.topic);
+                        Some(format!(
+                            "(value_json | selectattr('n', 'eq', '{}') | first).v",
+                            sensor.label
+                        ))
+                    }
+                };
+                if sensor.binary {
+                    payload["value_template"] = json!(format!(
+                        "{{{{ 'ON' if ({}) | float > 0 else 'OFF' }}}}",
+                        expression.as_deref().unwrap_or("value")
+                    ));
+                } else if let Some(expression) = expression {
+                    payload["value_template"] = json!(format!("{{{{ {} }}}}", expression));
+                }
+                if let Some(device_class) = sensor.device_class {
+                    payload["device_class"] = json!(device_class);
+                }
+                if let Some(state_class) = sensor.state_class {
+                    payload["state_class"] = json!(state_class);
+                }
+                if let Some(unit) = self.scales.unit(sensor.label).or(sensor.unit) {
+                    payload["unit_of_measurement"] = json!(unit);
+                }
+                if sensor.label == forecast::COST_LABEL {
+                    payload["unit_of_measurement"] = json!(self.forecast_currency);
+                }
+
+                (self.config_topic(&node_id, sensor), payload.to_string())
+            });
+        removals.chain(announcements).collect()
+    }
+
+    fn config_topic(&self, node_id: &str, sensor: &Sensor) -> String {
+        let component = if sensor.binary {
+            "binary_sensor"
+        } else {
+            "sensor"
+        };
+        format!(
+            "{}/{}/{}/{}/config",
+            self.prefix,
+            component,
+            node_id,
+            sensor.label.to_lowercase()
+        )
+    }
+}
+
+/// Filters decreasing index values: Home Assistant takes any decrease of a
+/// `total_increasing` sensor as a meter reset and would count the energy
+/// twice after a corrupted reading.
+#[derive(Debug, Default)]
+pub struct IndexGuard {
+    last: HashMap<String, u32>,
+    decreases: HashMap<String, u8>,
+}
+
+impl IndexGuard {
+    pub fn accept(&mut self, label: &str, value: u32) -> bool {
+        if let Some(last) = self.last.get(label) {
+            if value < *last {
+                let count = self.decreases.entry(label.to_string()).or_insert(0);
+                *count += 1;
+                if *count < RESET_CONFIRMATIONS {
+                    return false;
+                }
+            }
+        }
+        self.decreases.remove(label);
+        self.last.insert(label.to_string(), value);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn index_discovery() {
+        let discovery = Discovery {
+            prefix: "homeassistant",
+            device_name: "linky",
+            format: MqttFormat::Labels,
+            topic: "pitinfo/{label}",
+            availability_topic: "pitinfo/availability",
+            online: "online",
+            offline: "offline",
+            trend: false,
+            active_power: false,
+            power_factor: false,
+            forecast_currency: None,
+            offpeak: false,
+            production: false,
+            scales: &Scales::default(),
+            language: Language::En,
+        };
+        let messages = discovery.messages(TariffOptionValue::Tempo);
+        let (topic, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic.contains("bbrhcjb"))
+            .unwrap();
+        let payload: Value = serde_json::from_str(payload).unwrap();
+
+        assert_eq!(topic, "homeassistant/sensor/pitinfo_linky/bbrhcjb/config");
+        assert_eq!(payload["unique_id"], "pitinfo_linky_bbrhcjb");
+        assert_eq!(payload["state_topic"], "pitinfo/BBRHCJB");
+        assert_eq!(payload["state_class"], "total_increasing");
+        assert_eq!(payload["device_class"], "energy");
+        assert_eq!(payload["unit_of_measurement"], "Wh");
+        assert_eq!(payload["name"], "Off-peak blue days index");
+
+        let discovery = Discovery {
+            language: Language::Fr,
+            ..discovery
+        };
+        let messages = discovery.messages(TariffOptionValue::Tempo);
+        let (_, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic.contains("bbrhcjb"))
+            .unwrap();
+        let payload: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["name"], "Index heures creuses jours bleus");
+    }
+
+    #[test]
+    fn offpeak_discovery() {
+        let discovery = Discovery {
+            prefix: "homeassistant",
+            device_name: "linky",
+            format: MqttFormat::Json,
+            topic: "zigbee2mqtt/linky",
+            availability_topic: "zigbee2mqtt/linky/availability",
+            online: "online",
+            offline: "offline",
+            trend: false,
+            active_power: false,
+            power_factor: false,
+            forecast_currency: None,
+            offpeak: true,
+            production: false,
+            scales: &Scales::default(),
+            language: Language::En,
+        };
+        let messages = discovery.messages(TariffOptionValue::Tempo);
+        let (topic, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic.contains("offpeak_active"))
+            .unwrap();
+        let payload: Value = serde_json::from_str(payload).unwrap();
+
+        assert_eq!(
+            topic,
+            "homeassistant/binary_sensor/pitinfo_linky/offpeak_active/config"
+        );
+        assert_eq!(
+            payload["value_template"],
+            "{{ 'ON' if (value_json.OFFPEAK_ACTIVE) | float > 0 else 'OFF' }}"
+        );
+    }
+
+    #[test]
+    fn tariff_option_discovery() {
+        let discovery = Discovery {
+            prefix: "homeassistant",
+            device_name: "linky",
+            format: MqttFormat::Labels,
+            topic: "pitinfo/{label}",
+            availability_topic: "pitinfo/availability",
+            online: "online",
+            offline: "offline",
+            trend: false,
+            active_power: false,
+            power_factor: false,
+            forecast_currency: None,
+            offpeak: false,
+            production: false,
+            scales: &Scales::default(),
+            language: Language::En,
+        };
+        let payload = |option, object_id: &str| {
+            discovery
+                .messages(option)
+                .into_iter()
+                .find(|(topic, _)| topic.ends_with(&format!("/{}/config", object_id)))
+                .map(|(_, payload)| payload)
+        };
+
+        // Removed, not announced
+        assert_eq!(
+            payload(TariffOptionValue::Base, "bbrhcjb"),
+            Some(String::new())
+        );
+        assert_eq!(
+            payload(TariffOptionValue::Base, "pejp"),
+            Some(String::new())
+        );
+        assert!(payload(TariffOptionValue::Base, "papp").is_some_and(|p| !p.is_empty()));
+        let notice: Value =
+            serde_json::from_str(&payload(TariffOptionValue::EJP, "pejp").unwrap()).unwrap();
+        assert_eq!(notice["unit_of_measurement"], "min");
+        assert!(payload(TariffOptionValue::OffPeakHours, "hchc").is_some_and(|p| !p.is_empty()));
+    }
+
+    #[test]
+    fn index_guard() {
+        let mut guard = IndexGuard::default();
+        assert!(guard.accept("BBRHCJB", 1000));
+        assert!(guard.accept("BBRHCJB", 1001));
+        // Glitch
+        assert!(!guard.accept("BBRHCJB", 10));
+        assert!(guard.accept("BBRHCJB", 1002));
+        // Reset
+        assert!(!guard.accept("BBRHCJB", 5));
+        assert!(!guard.accept("BBRHCJB", 6));
+        assert!(guard.accept("BBRHCJB", 7));
+        assert!(guard.accept("BBRHCJB", 8));
+    }
+}
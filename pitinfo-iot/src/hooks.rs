@@ -0,0 +1,27 @@
+//! Shell commands run on events.
+
+use tokio::process::Command;
+
+/// Shell and option running a command line.
+#[cfg(windows)]
+const SHELL: [&str; 2] = ["cmd", "/C"];
+#[cfg(not(windows))]
+const SHELL: [&str; 2] = ["sh", "-c"];
+
+/// Runs a command with `sh -c`, `cmd /C` on Windows, with extra environment
+/// variables describing the event. Failures are logged.
+pub async fn run(command: &str, env: &[(&str, &str)]) {
+    match Command::new(SHELL[0])
+        .arg(SHELL[1])
+        .arg(command)
+        .envs(env.iter().copied())
+        .status()
+        .await
+    {
+        Ok(status) if !status.success() => {
+            eprintln!("Command '{}' failed: {}", command, status)
+        }
+        Ok(_) => (),
+        Err(e) => eprintln!("Unable to run command '{}': {}", command, e),
+    }
+}
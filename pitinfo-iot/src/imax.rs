@@ -0,0 +1,187 @@
+//! Maximum currents surviving the resets of the meter.
+//!
+//! IMAX1 to IMAX3 give the highest current reached on each phase since the
+//! meter last reset them, e.g. on a contract change or after a maintenance.
+//! The tracker detects these resets and keeps its own maxima per phase: the
+//! all-time one and a rolling one over the last `rolling_days`, computed from
+//! the maximum of each day. They are written to a JSON file on each change,
+//! so they also survive restarts of the daemon.
+
+use crate::config::ImaxConfig;
+use crate::pipeline::{self, Sink};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use pitinfo_parser::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tokio::task;
+
+/// Readings of a new meter value needed to take it into account: a corrupted
+/// value can still pass the checksum of its group, a single character, and
+/// would otherwise count as a reset or as a lasting maximum.
+const CONFIRMATIONS: u8 = 3;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseMaxima {
+    /// Value reported by the meter
+    pub meter: Option<u16>,
+    pub all_time: Option<u16>,
+    /// Maximum over the last `rolling_days`
+    pub rolling: Option<u16>,
+    /// Highest value of each day in the rolling window
+    pub daily: BTreeMap<NaiveDate, u16>,
+    /// Times the meter reset its maximum
+    pub resets: Vec<NaiveDateTime>,
+    /// New meter value and the number of times it was received in a row
+    #[serde(skip)]
+    pending: Option<(u16, u8)>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tracker {
+    pub phases: [PhaseMaxima; 3],
+    #[serde(skip)]
+    rolling_days: u32,
+}
+
+impl Tracker {
+    pub fn load(path: &Path, rolling_days: u32) -> Result<Tracker, io::Error> {
+        let mut tracker = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Tracker::default(),
+            Err(e) => return Err(e),
+        };
+        tracker.rolling_days = rolling_days.max(1);
+        Ok(tracker)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Written aside then renamed, so a power cut never leaves a truncated file
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, content)?;
+        fs::rename(temporary, path)
+    }
+
+    /// Takes a maximum current reported by the meter into account, returns
+    /// whether the maxima changed.
+    pub fn update(&mut self, phase: u8, value: u16, now: NaiveDateTime) -> bool {
+        if !(1..=3).contains(&phase) {
+            return false;
+        }
+        let maxima = &mut self.phases[phase as usize - 1];
+        let mut changed = false;
+        if maxima.meter == Some(value) {
+            maxima.pending = None;
+        } else {
+            let count = match maxima.pending {
+                Some((pending, count)) if pending == value => count + 1,
+                _ => 1,
+            };
+            // Without a previous value, there is nothing to confirm
+            if count < CONFIRMATIONS && maxima.meter.is_some() {
+                maxima.pending = Some((value, count));
+                return false;
+            }
+            maxima.pending = None;
+            if let Some(meter) = maxima.meter.filter(|meter| value < *meter) {
+                println!(
+                    "Maximum current of phase {} reset by the meter, from {} A to {} A",
+                    phase, meter, value
+                );
+                maxima.resets.push(now);
+            }
+            maxima.meter = Some(value);
+            if maxima.all_time.is_none_or(|all_time| value > all_time) {
+                maxima.all_time = Some(value);
+            }
+            changed = true;
+        }
+
+        // The meter value is the maximum since its last reset, so it is also
+        // the maximum of the day until the next reset
+        let today = now.date();
+        if maxima.daily.get(&today).is_none_or(|daily| value > *daily) {
+            maxima.daily.insert(today, value);
+            let first = today - Duration::days(self.rolling_days as i64 - 1);
+            maxima.daily.retain(|day, _| *day >= first);
+            maxima.rolling = maxima.daily.values().max().copied();
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Starts the tracker. Maximum currents sent to the returned sink update the
+/// maxima stored in the configured file, over the days of `timezone`.
+pub fn spawn(config: &ImaxConfig, timezone: Tz) -> Result<Sink, io::Error> {
+    let path = config.path.clone();
+    let mut tracker = Tracker::load(&path, config.rolling_days)?;
+    Ok(pipeline::spawn_sink("imax", |mut receiver| async move {
+        while let Some(message) = receiver.recv_message().await {
+            if let Message::MaxCurrent { phase, value } = message {
+                let now = Utc::now().with_timezone(&timezone).naive_local();
+                if tracker.update(phase, value, now) {
+                    let snapshot = tracker.clone();
+                    let path = path.clone();
+                    let result = task::spawn_blocking(move || snapshot.save(&path)).await;
+                    match result {
+                        Ok(Ok(())) => (),
+                        Ok(Err(e)) => eprintln!("Unable to save the maximum currents: {}", e),
+                        Err(e) => eprintln!("Unable to save the maximum currents: {}", e),
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn meter_resets() {
+        let mut tracker = Tracker {
+            rolling_days: 7,
+            ..Tracker::default()
+        };
+        assert!(tracker.update(1, 31, at(1, 12)));
+        assert!(!tracker.update(1, 31, at(1, 13)));
+        for _ in 1..CONFIRMATIONS {
+            assert!(!tracker.update(1, 34, at(1, 14)));
+        }
+        assert!(tracker.update(1, 34, at(1, 14)));
+
+        // A single corrupted reading is not a reset
+        assert!(!tracker.update(1, 3, at(2, 12)));
+        assert!(tracker.update(1, 34, at(2, 12)));
+        assert!(tracker.phases[0].resets.is_empty());
+
+        for _ in 0..CONFIRMATIONS {
+            tracker.update(1, 12, at(3, 12));
+        }
+        let maxima = &tracker.phases[0];
+        assert_eq!(maxima.meter, Some(12));
+        assert_eq!(maxima.resets, vec![at(3, 12)]);
+        assert_eq!(maxima.all_time, Some(34));
+        assert_eq!(maxima.rolling, Some(34));
+
+        // The days before the reset leave the rolling window
+        tracker.update(1, 12, at(9, 12));
+        assert_eq!(tracker.phases[0].rolling, Some(12));
+        assert_eq!(tracker.phases[0].all_time, Some(34));
+    }
+}
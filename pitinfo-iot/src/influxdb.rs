@@ -0,0 +1,190 @@
+//! Writes meter values to InfluxDB.
+//!
+//! The state of a frame is written as a single point, with one field per
+//! label, at most once per configured interval. Points carry their timestamp
+//! so writing the same history twice overwrites it instead of duplicating it.
+
+use crate::clock::Stamper;
+use crate::config::{ClockConfig, DeliveryConfig, InfluxDbConfig};
+use crate::pipeline::{self, Inbox, Sink};
+use crate::scale::Scales;
+use crate::state::{MeterState, Update, Value};
+use chrono::{DateTime, Utc};
+use pitinfo_parser::Message;
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct Client {
+    config: InfluxDbConfig,
+    scales: Scales,
+}
+
+impl Client {
+    pub fn new(config: &InfluxDbConfig) -> Result<Client, io::Error> {
+        Ok(Client {
+            config: config.clone(),
+            scales: Scales::new(&config.scale)?,
+        })
+    }
+
+    /// Writes points in line protocol with a millisecond precision.
+    pub fn write(&self, lines: &[String]) -> Result<(), Box<dyn Error>> {
+        let mut request = ureq::post(&format!(
+            "{}/api/v2/write",
+            self.config.url.trim_end_matches('/')
+        ))
+        .query("org", &self.config.org)
+        .query("bucket", &self.config.bucket)
+        .query("precision", "ms");
+        if let Some(token) = &self.config.token {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+        request.send_string(&lines.join("\n"))?;
+        Ok(())
+    }
+
+    pub fn line(&self, timestamp: DateTime<Utc>, values: &[(String, Value)]) -> String {
+        line(&self.config.measurement, timestamp, values, &self.scales)
+    }
+}
+
+/// Starts the InfluxDB writer. Messages sent to the returned sink are
+/// written as points, once the clock is synchronized.
+pub fn spawn(config: &InfluxDbConfig, clock: &ClockConfig) -> Result<Sink, io::Error> {
+    let client = Client::new(config)?;
+    let interval = Duration::from_secs(config.interval);
+    let stamper = Stamper::new(clock.wait_for_sync);
+    let delivery = config.delivery.clone();
+    Ok(pipeline::spawn_sink_with(
+        "influxdb",
+        &config.delivery,
+        move |receiver| run(client, interval, delivery, stamper, receiver),
+    ))
+}
+
+async fn run(
+    client: Client,
+    interval: Duration,
+    delivery: DeliveryConfig,
+    mut stamper: Stamper<Vec<(String, Value)>>,
+    mut receiver: Inbox<Update>,
+) {
+    let mut state = MeterState::default();
+    let mut last_write: Option<Instant> = None;
+    while let Some(item) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_)))
+            && last_write.is_none_or(|last| last.elapsed() >= interval)
+        {
+            let values = state.values();
+            if !values.is_empty() {
+                let lines: Vec<String> = stamper
+                    .stamp(values)
+                    .iter()
+                    .map(|(timestamp, values)| client.line(*timestamp, values))
+                    .collect();
+                if lines.is_empty() {
+                    // Held back until the clock is synchronized
+                    last_write = Some(Instant::now());
+                } else {
+                    let client = client.clone();
+                    // The HTTP client is blocking
+                    let result = pipeline::deliver("influxdb", &delivery, move || {
+                        client.write(&lines).map_err(|e| e.to_string())
+                    })
+                    .await;
+                    match result {
+                        Ok(()) => last_write = Some(Instant::now()),
+                        Err(e) => eprintln!("Unable to write to InfluxDB: {}", e),
+                    }
+                }
+            }
+        }
+        state.record(&item);
+    }
+}
+
+/// Point in InfluxDB line protocol.
+pub fn line(
+    measurement: &str,
+    timestamp: DateTime<Utc>,
+    values: &[(String, Value)],
+    scales: &Scales,
+) -> String {
+    format!(
+        "{} {} {}",
+        escape(measurement),
+        fields(values, scales),
+        timestamp.timestamp_millis()
+    )
+}
+
+/// Field set of a point, one field per label. Scaled integers are written
+/// as floats.
+pub fn fields(values: &[(String, Value)], scales: &Scales) -> String {
+    let fields: Vec<String> = values
+        .iter()
+        .map(|(label, value)| {
+            let value = match value {
+                Value::Integer(_) if scales.is_scaled(label) => scales.text(label, value),
+                Value::Integer(value) => format!("{}i", value),
+                Value::Text(text) => {
+                    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+                }
+            };
+            format!("{}={}", escape(label), value)
+        })
+        .collect();
+    fields.join(",")
+}
+
+pub fn escape(name: &str) -> String {
+    name.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScaleConfig;
+    use chrono::TimeZone;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn line_protocol() {
+        let timestamp = Utc.with_ymd_and_hms(2024, 1, 16, 12, 0, 0).unwrap();
+        assert_eq!(
+            line(
+                "teleinfo",
+                timestamp,
+                &[
+                    (String::from("PAPP"), Value::Integer(803)),
+                    (String::from("PTEC"), Value::Text(String::from("HC.."))),
+                ],
+                &Scales::default()
+            ),
+            r#"teleinfo PAPP=803i,PTEC="HC.." 1705406400000"#
+        );
+        let scales = Scales::new(&BTreeMap::from([(
+            String::from("PAPP"),
+            ScaleConfig {
+                factor: 0.001,
+                decimals: Some(3),
+                unit: None,
+            },
+        )]))
+        .unwrap();
+        assert_eq!(
+            line(
+                "teleinfo",
+                timestamp,
+                &[(String::from("PAPP"), Value::Integer(803))],
+                &scales
+            ),
+            "teleinfo PAPP=0.803 1705406400000"
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! Publishes selected values to KNX group addresses through a KNXnet/IP
+//! tunnelling connection (knxd, IP interfaces and routers).
+//!
+//! | Value          | Datapoint type                                  |
+//! |----------------|-------------------------------------------------|
+//! | Apparent power | 14.xxx, 4 bytes float, VA                       |
+//! | Tariff period  | 5.010: 1 off-peak, 2 peak                       |
+//! | Day color      | 5.010: 0 none, 1 blue, 2 white, 3 red           |
+//! | Tomorrow color | 5.010: 0 not known yet, 1 blue, 2 white, 3 red  |
+
+use crate::config::KnxConfig;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{day_color_code, hour_code, Update};
+use pitinfo_parser::Message;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+const CONNECT_REQUEST: u16 = 0x0205;
+const CONNECT_RESPONSE: u16 = 0x0206;
+const CONNECTIONSTATE_REQUEST: u16 = 0x0207;
+const CONNECTIONSTATE_RESPONSE: u16 = 0x0208;
+const DISCONNECT_REQUEST: u16 = 0x0209;
+const TUNNELLING_REQUEST: u16 = 0x0420;
+const TUNNELLING_ACK: u16 = 0x0421;
+
+const L_DATA_REQ: u8 = 0x11;
+const GROUP_VALUE_WRITE: u8 = 0x80;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+struct GroupAddresses {
+    apparent_power: Option<u16>,
+    tariff_period: Option<u16>,
+    day_color: Option<u16>,
+    tomorrow_color: Option<u16>,
+}
+
+/// Parses a group address written as `main/middle/sub` or `main/sub`.
+pub fn parse_group_address(address: &str) -> Option<u16> {
+    let parts = address
+        .split('/')
+        .map(|part| part.trim().parse::<u16>().ok())
+        .collect::<Option<Vec<u16>>>()?;
+
+    match parts[..] {
+        [main, middle, sub] if main < 32 && middle < 8 && sub < 256 => {
+            Some(main << 11 | middle << 8 | sub)
+        }
+        [main, sub] if main < 32 && sub < 2048 => Some(main << 11 | sub),
+        _ => None,
+    }
+}
+
+fn group_address(address: &Option<String>) -> Result<Option<u16>, io::Error> {
+    match address {
+        Some(address) => match parse_group_address(address) {
+            Some(address) => Ok(Some(address)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid KNX group address '{}'", address),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Starts the publisher. Messages sent to the returned sink are written to
+/// the bus when their value changes.
+pub fn spawn(config: &KnxConfig) -> Result<Sink, io::Error> {
+    let addresses = GroupAddresses {
+        apparent_power: group_address(&config.apparent_power)?,
+        tariff_period: group_address(&config.tariff_period)?,
+        day_color: group_address(&config.day_color)?,
+        tomorrow_color: group_address(&config.tomorrow_color)?,
+    };
+    let gateway = config
+        .gateway
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid KNX gateway"))?;
+    let power_interval = Duration::from_secs(config.power_interval);
+
+    Ok(pipeline::spawn_sink("knx", move |receiver| {
+        run(gateway, addresses, power_interval, receiver)
+    }))
+}
+
+fn telegrams(addresses: &GroupAddresses, message: &Message) -> Vec<(u16, Vec<u8>)> {
+    let mut telegrams = Vec::new();
+    match message {
+        Message::ApparentPower { value } => {
+            if let Some(address) = addresses.apparent_power {
+                telegrams.push((address, (*value as f32).to_be_bytes().to_vec()));
+            }
+        }
+        Message::CurrentTariffPeriod(period) => {
+            if let Some(address) = addresses.tariff_period {
+                telegrams.push((address, vec![hour_code(period.hour)]));
+            }
+            if let Some(address) = addresses.day_color {
+                telegrams.push((address, vec![day_color_code(period.day_color)]));
+            }
+        }
+        Message::Tomorrow(color) => {
+            if let Some(address) = addresses.tomorrow_color {
+                telegrams.push((address, vec![day_color_code(*color)]));
+            }
+        }
+        _ => (),
+    }
+    telegrams
+}
+
+async fn run(
+    gateway: SocketAddr,
+    addresses: GroupAddresses,
+    power_interval: Duration,
+    mut receiver: Inbox<Update>,
+) {
+    let mut tunnel: Option<Tunnel> = None;
+    let mut last_attempt: Option<Instant> = None;
+    let mut last_values: HashMap<u16, Vec<u8>> = HashMap::new();
+    let mut last_power: Option<Instant> = None;
+
+    loop {
+        let message = match time::timeout(RESPONSE_TIMEOUT, receiver.recv_message()).await {
+            Ok(Some(message)) => Some(message),
+            Ok(None) => return,
+            Err(_) => None,
+        };
+
+        if tunnel.is_none() && last_attempt.is_none_or(|t| t.elapsed() > RECONNECT_DELAY) {
+            last_attempt = Some(Instant::now());
+            match Tunnel::connect(gateway).await {
+                Ok(connected) => {
+                    // Everything is sent again after a reconnection
+                    last_values.clear();
+                    tunnel = Some(connected);
+                }
+                Err(e) => eprintln!("Unable to connect to KNX gateway {}: {}", gateway, e),
+            }
+        }
+        let connected = match tunnel.as_mut() {
+            Some(connected) => connected,
+            None => continue,
+        };
+
+        let mut result = connected.keep_alive().await;
+        let throttled = match message {
+            Some(Message::ApparentPower { .. }) => {
+                if last_power.is_some_and(|t| t.elapsed() < power_interval) {
+                    true
+                } else {
+                    last_power = Some(Instant::now());
+                    false
+                }
+            }
+            _ => false,
+        };
+        if let (Some(message), false) = (message, throttled) {
+            for (address, data) in telegrams(&addresses, &message) {
+                if result.is_err() || last_values.get(&address) == Some(&data) {
+                    continue;
+                }
+                result = connected.group_write(address, &data).await;
+                last_values.insert(address, data);
+            }
+        }
+        if let Err(e) = result {
+            eprintln!("KNX tunnel error: {}", e);
+            tunnel = None;
+        }
+    }
+}
+
+struct Tunnel {
+    socket: UdpSocket,
+    gateway: SocketAddr,
+    channel: u8,
+    sequence: u8,
+    last_heartbeat: Instant,
+}
+
+impl Tunnel {
+    async fn connect(gateway: SocketAddr) -> Result<Tunnel, io::Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let mut tunnel = Tunnel {
+            socket,
+            gateway,
+            channel: 0,
+            sequence: 0,
+            last_heartbeat: Instant::now(),
+        };
+
+        let local = tunnel.socket.local_addr()?;
+        let mut body = Vec::with_capacity(20);
+        body.extend_from_slice(&hpai(local));
+        body.extend_from_slice(&hpai(local));
+        // Connection request information: tunnel connection on the link layer
+        body.extend_from_slice(&[0x04, 0x04, 0x02, 0x00]);
+        tunnel.send(CONNECT_REQUEST, &body).await?;
+
+        let response = tunnel.wait_for(CONNECT_RESPONSE).await?;
+        if response.len() < 2 || response[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("connection refused with status {:?}", response.get(1)),
+            ));
+        }
+        tunnel.channel = response[0];
+        Ok(tunnel)
+    }
+
+    async fn group_write(&mut self, address: u16, data: &[u8]) -> Result<(), io::Error> {
+        let mut body = vec![0x04, self.channel, self.sequence, 0x00];
+        // cEMI L_Data.req, standard frame, group destination, hop count 6
+        body.extend_from_slice(&[L_DATA_REQ, 0x00, 0xBC, 0xE0, 0x00, 0x00]);
+        body.extend_from_slice(&address.to_be_bytes());
+        body.push(data.len() as u8 + 1);
+        body.extend_from_slice(&[0x00, GROUP_VALUE_WRITE]);
+        body.extend_from_slice(data);
+
+        for _ in 0..2 {
+            self.send(TUNNELLING_REQUEST, &body).await?;
+            match self.wait_for(TUNNELLING_ACK).await {
+                Ok(ack) if ack.len() >= 4 && ack[2] == self.sequence => {
+                    self.sequence = self.sequence.wrapping_add(1);
+                    return Ok(());
+                }
+                Ok(_) => (),
+                Err(ref e) if is_timeout(e) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "tunnelling request not acknowledged",
+        ))
+    }
+
+    async fn keep_alive(&mut self) -> Result<(), io::Error> {
+        if self.last_heartbeat.elapsed() < HEARTBEAT_INTERVAL {
+            return Ok(());
+        }
+        self.last_heartbeat = Instant::now();
+
+        let mut body = vec![self.channel, 0x00];
+        body.extend_from_slice(&hpai(self.socket.local_addr()?));
+        self.send(CONNECTIONSTATE_REQUEST, &body).await?;
+        let response = self.wait_for(CONNECTIONSTATE_RESPONSE).await?;
+        if response.len() < 2 || response[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection lost",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send(&self, service: u16, body: &[u8]) -> Result<(), io::Error> {
+        self.socket
+            .send_to(&packet(service, body), self.gateway)
+            .await?;
+        Ok(())
+    }
+
+    /// Reads packets until one of the wanted service type arrives and returns
+    /// its body. Telegrams forwarded by the gateway meanwhile are acknowledged.
+    async fn wait_for(&mut self, wanted: u16) -> Result<Vec<u8>, io::Error> {
+        let mut buffer = [0u8; 512];
+        let deadline = time::Instant::now() + RESPONSE_TIMEOUT;
+        while let Ok(received) = time::timeout_at(deadline, self.socket.recv(&mut buffer)).await {
+            let length = received?;
+            if length < 6 || buffer[0] != 0x06 || buffer[1] != 0x10 {
+                continue;
+            }
+            let service = u16::from_be_bytes([buffer[2], buffer[3]]);
+            let body = &buffer[6..length];
+            match service {
+                s if s == wanted => return Ok(body.to_vec()),
+                TUNNELLING_REQUEST if body.len() >= 4 => {
+                    let ack = [0x04, body[1], body[2], 0x00];
+                    self.send(TUNNELLING_ACK, &ack).await?;
+                }
+                DISCONNECT_REQUEST => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "disconnected by the gateway",
+                    ))
+                }
+                _ => (),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no response from gateway",
+        ))
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Ok(local) = self.socket.local_addr() {
+            let mut body = vec![self.channel, 0x00];
+            body.extend_from_slice(&hpai(local));
+            // Best effort, the gateway drops the connection after a while anyway
+            let _ = self
+                .socket
+                .try_send_to(&packet(DISCONNECT_REQUEST, &body), self.gateway);
+        }
+    }
+}
+
+/// KNXnet/IP packet, header included.
+fn packet(service: u16, body: &[u8]) -> Vec<u8> {
+    let length = (body.len() + 6) as u16;
+    let mut packet = Vec::with_capacity(length as usize);
+    packet.extend_from_slice(&[0x06, 0x10]);
+    packet.extend_from_slice(&service.to_be_bytes());
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Host protocol address information, UDP over IPv4.
+fn hpai(address: SocketAddr) -> [u8; 8] {
+    let mut hpai = [0x08, 0x01, 0, 0, 0, 0, 0, 0];
+    if let SocketAddr::V4(address) = address {
+        hpai[2..6].copy_from_slice(&address.ip().octets());
+        hpai[6..8].copy_from_slice(&address.port().to_be_bytes());
+    }
+    hpai
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, TarifPeriod};
+
+    #[test]
+    fn group_addresses() {
+        assert_eq!(parse_group_address("1/2/3"), Some(0x0A03));
+        assert_eq!(parse_group_address("31/7/255"), Some(0xFFFF));
+        assert_eq!(parse_group_address("1/259"), Some(0x0903));
+        assert_eq!(parse_group_address("32/0/0"), None);
+        assert_eq!(parse_group_address("1/8/0"), None);
+        assert_eq!(parse_group_address("1/a/0"), None);
+        assert_eq!(parse_group_address("1"), None);
+    }
+
+    #[test]
+    fn message_telegrams() {
+        let addresses = GroupAddresses {
+            apparent_power: Some(1),
+            tariff_period: Some(2),
+            day_color: None,
+            tomorrow_color: Some(4),
+        };
+        assert_eq!(
+            telegrams(&addresses, &Message::ApparentPower { value: 1000 }),
+            vec![(1, vec![0x44, 0x7A, 0x00, 0x00])]
+        );
+        assert_eq!(
+            telegrams(
+                &addresses,
+                &Message::CurrentTariffPeriod(TarifPeriod {
+                    hour: HourlyTarifPeriod::PeakHours,
+                    day_color: Some(DayColor::Red),
+                })
+            ),
+            vec![(2, vec![2])]
+        );
+        assert_eq!(
+            telegrams(&addresses, &Message::Tomorrow(Some(DayColor::White))),
+            vec![(4, vec![2])]
+        );
+        assert_eq!(
+            telegrams(
+                &addresses,
+                &Message::MeterAddress(String::from("031762120110"))
+            ),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Latency of the sinks, from the reception of a line to the sink being
+//! done with the message parsed from it.
+//!
+//! A sink is taken to be done with a message when it asks for the next one.
+//! The latencies are recorded per sink in histograms, and reported
+//! periodically when configured, flagging the sinks above the budget.
+
+use crate::config::LatencyConfig;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time;
+
+/// Upper bounds of the buckets of the histograms, in milliseconds.
+const BUCKETS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// Latencies of every sink since the last report, by name.
+static LATENCIES: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Histogram {
+    /// Latencies per bucket, the last one above the largest bound
+    counts: [u64; BUCKETS.len() + 1],
+    count: u64,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let milliseconds = latency.as_millis();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| milliseconds <= *bound as u128)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// Latency under which `percent` of the messages were handled, as the
+    /// upper bound of its bucket, or the maximum above the largest bound.
+    pub fn percentile(&self, percent: u64) -> Option<Duration> {
+        let rank = (self.count * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(match BUCKETS.get(bucket) {
+                    Some(bound) => Duration::from_millis(*bound).min(self.max),
+                    None => self.max,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Records the latency of a message handled by a sink.
+pub fn record(sink: &'static str, latency: Duration) {
+    LATENCIES
+        .lock()
+        .unwrap()
+        .entry(sink)
+        .or_default()
+        .record(latency);
+}
+
+/// Takes the latencies recorded since the last call.
+fn take() -> BTreeMap<&'static str, Histogram> {
+    std::mem::take(&mut *LATENCIES.lock().unwrap())
+}
+
+/// Reports the latencies of the sinks every configured interval.
+pub async fn report(config: LatencyConfig) {
+    let mut reports = time::interval(Duration::from_secs(config.interval));
+    // The first tick is immediate
+    reports.tick().await;
+    take();
+    loop {
+        reports.tick().await;
+        for (sink, histogram) in take() {
+            let milliseconds = |percent| {
+                histogram
+                    .percentile(percent)
+                    .unwrap_or_default()
+                    .as_millis()
+            };
+            let line = format!(
+                "Latency of sink {}: p50 {} ms, p95 {} ms, p99 {} ms, max {} ms over {} messages",
+                sink,
+                milliseconds(50),
+                milliseconds(95),
+                milliseconds(99),
+                histogram.max.as_millis(),
+                histogram.count
+            );
+            match config.budget {
+                Some(budget) if milliseconds(99) > budget as u128 => {
+                    eprintln!("WARNING: {}, above the {} ms budget", line, budget)
+                }
+                _ => println!("{}", line),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50), None);
+        for milliseconds in [0, 1, 3, 4, 8, 15, 30, 40, 45, 7000] {
+            histogram.record(Duration::from_millis(milliseconds));
+        }
+        assert_eq!(histogram.percentile(50), Some(Duration::from_millis(10)));
+        assert_eq!(histogram.percentile(90), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.percentile(99), Some(Duration::from_millis(7000)));
+        assert_eq!(histogram.max, Duration::from_millis(7000));
+
+        let mut fast = Histogram::default();
+        fast.record(Duration::from_micros(300));
+        // Not above the maximum
+        assert_eq!(fast.percentile(50), Some(Duration::from_micros(300)));
+    }
+}
@@ -0,0 +1,139 @@
+//! Names and numbers shown to people, in English or in French.
+//!
+//! Labels, topics and the values published stay those of the meter, only
+//! what people read is translated: the names of the Home Assistant sensors
+//! on its dashboards and the consumption reports sent by email, with the
+//! French decimal comma and dates.
+
+use crate::config::Language;
+use crate::estimate;
+use crate::factor;
+use crate::forecast;
+use crate::offpeak;
+use crate::production;
+use crate::trend;
+use chrono::NaiveDate;
+
+/// French names of the sensors, by label.
+const SENSOR_NAMES: &[(&str, &str)] = &[
+    ("PAPP", "Puissance apparente"),
+    ("IINST1", "Intensité phase 1"),
+    ("IINST2", "Intensité phase 2"),
+    ("IINST3", "Intensité phase 3"),
+    ("ISOUSC", "Intensité souscrite"),
+    ("PMAX", "Puissance maximale"),
+    ("PTEC", "Période tarifaire en cours"),
+    ("OPTARIF", "Option tarifaire"),
+    ("HHPHC", "Horaire heures creuses"),
+    ("HCHC", "Index heures creuses"),
+    ("HCHP", "Index heures pleines"),
+    ("PEJP", "Préavis EJP"),
+    ("BBRHCJB", "Index heures creuses jours bleus"),
+    ("BBRHPJB", "Index heures pleines jours bleus"),
+    ("BBRHCJW", "Index heures creuses jours blancs"),
+    ("BBRHPJW", "Index heures pleines jours blancs"),
+    ("BBRHCJR", "Index heures creuses jours rouges"),
+    ("BBRHPJR", "Index heures pleines jours rouges"),
+    ("DEMAIN", "Couleur du lendemain"),
+    (trend::AVERAGE_LABEL, "Puissance apparente lissée"),
+    (trend::RATE_LABEL, "Variation de la puissance apparente"),
+    (estimate::LABEL, "Puissance active estimée"),
+    (factor::LABEL, "Facteur de puissance mesuré"),
+    (
+        forecast::ENERGY_LABEL,
+        "Prévision d'énergie de la période de facturation",
+    ),
+    (
+        forecast::COST_LABEL,
+        "Prévision de coût de la période de facturation",
+    ),
+    (offpeak::ACTIVE_LABEL, "Heures creuses"),
+    (offpeak::SOON_LABEL, "Heures creuses bientôt"),
+    (offpeak::MINUTES_LABEL, "Minutes avant les heures creuses"),
+    (production::POWER_LABEL, "Puissance apparente produite"),
+    (production::NET_POWER_LABEL, "Puissance apparente nette"),
+    (production::ENERGY_LABEL, "Énergie produite"),
+    (production::IMPORT_LABEL, "Énergie nette importée"),
+    (production::EXPORT_LABEL, "Énergie nette exportée"),
+    (
+        production::SELF_CONSUMPTION_LABEL,
+        "Taux d'autoconsommation",
+    ),
+];
+
+/// Name of the sensor of a label, `name` being the English one.
+pub fn sensor_name(language: Language, label: &str, name: &'static str) -> &'static str {
+    match language {
+        Language::En => name,
+        Language::Fr => SENSOR_NAMES
+            .iter()
+            .find(|(known, _)| *known == label)
+            .map_or(name, |(_, name)| name),
+    }
+}
+
+/// Name of a tariff period, e.g. `HC` with the `B` color, as in the daily
+/// energy.
+pub fn period_name(language: Language, period: &str, color: Option<&str>) -> String {
+    match language {
+        Language::En => {
+            let days = match color {
+                Some("B") => " blue days",
+                Some("W") => " white days",
+                Some("R") => " red days",
+                _ => "",
+            };
+            format!("{}{}", period, days)
+        }
+        Language::Fr => {
+            let hours = match period {
+                "HC" => "Heures Creuses",
+                "HP" => "Heures Pleines",
+                period => period,
+            };
+            let days = match color {
+                Some("B") => " Jour Bleu",
+                Some("W") => " Jour Blanc",
+                Some("R") => " Jour Rouge",
+                _ => "",
+            };
+            format!("{}{}", hours, days)
+        }
+    }
+}
+
+/// Energy in kWh with one decimal, from Wh.
+pub fn kwh(language: Language, wh: i64) -> String {
+    let kwh = format!("{:.1} kWh", wh as f64 / 1000.0);
+    match language {
+        Language::En => kwh,
+        Language::Fr => kwh.replace('.', ","),
+    }
+}
+
+pub fn date(language: Language, date: NaiveDate) -> String {
+    match language {
+        Language::En => date.to_string(),
+        Language::Fr => date.format("%d/%m/%Y").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn french() {
+        assert_eq!(
+            sensor_name(Language::Fr, "PAPP", "Apparent power"),
+            "Puissance apparente"
+        );
+        assert_eq!(sensor_name(Language::Fr, "ADPS", "Overload"), "Overload");
+        assert_eq!(
+            period_name(Language::Fr, "HC", Some("B")),
+            "Heures Creuses Jour Bleu"
+        );
+        assert_eq!(period_name(Language::En, "HP", Some("R")), "HP red days");
+        assert_eq!(kwh(Language::Fr, 120_460), "120,5 kWh");
+    }
+}
@@ -0,0 +1,167 @@
+//! Ships the groups that cannot be parsed to Grafana Loki.
+//!
+//! Each error is a JSON log line with the error, the group as received and
+//! its bytes in hexadecimal, which shows the separators and the corrupted
+//! bits, and the raw bytes around it when kept, see the `context`
+//! module. Lines are pushed in batches, on a stream labelled
+//! `job="pitinfo"` and the configured labels, so that the data quality of
+//! several installations can be searched in one place, e.g.
+//! `{job="pitinfo"} | json | error =~ "checksum.*"`.
+
+use crate::config::LokiConfig;
+use crate::context;
+use crate::pipeline::{self, Inbox, Item, Sink};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use tokio::task;
+use tokio::time;
+
+/// Errors kept at most while Loki cannot be reached.
+const MAX_PENDING: usize = 10_000;
+
+/// Group the parser rejected.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub timestamp: DateTime<Utc>,
+    /// Group as split from the frame, without its line feed and carriage return
+    pub line: String,
+    pub error: String,
+    /// Raw bytes around the group, in hexadecimal
+    pub context: Option<String>,
+}
+
+impl Item for ParseError {}
+
+/// Starts the shipper. Errors sent to the returned sink are pushed every
+/// `interval` seconds.
+pub fn spawn(config: &LokiConfig) -> Result<Sink<ParseError>, io::Error> {
+    let config = config.clone();
+    Ok(pipeline::spawn_sink("loki", move |receiver| {
+        run(config, receiver)
+    }))
+}
+
+async fn run(config: LokiConfig, mut receiver: Inbox<ParseError>) {
+    let mut labels = BTreeMap::from([(String::from("job"), String::from("pitinfo"))]);
+    labels.extend(config.labels.clone());
+    let mut interval = time::interval(Duration::from_secs(config.interval.max(1)));
+    let mut errors = Vec::new();
+    loop {
+        tokio::select! {
+            error = receiver.recv() => match error {
+                Some(error) => {
+                    // Kept while Loki is unreachable, up to a limit
+                    if errors.len() < MAX_PENDING {
+                        errors.push(error);
+                    }
+                }
+                None => {
+                    push(&config, &labels, &mut errors).await;
+                    return;
+                }
+            },
+            _ = interval.tick() => push(&config, &labels, &mut errors).await,
+        }
+    }
+}
+
+/// Pushes the pending errors, which are kept for the next push on failure.
+async fn push(
+    config: &LokiConfig,
+    labels: &BTreeMap<String, String>,
+    errors: &mut Vec<ParseError>,
+) {
+    if errors.is_empty() {
+        return;
+    }
+    let body = push_body(labels, errors);
+    let config = config.clone();
+    // The HTTP client is blocking
+    let result =
+        task::spawn_blocking(move || write(&config, &body).map_err(|e| e.to_string())).await;
+    match result {
+        Ok(Ok(())) => errors.clear(),
+        Ok(Err(e)) => eprintln!("Unable to push to Loki: {}", e),
+        Err(e) => eprintln!("Unable to push to Loki: {}", e),
+    }
+}
+
+fn write(config: &LokiConfig, body: &serde_json::Value) -> Result<(), Box<dyn Error>> {
+    let mut request = ureq::post(&format!(
+        "{}/loki/api/v1/push",
+        config.url.trim_end_matches('/')
+    ));
+    if let Some(username) = &config.username {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!(
+            "{}:{}",
+            username,
+            config.password.as_deref().unwrap_or("")
+        ));
+        request = request.set("Authorization", &format!("Basic {}", credentials));
+    }
+    request.send_json(body)?;
+    Ok(())
+}
+
+/// Body of a push request, a single stream with one line per error.
+pub fn push_body(labels: &BTreeMap<String, String>, errors: &[ParseError]) -> serde_json::Value {
+    let values: Vec<serde_json::Value> = errors
+        .iter()
+        .map(|error| {
+            let mut line = json!({
+                "error": error.error,
+                "group": error.line,
+                "bytes": context::hex(error.line.as_bytes()),
+            });
+            if let Some(context) = &error.context {
+                line["context"] = json!(context);
+            }
+            json!([
+                error
+                    .timestamp
+                    .timestamp_nanos_opt()
+                    .unwrap_or_default()
+                    .to_string(),
+                line.to_string()
+            ])
+        })
+        .collect();
+    json!({ "streams": [{ "stream": labels, "values": values }] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn push_request() {
+        let labels = BTreeMap::from([
+            (String::from("job"), String::from("pitinfo")),
+            (String::from("site"), String::from("home")),
+        ]);
+        let error = ParseError {
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 16, 12, 0, 0).unwrap(),
+            line: String::from("PAPP 00803 /"),
+            error: String::from("checksum error"),
+            context: None,
+        };
+        assert_eq!(
+            push_body(&labels, &[error]),
+            json!({
+                "streams": [{
+                    "stream": {"job": "pitinfo", "site": "home"},
+                    "values": [[
+                        "1705406400000000000",
+                        r#"{"bytes":"50 41 50 50 20 30 30 38 30 33 20 2f","error":"checksum error","group":"PAPP 00803 /"}"#
+                    ]]
+                }]
+            })
+        );
+    }
+}
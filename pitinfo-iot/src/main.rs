@@ -1,50 +1,972 @@
-use pitinfo_parser::parse_group;
-use serialport::{self, DataBits, FlowControl, Parity, StopBits};
-use std::io::{self, BufRead, BufReader};
-use std::time::Duration;
-
-fn main() -> Result<(), io::Error> {
-    let port = serialport::new("/dev/ttyAMA0", 1200)
-        .parity(Parity::Even)
-        .data_bits(DataBits::Seven)
-        .flow_control(FlowControl::None)
-        .stop_bits(StopBits::One)
-        .timeout(Duration::from_millis(1000))
-        .open();
-
-    match port {
-        Ok(port) => {
-            let f = BufReader::with_capacity(20, port);
-
-            for line in f.lines().skip(1) {
-                match line {
-                    Ok(line) => {
-                        // PPOT at the end of the frame gets control chars:
-                        // \x03 -> enf of frame, \x02 -> start of frame, and new line
-                        let group =
-                            String::from(line.trim_end_matches(&['\x03', '\x02', '\x0d'] as &[_]));
-                        let result = parse_group(&group);
-                        match result {
-                            Ok(Some(message)) => {
-                                println!("Message: {:<20} -> {:?}", group, message);
+mod anomaly;
+mod api;
+mod backfill;
+mod bridge;
+mod clock;
+mod coap;
+mod command;
+mod config;
+mod context;
+mod daily;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod derived;
+mod ecowatt;
+mod email;
+mod emoncms;
+mod enedis;
+mod estimate;
+#[cfg(feature = "parquet")]
+mod export;
+mod factor;
+mod fleet;
+mod forecast;
+mod framing;
+mod gap;
+mod grafana;
+mod history;
+mod homeassistant;
+mod hooks;
+mod imax;
+mod influxdb;
+mod knx;
+mod latency;
+mod locale;
+mod loki;
+mod meter;
+mod modbus;
+mod mqtt;
+mod nilm;
+mod offpeak;
+mod openhab;
+mod pipeline;
+mod production;
+mod proxy;
+mod pushgateway;
+mod pvoutput;
+mod rte;
+mod scale;
+mod schedule;
+mod snmp;
+mod state;
+#[cfg(feature = "statistics")]
+mod statistics;
+mod storage;
+mod sync;
+mod tempo;
+mod thingsboard;
+mod trend;
+
+use chrono::Utc;
+use command::Command;
+use config::{Config, CONFIG_VARIABLE};
+use context::ErrorContexts;
+use framing::{MaskParity, Trials};
+use gap::{Event, Gap, GapWatch};
+use loki::ParseError;
+use meter::{Check, MeterInfo, MeterWatch};
+use pipeline::Sink;
+use pitinfo_parser::{ErrorKind, FrameParser, LabelFilter, Message, TicMode};
+use proxy::Tap;
+use state::{MeterState, Update, NON_NOMINAL_LABEL};
+use std::collections::VecDeque;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::mem;
+#[cfg(feature = "parquet")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempo::TempoCalendar;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::watch;
+use tokio::task;
+use tokio::time;
+use tokio_serial::{DataBits, FlowControl, SerialPortBuilderExt, StopBits};
+
+/// Serial port reading the teleinformation stream from the standard input.
+const STDIN_PORT: &str = "-";
+/// Longest wait for the sinks to handle their pending messages on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Period of the checks for gaps in the stream.
+const GAP_CHECK_PERIOD: Duration = Duration::from_secs(1);
+/// Remote commands waiting for the reading loop.
+const COMMAND_CAPACITY: usize = 8;
+
+/// Links between the reading loop and the sinks: whether frames are
+/// received, for the sinks reporting their availability, and the remote
+/// commands. Also holds the serial framing trials of the port and the parse
+/// errors waiting for their raw context.
+struct Control {
+    receiving: watch::Sender<bool>,
+    /// Address of the meter read
+    meter: watch::Sender<Option<MeterInfo>>,
+    commands: mpsc::Sender<Command>,
+    received: Receiver<Command>,
+    /// Configuration file, read again on reload
+    path: Option<PathBuf>,
+    /// Settings changed remotely, kept over the configuration file
+    settings: watch::Sender<Vec<(String, toml::Value)>>,
+    framing: Option<Trials>,
+    contexts: Option<ErrorContexts>,
+    /// Recent currents and apparent power, served by the HTTP API
+    history: Option<Arc<Mutex<history::History>>>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    match (args.get(1).map(String::as_str), args.get(2)) {
+        #[cfg(feature = "parquet")]
+        (Some("export"), Some(directory)) => {
+            return export(Path::new(directory), load_config(args.get(3))?)
+        }
+        #[cfg(feature = "parquet")]
+        (Some("export-anonymized"), Some(file)) => {
+            return export_anonymized(Path::new(file), load_config(args.get(3))?)
+        }
+        #[cfg(feature = "parquet")]
+        (Some("export-heatmap"), Some(file)) => {
+            return export_heatmap(Path::new(file), load_config(args.get(3))?)
+        }
+        #[cfg(not(feature = "parquet"))]
+        (Some("export" | "export-anonymized" | "export-heatmap"), Some(_)) => {
+            return Err(config::without_feature("exports", "parquet").into())
+        }
+        (Some("backfill"), Some(sink)) => return backfill(sink, load_config(args.get(3))?),
+        (Some("fleet"), path) => return fleet(load_config(path)?).await,
+        (Some("sync"), path) => return sync(load_config(path)?),
+        (Some("export"), None)
+        | (Some("export-anonymized"), None)
+        | (Some("export-heatmap"), None)
+        | (Some("backfill"), None) => {
+            eprintln!("Usage: {} export <directory> [config]", args[0]);
+            eprintln!("       {} export-anonymized <file> [config]", args[0]);
+            eprintln!("       {} export-heatmap <file> [config]", args[0]);
+            eprintln!(
+                "       {} backfill influxdb|homeassistant [config]",
+                args[0]
+            );
+            eprintln!("       {} fleet [config]", args[0]);
+            eprintln!("       {} sync [config]", args[0]);
+            ::std::process::exit(2);
+        }
+        _ => (),
+    }
+
+    let path = config_path(args.get(1));
+    let mut config = Config::load(path.as_deref())?;
+    let state = Arc::new(Mutex::new(MeterState::default()));
+
+    if let Some(modbus_tcp) = &config.modbus_tcp {
+        let listener = TcpListener::bind(&modbus_tcp.listen).await?;
+        tokio::spawn(modbus::serve_tcp(listener, Arc::clone(&state)));
+    }
+
+    if let Some(modbus_rtu) = &config.modbus_rtu {
+        let port = tokio_serial::new(&modbus_rtu.port, modbus_rtu.baud_rate)
+            .parity(modbus_rtu.parity.into())
+            .data_bits(DataBits::Eight)
+            .flow_control(FlowControl::None)
+            .stop_bits(StopBits::One)
+            .open_native_async()?;
+        tokio::spawn(modbus::serve_rtu(
+            port,
+            modbus::rtu_frame_delay(modbus_rtu.baud_rate),
+            modbus_rtu.unit_id,
+            Arc::clone(&state),
+        ));
+    }
+
+    let (commands, received) = mpsc::channel(COMMAND_CAPACITY);
+    let mut control = Control {
+        receiving: watch::channel(true).0,
+        meter: watch::channel(None).0,
+        commands,
+        received,
+        path,
+        settings: watch::channel(Vec::new()).0,
+        framing: None,
+        contexts: None,
+        history: None,
+    };
+    let mut sinks = spawn_sinks(&config, &control)?;
+    tokio::spawn(reload_on_hangup(control.commands.clone()));
+    if let Some(api) = &config.api {
+        let history = Arc::new(Mutex::new(history::History::new(api.history)));
+        api::spawn(
+            api,
+            control.path.clone(),
+            control.settings.subscribe(),
+            control.commands.clone(),
+            Arc::clone(&history),
+        )?;
+        control.history = Some(history);
+    }
+    let mut errors = config.loki.as_ref().map(loki::spawn).transpose()?;
+    if let Some(latency) = config.latency.clone() {
+        tokio::spawn(latency::report(latency));
+    }
+    let scheduler = schedule::Scheduler::new(
+        &config.schedule,
+        config.storage.as_ref(),
+        config.email.as_ref(),
+        config.clock.timezone,
+        config.language,
+    )?;
+    tokio::spawn(scheduler.run());
+    let tempo = config
+        .tempo
+        .as_ref()
+        .map(|tempo| tempo::spawn(tempo, config.clock.timezone));
+
+    let proxy = config.proxy.as_ref().map(proxy::spawn).transpose()?;
+    // Bridged groups have no raw bytes
+    if config.serial.error_context > 0 && config.serial.mqtt_topic.is_none() {
+        control.contexts = Some(ErrorContexts::new(config.serial.error_context));
+    }
+    let raw = control.contexts.as_ref().map(ErrorContexts::raw);
+    let lines = if let Some(topic) = &config.serial.mqtt_topic {
+        let mqtt = config.mqtt.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "serial.mqtt_topic requires an [mqtt] section",
+            )
+        })?;
+        bridge::spawn_reader(mqtt, topic)?
+    } else if config.serial.port == STDIN_PORT {
+        pipeline::spawn_reader(Tap::new(tokio::io::stdin(), proxy), raw)
+    } else {
+        let trials = Trials::new(config.serial.baud_rate, config.serial.reframe_error_rate);
+        let framing = trials.opener();
+        let path = config.serial.port.clone();
+        let open = move || {
+            let framing = framing();
+            tokio_serial::new(&path, framing.baud_rate)
+                .parity(framing.parity())
+                .data_bits(framing.data_bits())
+                .flow_control(FlowControl::None)
+                .stop_bits(StopBits::One)
+                .open_native_async()
+                .map(|port| Tap::new(MaskParity::new(port, framing.masked), proxy.clone()))
+        };
+        match open() {
+            Ok(port) if config.serial.stall_timeout > 0 => {
+                let reopen = trials.reopen();
+                if config.serial.reframe_error_rate > 0 {
+                    control.framing = Some(trials);
+                }
+                pipeline::spawn_port_reader(
+                    port,
+                    open,
+                    Duration::from_secs(config.serial.stall_timeout),
+                    reopen,
+                    raw,
+                )
+            }
+            Ok(port) => pipeline::spawn_reader(port, raw),
+            Err(e) => {
+                eprintln!("Failed to open \"{}\". Error: {}", config.serial.port, e);
+                report_serial_ports(&e);
+                ::std::process::exit(1);
+            }
+        }
+    };
+
+    tokio::select! {
+        result = process(lines, &mut config, &state, tempo.as_ref(), &mut control, &mut sinks, errors.as_mut()) => {
+            result?;
+            eprintln!("End of the teleinformation stream \"{}\"", config.serial.port);
+        }
+        result = shutdown_signal() => {
+            result?;
+            eprintln!("Shutting down");
+        }
+    }
+
+    // Lets the sinks write what they already received, e.g. the offline
+    // MQTT availability or the last NILM samples
+    if time::timeout(SHUTDOWN_TIMEOUT, close_sinks(sinks, errors))
+        .await
+        .is_err()
+    {
+        eprintln!("Sinks did not stop in time");
+    }
+    Ok(())
+}
+
+/// Starts the sinks configured.
+fn spawn_sinks(config: &Config, control: &Control) -> Result<Vec<Sink>, io::Error> {
+    let mut sinks = Vec::new();
+    if let Some(coap) = &config.coap {
+        sinks.push(coap::spawn(coap)?);
+    }
+    if let Some(knx) = &config.knx {
+        sinks.push(knx::spawn(knx)?);
+    }
+    // Published by the MQTT sink
+    let mut computed = mqtt::Computed::default();
+    if let Some(forecast) = &config.forecast {
+        let (sink, latest) = forecast::spawn(forecast, config.clock.timezone)?;
+        sinks.push(sink);
+        computed.forecast = Some(latest);
+    }
+    if let Some(offpeak) = &config.offpeak {
+        let (sink, latest) = offpeak::spawn(offpeak, config.clock.timezone)?;
+        sinks.push(sink);
+        computed.offpeak = Some(latest);
+    }
+    if let Some(production) = &config.production {
+        let (sink, latest) = production::spawn(production)?;
+        sinks.push(sink);
+        computed.production = Some(latest);
+    }
+    if let Some(mqtt) = &config.mqtt {
+        sinks.push(mqtt::spawn(
+            mqtt,
+            control.receiving.subscribe(),
+            control.meter.subscribe(),
+            computed,
+            control.commands.clone(),
+            config.clock.timezone,
+            config.language,
+        )?);
+    }
+    if let Some(enedis) = &config.enedis {
+        sinks.push(enedis::spawn(enedis, config.clock.timezone)?);
+    }
+    if let Some(ecowatt) = &config.ecowatt {
+        sinks.push(ecowatt::spawn(ecowatt)?);
+    }
+    if let Some(storage) = &config.storage {
+        sinks.push(storage::spawn(storage, &config.clock)?);
+    }
+    if let Some(influxdb) = &config.influxdb {
+        sinks.push(influxdb::spawn(influxdb, &config.clock)?);
+    }
+    if let Some(grafana_live) = &config.grafana_live {
+        sinks.push(grafana::spawn(grafana_live)?);
+    }
+    if let Some(pushgateway) = &config.pushgateway {
+        sinks.push(pushgateway::spawn(pushgateway)?);
+    }
+    if let Some(emoncms) = &config.emoncms {
+        sinks.push(emoncms::spawn(emoncms)?);
+    }
+    if let Some(pvoutput) = &config.pvoutput {
+        sinks.push(pvoutput::spawn(pvoutput, &config.clock)?);
+    }
+    if let Some(thingsboard) = &config.thingsboard {
+        sinks.push(thingsboard::spawn(thingsboard, control.meter.subscribe())?);
+    }
+    if let Some(openhab) = &config.openhab {
+        sinks.push(openhab::spawn(openhab)?);
+    }
+    #[cfg(feature = "dbus")]
+    if let Some(dbus) = &config.dbus {
+        sinks.push(dbus::spawn(dbus, control.meter.subscribe())?);
+    }
+    if let Some(snmp) = &config.snmp {
+        sinks.push(snmp::spawn(snmp, control.meter.subscribe())?);
+    }
+    if let Some(anomaly) = &config.anomaly {
+        let mailer = config.email.as_ref().map(email::Mailer::new).transpose()?;
+        sinks.push(anomaly::spawn(anomaly, config.clock.timezone, mailer)?);
+    }
+    if let Some(nilm) = &config.nilm {
+        sinks.push(nilm::spawn(nilm)?);
+    }
+    if let Some(imax) = &config.imax {
+        sinks.push(imax::spawn(imax, config.clock.timezone)?);
+    }
+    Ok(sinks)
+}
+
+/// Parses the frames of the serial port and fans their messages out, and
+/// the parse errors to their own sink. The sinks start over when the meter
+/// changes or the configuration is changed remotely, and `receiving` is
+/// cleared during the gaps of the stream.
+async fn process(
+    mut chunks: pipeline::Chunks,
+    config: &mut Config,
+    state: &Mutex<MeterState>,
+    tempo: Option<&TempoCalendar>,
+    control: &mut Control,
+    sinks: &mut Vec<Sink>,
+    mut errors: Option<&mut Sink<ParseError>>,
+) -> Result<(), io::Error> {
+    let mut watch = MeterWatch::new(config.serial.meter.clone());
+    let metrics = derived::metrics(&config.derived)?;
+    // ADCO, or ADSC in standard mode, starts the frames
+    let filter = (!config.serial.labels.is_empty()).then(|| {
+        let starts = [String::from("ADCO"), String::from("ADSC")];
+        LabelFilter::new(config.serial.labels.iter().chain(&starts))
+    });
+    // Switched when the groups are those of the other mode
+    let mut mode = TicMode::from_baud_rate(config.serial.baud_rate);
+    // Whether the groups come from the meter read so far
+    let mut forwarding = false;
+    // Whether groups were read in another mode than sent, reported once
+    let mut misconfigured = false;
+    // Flag of the frames published last
+    let mut non_nominal = false;
+    let mut gaps = (config.serial.gap_timeout > 0).then(|| {
+        GapWatch::new(
+            Duration::from_secs(config.serial.gap_timeout),
+            Instant::now(),
+        )
+    });
+    let mut gap_checks = time::interval(GAP_CHECK_PERIOD);
+    // Groups outside a frame, read before the first one starts, are dropped
+    let mut frames = FrameParser::new(mode, config.serial.checksum);
+    // Groups of the frames not ended yet, with the range of their text in
+    // `text`, the position of their end in the stream and their message,
+    // used once their frame is complete. The text of the groups is appended
+    // to one buffer, kept from chunk to chunk.
+    let mut groups = VecDeque::new();
+    let mut text = String::new();
+    loop {
+        let pipeline::Chunk {
+            received,
+            bytes,
+            start,
+        } = tokio::select! {
+            chunk = chunks.recv() => match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            },
+            _ = gap_checks.tick() => {
+                if let Some(event) = gaps.as_mut().and_then(|gaps| gaps.check(Instant::now())) {
+                    report_gap(event, config, &control.receiving);
+                }
+                report_contexts(control.contexts.as_mut(), &mut errors, false);
+                continue;
+            }
+            Some(command) = control.received.recv() => {
+                reconfigure(command, config, control, sinks).await?;
+                continue;
+            }
+        };
+        report_contexts(control.contexts.as_mut(), &mut errors, false);
+        let read = frames.push_bytes_counted(&bytes, |group, end| {
+            let parse = |mode: TicMode| match &filter {
+                Some(filter) if !filter.wants(group) => Ok(None),
+                _ => mode.parse_group(group, config.serial.checksum),
+            };
+            let mut result = parse(mode);
+            if result.as_ref().is_err_and(|e| !e.is_recoverable()) {
+                if let Ok(message) = parse(mode.other()) {
+                    mode = mode.other();
+                    println!("Reading the TIC in {} mode", mode);
+                    control.meter.send_if_modified(|meter| match meter {
+                        Some(meter) if meter.mode != mode => {
+                            meter.mode = mode;
+                            true
+                        }
+                        _ => false,
+                    });
+                    result = Ok(message);
+                }
+            }
+            let end = start.map(|start| start + end as u64);
+            let from = text.len();
+            text.push_str(group);
+            groups.push_back((from..text.len(), end, result.clone()));
+            result
+        });
+        for (frame, count) in read {
+            // The groups of a frame cut short may mix with those of the next
+            if let Err(e @ pitinfo_parser::ParseError::TruncatedFrame) = frame {
+                if forwarding {
+                    eprintln!("WARNING: {}, its {} groups were dropped", e, count);
+                }
+                groups.drain(..count);
+                continue;
+            }
+            for (range, end, result) in groups.drain(..count) {
+                if let Some(gaps) = gaps.as_mut() {
+                    gaps.line(Instant::now());
+                }
+                let group = &text[range];
+                if let Some(framing) = control.framing.as_mut() {
+                    framing.record(result.is_err());
+                }
+                match result {
+                    Ok(Some(message)) => {
+                        let message = match tempo {
+                            Some(tempo) => tempo.reconcile(message),
+                            None => message,
+                        };
+                        // Sinks only see the indexes of known periods
+                        let message = match message {
+                            Message::SupplierIndex { index, value } => {
+                                match state.lock().unwrap().supplier_period(index) {
+                                    Some(period) => Message::Index { period, value },
+                                    None => {
+                                        println!("Message: {:<20} -> Ignored", group);
+                                        continue;
+                                    }
+                                }
                             }
-                            Ok(None) => {
-                                println!("Message: {:<20} -> Ignored", group);
+                            message => message,
+                        };
+                        if let Message::MeterAddress(address) = &message {
+                            let check = watch.check(address);
+                            match &check {
+                                Check::Same | Check::Pending => (),
+                                Check::Unexpected if forwarding => eprintln!(
+                                    "WARNING: reading meter {} instead of {}, ignoring its frames",
+                                    address,
+                                    config.serial.meter.as_deref().unwrap_or_default()
+                                ),
+                                Check::Unexpected => (),
+                                Check::Changed { previous: None } => {
+                                    println!("Reading meter {}", address);
+                                    control.meter.send_replace(Some(MeterInfo {
+                                        address: address.to_string(),
+                                        mode,
+                                    }));
+                                }
+                                Check::Changed {
+                                    previous: Some(previous),
+                                } => {
+                                    eprintln!(
+                                    "WARNING: the meter changed from {} to {}, starting new data series",
+                                    previous, address
+                                );
+                                    *state.lock().unwrap() = MeterState::default();
+                                    control.meter.send_replace(Some(MeterInfo {
+                                        address: address.to_string(),
+                                        mode,
+                                    }));
+                                    close_sinks(mem::take(sinks), None).await;
+                                    *sinks = spawn_sinks(config, control)?;
+                                }
                             }
-                            Err(e) => {
-                                eprintln!("Error reading group: '{}': {}", group, e);
+                            forwarding = matches!(check, Check::Same | Check::Changed { .. });
+                        }
+                        if !forwarding {
+                            continue;
+                        }
+                        println!("Message: {:<20} -> {:?}", group, message);
+                        state.lock().unwrap().update(&message);
+                        if let Some(history) = &control.history {
+                            history.lock().unwrap().record(&message, Utc::now());
+                        }
+                        let item = Update::Meter(message);
+                        for sink in sinks.iter_mut() {
+                            sink.send_received(&item, received);
+                        }
+                    }
+                    Ok(None) => {
+                        println!("Message: {:<20} -> Ignored", group);
+                    }
+                    Err(e) => {
+                        if !e.is_recoverable() {
+                            if !misconfigured {
+                                eprintln!(
+                                    "WARNING: {}, check the baud rate and mode of the meter",
+                                    e
+                                );
+                                misconfigured = true;
+                            }
+                        } else if forwarding && is_unusual(&e, config.serial.checksum) {
+                            // Test or maintenance frames, the rest of the frame is still used
+                            let mut state = state.lock().unwrap();
+                            if state.unusual {
+                                eprintln!("Unusual group: '{}': {}", group, e);
+                            } else {
+                                eprintln!(
+                                "WARNING: unusual group '{}' ({}), frame flagged as non-nominal",
+                                group, e
+                            );
+                                state.flag_unusual();
+                            }
+                        } else {
+                            eprintln!("Error reading group: '{}': {}", group, e);
+                        }
+                        let error = ParseError {
+                            timestamp: Utc::now(),
+                            line: group.to_string(),
+                            error: e.to_string(),
+                            context: None,
+                        };
+                        match (control.contexts.as_mut(), end) {
+                            (Some(contexts), Some(end)) => {
+                                contexts.hold(end, error, Instant::now())
+                            }
+                            _ => {
+                                if let Some(errors) = errors.as_mut() {
+                                    errors.send(&error);
+                                }
                             }
                         }
                     }
-                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
-                    Err(e) => eprintln!("{:?}", e),
                 }
             }
-            Ok(())
+            if !forwarding {
+                continue;
+            }
+            if let Some(event) = gaps.as_mut().and_then(|gaps| gaps.frame(Instant::now())) {
+                report_gap(event, config, &control.receiving);
+            }
+            // The frame is complete
+            let derived: Vec<Update> = {
+                let state = state.lock().unwrap();
+                let mut derived: Vec<Update> = metrics
+                    .iter()
+                    .filter_map(|metric| {
+                        Some(Update::Derived {
+                            label: metric.label.clone(),
+                            value: metric.evaluate(&state)?,
+                        })
+                    })
+                    .collect();
+                // Published when it changes, a flagged frame is followed by
+                // the first nominal one
+                if state.unusual != non_nominal {
+                    non_nominal = state.unusual;
+                    derived.push(Update::Derived {
+                        label: String::from(NON_NOMINAL_LABEL),
+                        value: i64::from(non_nominal),
+                    });
+                }
+                derived
+            };
+            for item in derived {
+                println!("Derived: {:?}", item);
+                state.lock().unwrap().record(&item);
+                for sink in sinks.iter_mut() {
+                    sink.send_received(&item, received);
+                }
+            }
+        }
+        // Only the text of the frame not ended yet is kept
+        let used = groups.front().map_or(text.len(), |(range, ..)| range.start);
+        text.drain(..used);
+        for (range, ..) in groups.iter_mut() {
+            *range = range.start - used..range.end - used;
+        }
+    }
+    report_contexts(control.contexts.as_mut(), &mut errors, true);
+    Ok(())
+}
+
+/// Whether a group was sent as is by the meter but holds unusual content, as
+/// in the frames of meters in test or maintenance mode, rather than damaged
+/// on the line. Without checksums, both look the same and are taken for
+/// damage.
+fn is_unusual(error: &pitinfo_parser::ParseError, checked: bool) -> bool {
+    match error.kind() {
+        ErrorKind::Protocol => checked,
+        ErrorKind::Corruption => {
+            checked && matches!(error, pitinfo_parser::ParseError::FieldError(..))
+        }
+        ErrorKind::Configuration => false,
+    }
+}
+
+/// Reports the parse errors whose raw context was read, or all of them with
+/// `flush`.
+fn report_contexts(
+    contexts: Option<&mut ErrorContexts>,
+    errors: &mut Option<&mut Sink<ParseError>>,
+    flush: bool,
+) {
+    let Some(contexts) = contexts else {
+        return;
+    };
+    for error in contexts.ready(Instant::now(), flush) {
+        eprintln!(
+            "Raw bytes around '{}': {}",
+            error.line,
+            error.context.as_deref().unwrap_or_default()
+        );
+        if let Some(errors) = errors.as_mut() {
+            errors.send(&error);
+        }
+    }
+}
+
+/// Reloads the configuration, with a setting changed when asked to and
+/// allowed remotely, and restarts the sinks. The previous configuration is
+/// kept when the new one cannot be loaded or its sinks cannot start. The
+/// serial port, Modbus, Loki, API, Tempo and derived metrics settings are
+/// only read when the daemon starts.
+async fn reconfigure(
+    command: Command,
+    config: &mut Config,
+    control: &mut Control,
+    sinks: &mut Vec<Sink>,
+) -> Result<(), io::Error> {
+    let mut settings = control.settings.borrow().clone();
+    match command {
+        // Handled by the MQTT sink
+        Command::Republish => return Ok(()),
+        Command::Sync => {
+            println!("Syncing the stored history on request");
+            let path = control.path.clone();
+            task::spawn_blocking(move || {
+                let result = Config::load_with(path.as_deref(), &settings)
+                    .map_err(Box::<dyn Error>::from)
+                    .and_then(|config| sync::sync(&config));
+                match result {
+                    Ok(synced) => {
+                        sync::report(&synced);
+                    }
+                    Err(e) => eprintln!("Sync failed: {}", e),
+                }
+            });
+            return Ok(());
+        }
+        Command::Reload => println!("Reloading the configuration"),
+        Command::Set { key, value } => {
+            if let Err(e) = config::check_remote(&key) {
+                eprintln!("WARNING: ignoring the set command: {}", e);
+                return Ok(());
+            }
+            println!("Setting {} to {}", key, value);
+            settings.retain(|(name, _)| *name != key);
+            settings.push((key, value));
+        }
+    }
+    let reloaded = match Config::load_with(control.path.as_deref(), &settings) {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            eprintln!("WARNING: keeping the current configuration: {}", e);
+            return Ok(());
+        }
+    };
+    close_sinks(mem::take(sinks), None).await;
+    *sinks = match spawn_sinks(&reloaded, control) {
+        Ok(reloaded_sinks) => {
+            *config = reloaded;
+            control.settings.send_replace(settings);
+            reloaded_sinks
+        }
+        Err(e) => {
+            eprintln!("WARNING: keeping the current configuration: {}", e);
+            spawn_sinks(config, control)?
+        }
+    };
+    Ok(())
+}
+
+/// Logs the start or end of a gap, updates `receiving` and runs the
+/// configured command, with the kind of gap in `PITINFO_GAP`. The start of
+/// a gap is also sent by email when configured.
+fn report_gap(event: Event, config: &Config, receiving: &watch::Sender<bool>) {
+    let timeout = config.serial.gap_timeout;
+    let (gap, alert) = match event {
+        Event::Started(gap @ Gap::Silence) => (
+            gap,
+            format!("no data received for {} s, the line is silent", timeout),
+        ),
+        Event::Started(gap @ Gap::ParseFailures) => (
+            gap,
+            format!("lines received but no valid frame for {} s", timeout),
+        ),
+        Event::Ended { gap, duration } => (
+            gap,
+            format!("frames received again after {} s", duration.as_secs()),
+        ),
+    };
+    let started = matches!(event, Event::Started(_));
+    if started {
+        eprintln!("WARNING: {}", alert);
+    } else {
+        println!("{}", alert);
+    }
+    receiving.send_replace(!started);
+    if let (true, Some(email)) = (started, &config.email) {
+        match email::Mailer::new(email) {
+            Ok(mailer) => {
+                let alert = alert.clone();
+                tokio::spawn(async move { mailer.alert(&alert).await });
+            }
+            Err(e) => eprintln!("Unable to send email: {}", e),
+        }
+    }
+    if let Some(command) = config.serial.gap_command.clone() {
+        let state = if started { gap.name() } else { "ended" };
+        tokio::spawn(async move {
+            hooks::run(
+                &command,
+                &[("PITINFO_GAP", state), ("PITINFO_ALERT", &alert)],
+            )
+            .await;
+        });
+    }
+}
+
+/// Closes the sinks one after the other.
+async fn close_sinks(sinks: Vec<Sink>, errors: Option<Sink<ParseError>>) {
+    for sink in sinks {
+        sink.close().await;
+    }
+    if let Some(errors) = errors {
+        errors.close().await;
+    }
+}
+
+/// Helps finding the right port when the configured one cannot be opened.
+fn report_serial_ports(error: &tokio_serial::Error) {
+    if cfg!(unix) && error.kind() == tokio_serial::ErrorKind::Io(io::ErrorKind::PermissionDenied) {
+        eprintln!("The user running the daemon needs read access to the port, e.g. through the dialout group");
+    }
+    match tokio_serial::available_ports() {
+        Ok(ports) if !ports.is_empty() => {
+            let names: Vec<String> = ports.into_iter().map(|port| port.port_name).collect();
+            eprintln!("Available serial ports: {}", names.join(", "));
         }
+        Ok(_) => eprintln!("No serial port found"),
+        Err(e) => eprintln!("Unable to list the serial ports: {}", e),
+    }
+}
+
+/// Waits for Ctrl-C or, on Unix, for the SIGTERM sent by systemd.
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<(), io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<(), io::Error> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Reloads the configuration on SIGHUP, like the `reload` command.
+#[cfg(unix)]
+async fn reload_on_hangup(commands: mpsc::Sender<Command>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
         Err(e) => {
-            eprintln!("Failed to open \"blabla\". Error: {}", e);
-            ::std::process::exit(1);
+            eprintln!("Unable to handle SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if commands.send(Command::Reload).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_commands: mpsc::Sender<Command>) {}
+
+/// Loads the configuration file given as argument, or by `PITINFO_CONFIG`.
+fn load_config(path: Option<&String>) -> Result<Config, io::Error> {
+    Config::load(config_path(path).as_deref())
+}
+
+fn config_path(path: Option<&String>) -> Option<PathBuf> {
+    path.map(PathBuf::from)
+        .or_else(|| env::var_os(CONFIG_VARIABLE).map(PathBuf::from))
+}
+
+/// Dumps the stored history to daily Parquet files.
+#[cfg(feature = "parquet")]
+fn export(directory: &Path, config: Config) -> Result<(), Box<dyn Error>> {
+    let storage = config
+        .storage
+        .ok_or("the export requires a [storage] section in the configuration")?;
+    let store = storage::Store::load(&storage, config.clock.timezone)?;
+    let files = export::export(&store, directory)?;
+    println!("Exported {} days to {}", files, directory.display());
+    Ok(())
+}
+
+/// Writes the anonymized 15 minute aggregates of the stored history.
+#[cfg(feature = "parquet")]
+fn export_anonymized(path: &Path, config: Config) -> Result<(), Box<dyn Error>> {
+    let storage = config
+        .storage
+        .ok_or("the export requires a [storage] section in the configuration")?;
+    let store = storage::Store::load(&storage, config.clock.timezone)?;
+    let periods = export::export_anonymized(&store, path)?;
+    println!("Exported {} periods to {}", periods, path.display());
+    Ok(())
+}
+
+/// Writes the energy of the stored history per hour and day.
+#[cfg(feature = "parquet")]
+fn export_heatmap(path: &Path, config: Config) -> Result<(), Box<dyn Error>> {
+    let storage = config
+        .storage
+        .ok_or("the export requires a [storage] section in the configuration")?;
+    let store = storage::Store::load(&storage, config.clock.timezone)?;
+    let cells = export::export_heatmap(&store, path, config.clock.timezone)?;
+    println!("Exported {} hours to {}", cells, path.display());
+    Ok(())
+}
+
+/// Aggregates the sites of the fleet until stopped.
+async fn fleet(config: Config) -> Result<(), Box<dyn Error>> {
+    let shutdown = async {
+        if let Err(e) = shutdown_signal().await {
+            eprintln!("Unable to wait for the shutdown signal: {}", e);
+        }
+        eprintln!("Shutting down");
+    };
+    fleet::run(&config, shutdown).await?;
+    Ok(())
+}
+
+/// Sends the stored history to the remote sinks, from their last sync.
+fn sync(config: Config) -> Result<(), Box<dyn Error>> {
+    if sync::report(&sync::sync(&config)?) {
+        Ok(())
+    } else {
+        Err("the sync of some sinks failed".into())
+    }
+}
+
+/// Replays the stored history into a sink.
+fn backfill(sink: &str, config: Config) -> Result<(), Box<dyn Error>> {
+    let storage = config
+        .storage
+        .ok_or("the backfill requires a [storage] section in the configuration")?;
+    let store = storage::Store::load(&storage, config.clock.timezone)?;
+    match sink {
+        "influxdb" => {
+            let influxdb = config
+                .influxdb
+                .ok_or("the backfill requires an [influxdb] section in the configuration")?;
+            backfill::backfill(&store, &influxdb::Client::new(&influxdb)?, None)?;
+            Ok(())
+        }
+        #[cfg(feature = "statistics")]
+        "homeassistant" => {
+            let home_assistant = config
+                .home_assistant
+                .ok_or("the backfill requires a [home_assistant] section in the configuration")?;
+            statistics::backfill(
+                &store,
+                &mut statistics::Client::connect(&home_assistant)?,
+                None,
+            )?;
+            Ok(())
+        }
+        #[cfg(not(feature = "statistics"))]
+        "homeassistant" => {
+            Err(config::without_feature("the Home Assistant backfill", "statistics").into())
         }
+        _ => Err(format!(
+            "unsupported backfill sink '{}', expected influxdb or homeassistant",
+            sink
+        )
+        .into()),
     }
 }
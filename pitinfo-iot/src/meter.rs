@@ -0,0 +1,118 @@
+//! Detection of a change of the meter the stream comes from.
+//!
+//! The address of the meter, sent in ADCO at the start of each frame,
+//! changes when the meter is swapped, or when the daemon reads another meter
+//! than expected, e.g. from the wrong port. A corrupted group can still pass
+//! its checksum, a single character, so a new address is only accepted once
+//! received in a few frames in a row. Frames are held back in the meantime,
+//! so that the values of two meters are never mixed.
+
+use pitinfo_parser::TicMode;
+
+/// Frames a new address must be received in before being accepted.
+const CONFIRMATIONS: u8 = 3;
+
+/// Meter read, as described by the sinks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeterInfo {
+    pub address: String,
+    pub mode: TicMode,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    /// Frame of the current meter
+    Same,
+    /// Frame of a new meter, not confirmed yet
+    Pending,
+    /// Frame of another meter than the configured one
+    Unexpected,
+    /// First frame of a new meter, `None` when it is the first one read
+    Changed { previous: Option<String> },
+}
+
+#[derive(Debug, Default)]
+pub struct MeterWatch {
+    /// Address of the meter expected on the port, when configured
+    expected: Option<String>,
+    current: Option<String>,
+    pending: Option<(String, u8)>,
+}
+
+impl MeterWatch {
+    pub fn new(expected: Option<String>) -> MeterWatch {
+        MeterWatch {
+            expected,
+            ..MeterWatch::default()
+        }
+    }
+
+    /// Checks the address of the meter a frame comes from.
+    pub fn check(&mut self, address: &str) -> Check {
+        if self.current.as_deref() == Some(address) {
+            self.pending = None;
+            return Check::Same;
+        }
+        if self
+            .expected
+            .as_ref()
+            .is_some_and(|expected| expected != address)
+        {
+            return Check::Unexpected;
+        }
+        let count = match &self.pending {
+            Some((pending, count)) if pending == address => count + 1,
+            _ => 1,
+        };
+        // The first address has nothing to be confirmed against
+        if count < CONFIRMATIONS && self.current.is_some() {
+            self.pending = Some((String::from(address), count));
+            return Check::Pending;
+        }
+        self.pending = None;
+        Check::Changed {
+            previous: self.current.replace(String::from(address)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_swap() {
+        let mut watch = MeterWatch::new(None);
+        assert_eq!(
+            watch.check("031762120110"),
+            Check::Changed { previous: None }
+        );
+        assert_eq!(watch.check("031762120110"), Check::Same);
+        // A corrupted address
+        assert_eq!(watch.check("031762120111"), Check::Pending);
+        assert_eq!(watch.check("031762120110"), Check::Same);
+
+        assert_eq!(watch.check("020830022493"), Check::Pending);
+        assert_eq!(watch.check("020830022493"), Check::Pending);
+        assert_eq!(
+            watch.check("020830022493"),
+            Check::Changed {
+                previous: Some(String::from("031762120110"))
+            }
+        );
+    }
+
+    #[test]
+    fn expected_meter() {
+        let mut watch = MeterWatch::new(Some(String::from("031762120110")));
+        assert_eq!(watch.check("020830022493"), Check::Unexpected);
+        assert_eq!(
+            watch.check("031762120110"),
+            Check::Changed { previous: None }
+        );
+        for _ in 0..CONFIRMATIONS {
+            assert_eq!(watch.check("020830022493"), Check::Unexpected);
+        }
+        assert_eq!(watch.check("031762120110"), Check::Same);
+    }
+}
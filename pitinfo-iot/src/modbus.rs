@@ -0,0 +1,397 @@
+//! Modbus slave exposing the latest meter values, over TCP or RTU.
+//!
+//! Holding (0x03) and input (0x04) registers share the same map:
+//!
+//! | Register | Content                                          | Unit |
+//! |----------|--------------------------------------------------|------|
+//! | 0        | PAPP, or SINSTS in standard mode, apparent power | VA   |
+//! | 1 - 3    | IINST1 to IINST3, or IRMS1 to IRMS3, current     | A    |
+//! | 4        | OPTARIF: 1 BASE, 2 HC, 3 EJP, 4 Tempo            |      |
+//! | 5        | PTEC hour: 1 off-peak, 2 peak                    |      |
+//! | 6        | PTEC day color: 0 none, 1 blue, 2 white, 3 red   |      |
+//! | 7        | DEMAIN: 0 not known yet, 1 blue, 2 white, 3 red  |      |
+//! | 8        | HHPHC schedule letter, as ASCII                  |      |
+//! | 9        | Reserved                                         |      |
+//! | 10 - 21  | BBRHCJB, BBRHPJB, BBRHCJW, BBRHPJW, BBRHCJR, BBRHPJR, 32 bits high word first | Wh |
+//! | 22       | ISOUSC, subscribed current                       | A    |
+//! | 23 - 25  | IMAX1 to IMAX3, maximum current                  | A    |
+//! | 26 - 27  | PMAX, maximum three-phase power, 32 bits         | W    |
+//! | 28 - 29  | EAST, active energy supplied, 32 bits            | Wh   |
+//! | 30 - 32  | URMS1 to URMS3, RMS voltage                      | V    |
+//!
+//! Values that have not been received yet read as `0xFFFF` (`0xFFFFFFFF` for
+//! 32 bits registers).
+
+use crate::state::{day_color_code, hour_code, MeterState};
+use pitinfo_parser::{HHPHCValue, TariffOptionValue};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+pub const REGISTER_COUNT: usize = 33;
+const NOT_AVAILABLE: u16 = 0xFFFF;
+const INDEX_REGISTERS: usize = 10;
+const SUBSCRIBED_CURRENT_REGISTER: usize = 22;
+const MAX_CURRENT_REGISTERS: usize = 23;
+const MAX_POWER_REGISTER: usize = 26;
+const SUPPLIED_ENERGY_REGISTER: usize = 28;
+const VOLTAGE_REGISTERS: usize = 30;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+const MAX_READ_QUANTITY: usize = 125;
+const MAX_RTU_FRAME: usize = 256;
+
+pub fn registers(state: &MeterState) -> [u16; REGISTER_COUNT] {
+    let mut registers = [NOT_AVAILABLE; REGISTER_COUNT];
+
+    if let Some(power) = state.apparent_power {
+        registers[0] = power;
+    }
+    for (phase, current) in state.instantaneous_current.iter().enumerate() {
+        if let Some(current) = current {
+            registers[1 + phase] = *current as u16;
+        }
+    }
+    if let Some(option) = state.tariff_option {
+        registers[4] = match option {
+            TariffOptionValue::Base => 1,
+            TariffOptionValue::OffPeakHours => 2,
+            TariffOptionValue::EJP => 3,
+            TariffOptionValue::Tempo => 4,
+        };
+    }
+    if let Some(period) = state.current_period {
+        registers[5] = hour_code(period.hour) as u16;
+        registers[6] = day_color_code(period.day_color) as u16;
+    }
+    if let Some(tomorrow) = state.tomorrow {
+        registers[7] = day_color_code(tomorrow) as u16;
+    }
+    if let Some(hhphc) = state.hhphc {
+        registers[8] = match hhphc {
+            HHPHCValue::A => b'A',
+            HHPHCValue::C => b'C',
+            HHPHCValue::D => b'D',
+            HHPHCValue::E => b'E',
+            HHPHCValue::Y => b'Y',
+        } as u16;
+    }
+    for (slot, index) in state.indexes.iter().enumerate() {
+        if let Some(index) = index {
+            set_u32(&mut registers, INDEX_REGISTERS + 2 * slot, *index);
+        }
+    }
+    if let Some(current) = state.subscribed_current {
+        registers[SUBSCRIBED_CURRENT_REGISTER] = current as u16;
+    }
+    for (phase, current) in state.max_current.iter().enumerate() {
+        if let Some(current) = current {
+            registers[MAX_CURRENT_REGISTERS + phase] = *current;
+        }
+    }
+    if let Some(power) = state.max_power {
+        set_u32(&mut registers, MAX_POWER_REGISTER, power);
+    }
+    if let Some(energy) = state.supplied_energy {
+        set_u32(&mut registers, SUPPLIED_ENERGY_REGISTER, energy);
+    }
+    for (phase, voltage) in state.voltage.iter().enumerate() {
+        if let Some(voltage) = voltage {
+            registers[VOLTAGE_REGISTERS + phase] = *voltage;
+        }
+    }
+
+    registers
+}
+
+/// Sets a 32 bits value on two registers, high word first.
+fn set_u32(registers: &mut [u16], register: usize, value: u32) {
+    registers[register] = (value >> 16) as u16;
+    registers[register + 1] = (value & 0xFFFF) as u16;
+}
+
+/// Answers a Modbus request PDU (function code and data), independently of
+/// the transport. Returns `None` when the request is too short to be answered.
+pub fn process_pdu(state: &MeterState, pdu: &[u8]) -> Option<Vec<u8>> {
+    let function = *pdu.first()?;
+
+    match function {
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            if pdu.len() != 5 {
+                return Some(exception(function, ILLEGAL_DATA_VALUE));
+            }
+            let start = u16::from_be_bytes([pdu[1], pdu[2]]) as usize;
+            let quantity = u16::from_be_bytes([pdu[3], pdu[4]]) as usize;
+            if quantity == 0 || quantity > MAX_READ_QUANTITY {
+                return Some(exception(function, ILLEGAL_DATA_VALUE));
+            }
+            if start + quantity > REGISTER_COUNT {
+                return Some(exception(function, ILLEGAL_DATA_ADDRESS));
+            }
+
+            let registers = registers(state);
+            let mut response = Vec::with_capacity(2 + 2 * quantity);
+            response.push(function);
+            response.push((2 * quantity) as u8);
+            for register in &registers[start..start + quantity] {
+                response.extend_from_slice(&register.to_be_bytes());
+            }
+            Some(response)
+        }
+        _ => Some(exception(function, ILLEGAL_FUNCTION)),
+    }
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+pub async fn serve_tcp(listener: TcpListener, state: Arc<Mutex<MeterState>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_tcp_client(stream, &state).await {
+                        eprintln!("Modbus TCP client error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Modbus TCP connection failed: {}", e),
+        }
+    }
+}
+
+async fn handle_tcp_client(
+    mut stream: TcpStream,
+    state: &Mutex<MeterState>,
+) -> Result<(), io::Error> {
+    // MBAP header: transaction id, protocol id, length, unit id
+    let mut header = [0u8; 7];
+    loop {
+        match stream.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if !(2..=254).contains(&length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid MBAP length {}", length),
+            ));
+        }
+        let mut pdu = vec![0u8; length - 1];
+        stream.read_exact(&mut pdu).await?;
+        if header[2..4] != [0, 0] {
+            // Not a Modbus request
+            continue;
+        }
+
+        let response = process_pdu(&state.lock().unwrap(), &pdu);
+        if let Some(response) = response {
+            let mut frame = Vec::with_capacity(7 + response.len());
+            frame.extend_from_slice(&header[0..4]);
+            frame.extend_from_slice(&((response.len() + 1) as u16).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame).await?;
+        }
+    }
+}
+
+/// Silence marking the end of an RTU frame: 3.5 characters of 11 bits, with
+/// the fixed 1.75ms value recommended above 19200 bauds.
+pub fn rtu_frame_delay(baud_rate: u32) -> Duration {
+    if baud_rate > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        Duration::from_micros(38_500_000 / baud_rate as u64)
+    }
+}
+
+/// Runs the RTU slave on a serial port. A silence of `frame_delay`, see
+/// `rtu_frame_delay`, marks the end of a frame.
+pub async fn serve_rtu<P: AsyncRead + AsyncWrite + Unpin>(
+    mut port: P,
+    frame_delay: Duration,
+    unit_id: u8,
+    state: Arc<Mutex<MeterState>>,
+) {
+    let mut frame = Vec::with_capacity(MAX_RTU_FRAME);
+    let mut buffer = [0u8; MAX_RTU_FRAME];
+    loop {
+        let read = if frame.is_empty() {
+            Ok(port.read(&mut buffer).await)
+        } else {
+            time::timeout(frame_delay, port.read(&mut buffer)).await
+        };
+        match read {
+            Ok(Ok(0)) => return,
+            Ok(Ok(n)) => {
+                frame.extend_from_slice(&buffer[..n]);
+                if frame.len() > MAX_RTU_FRAME {
+                    frame.clear();
+                }
+            }
+            Err(_) => {
+                let response = process_rtu_frame(&state.lock().unwrap(), unit_id, &frame);
+                frame.clear();
+                if let Some(response) = response {
+                    if let Err(e) = port.write_all(&response).await {
+                        eprintln!("Modbus RTU write error: {}", e);
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                eprintln!("Modbus RTU read error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Answers an RTU frame (address, PDU, CRC) addressed to `unit_id`. Frames
+/// with a bad CRC or for another slave are silently dropped.
+pub fn process_rtu_frame(state: &MeterState, unit_id: u8, frame: &[u8]) -> Option<Vec<u8>> {
+    if frame.len() < 4 {
+        return None;
+    }
+    let (body, checksum) = frame.split_at(frame.len() - 2);
+    if crc16(body).to_le_bytes() != checksum || body[0] != unit_id {
+        return None;
+    }
+
+    let pdu = process_pdu(state, &body[1..])?;
+    let mut response = Vec::with_capacity(pdu.len() + 3);
+    response.push(unit_id);
+    response.extend_from_slice(&pdu);
+    let checksum = crc16(&response);
+    response.extend_from_slice(&checksum.to_le_bytes());
+    Some(response)
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, Message, TarifPeriod};
+
+    fn sample_state() -> MeterState {
+        let mut state = MeterState::default();
+        state.update(&Message::ApparentPower { value: 5998 });
+        state.update(&Message::InstantaneousPower { phase: 2, value: 7 });
+        state.update(&Message::TariffOption(TariffOptionValue::Tempo));
+        state.update(&Message::CurrentTariffPeriod(TarifPeriod {
+            hour: HourlyTarifPeriod::PeakHours,
+            day_color: Some(DayColor::Red),
+        }));
+        state.update(&Message::Tomorrow(None));
+        state.update(&Message::SubscribedCurrent { value: 45 });
+        state.update(&Message::MaxCurrent {
+            phase: 1,
+            value: 30,
+        });
+        state.update(&Message::MaxPower { value: 8350 });
+        state.update(&Message::SuppliedEnergy { value: 12345678 });
+        state.update(&Message::Voltage {
+            phase: 3,
+            value: 231,
+        });
+        state.update(&Message::Index {
+            period: TarifPeriod {
+                hour: HourlyTarifPeriod::PeakHours,
+                day_color: Some(DayColor::Red),
+            },
+            value: 7659709,
+        });
+        state
+    }
+
+    #[test]
+    fn registers_map() {
+        let registers = registers(&sample_state());
+        assert_eq!(registers[0], 5998);
+        assert_eq!(registers[1..4], [NOT_AVAILABLE, 7, NOT_AVAILABLE]);
+        assert_eq!(registers[4..9], [4, 2, 3, 0, NOT_AVAILABLE]);
+        assert_eq!(registers[20..22], [0x0074, 0xE0BD]);
+        assert_eq!(registers[10..12], [NOT_AVAILABLE, NOT_AVAILABLE]);
+        assert_eq!(registers[22..26], [45, 30, NOT_AVAILABLE, NOT_AVAILABLE]);
+        assert_eq!(registers[26..30], [0x0000, 0x209E, 0x00BC, 0x614E]);
+        assert_eq!(registers[30..33], [NOT_AVAILABLE, NOT_AVAILABLE, 231]);
+    }
+
+    #[test]
+    fn read_registers() {
+        let state = sample_state();
+        assert_eq!(
+            process_pdu(&state, &[0x03, 0x00, 0x00, 0x00, 0x02]),
+            Some(vec![0x03, 0x04, 0x17, 0x6E, 0xFF, 0xFF])
+        );
+        assert_eq!(
+            process_pdu(&state, &[0x04, 0x00, 0x04, 0x00, 0x01]),
+            Some(vec![0x04, 0x02, 0x00, 0x04])
+        );
+    }
+
+    #[test]
+    fn read_registers_errors() {
+        let state = sample_state();
+        assert_eq!(
+            process_pdu(&state, &[0x03, 0x00, 0x20, 0x00, 0x02]),
+            Some(vec![0x83, ILLEGAL_DATA_ADDRESS])
+        );
+        assert_eq!(
+            process_pdu(&state, &[0x03, 0x00, 0x00, 0x00, 0x00]),
+            Some(vec![0x83, ILLEGAL_DATA_VALUE])
+        );
+        assert_eq!(
+            process_pdu(&state, &[0x06, 0x00, 0x00, 0x00, 0x01]),
+            Some(vec![0x86, ILLEGAL_FUNCTION])
+        );
+        assert_eq!(process_pdu(&state, &[]), None);
+    }
+
+    #[test]
+    fn rtu_frames() {
+        let state = sample_state();
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x02]), 0x0BC4);
+        assert_eq!(
+            process_rtu_frame(&state, 1, &[0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC4, 0x0B]),
+            Some(vec![0x01, 0x03, 0x04, 0x17, 0x6E, 0xFF, 0xFF, 0x9F, 0xEA])
+        );
+        // Bad CRC
+        assert_eq!(
+            process_rtu_frame(&state, 1, &[0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC4, 0x0C]),
+            None
+        );
+        // Other slave
+        assert_eq!(
+            process_rtu_frame(&state, 2, &[0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC4, 0x0B]),
+            None
+        );
+    }
+}
@@ -0,0 +1,891 @@
+//! Publishes meter values to an MQTT broker.
+//!
+//! Two profiles are available:
+//!
+//! - `default`: every group is published on `<base_topic>/<LABEL>` with its
+//!   value as payload, e.g. `pitinfo/PAPP` -> `803`.
+//! - `zigbee2mqtt`: mimics zigbee2mqtt devices. The whole state is published as
+//!   JSON attributes on `<base_topic>/<device_name>` once per frame, and the
+//!   availability on `<base_topic>/<device_name>/availability`.
+//!
+//! The `format` (one topic per label, a JSON state topic or a SenML state
+//! topic) and the topic template can be overridden independently of the
+//! profile.
+//!
+//! Home Assistant discovery messages are published, when enabled, once the
+//! tariff option is received, then on each connection and when Home Assistant
+//! restarts.
+//!
+//! The smoothed apparent power and its rate of change can be published along
+//! with the groups, as the `PAPP_AVG` and `PAPP_RATE` labels, the estimated
+//! active power, as `PACT_EST`, and so can the
+//! values computed by other sinks: the forecast of the billing period, as
+//! `FORECAST_KWH` and `FORECAST_COST`, the off-peak hours, as
+//! `OFFPEAK_ACTIVE` and `OFFPEAK_SOON`, and the balance with a production
+//! meter, as `PRODUCTION_PAPP`, `NET_PAPP` and the labels that follow.
+//!
+//! With a command topic, the commands published on it are run, see the
+//! `command` module. Republishing is handled here, the other commands are
+//! forwarded to the daemon.
+//!
+//! Device information, the address and tariff option of the meter, the mode
+//! of the teleinformation and the version of the daemon, is published as
+//! JSON, retained, on `<base_topic>/info`, `<base_topic>/<device_name>/info`
+//! with the `zigbee2mqtt` profile, on each connection and when it changes.
+//!
+//! The availability topic is set to online on each connection and to offline
+//! when the daemon stops, or by the broker, through the last will, when the
+//! daemon or the Pi dies. It is also offline during the gaps of the
+//! teleinformation stream, so that stale values are not taken as readings.
+
+use crate::command::Command;
+use crate::config::{Language, MqttConfig, MqttFormat, MqttProfile, MqttTlsConfig};
+use crate::estimate::{self, ActivePower};
+use crate::factor::{self, PowerFactor};
+use crate::forecast::{self, LatestForecast};
+use crate::homeassistant::{Discovery, IndexGuard, TARIFF_OPTIONS};
+use crate::meter::MeterInfo;
+use crate::offpeak::LatestOffPeak;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::production::{self, LatestProduction};
+use crate::scale::Scales;
+use crate::state::{index_label, is_index, label_value, MeterState, Update, Value};
+use crate::trend::{self, PowerTrend};
+use chrono::Utc;
+use chrono_tz::Tz;
+use pitinfo_parser::{Message, TariffOptionValue};
+use rumqttc::{
+    AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport,
+};
+use serde_json::json;
+use std::fs;
+use std::io;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+pub const REQUEST_CAPACITY: usize = 100;
+/// Longest wait for the offline availability to reach the broker on shutdown.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Retained messages, by topic.
+type Retained = Vec<(String, String)>;
+
+/// Values computed by other sinks, published along with the groups.
+#[derive(Clone, Default)]
+pub struct Computed {
+    pub forecast: Option<LatestForecast>,
+    pub offpeak: Option<LatestOffPeak>,
+    pub production: Option<LatestProduction>,
+}
+
+impl Computed {
+    fn values(&self) -> Vec<(&'static str, f64)> {
+        let forecast = self.forecast.as_ref().map(LatestForecast::values);
+        let offpeak = self.offpeak.as_ref().map(LatestOffPeak::values);
+        let production = self.production.as_ref().map(LatestProduction::values);
+        forecast
+            .into_iter()
+            .chain(offpeak)
+            .chain(production)
+            .flatten()
+            .collect()
+    }
+}
+
+/// Starts the MQTT client. Messages sent to the returned sink are published
+/// according to the configured profile, along with the computed values, and
+/// the availability follows `receiving`, whether frames are received, and
+/// the device information `meter`, the address of the meter read. The remote
+/// commands not handled here are sent to `commands`.
+pub fn spawn(
+    config: &MqttConfig,
+    receiving: watch::Receiver<bool>,
+    meter: watch::Receiver<Option<MeterInfo>>,
+    computed: Computed,
+    commands: Sender<Command>,
+    timezone: Tz,
+    language: Language,
+) -> Result<Sink, io::Error> {
+    let active_power = config
+        .active_power
+        .as_ref()
+        .map(|active_power| ActivePower::new(active_power, timezone))
+        .transpose()?;
+    let format = config.format.unwrap_or(match config.profile {
+        MqttProfile::Default => MqttFormat::Labels,
+        MqttProfile::Zigbee2mqtt => MqttFormat::Json,
+    });
+    let template = match (&config.topic, format) {
+        (Some(template), _) => template.as_str(),
+        (None, MqttFormat::Labels) => "{base_topic}/{label}",
+        (None, MqttFormat::Json | MqttFormat::Senml) => "{base_topic}/{device_name}",
+    };
+    let allowed: &[&str] = match format {
+        MqttFormat::Labels => &["base_topic", "device_name", "label"],
+        MqttFormat::Json | MqttFormat::Senml => &["base_topic", "device_name"],
+    };
+    check_template(template, allowed)?;
+    let scales = Scales::new(&config.scale)?;
+    if config.delivery.retries > 0 {
+        eprintln!("WARNING: mqtt.delivery.retries is ignored, the client reconnects by itself");
+    }
+    let qos = rumqttc::qos(config.qos).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid MQTT QoS {}, expected 0, 1 or 2", config.qos),
+        )
+    })?;
+    let topic = render_topic(
+        template,
+        &[
+            ("base_topic", &config.base_topic),
+            ("device_name", &config.device_name),
+        ],
+    );
+
+    let availability = Availability::new(config);
+    let mut options = options(config, &config.client_id)?;
+    options.set_last_will(LastWill::new(
+        &availability.topic,
+        availability.offline.clone(),
+        QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+
+    // Discovery messages of each tariff option, announced once the meter sent
+    // its own, then published on each connection, after the availability
+    let mut discoveries = Vec::new();
+    let announcements: Arc<Mutex<Retained>> = Arc::default();
+    let mut status_topic = None;
+    if config.home_assistant {
+        let discovery = Discovery {
+            prefix: &config.discovery_prefix,
+            device_name: &config.device_name,
+            format,
+            topic: &topic,
+            availability_topic: &availability.topic,
+            online: &availability.online,
+            offline: &availability.offline,
+            trend: config.trend.is_some(),
+            active_power: active_power.is_some(),
+            power_factor: config.power_factor.is_some(),
+            forecast_currency: computed
+                .forecast
+                .as_ref()
+                .map(|forecast| forecast.currency.as_str()),
+            offpeak: computed.offpeak.is_some(),
+            production: computed.production.is_some(),
+            scales: &scales,
+            language,
+        };
+        discoveries = TARIFF_OPTIONS
+            .iter()
+            .map(|option| (*option, discovery.messages(*option)))
+            .collect();
+        status_topic = Some(format!("{}/status", config.discovery_prefix));
+    }
+
+    let info_topic = config
+        .info_topic
+        .clone()
+        .unwrap_or_else(|| match config.profile {
+            MqttProfile::Default => format!("{}/info", config.base_topic),
+            MqttProfile::Zigbee2mqtt => format!("{}/info", device_topic(config)),
+        });
+    let info: Arc<Mutex<Option<(String, String)>>> = Arc::default();
+
+    let command_topic = config.command_topic.clone();
+    let republish = Arc::new(Notify::new());
+    let connection_client = client.clone();
+    let connection_availability = availability.clone();
+    let connection_receiving = receiving.clone();
+    let connection_republish = Arc::clone(&republish);
+    let connection_announcements = Arc::clone(&announcements);
+    let connection_info = Arc::clone(&info);
+    let connection = tokio::spawn(async move {
+        loop {
+            let announce = match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    for topic in status_topic.iter().chain(&command_topic) {
+                        if let Err(e) = connection_client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            eprintln!("Unable to subscribe to {}: {}", topic, e);
+                        }
+                    }
+                    true
+                }
+                Ok(Event::Incoming(Packet::Publish(publish)))
+                    if Some(&publish.topic) == command_topic.as_ref() =>
+                {
+                    // A retained command would run again on every connection
+                    let command = if publish.retain {
+                        Err(String::from("retained command"))
+                    } else {
+                        Command::parse(&publish.payload)
+                    };
+                    match command {
+                        Ok(Command::Republish) => {
+                            println!("Republishing on request");
+                            connection_republish.notify_one();
+                            true
+                        }
+                        Ok(command) => {
+                            if let Err(e) = commands.try_send(command) {
+                                eprintln!("Unable to run the command: {}", e);
+                            }
+                            false
+                        }
+                        Err(e) => {
+                            eprintln!("WARNING: ignoring {} on {}", e, publish.topic);
+                            false
+                        }
+                    }
+                }
+                // Home Assistant restarted and needs the discovery messages again
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    Some(&publish.topic) == status_topic.as_ref()
+                        && publish.payload.as_ref() == b"online"
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+                Ok(_) => false,
+                Err(e) => {
+                    eprintln!("MQTT connection error: {}", e);
+                    time::sleep(RECONNECT_DELAY).await;
+                    false
+                }
+            };
+            if announce {
+                let availability = connection_availability.payload(*connection_receiving.borrow());
+                let availability = (connection_availability.topic.clone(), availability);
+                let info = connection_info.lock().unwrap().clone();
+                let announcements = connection_announcements.lock().unwrap().clone();
+                for (topic, payload) in iter::once(&availability).chain(&info).chain(&announcements)
+                {
+                    let result = connection_client.try_publish(
+                        topic,
+                        QoS::AtLeastOnce,
+                        true,
+                        payload.clone(),
+                    );
+                    if let Err(e) = result {
+                        eprintln!("Unable to publish to MQTT: {}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    let publisher = Publisher {
+        client,
+        connection,
+        availability,
+        receiving,
+        meter,
+        info_topic,
+        info,
+        format,
+        topic,
+        base_name: format!("{}:", config.device_name),
+        state: MeterState::default(),
+        index_guard: IndexGuard::default(),
+        trend: config
+            .trend
+            .as_ref()
+            .map(|trend| PowerTrend::new(Duration::from_secs(trend.window))),
+        active_power,
+        power_factor: config
+            .power_factor
+            .as_ref()
+            .map(|factor| PowerFactor::new(Duration::from_secs(factor.interval))),
+        computed,
+        published_computed: Vec::new(),
+        scales,
+        qos,
+        republish,
+        discoveries,
+        announcements,
+    };
+    Ok(pipeline::spawn_sink_with(
+        "mqtt",
+        &config.delivery,
+        |receiver| publisher.run(receiver),
+    ))
+}
+
+struct Publisher {
+    client: AsyncClient,
+    connection: JoinHandle<()>,
+    availability: Availability,
+    receiving: watch::Receiver<bool>,
+    /// Address of the meter read
+    meter: watch::Receiver<Option<MeterInfo>>,
+    info_topic: String,
+    /// Device information last published, for the next connections
+    info: Arc<Mutex<Option<(String, String)>>>,
+    format: MqttFormat,
+    /// Topic, or topic template with the `{label}` placeholder left
+    topic: String,
+    /// SenML base name, prefixed to the labels
+    base_name: String,
+    state: MeterState,
+    index_guard: IndexGuard,
+    trend: Option<PowerTrend>,
+    active_power: Option<ActivePower>,
+    power_factor: Option<PowerFactor>,
+    computed: Computed,
+    /// Computed values last published, with the `labels` format
+    published_computed: Vec<(&'static str, f64)>,
+    scales: Scales,
+    /// QoS of the values, retained messages are always sent at least once
+    qos: QoS,
+    /// Notified when the whole state must be published again
+    republish: Arc<Notify>,
+    /// Discovery messages of each tariff option
+    discoveries: Vec<(TariffOptionValue, Retained)>,
+    /// Discovery messages of the tariff option of the meter
+    announcements: Arc<Mutex<Retained>>,
+}
+
+impl Publisher {
+    async fn run(mut self, mut receiver: Inbox<Update>) {
+        if self.meter.borrow_and_update().is_some() {
+            self.publish_info();
+        }
+        loop {
+            tokio::select! {
+                item = receiver.recv() => match item {
+                    Some(item) => self.handle(item),
+                    None => break,
+                },
+                Ok(()) = self.receiving.changed() => {
+                    let payload = self.availability.payload(*self.receiving.borrow_and_update());
+                    self.publish(self.availability.topic.clone(), payload, true);
+                }
+                Ok(()) = self.meter.changed() => {
+                    self.meter.borrow_and_update();
+                    self.publish_info();
+                }
+                _ = self.republish.notified() => self.publish_state(),
+            }
+        }
+        self.disconnect().await;
+    }
+
+    fn handle(&mut self, item: Update) {
+        let power_updated = match &item {
+            Update::Meter(message) => match self.follow(message) {
+                Some(power_updated) => power_updated,
+                None => return,
+            },
+            Update::Derived { .. } => false,
+        };
+        match self.format {
+            MqttFormat::Labels => {
+                if let Some((label, value)) = item.label_value() {
+                    let topic = render_topic(&self.topic, &[("label", &label)]);
+                    self.publish(topic, self.scales.text(&label, &value), false);
+                }
+                if power_updated {
+                    for (label, value) in self.power_values() {
+                        let topic = render_topic(&self.topic, &[("label", label)]);
+                        self.publish(topic, self.scales.format(label, value), false);
+                    }
+                }
+                let computed = self.computed.values();
+                if computed != self.published_computed {
+                    self.publish_computed(&computed);
+                    self.published_computed = computed;
+                }
+            }
+            // Frames start with ADCO: the state of the previous frame is complete
+            MqttFormat::Json | MqttFormat::Senml
+                if matches!(item, Update::Meter(Message::MeterAddress(_))) =>
+            {
+                self.publish_state();
+            }
+            MqttFormat::Json | MqttFormat::Senml => (),
+        }
+        self.state.record(&item);
+    }
+
+    /// Follows the device information and the values the power values are
+    /// computed from. Returns whether the power values changed, `None` for
+    /// a message to drop.
+    fn follow(&mut self, message: &Message) -> Option<bool> {
+        if let Message::Index { period, value } = message {
+            if !self.index_guard.accept(&index_label(period), *value) {
+                eprintln!("Ignoring decreasing index {:?}", message);
+                return None;
+            }
+        }
+        if changes_info(&self.state, message) {
+            if let Message::TariffOption(option) = message {
+                self.announce(*option);
+            }
+            self.state.update(message);
+            self.publish_info();
+        }
+        let power_updated = match message {
+            Message::ApparentPower { value } => {
+                if let Some(trend) = &mut self.trend {
+                    trend.update(*value, Instant::now());
+                }
+                if let Some(active_power) = &mut self.active_power {
+                    active_power.update(*value, Utc::now());
+                }
+                if let Some(power_factor) = &mut self.power_factor {
+                    power_factor.power(*value, Instant::now());
+                }
+                true
+            }
+            // Published with the next apparent power
+            Message::SuppliedEnergy { value } => {
+                if let Some(power_factor) = &mut self.power_factor {
+                    power_factor.index(*value, Instant::now());
+                }
+                false
+            }
+            _ => false,
+        };
+        Some(power_updated)
+    }
+
+    /// Publishes the discovery messages of a tariff option, and keeps them for
+    /// the next connections.
+    fn announce(&self, option: TariffOptionValue) {
+        let Some((_, messages)) = self.discoveries.iter().find(|(o, _)| *o == option) else {
+            return;
+        };
+        *self.announcements.lock().unwrap() = messages.clone();
+        for (topic, payload) in messages {
+            self.publish(topic.clone(), payload.clone(), true);
+        }
+    }
+
+    /// Publishes the device information, and keeps it for the next
+    /// connections.
+    fn publish_info(&self) {
+        let payload = device_info(self.meter.borrow().as_ref(), &self.state);
+        *self.info.lock().unwrap() = Some((self.info_topic.clone(), payload.clone()));
+        self.publish(self.info_topic.clone(), payload, true);
+    }
+
+    /// Publishes the whole state: every label, with the `labels` format, or
+    /// the state topic.
+    fn publish_state(&self) {
+        let values = self.state.values();
+        match self.format {
+            MqttFormat::Labels => {
+                for (label, value) in &values {
+                    let topic = render_topic(&self.topic, &[("label", label)]);
+                    self.publish(topic, self.scales.text(label, value), false);
+                }
+                for (label, value) in self.power_values() {
+                    let topic = render_topic(&self.topic, &[("label", label)]);
+                    self.publish(topic, self.scales.format(label, value), false);
+                }
+                self.publish_computed(&self.computed.values());
+            }
+            MqttFormat::Json | MqttFormat::Senml => {
+                if !values.is_empty() {
+                    let mut computed = self.power_values();
+                    computed.extend(self.computed.values());
+                    let payload = match self.format {
+                        MqttFormat::Senml => {
+                            senml_pack(&self.base_name, now(), &values, &computed, &self.scales)
+                        }
+                        _ => json_state(&values, &computed, &self.scales),
+                    };
+                    self.publish(self.topic.clone(), payload, false);
+                }
+            }
+        }
+    }
+
+    /// Publishes computed values on their own topics, retained.
+    fn publish_computed(&self, computed: &[(&'static str, f64)]) {
+        for (label, value) in computed {
+            let topic = render_topic(&self.topic, &[("label", label)]);
+            self.publish(topic, self.scales.format(label, *value), true);
+        }
+    }
+
+    /// Sets the availability to offline and closes the connection.
+    async fn disconnect(self) {
+        self.publish(
+            self.availability.topic.clone(),
+            self.availability.offline.clone(),
+            true,
+        );
+        if let Err(e) = self.client.try_disconnect() {
+            eprintln!("Unable to disconnect from MQTT: {}", e);
+        }
+        if time::timeout(DISCONNECT_TIMEOUT, self.connection)
+            .await
+            .is_err()
+        {
+            eprintln!("MQTT broker did not acknowledge the disconnection");
+        }
+    }
+
+    /// Values derived from the apparent power: its trend, the estimated
+    /// active power and the measured power factor.
+    fn power_values(&self) -> Vec<(&'static str, f64)> {
+        let trend = self.trend.as_ref().map(PowerTrend::values);
+        let active_power = self.active_power.as_ref().map(ActivePower::values);
+        let power_factor = self.power_factor.as_ref().map(PowerFactor::values);
+        trend
+            .into_iter()
+            .chain(active_power)
+            .chain(power_factor)
+            .flatten()
+            .collect()
+    }
+
+    fn publish(&self, topic: String, payload: String, retain: bool) {
+        let qos = if retain { QoS::AtLeastOnce } else { self.qos };
+        if let Err(e) = self.client.try_publish(topic, qos, retain, payload) {
+            eprintln!("Unable to publish to MQTT: {}", e);
+        }
+    }
+}
+
+/// Options connecting to the configured broker as `client_id`.
+pub fn options(config: &MqttConfig, client_id: &str) -> Result<MqttOptions, io::Error> {
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+    }
+    if let Some(tls) = &config.tls {
+        options.set_transport(transport(tls)?);
+    }
+    Ok(options)
+}
+
+pub fn transport(tls: &MqttTlsConfig) -> Result<Transport, io::Error> {
+    let client_auth = match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(cert), Some(key)) => Some((fs::read(cert)?, fs::read(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "MQTT client certificate and key must be configured together",
+            ))
+        }
+    };
+    match (&tls.ca_file, client_auth) {
+        (Some(ca_file), client_auth) => Ok(Transport::tls_with_config(TlsConfiguration::Simple {
+            ca: fs::read(ca_file)?,
+            alpn: None,
+            client_auth,
+        })),
+        (None, None) => Ok(Transport::tls_with_default_config()),
+        (None, Some(_)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "MQTT client certificates require a CA file",
+        )),
+    }
+}
+
+/// Replaces the `{name}` placeholders of a topic template.
+pub fn render_topic(template: &str, variables: &[(&str, &str)]) -> String {
+    variables
+        .iter()
+        .fold(template.to_string(), |topic, (name, value)| {
+            topic.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+fn check_template(template: &str, allowed: &[&str]) -> Result<(), io::Error> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 1..end];
+        if !allowed.contains(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown placeholder '{{{}}}' in MQTT topic '{}', expected one of {:?}",
+                    name, template, allowed
+                ),
+            ));
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+/// JSON device information, `null` for what was not received yet.
+pub fn device_info(meter: Option<&MeterInfo>, state: &MeterState) -> String {
+    let tariff_option = state
+        .tariff_option
+        .and_then(|option| label_value(&Message::TariffOption(option)))
+        .map(|(_, value)| value.to_string());
+    json!({
+        "meter": meter.map(|meter| &meter.address),
+        "delivery_point": state.delivery_point,
+        "tariff_option": tariff_option,
+        "subscribed_current": state.subscribed_current,
+        "mode": meter.map(|meter| meter.mode.to_string()),
+        "version": env!("CARGO_PKG_VERSION"),
+    })
+    .to_string()
+}
+
+/// Whether a message changes the device information.
+pub fn changes_info(state: &MeterState, message: &Message) -> bool {
+    match message {
+        Message::TariffOption(option) => state.tariff_option != Some(*option),
+        Message::SubscribedCurrent { value } => state.subscribed_current != Some(*value),
+        Message::DeliveryPoint(point) => state.delivery_point.as_ref() != Some(point),
+        _ => false,
+    }
+}
+
+fn device_topic(config: &MqttConfig) -> String {
+    format!("{}/{}", config.base_topic, config.device_name)
+}
+
+#[derive(Clone)]
+struct Availability {
+    topic: String,
+    online: String,
+    offline: String,
+}
+
+impl Availability {
+    fn new(config: &MqttConfig) -> Availability {
+        match config.profile {
+            MqttProfile::Default => Availability {
+                topic: config
+                    .availability_topic
+                    .clone()
+                    .unwrap_or_else(|| format!("{}/availability", config.base_topic)),
+                online: String::from("online"),
+                offline: String::from("offline"),
+            },
+            MqttProfile::Zigbee2mqtt => Availability {
+                topic: config
+                    .availability_topic
+                    .clone()
+                    .unwrap_or_else(|| format!("{}/availability", device_topic(config))),
+                online: json!({ "state": "online" }).to_string(),
+                offline: json!({ "state": "offline" }).to_string(),
+            },
+        }
+    }
+
+    fn payload(&self, online: bool) -> String {
+        if online {
+            self.online.clone()
+        } else {
+            self.offline.clone()
+        }
+    }
+}
+
+/// JSON object with one attribute per label, numbers for numeric values.
+/// Computed values, e.g. the trend, follow the groups.
+pub fn json_state(values: &[(String, Value)], computed: &[(&str, f64)], scales: &Scales) -> String {
+    let mut attributes: serde_json::Map<String, serde_json::Value> = values
+        .iter()
+        .map(|(label, value)| (label.clone(), scales.json(label, value)))
+        .collect();
+    for (label, value) in computed {
+        attributes.insert(label.to_string(), scales.json_number(label, *value));
+    }
+    serde_json::Value::Object(attributes).to_string()
+}
+
+/// SenML pack of the state: the base name and time on the first record,
+/// numbers as `v` with their unit and texts as `vs`.
+pub fn senml_pack(
+    base_name: &str,
+    time: f64,
+    values: &[(String, Value)],
+    computed: &[(&str, f64)],
+    scales: &Scales,
+) -> String {
+    let mut records: Vec<serde_json::Value> = values
+        .iter()
+        .map(|(label, value)| match value {
+            Value::Integer(_) => senml_record(label, scales.json(label, value), scales),
+            Value::Text(value) => json!({ "n": label, "vs": value }),
+        })
+        .collect();
+    for (label, value) in computed {
+        records.push(senml_record(
+            label,
+            scales.json_number(label, *value),
+            scales,
+        ));
+    }
+    if let Some(serde_json::Value::Object(first)) = records.first_mut() {
+        first.insert(String::from("bn"), json!(base_name));
+        first.insert(String::from("bt"), json!(time));
+    }
+    serde_json::Value::Array(records).to_string()
+}
+
+fn senml_record(label: &str, value: serde_json::Value, scales: &Scales) -> serde_json::Value {
+    let mut record = json!({ "n": label, "v": value });
+    if let Some(unit) = scales.unit(label).or_else(|| senml_unit(label)) {
+        record["u"] = json!(unit);
+    }
+    record
+}
+
+/// Unit of a label, among those registered for SenML.
+fn senml_unit(label: &str) -> Option<&'static str> {
+    if label == "PAPP"
+        || label == trend::AVERAGE_LABEL
+        || label == production::POWER_LABEL
+        || label == production::NET_POWER_LABEL
+    {
+        Some("VA")
+    } else if label == estimate::LABEL {
+        Some("W")
+    } else if label.starts_with("IINST") {
+        Some("A")
+    } else if is_index(label) {
+        Some("Wh")
+    } else if label == forecast::ENERGY_LABEL {
+        Some("kWh")
+    } else if label == production::ENERGY_LABEL
+        || label == production::IMPORT_LABEL
+        || label == production::EXPORT_LABEL
+    {
+        Some("Wh")
+    } else if label == production::SELF_CONSUMPTION_LABEL {
+        Some("%")
+    } else if label == factor::LABEL {
+        Some("/")
+    } else {
+        None
+    }
+}
+
+/// Seconds since the Unix epoch, as SenML times.
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, TarifPeriod, TicMode};
+
+    #[test]
+    fn topic_templates() {
+        assert_eq!(
+            render_topic(
+                "home/{device_name}/teleinfo/{label}",
+                &[("device_name", "linky"), ("label", "PAPP")]
+            ),
+            "home/linky/teleinfo/PAPP"
+        );
+        assert_eq!(
+            render_topic("{base_topic}/{label}", &[("base_topic", "pitinfo")]),
+            "pitinfo/{label}"
+        );
+        assert!(check_template("home/{device_name}/{label}", &["device_name", "label"]).is_ok());
+        assert!(check_template("home/{device}/{label}", &["device_name", "label"]).is_err());
+    }
+
+    #[test]
+    fn zigbee2mqtt_state() {
+        let mut state = MeterState::default();
+        state.update(&Message::ApparentPower { value: 803 });
+        state.update(&Message::CurrentTariffPeriod(TarifPeriod {
+            hour: HourlyTarifPeriod::OffPeakHours,
+            day_color: Some(DayColor::Red),
+        }));
+        state.update(&Message::Index {
+            period: TarifPeriod {
+                hour: HourlyTarifPeriod::OffPeakHours,
+                day_color: Some(DayColor::Blue),
+            },
+            value: 23916830,
+        });
+        assert_eq!(
+            json_state(&state.values(), &[], &Scales::default()),
+            r#"{"BBRHCJB":23916830,"PAPP":803,"PTEC":"HCJR"}"#
+        );
+    }
+
+    #[test]
+    fn senml_state() {
+        let values = vec![
+            (String::from("BBRHCJB"), Value::Integer(23916830)),
+            (String::from("PTEC"), Value::Text(String::from("HCJR"))),
+            (String::from("PAPP"), Value::Integer(803)),
+        ];
+        let pack: serde_json::Value = serde_json::from_str(&senml_pack(
+            "linky:",
+            1705435200.0,
+            &values,
+            &[(trend::RATE_LABEL, 12.5)],
+            &Scales::default(),
+        ))
+        .unwrap();
+        assert_eq!(
+            pack,
+            json!([
+                {"bn": "linky:", "bt": 1705435200.0, "n": "BBRHCJB", "u": "Wh", "v": 23916830},
+                {"n": "PTEC", "vs": "HCJR"},
+                {"n": "PAPP", "u": "VA", "v": 803},
+                {"n": "PAPP_RATE", "v": 12.5},
+            ])
+        );
+    }
+
+    #[test]
+    fn device_information() {
+        let meter = MeterInfo {
+            address: String::from("031762120110"),
+            mode: TicMode::Historic,
+        };
+        let mut state = MeterState::default();
+        state.update(&Message::TariffOption(TariffOptionValue::Tempo));
+        state.update(&Message::SubscribedCurrent { value: 45 });
+        let info: serde_json::Value =
+            serde_json::from_str(&device_info(Some(&meter), &state)).unwrap();
+        assert_eq!(info["meter"], "031762120110");
+        assert_eq!(info["tariff_option"], "BBR");
+        assert_eq!(info["subscribed_current"], 45);
+        assert!(info["delivery_point"].is_null());
+        assert_eq!(info["mode"], "historic");
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+
+        let meter = MeterInfo {
+            address: String::from("041876097437"),
+            mode: TicMode::Standard,
+        };
+        let mut state = MeterState::default();
+        state.update(&Message::DeliveryPoint(String::from("30001610071843")));
+        let info: serde_json::Value =
+            serde_json::from_str(&device_info(Some(&meter), &state)).unwrap();
+        assert_eq!(info["meter"], "041876097437");
+        assert_eq!(info["delivery_point"], "30001610071843");
+        assert_eq!(info["mode"], "standard");
+
+        let info: serde_json::Value =
+            serde_json::from_str(&device_info(None, &MeterState::default())).unwrap();
+        assert!(info["meter"].is_null());
+        assert!(info["subscribed_current"].is_null());
+        assert!(info["mode"].is_null());
+    }
+}
@@ -0,0 +1,74 @@
+//! Full resolution apparent power capture for load disaggregation tools.
+//!
+//! Every PAPP value is appended to `channel_1.dat` in the layout of the REDD
+//! dataset, one `<unix timestamp> <power>` line per sample, with a
+//! `labels.dat` naming the channel `mains`. NILMTK and similar tools import
+//! this layout with their REDD converters.
+
+use crate::config::NilmConfig;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::Update;
+use chrono::{DateTime, Utc};
+use pitinfo_parser::Message;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+/// Time between two flushes of the capture, to spare SD cards.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts the capture. Messages sent to the returned sink are recorded.
+pub fn spawn(config: &NilmConfig) -> Result<Sink, io::Error> {
+    fs::create_dir_all(&config.directory)?;
+    fs::write(config.directory.join("labels.dat"), "1 mains\n")?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config.directory.join("channel_1.dat"))?;
+    let writer = BufWriter::new(File::from_std(file));
+    Ok(pipeline::spawn_sink("nilm", |receiver| {
+        record(writer, receiver)
+    }))
+}
+
+async fn record(mut writer: BufWriter<File>, mut receiver: Inbox<Update>) {
+    let mut last_flush = Instant::now();
+    while let Some(message) = receiver.recv_message().await {
+        if let Message::ApparentPower { value } = message {
+            let mut result = writer.write_all(line(Utc::now(), value).as_bytes()).await;
+            if result.is_ok() && last_flush.elapsed() >= FLUSH_INTERVAL {
+                last_flush = Instant::now();
+                result = writer.flush().await;
+            }
+            if let Err(e) = result {
+                eprintln!("Unable to write the NILM capture: {}", e);
+            }
+        }
+    }
+    // Keeps the last samples on shutdown
+    if let Err(e) = writer.flush().await {
+        eprintln!("Unable to write the NILM capture: {}", e);
+    }
+}
+
+fn line(timestamp: DateTime<Utc>, power: u16) -> String {
+    format!(
+        "{:.3} {}\n",
+        timestamp.timestamp_millis() as f64 / 1000.0,
+        power
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn redd_line() {
+        let timestamp = Utc.timestamp_millis_opt(1705406400250).unwrap();
+        assert_eq!(line(timestamp, 803), "1705406400.250 803\n");
+    }
+}
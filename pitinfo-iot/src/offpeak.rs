@@ -0,0 +1,376 @@
+//! Off-peak hours of the contract.
+//!
+//! HHPHC only names the schedule of the off-peak hours, and most schedules
+//! have their times set by the local grid. The windows of the contract are
+//! thus configured, in local time, or decoded from the schedule of the meter
+//! when it defines them, and their transitions reported: when off-peak hours
+//! are about to start, when they start and when they end, so that water
+//! heaters or car chargers can be started on time. The minutes until the next
+//! off-peak hours are published too. The meter remains the reference: a
+//! lasting disagreement with its current period is reported.
+
+use crate::config::OffPeakConfig;
+use crate::hooks;
+use crate::pipeline::{self, Sink};
+use chrono::{Duration as ChronoDuration, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use pitinfo_parser::{HHPHCValue, HourlyTarifPeriod, Message, OffPeakWindow};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time;
+
+pub const ACTIVE_LABEL: &str = "OFFPEAK_ACTIVE";
+pub const SOON_LABEL: &str = "OFFPEAK_SOON";
+pub const MINUTES_LABEL: &str = "OFFPEAK_MINUTES";
+
+/// Time between two checks of the schedule.
+const CHECK_PERIOD: Duration = Duration::from_secs(10);
+/// Disagreement with the meter tolerated, e.g. for the drift of its clock.
+const DISAGREEMENT_MINUTES: i64 = 5;
+
+/// Off-peak windows of a day, in local time. A window ending before its start
+/// runs over midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    windows: Vec<(NaiveTime, NaiveTime)>,
+}
+
+impl Schedule {
+    /// Parses windows written as `22:30-06:30`.
+    pub fn parse(windows: &[String]) -> Result<Schedule, String> {
+        let windows = windows
+            .iter()
+            .map(|window| {
+                let (start, end) = window
+                    .split_once('-')
+                    .ok_or_else(|| format!("invalid off-peak window '{}'", window))?;
+                let time = |time: &str| {
+                    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                        .map_err(|e| format!("invalid off-peak window '{}': {}", window, e))
+                };
+                Ok((time(start)?, time(end)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Schedule { windows })
+    }
+
+    /// Windows decoded from the HHPHC schedule of the meter.
+    pub fn from_windows(windows: &[OffPeakWindow]) -> Schedule {
+        let time = |minute: u16| {
+            NaiveTime::from_hms_opt(u32::from(minute / 60), u32::from(minute % 60), 0)
+                .unwrap_or(NaiveTime::MIN)
+        };
+        Schedule {
+            windows: windows
+                .iter()
+                .map(|window| (time(window.start), time(window.end)))
+                .collect(),
+        }
+    }
+
+    pub fn active(&self, time: NaiveTime) -> bool {
+        self.windows.iter().any(|(start, end)| {
+            if start <= end {
+                *start <= time && time < *end
+            } else {
+                time >= *start || time < *end
+            }
+        })
+    }
+
+    /// Time until the next start of a window.
+    pub fn until_start(&self, time: NaiveTime) -> Option<ChronoDuration> {
+        self.windows
+            .iter()
+            .map(|(start, _)| {
+                let until = *start - time;
+                if until < ChronoDuration::zero() {
+                    until + ChronoDuration::days(1)
+                } else {
+                    until
+                }
+            })
+            .min()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// Off-peak hours start within the notice
+    Soon,
+    Started,
+    Ended,
+}
+
+impl Event {
+    /// Name given to the hook, in `PITINFO_OFFPEAK`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Soon => "soon",
+            Event::Started => "started",
+            Event::Ended => "ended",
+        }
+    }
+}
+
+/// Follows the transitions of the schedule.
+#[derive(Debug)]
+pub struct OffPeakWatch {
+    /// None until decoded from the meter when not configured
+    schedule: Option<Schedule>,
+    notice: ChronoDuration,
+    /// Whether off-peak hours are active, and start soon, with the minutes
+    /// until they start, once checked
+    state: Option<(bool, bool, i64)>,
+    /// Start of the disagreement with the meter, and whether it was reported
+    disagreement: Option<(NaiveDateTime, bool)>,
+}
+
+impl OffPeakWatch {
+    pub fn new(schedule: Option<Schedule>, notice: ChronoDuration) -> OffPeakWatch {
+        OffPeakWatch {
+            schedule,
+            notice,
+            state: None,
+            disagreement: None,
+        }
+    }
+
+    pub fn has_schedule(&self) -> bool {
+        self.schedule.is_some()
+    }
+
+    /// Takes the windows decoded from the HHPHC schedule of the meter, when
+    /// it defines them. Returns whether it did.
+    pub fn decode(&mut self, code: HHPHCValue) -> bool {
+        match code.off_peak_windows() {
+            Some(windows) => {
+                self.schedule = Some(Schedule::from_windows(windows));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks the schedule at a local time. Nothing is reported on the first
+    /// check, the state is only taken.
+    pub fn check(&mut self, time: NaiveTime) -> Vec<Event> {
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule,
+            None => return Vec::new(),
+        };
+        let active = schedule.active(time);
+        let until = schedule.until_start(time).filter(|_| !active);
+        let soon = until.is_some_and(|until| until <= self.notice);
+        let until = until.map_or(0, |until| until.num_minutes());
+        let mut events = Vec::new();
+        if let Some((was_active, was_soon, _)) = self.state {
+            if soon && !was_soon {
+                events.push(Event::Soon);
+            }
+            if active && !was_active {
+                events.push(Event::Started);
+            }
+            if !active && was_active {
+                events.push(Event::Ended);
+            }
+        }
+        self.state = Some((active, soon, until));
+        events
+    }
+
+    /// Compares the period of the meter, off-peak or not, with the schedule.
+    /// Returns whether a lasting disagreement must be reported.
+    pub fn meter(&mut self, off_peak: bool, now: NaiveDateTime) -> bool {
+        let expected = match &self.schedule {
+            Some(schedule) => schedule.active(now.time()),
+            None => return false,
+        };
+        if off_peak == expected {
+            self.disagreement = None;
+            return false;
+        }
+        let (since, reported) = self.disagreement.get_or_insert((now, false));
+        if *reported || now - *since < ChronoDuration::minutes(DISAGREEMENT_MINUTES) {
+            return false;
+        }
+        *reported = true;
+        true
+    }
+
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        match self.state {
+            Some((active, soon, until)) => vec![
+                (ACTIVE_LABEL, if active { 1.0 } else { 0.0 }),
+                (SOON_LABEL, if soon { 1.0 } else { 0.0 }),
+                (MINUTES_LABEL, until as f64),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Latest state of the off-peak hours, shared with the sinks publishing it.
+#[derive(Clone, Default)]
+pub struct LatestOffPeak {
+    values: Arc<Mutex<Vec<(&'static str, f64)>>>,
+}
+
+impl LatestOffPeak {
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        self.values.lock().unwrap().clone()
+    }
+}
+
+/// Starts following the off-peak windows, in the local time of `timezone`.
+/// Without configured windows, they are decoded from the HHPHC schedule sent
+/// to the returned sink. The current periods sent to it are compared to them.
+pub fn spawn(config: &OffPeakConfig, timezone: Tz) -> Result<(Sink, LatestOffPeak), io::Error> {
+    let schedule = if config.windows.is_empty() {
+        None
+    } else {
+        Some(
+            Schedule::parse(&config.windows)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        )
+    };
+    let mut watch = OffPeakWatch::new(schedule, ChronoDuration::minutes(config.notice as i64));
+    let mut undefined = false;
+    let notice = config.notice;
+    let command = config.command.clone();
+    let latest = LatestOffPeak::default();
+    let values = Arc::clone(&latest.values);
+    let sink = pipeline::spawn_sink("offpeak", |mut receiver| async move {
+        let mut checks = time::interval(CHECK_PERIOD);
+        loop {
+            let now = || Utc::now().with_timezone(&timezone).naive_local();
+            tokio::select! {
+                message = receiver.recv_message() => match message {
+                    Some(Message::CurrentTariffPeriod(period)) => {
+                        let off_peak = period.hour == HourlyTarifPeriod::OffPeakHours;
+                        if watch.meter(off_peak, now()) {
+                            eprintln!(
+                                "WARNING: the meter is {} off-peak hours, unlike the configured windows",
+                                if off_peak { "in" } else { "out of" }
+                            );
+                        }
+                    }
+                    Some(Message::HHPHC(code)) if !watch.has_schedule() => {
+                        if watch.decode(code) {
+                            println!("Off-peak windows decoded from the {:?} HHPHC schedule", code);
+                        } else if !undefined {
+                            eprintln!(
+                                "WARNING: the off-peak hours of the {:?} HHPHC schedule are set locally, configure their windows",
+                                code
+                            );
+                            undefined = true;
+                        }
+                    }
+                    Some(_) => (),
+                    None => break,
+                },
+                _ = checks.tick() => {
+                    let events = watch.check(now().time());
+                    *values.lock().unwrap() = watch.values();
+                    for event in events {
+                        report(event, notice, command.as_deref()).await;
+                    }
+                }
+            }
+        }
+    });
+    Ok((sink, latest))
+}
+
+/// Logs a transition and runs the command with it.
+async fn report(event: Event, notice: u64, command: Option<&str>) {
+    let message = match event {
+        Event::Soon => format!("Off-peak hours start within {} minutes", notice),
+        Event::Started => String::from("Off-peak hours started"),
+        Event::Ended => String::from("Off-peak hours ended"),
+    };
+    println!("{}", message);
+    if let Some(command) = command {
+        hooks::run(
+            command,
+            &[
+                ("PITINFO_OFFPEAK", event.name()),
+                ("PITINFO_ALERT", &message),
+            ],
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn transitions() {
+        let windows = [String::from("22:30-06:30"), String::from("12:30-14:30")];
+        let schedule = Schedule::parse(&windows).unwrap();
+        assert!(schedule.active(at(23, 0)));
+        assert!(schedule.active(at(6, 29)));
+        assert!(!schedule.active(at(6, 30)));
+        assert_eq!(
+            schedule.until_start(at(22, 0)),
+            Some(ChronoDuration::minutes(30))
+        );
+        assert!(Schedule::parse(&[String::from("22:30")]).is_err());
+
+        let mut watch = OffPeakWatch::new(Some(schedule), ChronoDuration::minutes(15));
+        assert!(watch.check(at(22, 0)).is_empty());
+        assert!(watch.check(at(22, 14)).is_empty());
+        assert_eq!(watch.check(at(22, 15)), vec![Event::Soon]);
+        assert_eq!(
+            watch.values(),
+            vec![
+                (ACTIVE_LABEL, 0.0),
+                (SOON_LABEL, 1.0),
+                (MINUTES_LABEL, 15.0)
+            ]
+        );
+        assert_eq!(watch.check(at(22, 30)), vec![Event::Started]);
+        assert_eq!(watch.check(at(6, 30)), vec![Event::Ended]);
+        assert_eq!(watch.values()[2], (MINUTES_LABEL, 360.0));
+    }
+
+    #[test]
+    fn decoded() {
+        let mut watch = OffPeakWatch::new(None, ChronoDuration::minutes(15));
+        assert!(watch.check(at(21, 0)).is_empty());
+        assert!(watch.values().is_empty());
+        assert!(!watch.decode(HHPHCValue::C));
+        assert!(!watch.has_schedule());
+        // Tempo, from 22:00 to 06:00
+        assert!(watch.decode(HHPHCValue::Y));
+        assert!(watch.check(at(21, 0)).is_empty());
+        assert_eq!(watch.values()[2], (MINUTES_LABEL, 60.0));
+        assert_eq!(watch.check(at(21, 45)), vec![Event::Soon]);
+        assert_eq!(watch.check(at(22, 0)), vec![Event::Started]);
+        assert_eq!(watch.values()[2], (MINUTES_LABEL, 0.0));
+    }
+
+    #[test]
+    fn meter_disagreement() {
+        let schedule = Schedule::parse(&[String::from("22:30-06:30")]).unwrap();
+        let mut watch = OffPeakWatch::new(Some(schedule), ChronoDuration::minutes(15));
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        let at = |hour, minute| day.and_hms_opt(hour, minute, 0).unwrap();
+        // The meter clock is a bit late
+        assert!(!watch.meter(false, at(22, 30)));
+        assert!(!watch.meter(true, at(22, 32)));
+        // Not off-peak at all
+        assert!(!watch.meter(false, at(23, 0)));
+        assert!(watch.meter(false, at(23, 5)));
+        // Reported once
+        assert!(!watch.meter(false, at(23, 10)));
+    }
+}
@@ -0,0 +1,206 @@
+//! Updates openHAB items with the meter values.
+//!
+//! The values of a frame update, at most once per configured interval, the
+//! states of items named after the labels with a prefix, e.g. `Pitinfo_PAPP`,
+//! through the REST API. Missing items are created on their first value, as
+//! `Number` items for numeric labels and `String` items for the others,
+//! grouped under a group item named after the prefix, so that they can be
+//! used in rules and sitemaps without declaring them first. Existing items,
+//! maybe customized, are left as they are.
+
+use crate::config::OpenHabConfig;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::scale::Scales;
+use crate::state::{MeterState, Update, Value};
+use pitinfo_parser::Message;
+use serde_json::json;
+use std::collections::HashSet;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Starts the updater. Messages sent to the returned sink update the items
+/// once per interval.
+pub fn spawn(config: &OpenHabConfig) -> Result<Sink, io::Error> {
+    let scales = Scales::new(&config.scale)?;
+    let config = config.clone();
+    let delivery = config.delivery.clone();
+    Ok(pipeline::spawn_sink_with(
+        "openhab",
+        &delivery,
+        move |receiver| run(config, scales, receiver),
+    ))
+}
+
+async fn run(config: OpenHabConfig, scales: Scales, mut receiver: Inbox<Update>) {
+    let interval = Duration::from_secs(config.interval);
+    let mut state = MeterState::default();
+    let mut last_update: Option<Instant> = None;
+    // Items known to exist
+    let mut created = HashSet::new();
+    while let Some(update) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(update, Update::Meter(Message::MeterAddress(_)))
+            && last_update.is_none_or(|last| last.elapsed() >= interval)
+        {
+            let values = state.values();
+            if !values.is_empty() {
+                let missing: Vec<(String, serde_json::Value)> = if config.create_items {
+                    values
+                        .iter()
+                        .filter(|(label, _)| !created.contains(label))
+                        .map(|(label, value)| {
+                            (
+                                item(&config.prefix, label),
+                                definition(&config.prefix, label, value),
+                            )
+                        })
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                let states: Vec<(String, String)> = values
+                    .iter()
+                    .map(|(label, value)| (item(&config.prefix, label), scales.text(label, value)))
+                    .collect();
+                let client = Client {
+                    url: config.url.trim_end_matches('/').to_string(),
+                    token: config.token.clone(),
+                    group: group(&config.prefix),
+                };
+                // The HTTP client is blocking
+                let result = pipeline::deliver("openhab", &config.delivery, move || {
+                    if !missing.is_empty() {
+                        client.create_group()?;
+                    }
+                    for (name, definition) in &missing {
+                        client.create(name, definition)?;
+                    }
+                    for (name, state) in &states {
+                        client.update(name, state)?;
+                    }
+                    Ok(())
+                })
+                .await;
+                match result {
+                    Ok(()) => {
+                        created.extend(values.into_iter().map(|(label, _)| label));
+                        last_update = Some(Instant::now());
+                    }
+                    Err(e) => eprintln!("Unable to update openHAB: {}", e),
+                }
+            }
+        }
+        state.record(&update);
+    }
+}
+
+struct Client {
+    url: String,
+    token: Option<String>,
+    group: String,
+}
+
+impl Client {
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = ureq::request(method, &format!("{}/rest/items/{}", self.url, path));
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn create_group(&self) -> Result<(), String> {
+        let definition = json!({
+            "type": "Group",
+            "name": self.group,
+            "label": "Teleinformation",
+            "category": "energy",
+        });
+        self.create(&self.group, &definition)
+    }
+
+    /// Creates an item, unless it exists.
+    fn create(&self, name: &str, definition: &serde_json::Value) -> Result<(), String> {
+        let error = |e| format!("item {}: {}", name, e);
+        match self.request("GET", name).call() {
+            Ok(_) => return Ok(()),
+            Err(ureq::Error::Status(404, _)) => (),
+            Err(e) => return Err(error(e)),
+        }
+        self.request("PUT", name)
+            .send_json(definition)
+            .map(|_| ())
+            .map_err(error)
+    }
+
+    fn update(&self, name: &str, state: &str) -> Result<(), String> {
+        self.request("PUT", &format!("{}/state", name))
+            .set("Content-Type", "text/plain")
+            .send_string(state)
+            .map(|_| ())
+            .map_err(|e| format!("item {}: {}", name, e))
+    }
+}
+
+/// Name of the item of a label, openHAB names being letters, digits and
+/// underscores.
+fn item(prefix: &str, label: &str) -> String {
+    let label: String = label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}{}", prefix, label)
+}
+
+/// Group item of the items, named after the prefix.
+fn group(prefix: &str) -> String {
+    let group = prefix.trim_end_matches('_');
+    if group.is_empty() {
+        String::from("Pitinfo")
+    } else {
+        group.to_string()
+    }
+}
+
+fn definition(prefix: &str, label: &str, value: &Value) -> serde_json::Value {
+    let kind = match value {
+        Value::Integer(_) => "Number",
+        Value::Text(_) => "String",
+    };
+    json!({
+        "type": kind,
+        "name": item(prefix, label),
+        "label": label,
+        "category": "energy",
+        "groupNames": [group(prefix)],
+        "tags": ["Measurement"],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn item_definitions() {
+        assert_eq!(item("Pitinfo_", "PAPP_AVG"), "Pitinfo_PAPP_AVG");
+        assert_eq!(item("Linky_", "ENERGY-DAY"), "Linky_ENERGY_DAY");
+        assert_eq!(group("Linky_"), "Linky");
+        assert_eq!(group(""), "Pitinfo");
+        assert_eq!(
+            definition("Pitinfo_", "PTEC", &Value::Text(String::from("HPJB"))),
+            json!({
+                "type": "String",
+                "name": "Pitinfo_PTEC",
+                "label": "PTEC",
+                "category": "energy",
+                "groupNames": ["Pitinfo"],
+                "tags": ["Measurement"],
+            })
+        );
+        assert_eq!(
+            definition("Pitinfo_", "PAPP", &Value::Integer(803))["type"],
+            "Number"
+        );
+    }
+}
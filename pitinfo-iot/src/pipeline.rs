@@ -0,0 +1,907 @@
+//! Stages of the daemon, connected by bounded channels.
+//!
+//! The serial port is read by its own task, which hands the bytes read to
+//! the parsing stage, and parsed messages are fanned out to the sinks, each
+//! running in its own task. Stages never block on a full channel: a slow
+//! sink, like a remote database over a flaky link, loses messages instead of
+//! stalling the serial reading, which would lose whole frames.
+//!
+//! Chunks of bytes carry the time they were read, handed over to the
+//! messages parsed from them, to measure the latency of each sink, see the `latency` module.
+//!
+//! A serial port that stays open without returning anything is closed and
+//! opened again after a while: some USB adapters only recover this way from
+//! an electromagnetic glitch.
+//!
+//! Sinks writing to remote services have a delivery policy: the size of
+//! their queue, whether the newest or the oldest messages are dropped when it
+//! is full, and how often a failed write is tried again. A sink retrying
+//! does not handle its queue, which fills up meanwhile. Once it is full, the
+//! indexes are coalesced to their latest value and the notices of the meter
+//! kept instead, unless set otherwise for their class. Those take a reserve
+//! beyond the queue, and once it is used up, the oldest message of a class
+//! dropped makes room for them, or they are dropped too: a sink down for
+//! good never makes the daemon run out of memory.
+//!
+//! The parsing stage splits the bytes read into frames with the
+//! `FrameParser` of the parser. The last bytes read can be kept in a raw
+//! history, each chunk telling where it starts in the stream, to show what
+//! was around the groups that cannot be parsed.
+
+use crate::config::{ClassPolicy, DeliveryConfig, Overflow};
+use crate::latency;
+use crate::state::Update;
+use pitinfo_parser::Message;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::future::{self, Future};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+/// Chunks of bytes waiting to be parsed.
+const CHUNK_CAPACITY: usize = 100;
+/// Messages waiting to be handled by a sink, a few minutes of frames.
+const SINK_CAPACITY: usize = 1000;
+/// Least number of messages kept or coalesced beyond a full queue, which
+/// grows by a tenth at most.
+const MIN_RESERVE: usize = 10;
+/// Bytes read from the port at once, a few groups at 9600 bauds.
+const READ_CAPACITY: usize = 256;
+/// Time between two attempts to open the serial port again.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+/// Time before reading again after a read error, doubled on each error in a
+/// row.
+const READ_ERROR_DELAY: Duration = Duration::from_millis(50);
+/// Read errors in a row ending the reading of the port.
+const MAX_READ_ERRORS: u32 = 5;
+
+/// Stage consuming the items of a channel, the readings unless stated
+/// otherwise.
+pub struct Sink<T = Update> {
+    name: &'static str,
+    /// Items, with the time their line was read
+    sender: Sending<T>,
+    task: JoinHandle<()>,
+    capacity: usize,
+    /// Messages in the queue beyond which none is added
+    ceiling: usize,
+    delivery: DeliveryConfig,
+    /// Messages dropped since the sink fell behind
+    dropped: u64,
+    stopped: bool,
+}
+
+/// Class of the items of a sink, setting what becomes of them once its
+/// queue is full.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DataClass {
+    Index,
+    /// Powers, sent in each frame
+    Sample,
+    /// Notices of the meter
+    Alert,
+    Other,
+}
+
+/// Item handed to the sinks.
+pub trait Item: Clone {
+    fn class(&self) -> DataClass {
+        DataClass::Other
+    }
+
+    /// Whether the item is a newer value of the same group as `queued`.
+    fn supersedes(&self, _queued: &Self) -> bool {
+        false
+    }
+}
+
+impl Item for Message {
+    fn class(&self) -> DataClass {
+        match self {
+            Message::Index { .. }
+            | Message::SupplierIndex { .. }
+            | Message::SuppliedEnergy { .. }
+            | Message::InjectedEnergy { .. } => DataClass::Index,
+            Message::ApparentPower { .. }
+            | Message::InstantaneousPower { .. }
+            | Message::Voltage { .. }
+            | Message::InjectedPower { .. } => DataClass::Sample,
+            Message::EJPNotice { .. } => DataClass::Alert,
+            _ => DataClass::Other,
+        }
+    }
+
+    fn supersedes(&self, queued: &Message) -> bool {
+        match (self, queued) {
+            (Message::Index { period, .. }, Message::Index { period: queued, .. }) => {
+                period == queued
+            }
+            (
+                Message::InstantaneousPower { phase, .. },
+                Message::InstantaneousPower { phase: queued, .. },
+            )
+            | (Message::MaxCurrent { phase, .. }, Message::MaxCurrent { phase: queued, .. })
+            | (Message::Voltage { phase, .. }, Message::Voltage { phase: queued, .. }) => {
+                phase == queued
+            }
+            _ => self.kind() == queued.kind(),
+        }
+    }
+}
+
+impl Item for Update {
+    fn class(&self) -> DataClass {
+        match self {
+            Update::Meter(message) => message.class(),
+            Update::Derived { .. } => DataClass::Other,
+        }
+    }
+
+    fn supersedes(&self, queued: &Update) -> bool {
+        match (self, queued) {
+            (Update::Meter(message), Update::Meter(queued)) => message.supersedes(queued),
+            (Update::Derived { label, .. }, Update::Derived { label: queued, .. }) => {
+                label == queued
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Starts a sink as a task consuming the messages of its channel.
+pub fn spawn_sink<T, F, R>(name: &'static str, run: F) -> Sink<T>
+where
+    T: Item,
+    F: FnOnce(Inbox<T>) -> R,
+    R: Future<Output = ()> + Send + 'static,
+{
+    spawn_sink_with(name, &DeliveryConfig::default(), run)
+}
+
+/// Starts a sink with a delivery policy.
+pub fn spawn_sink_with<T, F, R>(name: &'static str, delivery: &DeliveryConfig, run: F) -> Sink<T>
+where
+    T: Item,
+    F: FnOnce(Inbox<T>) -> R,
+    R: Future<Output = ()> + Send + 'static,
+{
+    let queue = Arc::new(Queue::default());
+    let task = tokio::spawn(run(Inbox::new(name, Arc::clone(&queue))));
+    Sink::new(name, queue, task, delivery)
+}
+
+/// Starts a sink doing blocking work, like database writes, on the blocking
+/// thread pool.
+pub fn spawn_blocking_sink<F>(name: &'static str, run: F) -> Sink
+where
+    F: FnOnce(Inbox<Update>) + Send + 'static,
+{
+    let queue = Arc::new(Queue::default());
+    let inbox = Inbox::new(name, Arc::clone(&queue));
+    let task = task::spawn_blocking(move || run(inbox));
+    Sink::new(name, queue, task, &DeliveryConfig::default())
+}
+
+/// Channel of a sink, shared by both ends.
+struct Queue<T> {
+    state: Mutex<Queued<T>>,
+    /// Wakes the receiving end, waiting in a task or on a blocking thread
+    ready: Notify,
+    ready_blocking: Condvar,
+}
+
+struct Queued<T> {
+    items: VecDeque<(Instant, T)>,
+    sending: bool,
+    receiving: bool,
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Queue {
+            state: Mutex::new(Queued {
+                items: VecDeque::new(),
+                sending: true,
+                receiving: true,
+            }),
+            ready: Notify::new(),
+            ready_blocking: Condvar::new(),
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    fn wake(&self) {
+        self.ready.notify_one();
+        self.ready_blocking.notify_one();
+    }
+
+    /// Next item, `None` once the sending end is closed, or `Pending` while
+    /// there is none.
+    fn pop(queued: &mut Queued<T>) -> Poll<Option<(Instant, T)>> {
+        match queued.items.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if !queued.sending => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Sending end of the channel of a sink, closing it when dropped.
+struct Sending<T>(Arc<Queue<T>>);
+
+impl<T> Drop for Sending<T> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().sending = false;
+        self.0.wake();
+    }
+}
+
+impl<T: Item> Sink<T> {
+    fn new(
+        name: &'static str,
+        queue: Arc<Queue<T>>,
+        task: JoinHandle<()>,
+        delivery: &DeliveryConfig,
+    ) -> Sink<T> {
+        let capacity = delivery.queue.unwrap_or(SINK_CAPACITY).max(1);
+        Sink {
+            name,
+            sender: Sending(queue),
+            task,
+            capacity,
+            ceiling: capacity + (capacity / 10).max(MIN_RESERVE),
+            delivery: delivery.clone(),
+            dropped: 0,
+            stopped: false,
+        }
+    }
+
+    /// Hands a message to the sink, dropping it when the sink is behind.
+    pub fn send(&mut self, message: &T) {
+        self.send_received(message, Instant::now());
+    }
+
+    /// Hands a message parsed from a line read at `received` to the sink.
+    /// Once its queue is full, the message is queued anyway, replaces the
+    /// previous one of its group, or is dropped, as set for its class. Past
+    /// the reserve of the queue, a message queued anyway replaces the oldest
+    /// one of a class dropped, or is dropped.
+    pub fn send_received(&mut self, message: &T, received: Instant) {
+        let queue = Arc::clone(&self.sender.0);
+        let mut queued = queue.state.lock().unwrap();
+        if !queued.receiving {
+            if !self.stopped {
+                eprintln!("Sink {} stopped", self.name);
+                self.stopped = true;
+            }
+            return;
+        }
+        let full = queued.items.len() >= self.capacity;
+        let dropped = if !full {
+            false
+        } else {
+            let policy = self.delivery.policy(message.class());
+            let previous = match policy {
+                ClassPolicy::Coalesce => queued
+                    .items
+                    .iter()
+                    .position(|(_, item)| message.supersedes(item)),
+                _ => None,
+            };
+            match previous {
+                Some(index) => queued.items.remove(index).is_some(),
+                None if policy == ClassPolicy::Drop
+                    && self.delivery.overflow == Overflow::DropNewest =>
+                {
+                    self.drop_message();
+                    return;
+                }
+                None if policy == ClassPolicy::Drop || queued.items.len() >= self.ceiling => {
+                    if !self.drop_oldest(&mut queued.items) {
+                        self.drop_message();
+                        return;
+                    }
+                    true
+                }
+                None => false,
+            }
+        };
+        queued.items.push_back((received, message.clone()));
+        drop(queued);
+        queue.wake();
+        if dropped {
+            self.drop_message();
+        } else if !full && self.dropped > 0 {
+            eprintln!(
+                "Sink {} caught up, {} messages dropped",
+                self.name, self.dropped
+            );
+            self.dropped = 0;
+        }
+    }
+
+    /// Drops the oldest message of a class dropped, returning whether there
+    /// was one.
+    fn drop_oldest(&self, items: &mut VecDeque<(Instant, T)>) -> bool {
+        let oldest = items
+            .iter()
+            .position(|(_, item)| self.delivery.policy(item.class()) == ClassPolicy::Drop);
+        oldest.and_then(|index| items.remove(index)).is_some()
+    }
+
+    fn drop_message(&mut self) {
+        if self.dropped == 0 {
+            eprintln!("Sink {} is behind, dropping messages", self.name);
+        }
+        self.dropped += 1;
+    }
+
+    /// Closes the channel and waits for the sink to handle the messages
+    /// already sent.
+    pub async fn close(self) {
+        drop(self.sender);
+        if let Err(e) = self.task.await {
+            eprintln!("Sink {} failed: {}", self.name, e);
+        }
+    }
+}
+
+/// Receiving end of a sink, recording the latency of each message once the
+/// sink asks for the next one.
+pub struct Inbox<T> {
+    name: &'static str,
+    queue: Arc<Queue<T>>,
+    /// Time the line of the message being handled was read
+    handling: Option<Instant>,
+}
+
+impl<T: Clone> Inbox<T> {
+    fn new(name: &'static str, queue: Arc<Queue<T>>) -> Inbox<T> {
+        Inbox {
+            name,
+            queue,
+            handling: None,
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<T> {
+        self.done();
+        let (received, item) = loop {
+            let next = Queue::pop(&mut self.queue.state.lock().unwrap());
+            match next {
+                Poll::Ready(item) => break item?,
+                Poll::Pending => self.queue.ready.notified().await,
+            }
+        };
+        self.handling = Some(received);
+        Some(item)
+    }
+
+    pub fn blocking_recv(&mut self) -> Option<T> {
+        self.done();
+        let mut queued = self.queue.state.lock().unwrap();
+        let (received, item) = loop {
+            match Queue::pop(&mut queued) {
+                Poll::Ready(item) => break item?,
+                Poll::Pending => queued = self.queue.ready_blocking.wait(queued).unwrap(),
+            }
+        };
+        self.handling = Some(received);
+        Some(item)
+    }
+
+    fn done(&mut self) {
+        if let Some(received) = self.handling.take() {
+            latency::record(self.name, received.elapsed());
+        }
+    }
+}
+
+impl Inbox<Update> {
+    /// Next message of the meter, skipping the derived values, for the sinks
+    /// only handling some groups.
+    pub async fn recv_message(&mut self) -> Option<Message> {
+        loop {
+            if let Update::Meter(message) = self.recv().await? {
+                return Some(message);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Inbox<T> {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().receiving = false;
+    }
+}
+
+/// Runs a blocking write, like an HTTP request, on the blocking thread pool,
+/// trying again on failure as many times as the policy allows, twice later
+/// each time.
+pub async fn deliver<F>(name: &str, delivery: &DeliveryConfig, write: F) -> Result<(), String>
+where
+    F: Fn() -> Result<(), String> + Send + Sync + 'static,
+{
+    let write = Arc::new(write);
+    let mut backoff = Duration::from_secs(delivery.backoff);
+    let mut retries = delivery.retries;
+    loop {
+        let attempt = write.clone();
+        let result = task::spawn_blocking(move || attempt())
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result);
+        match result {
+            Err(e) if retries > 0 => {
+                eprintln!(
+                    "Sink {} failed, retrying in {} s: {}",
+                    name,
+                    backoff.as_secs(),
+                    e
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(delivery.max_backoff));
+                retries -= 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Bytes read at once, with the time they were read.
+#[derive(Debug)]
+pub struct Chunk {
+    pub received: Instant,
+    pub bytes: Vec<u8>,
+    /// Position in the stream of the first byte, when the bytes read are
+    /// kept in a raw history
+    pub start: Option<u64>,
+}
+
+pub type Chunks = Receiver<Chunk>;
+
+/// Last bytes read from a port, shared by its reader.
+#[derive(Debug)]
+pub struct RawHistory {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+    /// Bytes read since the start
+    read: u64,
+}
+
+pub type Raw = Arc<Mutex<RawHistory>>;
+
+impl RawHistory {
+    pub fn new(capacity: usize) -> RawHistory {
+        RawHistory {
+            bytes: VecDeque::with_capacity(capacity),
+            capacity,
+            read: 0,
+        }
+    }
+
+    /// Records bytes read, returning the position of the first one.
+    pub fn record(&mut self, bytes: &[u8]) -> u64 {
+        let position = self.read;
+        let kept = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.bytes.len() + kept.len()).saturating_sub(self.capacity);
+        self.bytes.drain(..overflow);
+        self.bytes.extend(kept);
+        self.read += bytes.len() as u64;
+        position
+    }
+
+    pub fn read(&self) -> u64 {
+        self.read
+    }
+
+    /// Bytes from the `start` position of the stream to `end`, excluded,
+    /// those no longer kept or not read yet left out.
+    pub fn range(&self, start: u64, end: u64) -> Vec<u8> {
+        let first = self.read - self.bytes.len() as u64;
+        let start = start.clamp(first, self.read);
+        let end = end.clamp(start, self.read);
+        self.bytes
+            .range((start - first) as usize..(end - first) as usize)
+            .copied()
+            .collect()
+    }
+}
+
+/// Reads the serial port in a dedicated task, keeping the bytes read in
+/// `raw` if any, until its end or `MAX_READ_ERRORS` read errors in a row.
+pub fn spawn_reader<R: AsyncRead + Unpin + Send + 'static>(port: R, raw: Option<Raw>) -> Chunks {
+    let (sender, receiver) = mpsc::channel(CHUNK_CAPACITY);
+    tokio::spawn(async move {
+        if read_chunks(port, &sender, None, None, raw.as_ref()).await == ReadEnd::Failed {
+            eprintln!(
+                "WARNING: {} read errors in a row, stopping",
+                MAX_READ_ERRORS
+            );
+        }
+    });
+    receiver
+}
+
+/// Reads the serial port in a dedicated task, closing the port
+/// and opening it again with `reopen` once nothing was read for `stall`, when
+/// it reaches its end, after `MAX_READ_ERRORS` read errors in a row, or when
+/// `reopen_now` is notified.
+pub fn spawn_port_reader<R, E, F>(
+    port: R,
+    mut reopen: F,
+    stall: Duration,
+    reopen_now: Arc<Notify>,
+    raw: Option<Raw>,
+) -> Chunks
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    E: Display,
+    F: FnMut() -> Result<R, E> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(CHUNK_CAPACITY);
+    tokio::spawn(async move {
+        let mut port = Some(port);
+        loop {
+            if let Some(port) = port.take() {
+                match read_chunks(port, &sender, Some(stall), Some(&reopen_now), raw.as_ref()).await
+                {
+                    ReadEnd::Closed => return,
+                    ReadEnd::Reopen => (),
+                    ReadEnd::Stalled => eprintln!(
+                        "WARNING: nothing read from the serial port for {} s, opening it again",
+                        stall.as_secs()
+                    ),
+                    ReadEnd::End => {
+                        eprintln!("WARNING: end of the serial port, opening it again")
+                    }
+                    ReadEnd::Failed => eprintln!(
+                        "WARNING: {} read errors in a row on the serial port, opening it again",
+                        MAX_READ_ERRORS
+                    ),
+                }
+            }
+            time::sleep(REOPEN_DELAY).await;
+            match reopen() {
+                Ok(reopened) => port = Some(reopened),
+                Err(e) => eprintln!("Unable to open the serial port again: {}", e),
+            }
+        }
+    });
+    receiver
+}
+
+/// How the reading of a port ended.
+#[derive(Debug, PartialEq)]
+enum ReadEnd {
+    /// Nothing to send the bytes to anymore
+    Closed,
+    End,
+    /// Nothing read for the stall timeout, errors included
+    Stalled,
+    /// Asked to open the port again
+    Reopen,
+    /// `MAX_READ_ERRORS` read errors in a row
+    Failed,
+}
+
+async fn read_chunks<R: AsyncRead + Unpin>(
+    mut port: R,
+    sender: &Sender<Chunk>,
+    stall: Option<Duration>,
+    reopen: Option<&Notify>,
+    raw: Option<&Raw>,
+) -> ReadEnd {
+    let mut buffer = [0; READ_CAPACITY];
+    let mut last_read = Instant::now();
+    let mut errors = 0;
+    loop {
+        let reading = async {
+            match stall {
+                Some(stall) => {
+                    let Some(left) = stall.checked_sub(last_read.elapsed()) else {
+                        return Err(ReadEnd::Stalled);
+                    };
+                    time::timeout(left, port.read(&mut buffer))
+                        .await
+                        .map_err(|_| ReadEnd::Stalled)
+                }
+                None => Ok(port.read(&mut buffer).await),
+            }
+        };
+        let reopening = async {
+            match reopen {
+                Some(reopen) => reopen.notified().await,
+                None => future::pending().await,
+            }
+        };
+        let read = tokio::select! {
+            read = reading => match read {
+                Ok(read) => read,
+                Err(end) => return end,
+            },
+            _ = reopening => return ReadEnd::Reopen,
+        };
+        let count = match read {
+            Ok(0) => return ReadEnd::End,
+            Ok(count) => count,
+            Err(e) => {
+                eprintln!("Unable to read the serial port: {}", e);
+                errors += 1;
+                if errors == MAX_READ_ERRORS {
+                    return ReadEnd::Failed;
+                }
+                time::sleep(READ_ERROR_DELAY * 2u32.pow(errors - 1)).await;
+                continue;
+            }
+        };
+        errors = 0;
+        last_read = Instant::now();
+        let chunk = Chunk {
+            received: last_read,
+            bytes: buffer[..count].to_vec(),
+            start: raw.map(|raw| raw.lock().unwrap().record(&buffer[..count])),
+        };
+        match sender.try_send(chunk) {
+            Ok(()) => (),
+            Err(TrySendError::Full(chunk)) => {
+                eprintln!("Parsing is behind, dropping {} bytes", chunk.bytes.len())
+            }
+            Err(TrySendError::Closed(_)) => return ReadEnd::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, TarifPeriod};
+
+    /// Sink with a queue of 2 messages, whose inbox is not read yet.
+    fn stalled_sink(delivery: DeliveryConfig) -> (Sink<Message>, Inbox<Message>) {
+        let queue = Arc::new(Queue::default());
+        let inbox = Inbox::new("test", Arc::clone(&queue));
+        let delivery = DeliveryConfig {
+            queue: Some(2),
+            ..delivery
+        };
+        let sink = Sink::new("test", queue, tokio::spawn(async {}), &delivery);
+        (sink, inbox)
+    }
+
+    fn queued(inbox: &Inbox<Message>) -> Vec<Message> {
+        let queued = inbox.queue.state.lock().unwrap();
+        queued
+            .items
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn slow_sink() {
+        let (mut sink, mut receiver) = stalled_sink(DeliveryConfig::default());
+        for value in 0..5 {
+            sink.send(&Message::ApparentPower { value });
+        }
+        assert_eq!(sink.dropped, 3);
+
+        assert_eq!(
+            receiver.recv().await,
+            Some(Message::ApparentPower { value: 0 })
+        );
+        sink.send(&Message::ApparentPower { value: 5 });
+        assert_eq!(sink.dropped, 0);
+        assert_eq!(
+            queued(&receiver),
+            vec![
+                Message::ApparentPower { value: 1 },
+                Message::ApparentPower { value: 5 }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn class_policies() {
+        let index = |value| Message::Index {
+            period: TarifPeriod {
+                hour: HourlyTarifPeriod::OffPeakHours,
+                day_color: Some(DayColor::Blue),
+            },
+            value,
+        };
+        let (mut sink, receiver) = stalled_sink(DeliveryConfig::default());
+        sink.send(&Message::MeterAddress(String::from("031762120110")));
+        sink.send(&Message::ApparentPower { value: 800 });
+        // Indexes coalesced, notices kept, powers dropped
+        sink.send(&index(1));
+        sink.send(&Message::EJPNotice { minutes: 30 });
+        sink.send(&index(2));
+        sink.send(&Message::ApparentPower { value: 900 });
+        sink.send(&Message::EJPNotice { minutes: 30 });
+        assert_eq!(
+            queued(&receiver),
+            vec![
+                Message::MeterAddress(String::from("031762120110")),
+                Message::ApparentPower { value: 800 },
+                Message::EJPNotice { minutes: 30 },
+                index(2),
+                Message::EJPNotice { minutes: 30 },
+            ]
+        );
+        assert_eq!(sink.dropped, 2);
+
+        // The oldest messages dropped are those of the classes dropped
+        let (mut sink, receiver) = stalled_sink(DeliveryConfig {
+            overflow: Overflow::DropOldest,
+            indexes: ClassPolicy::Keep,
+            ..DeliveryConfig::default()
+        });
+        sink.send(&index(1));
+        sink.send(&Message::ApparentPower { value: 800 });
+        sink.send(&Message::ApparentPower { value: 900 });
+        sink.send(&index(2));
+        assert_eq!(
+            queued(&receiver),
+            vec![index(1), Message::ApparentPower { value: 900 }, index(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn bounded_reserve() {
+        let (mut sink, receiver) = stalled_sink(DeliveryConfig::default());
+        assert_eq!(sink.ceiling, 2 + MIN_RESERVE);
+        sink.send(&Message::ApparentPower { value: 800 });
+        for _ in 0..20 {
+            sink.send(&Message::EJPNotice { minutes: 30 });
+        }
+        // The power made room for a notice, the notices past it dropped
+        assert_eq!(
+            queued(&receiver),
+            vec![Message::EJPNotice { minutes: 30 }; sink.ceiling]
+        );
+        assert_eq!(sink.dropped, 9);
+    }
+
+    #[tokio::test]
+    async fn close_drains_sink() {
+        let (done, mut received) = mpsc::unbounded_channel();
+        let mut sink = spawn_sink("test", |mut receiver| async move {
+            while let Some(message) = receiver.recv().await {
+                done.send(message).unwrap();
+            }
+        });
+        sink.send(&Message::ApparentPower { value: 803 });
+        sink.close().await;
+        assert_eq!(
+            received.try_recv(),
+            Ok(Message::ApparentPower { value: 803 })
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let delivery = DeliveryConfig {
+            queue: Some(2),
+            overflow: Overflow::DropOldest,
+            ..DeliveryConfig::default()
+        };
+        let (done, mut received) = mpsc::unbounded_channel();
+        let (start, started) = tokio::sync::oneshot::channel::<()>();
+        let mut sink = spawn_sink_with("test", &delivery, |mut receiver| async move {
+            started.await.unwrap();
+            while let Some(message) = receiver.recv().await {
+                done.send(message).unwrap();
+            }
+        });
+        for value in 0..5 {
+            sink.send(&Message::ApparentPower { value });
+        }
+        start.send(()).unwrap();
+        sink.close().await;
+        let mut values = Vec::new();
+        while let Ok(message) = received.try_recv() {
+            values.push(message);
+        }
+        assert_eq!(
+            values,
+            vec![
+                Message::ApparentPower { value: 3 },
+                Message::ApparentPower { value: 4 }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn retried_delivery() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let delivery = DeliveryConfig {
+            retries: 2,
+            backoff: 0,
+            ..DeliveryConfig::default()
+        };
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let result = deliver("test", &delivery, move || {
+            match counted.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(String::from("unreachable")),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let result = deliver("test", &delivery, || Err(String::from("unreachable"))).await;
+        assert_eq!(result, Err(String::from("unreachable")));
+    }
+
+    /// A port that stays open without returning anything.
+    struct Stuck;
+
+    impl AsyncRead for Stuck {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_port() {
+        use tokio::io::AsyncReadExt;
+        let port = |lines: &'static [u8]| lines.chain(Stuck);
+        let mut reopened = 0;
+        let mut receiver = spawn_port_reader(
+            port(b"ADCO\nPAPP 1\n"),
+            move || {
+                reopened += 1;
+                match reopened {
+                    1 => Err("busy"),
+                    _ => Ok(port(b"ADCO\nPAPP 2\n")),
+                }
+            },
+            Duration::from_millis(50),
+            Arc::new(Notify::new()),
+            None,
+        );
+        let chunk = receiver.recv().await.unwrap();
+        assert_eq!(chunk.bytes, b"ADCO\nPAPP 1\n");
+        // Opened again after a failed attempt
+        let chunk = receiver.recv().await.unwrap();
+        assert_eq!(chunk.bytes, b"ADCO\nPAPP 2\n");
+    }
+
+    /// A port whose reads all fail.
+    struct Failing;
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::Error::other("device disconnected")))
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_port() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let start = Instant::now();
+        let end = read_chunks(Failing, &sender, None, None, None).await;
+        assert_eq!(end, ReadEnd::Failed);
+        // Backing off between the errors rather than spinning
+        assert!(start.elapsed() >= READ_ERROR_DELAY * 15);
+    }
+}
@@ -0,0 +1,210 @@
+//! Production meter of solar installations.
+//!
+//! Before Linky, a solar installation selling all its production had two
+//! meters: the usual one for the consumption of the home and a second one
+//! for the production, each with its own TIC. The second TIC is read here
+//! and combined with the apparent power of the first into a balance: the
+//! production, the net power, positive when the home draws more than it
+//! produces, and the energies of the net power in each direction, that is
+//! what the home would have taken from and given to the grid had it
+//! consumed its production, with the self-consumption ratio they give.
+//! Meters only send apparent powers and the energies are integrated from
+//! them since the start of the daemon, as estimates.
+
+use crate::config::ProductionConfig;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::Update;
+use pitinfo_parser::{FrameParser, Message, MessageKind, TicMode};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilderExt, StopBits};
+
+pub const POWER_LABEL: &str = "PRODUCTION_PAPP";
+pub const NET_POWER_LABEL: &str = "NET_PAPP";
+pub const ENERGY_LABEL: &str = "PRODUCTION_ENERGY";
+pub const IMPORT_LABEL: &str = "IMPORT_ENERGY";
+pub const EXPORT_LABEL: &str = "EXPORT_ENERGY";
+pub const SELF_CONSUMPTION_LABEL: &str = "SELF_CONSUMPTION";
+
+/// Age after which the power of a meter is no longer trusted, e.g. when its
+/// TIC is disconnected.
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// Balance of the consumption and production meters.
+#[derive(Debug, Default)]
+pub struct Balance {
+    /// Apparent powers in VA, with the time they were read
+    consumption: Option<(u16, Instant)>,
+    production: Option<(u16, Instant)>,
+    /// Energies since the start, in Wh
+    produced: f64,
+    imported: f64,
+    exported: f64,
+}
+
+impl Balance {
+    /// Records the apparent power of the production meter.
+    pub fn production(&mut self, power: u16, now: Instant) {
+        self.production = Some((power, now));
+    }
+
+    /// Records the apparent power of the consumption meter, the energies
+    /// being integrated over the time since the previous one.
+    pub fn consumption(&mut self, power: u16, now: Instant) {
+        if let Some((consumption, production)) = self.powers(now) {
+            let (_, last) = self.consumption.unwrap_or((0, now));
+            let hours = now.duration_since(last).as_secs_f64() / 3600.0;
+            let net = f64::from(consumption) - f64::from(production);
+            self.produced += f64::from(production) * hours;
+            if net > 0.0 {
+                self.imported += net * hours;
+            } else {
+                self.exported -= net * hours;
+            }
+        }
+        self.consumption = Some((power, now));
+    }
+
+    /// Powers of both meters, when recent.
+    fn powers(&self, now: Instant) -> Option<(u16, u16)> {
+        let recent = |reading: Option<(u16, Instant)>| {
+            reading
+                .filter(|(_, time)| now.duration_since(*time) < STALE_AFTER)
+                .map(|(power, _)| power)
+        };
+        Some((recent(self.consumption)?, recent(self.production)?))
+    }
+
+    pub fn values(&self, now: Instant) -> Vec<(&'static str, f64)> {
+        let Some((consumption, production)) = self.powers(now) else {
+            return Vec::new();
+        };
+        let mut values = vec![
+            (POWER_LABEL, f64::from(production)),
+            (
+                NET_POWER_LABEL,
+                f64::from(consumption) - f64::from(production),
+            ),
+            (ENERGY_LABEL, self.produced.round()),
+            (IMPORT_LABEL, self.imported.round()),
+            (EXPORT_LABEL, self.exported.round()),
+        ];
+        if self.produced > 0.0 {
+            let ratio = (self.produced - self.exported) / self.produced * 100.0;
+            values.push((SELF_CONSUMPTION_LABEL, (ratio * 10.0).round() / 10.0));
+        }
+        values
+    }
+}
+
+/// Latest balance, shared with the sinks publishing it.
+#[derive(Clone, Default)]
+pub struct LatestProduction {
+    values: Arc<Mutex<Vec<(&'static str, f64)>>>,
+}
+
+impl LatestProduction {
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        self.values.lock().unwrap().clone()
+    }
+}
+
+/// Starts reading the production meter. The apparent powers sent to the
+/// returned sink are those of the consumption meter.
+pub fn spawn(config: &ProductionConfig) -> Result<(Sink, LatestProduction), io::Error> {
+    let port = tokio_serial::new(&config.port, config.baud_rate)
+        .parity(Parity::Even)
+        .data_bits(DataBits::Seven)
+        .flow_control(FlowControl::None)
+        .stop_bits(StopBits::One)
+        .open_native_async()
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "unable to open the production meter \"{}\": {}",
+                    config.port, e
+                ),
+            )
+        })?;
+    let mut chunks = pipeline::spawn_reader(port, None);
+    let name = config.port.clone();
+    let latest = LatestProduction::default();
+    let values = Arc::clone(&latest.values);
+    let sink = pipeline::spawn_sink("production", |mut receiver: Inbox<Update>| async move {
+        let mut balance = Balance::default();
+        let mut frames = FrameParser::new(TicMode::Historic, true);
+        let mut reading = true;
+        loop {
+            tokio::select! {
+                message = receiver.recv_message() => match message {
+                    Some(Message::ApparentPower { value }) => {
+                        let now = Instant::now();
+                        balance.consumption(value, now);
+                        *values.lock().unwrap() = balance.values(now);
+                    }
+                    Some(_) => (),
+                    None => break,
+                },
+                chunk = chunks.recv(), if reading => match chunk {
+                    Some(chunk) => {
+                        for frame in frames.push_bytes(&chunk.bytes).into_iter().flatten() {
+                            if let Some(Message::ApparentPower { value }) =
+                                frame.get(MessageKind::ApparentPower)
+                            {
+                                balance.production(*value, Instant::now());
+                            }
+                        }
+                    }
+                    None => {
+                        eprintln!("End of the teleinformation stream of the production meter \"{}\"", name);
+                        reading = false;
+                    }
+                },
+            }
+        }
+    });
+    Ok((sink, latest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balance() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut balance = Balance::default();
+        balance.consumption(500, start);
+        assert!(balance.values(start).is_empty());
+
+        // An hour producing 1500 VA for a consumption of 500, then half an
+        // hour consuming 2500 VA, each power holding until the next one
+        for step in 0..120 {
+            balance.production(1500, at(step * 30));
+            balance.consumption(500, at(step * 30));
+        }
+        balance.production(1500, at(3600));
+        balance.consumption(2500, at(3600));
+        let values = balance.values(at(3600));
+        assert!(values.contains(&(POWER_LABEL, 1500.0)));
+        assert!(values.contains(&(NET_POWER_LABEL, 1000.0)));
+        assert!(values.contains(&(ENERGY_LABEL, 1500.0)));
+        assert!(values.contains(&(EXPORT_LABEL, 1000.0)));
+        assert!(values.contains(&(SELF_CONSUMPTION_LABEL, 33.3)));
+        for step in 121..=180 {
+            balance.production(1500, at(step * 30));
+            balance.consumption(2500, at(step * 30));
+        }
+        let values = balance.values(at(5400));
+        assert!(values.contains(&(ENERGY_LABEL, 2250.0)));
+        assert!(values.contains(&(IMPORT_LABEL, 500.0)));
+        assert!(values.contains(&(SELF_CONSUMPTION_LABEL, 55.6)));
+
+        // The production meter is silent
+        balance.consumption(2500, at(5500));
+        assert!(balance.values(at(5500)).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+//! Raw teleinformation stream shared over TCP.
+//!
+//! The serial port can only be opened once. Tools that want the raw TIC,
+//! e.g. a legacy logger, connect to the proxy instead and receive the bytes
+//! of the port as read by the daemon, which keeps parsing them. The proxy is
+//! read-only: whatever clients send is ignored. A client falling behind
+//! loses bytes rather than slowing the others down.
+
+use crate::config::ProxyConfig;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Chunks of bytes waiting to be sent to the clients.
+const CHUNK_CAPACITY: usize = 256;
+
+/// Sending end of the proxy, handing the bytes read to the clients.
+#[derive(Clone)]
+pub struct Proxy {
+    sender: broadcast::Sender<Vec<u8>>,
+}
+
+/// Starts listening for clients.
+pub fn spawn(config: &ProxyConfig) -> Result<Proxy, io::Error> {
+    let listener = std::net::TcpListener::bind(&config.listen)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let (sender, _) = broadcast::channel(CHUNK_CAPACITY);
+    let proxy = Proxy { sender };
+    let clients = proxy.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    println!("Raw stream client {} connected", address);
+                    tokio::spawn(serve(stream, clients.sender.subscribe()));
+                }
+                Err(e) => eprintln!("Raw stream proxy error: {}", e),
+            }
+        }
+    });
+    Ok(proxy)
+}
+
+async fn serve(mut stream: TcpStream, mut receiver: broadcast::Receiver<Vec<u8>>) {
+    loop {
+        match receiver.recv().await {
+            Ok(bytes) => {
+                if stream.write_all(&bytes).await.is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(chunks)) => {
+                eprintln!("Raw stream client is behind, {} chunks dropped", chunks)
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Port copying the bytes read to the proxy, when there is one.
+pub struct Tap<R> {
+    port: R,
+    proxy: Option<Proxy>,
+}
+
+impl<R> Tap<R> {
+    pub fn new(port: R, proxy: Option<Proxy>) -> Tap<R> {
+        Tap { port, proxy }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tap<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buffer.filled().len();
+        let poll = Pin::new(&mut self.port).poll_read(context, buffer);
+        if let Some(proxy) = &self.proxy {
+            let read = &buffer.filled()[start..];
+            // Sending fails without clients
+            if !read.is_empty() && proxy.sender.receiver_count() > 0 {
+                let _ = proxy.sender.send(read.to_vec());
+            }
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+    #[tokio::test]
+    async fn raw_stream() {
+        // A free port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let proxy = spawn(&ProxyConfig {
+            listen: address.to_string(),
+        })
+        .unwrap();
+        let mut client = TcpStream::connect(address).await.unwrap();
+        // Let the proxy subscribe the client
+        while proxy.sender.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let frame: &[u8] = b"\x02\nADCO 031762120110 @\r\nPAPP 00803 .\r\x03";
+        let mut lines = BufReader::new(Tap::new(frame, Some(proxy))).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("\x02"));
+        while lines.next_line().await.unwrap().is_some() {}
+
+        let mut received = vec![0; frame.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, frame);
+    }
+}
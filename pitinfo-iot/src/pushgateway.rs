@@ -0,0 +1,133 @@
+//! Pushes meter values to a Prometheus Pushgateway.
+//!
+//! Replays and short-lived runs end before Prometheus could scrape them, so
+//! the state of a frame is pushed instead, at most once per configured
+//! interval, and a last time when the stream ends. Each push replaces the
+//! metrics of the grouping key, the job and optionally the instance.
+
+use crate::config::{DeliveryConfig, PushgatewayConfig};
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{MeterState, Update, Value};
+use pitinfo_parser::Message;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Prefix of the metric names.
+const NAMESPACE: &str = "pitinfo";
+
+/// Starts the pusher. Messages sent to the returned sink are pushed once per
+/// interval.
+pub fn spawn(config: &PushgatewayConfig) -> Result<Sink, io::Error> {
+    let url = push_url(&config.url, &config.job, config.instance.as_deref());
+    let interval = Duration::from_secs(config.interval);
+    let delivery = config.delivery.clone();
+    Ok(pipeline::spawn_sink_with(
+        "pushgateway",
+        &config.delivery,
+        move |receiver| run(url, interval, delivery, receiver),
+    ))
+}
+
+async fn run(
+    url: String,
+    interval: Duration,
+    delivery: DeliveryConfig,
+    mut receiver: Inbox<Update>,
+) {
+    let mut state = MeterState::default();
+    let mut last_push: Option<Instant> = None;
+    while let Some(item) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_)))
+            && last_push.is_none_or(|last| last.elapsed() >= interval)
+            && push(&url, &state, &delivery).await
+        {
+            last_push = Some(Instant::now());
+        }
+        state.record(&item);
+    }
+    // The last values of a replay
+    push(&url, &state, &delivery).await;
+}
+
+/// Pushes the values of the state, if any. Returns whether they were pushed.
+async fn push(url: &str, state: &MeterState, delivery: &DeliveryConfig) -> bool {
+    let values = state.values();
+    if values.is_empty() {
+        return false;
+    }
+    let body = metrics(&values);
+    let url = url.to_string();
+    // The HTTP client is blocking
+    let result = pipeline::deliver("pushgateway", delivery, move || {
+        ureq::put(&url)
+            .set("Content-Type", "text/plain; version=0.0.4")
+            .send_string(&body)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+    .await;
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Unable to push to the Pushgateway: {}", e);
+            false
+        }
+    }
+}
+
+fn push_url(url: &str, job: &str, instance: Option<&str>) -> String {
+    let mut url = format!("{}/metrics/job/{}", url.trim_end_matches('/'), job);
+    if let Some(instance) = instance {
+        url.push_str(&format!("/instance/{}", instance));
+    }
+    url
+}
+
+/// Metrics in the Prometheus text format: a gauge per numeric label, e.g.
+/// `pitinfo_papp`, and an info metric carrying text values as a label, e.g.
+/// `pitinfo_ptec_info{value="HPJB"} 1`.
+fn metrics(values: &[(String, Value)]) -> String {
+    let mut metrics = String::new();
+    for (label, value) in values {
+        let name = format!("{}_{}", NAMESPACE, label.to_lowercase());
+        match value {
+            Value::Integer(value) => {
+                metrics.push_str(&format!("# TYPE {} gauge\n{} {}\n", name, name, value));
+            }
+            Value::Text(text) => {
+                let text = text
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                metrics.push_str(&format!(
+                    "# TYPE {}_info gauge\n{}_info{{value=\"{}\"}} 1\n",
+                    name, name, text
+                ));
+            }
+        }
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition() {
+        assert_eq!(
+            push_url("http://localhost:9091/", "pitinfo", Some("linky")),
+            "http://localhost:9091/metrics/job/pitinfo/instance/linky"
+        );
+        let values = [
+            (String::from("PAPP"), Value::Integer(800)),
+            (String::from("PTEC"), Value::Text(String::from("HPJB"))),
+        ];
+        assert_eq!(
+            metrics(&values),
+            "# TYPE pitinfo_papp gauge\npitinfo_papp 800\n\
+             # TYPE pitinfo_ptec_info gauge\npitinfo_ptec_info{value=\"HPJB\"} 1\n"
+        );
+    }
+}
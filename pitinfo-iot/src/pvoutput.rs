@@ -0,0 +1,222 @@
+//! Pushes the consumption to PVOutput.
+//!
+//! PVOutput compares the generation of solar systems with the consumption of
+//! their owners, reported by the Add Status API once per status interval of
+//! the system, every 5 minutes by default. A status is posted on the first
+//! frame of each interval, stamped with its start in local time: the sum of
+//! the indexes as the lifetime consumption, PVOutput working out the energy
+//! of the day, and the apparent power as the consumption power. Donation
+//! accounts can add up to six extended parameters, taken from labels.
+
+use crate::clock::Stamper;
+use crate::config::{ClockConfig, PvOutputConfig};
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{MeterState, Update, Value};
+use chrono::{DateTime, NaiveDate, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+use pitinfo_parser::Message;
+use std::io;
+
+/// Status intervals PVOutput allows, in minutes.
+const INTERVALS: [u32; 3] = [5, 10, 15];
+/// Extended parameters of donation accounts.
+const EXTENDED: [&str; 6] = ["v7", "v8", "v9", "v10", "v11", "v12"];
+
+/// Starts the pusher. Messages sent to the returned sink are posted once per
+/// status interval.
+pub fn spawn(config: &PvOutputConfig, clock: &ClockConfig) -> Result<Sink, io::Error> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if !INTERVALS.contains(&config.interval) {
+        return Err(invalid(format!(
+            "invalid PVOutput interval {}, expected 5, 10 or 15 minutes",
+            config.interval
+        )));
+    }
+    if let Some(parameter) = config
+        .extended
+        .keys()
+        .find(|parameter| !EXTENDED.contains(&parameter.as_str()))
+    {
+        return Err(invalid(format!(
+            "invalid PVOutput extended parameter {}, expected v7 to v12",
+            parameter
+        )));
+    }
+    let config = config.clone();
+    let delivery = config.delivery.clone();
+    let stamper = Stamper::new(clock.wait_for_sync);
+    let timezone = clock.timezone;
+    Ok(pipeline::spawn_sink_with(
+        "pvoutput",
+        &delivery,
+        move |receiver| run(config, stamper, timezone, receiver),
+    ))
+}
+
+async fn run(
+    config: PvOutputConfig,
+    mut stamper: Stamper<()>,
+    timezone: Tz,
+    mut receiver: Inbox<Update>,
+) {
+    let url = status_url(&config.url);
+    // Interval of the last status posted
+    let mut posted: Option<(NaiveDate, NaiveTime)> = None;
+    let mut state = MeterState::default();
+    while let Some(item) = receiver.recv().await {
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_))) && stamper.synchronized() {
+            let slot = slot(Utc::now(), timezone, config.interval);
+            if posted != Some(slot) {
+                if let Some(status) = status(slot, &state, &config) {
+                    let url = url.clone();
+                    let apikey = config.apikey.clone();
+                    let system_id = config.system_id.clone();
+                    let result = pipeline::deliver("pvoutput", &config.delivery, move || {
+                        let status: Vec<(&str, &str)> = status
+                            .iter()
+                            .map(|(name, value)| (*name, value.as_str()))
+                            .collect();
+                        ureq::post(&url)
+                            .set("X-Pvoutput-Apikey", &apikey)
+                            .set("X-Pvoutput-SystemId", &system_id)
+                            .send_form(&status)
+                            .map(|_| ())
+                            .map_err(|e| e.to_string())
+                    })
+                    .await;
+                    match result {
+                        Ok(()) => posted = Some(slot),
+                        Err(e) => eprintln!("Unable to post to PVOutput: {}", e),
+                    }
+                }
+            }
+        }
+        state.record(&item);
+    }
+}
+
+fn status_url(url: &str) -> String {
+    format!("{}/service/r2/addstatus.jsp", url.trim_end_matches('/'))
+}
+
+/// Local date and start time of the status interval of `now`.
+fn slot(now: DateTime<Utc>, timezone: Tz, interval: u32) -> (NaiveDate, NaiveTime) {
+    let local = now.with_timezone(&timezone);
+    let minute = local.minute() - local.minute() % interval;
+    let start = NaiveTime::from_hms_opt(local.hour(), minute, 0).unwrap_or_default();
+    (local.date_naive(), start)
+}
+
+/// Parameters of the status of an interval, `None` before the indexes and
+/// the apparent power were received.
+fn status(
+    (date, time): (NaiveDate, NaiveTime),
+    state: &MeterState,
+    config: &PvOutputConfig,
+) -> Option<Vec<(&'static str, String)>> {
+    let power = state.apparent_power?;
+    let indexes = state.indexes.iter().chain(&state.off_peak_indexes);
+    if indexes.clone().all(Option::is_none) {
+        return None;
+    }
+    let energy: u64 = indexes.flatten().map(|&wh| u64::from(wh)).sum();
+    let mut status = vec![
+        ("d", date.format("%Y%m%d").to_string()),
+        ("t", time.format("%H:%M").to_string()),
+        ("v3", energy.to_string()),
+        ("v4", power.to_string()),
+        // The energy is a lifetime value
+        ("c1", String::from("1")),
+    ];
+    let values = state.values();
+    for parameter in EXTENDED {
+        let value = config.extended.get(parameter).and_then(|label| {
+            values.iter().find_map(|(l, value)| match value {
+                Value::Integer(value) if l == label => Some(value.to_string()),
+                _ => None,
+            })
+        });
+        if let Some(value) = value {
+            status.push((parameter, value));
+        }
+    }
+    Some(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, TarifPeriod};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn statuses() {
+        let paris = chrono_tz::Europe::Paris;
+        let now = Utc.with_ymd_and_hms(2024, 1, 16, 11, 58, 30).unwrap();
+        let slot = slot(now, paris, 5);
+        assert_eq!(
+            slot,
+            (
+                NaiveDate::from_ymd_opt(2024, 1, 16).unwrap(),
+                NaiveTime::from_hms_opt(12, 55, 0).unwrap()
+            )
+        );
+
+        let config = PvOutputConfig {
+            url: String::from("https://pvoutput.org"),
+            apikey: String::from("key"),
+            system_id: String::from("1234"),
+            interval: 5,
+            extended: BTreeMap::from([(String::from("v7"), String::from("IINST1"))]),
+            delivery: Default::default(),
+        };
+        let mut state = MeterState::default();
+        state.update(&Message::ApparentPower { value: 803 });
+        assert_eq!(status(slot, &state, &config), None);
+        // Indexes of the off-peak hours option
+        let mut off_peak = MeterState::default();
+        off_peak.update(&Message::ApparentPower { value: 803 });
+        off_peak.update(&Message::Index {
+            period: TarifPeriod {
+                hour: HourlyTarifPeriod::PeakHours,
+                day_color: None,
+            },
+            value: 500,
+        });
+        assert_eq!(
+            status(slot, &off_peak, &config).unwrap()[2],
+            ("v3", String::from("500"))
+        );
+        for (hour, value) in [
+            (HourlyTarifPeriod::OffPeakHours, 1000),
+            (HourlyTarifPeriod::PeakHours, 234),
+        ] {
+            state.update(&Message::Index {
+                period: TarifPeriod {
+                    hour,
+                    day_color: Some(DayColor::Blue),
+                },
+                value,
+            });
+        }
+        state.update(&Message::InstantaneousPower { phase: 1, value: 4 });
+        let status = status(slot, &state, &config).unwrap();
+        let status: Vec<(&str, &str)> = status
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        assert_eq!(
+            status,
+            [
+                ("d", "20240116"),
+                ("t", "12:55"),
+                ("v3", "1234"),
+                ("v4", "803"),
+                ("c1", "1"),
+                ("v7", "4"),
+            ]
+        );
+    }
+}
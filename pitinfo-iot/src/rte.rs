@@ -0,0 +1,25 @@
+//! Authentication to the RTE data APIs.
+
+use base64::Engine;
+use serde::Deserialize;
+use std::error::Error;
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+/// Requests an OAuth access token for an RTE API application.
+pub fn access_token(
+    token_url: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> Result<String, Box<dyn Error>> {
+    let credentials = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", client_id, client_secret));
+    let token: Token = ureq::post(token_url)
+        .set("Authorization", &format!("Basic {}", credentials))
+        .call()?
+        .into_json()?;
+    Ok(token.access_token)
+}
@@ -0,0 +1,152 @@
+//! Scaling of the values before they are published.
+//!
+//! Downstream systems expect different units: the indexes in kWh rather than
+//! Wh, the power in kW rather than VA or W. Each sink publishing numbers can
+//! scale the values of a label, or of the labels starting with a prefix with
+//! `PREFIX*`, by a factor, rounded to a number of decimals, and announce them
+//! with another unit.
+
+use crate::config::ScaleConfig;
+use crate::state::Value;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Most decimals kept, beyond the precision of the meter anyway.
+const MAX_DECIMALS: usize = 6;
+
+#[derive(Debug, Clone, Default)]
+pub struct Scales {
+    /// Scales of the labels, then of the prefixes, longest first
+    scales: Vec<(String, bool, ScaleConfig)>,
+}
+
+impl Scales {
+    pub fn new(config: &BTreeMap<String, ScaleConfig>) -> Result<Scales, io::Error> {
+        let mut scales = Vec::new();
+        for (pattern, scale) in config {
+            if !scale.factor.is_normal() || scale.decimals.is_some_and(|d| d > MAX_DECIMALS) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid scale of {}, expected a non-zero factor and at most {} decimals",
+                        pattern, MAX_DECIMALS
+                    ),
+                ));
+            }
+            match pattern.strip_suffix('*') {
+                Some(prefix) => scales.push((prefix.to_string(), true, scale.clone())),
+                None => scales.push((pattern.clone(), false, scale.clone())),
+            }
+        }
+        scales.sort_by_key(|(pattern, prefix, _)| (*prefix, usize::MAX - pattern.len()));
+        Ok(Scales { scales })
+    }
+
+    fn get(&self, label: &str) -> Option<&ScaleConfig> {
+        self.scales
+            .iter()
+            .find(|(pattern, prefix, _)| {
+                if *prefix {
+                    label.starts_with(pattern.as_str())
+                } else {
+                    label == pattern
+                }
+            })
+            .map(|(_, _, scale)| scale)
+    }
+
+    /// Scaled value of a label and its decimals, `None` when not scaled.
+    fn scale(&self, label: &str, value: f64) -> Option<(f64, Option<usize>)> {
+        let scale = self.get(label)?;
+        let scaled = value * scale.factor;
+        let scaled = match scale.decimals {
+            Some(decimals) => {
+                let power = 10f64.powi(decimals as i32);
+                (scaled * power).round() / power
+            }
+            None => scaled,
+        };
+        Some((scaled, scale.decimals))
+    }
+
+    /// Unit of a scaled label, if given.
+    pub fn unit(&self, label: &str) -> Option<&str> {
+        self.get(label)?.unit.as_deref()
+    }
+
+    /// Number as text, e.g. for an MQTT payload.
+    pub fn format(&self, label: &str, value: f64) -> String {
+        match self.scale(label, value) {
+            Some((scaled, Some(decimals))) => format!("{:.*}", decimals, scaled),
+            Some((scaled, None)) => scaled.to_string(),
+            None => value.to_string(),
+        }
+    }
+
+    /// Value of a group as text.
+    pub fn text(&self, label: &str, value: &Value) -> String {
+        match value {
+            Value::Integer(integer) if self.get(label).is_some() => {
+                self.format(label, *integer as f64)
+            }
+            value => value.to_string(),
+        }
+    }
+
+    /// Number as JSON.
+    pub fn json_number(&self, label: &str, value: f64) -> serde_json::Value {
+        json!(self.scale(label, value).map_or(value, |(scaled, _)| scaled))
+    }
+
+    /// Value of a group as JSON, integers staying integers when not scaled.
+    pub fn json(&self, label: &str, value: &Value) -> serde_json::Value {
+        match value {
+            Value::Integer(integer) => match self.scale(label, *integer as f64) {
+                Some((scaled, _)) => json!(scaled),
+                None => json!(integer),
+            },
+            Value::Text(text) => json!(text),
+        }
+    }
+
+    /// Whether a label is scaled, and its values no longer integers.
+    pub fn is_scaled(&self, label: &str) -> bool {
+        self.get(label).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_values() {
+        let scale = |factor, decimals, unit: Option<&str>| ScaleConfig {
+            factor,
+            decimals,
+            unit: unit.map(String::from),
+        };
+        let config = BTreeMap::from([
+            (String::from("BBRH*"), scale(0.001, Some(3), Some("kWh"))),
+            (String::from("BBRHPJR"), scale(0.001, Some(1), None)),
+            (String::from("PACT_EST"), scale(0.001, None, Some("kW"))),
+        ]);
+        let scales = Scales::new(&config).unwrap();
+        let index = Value::Integer(23_916_830);
+        assert_eq!(scales.text("BBRHCJB", &index), "23916.830");
+        assert_eq!(scales.json("BBRHCJB", &index), json!(23916.83));
+        assert_eq!(scales.unit("BBRHCJB"), Some("kWh"));
+        // The label is preferred to the prefix
+        assert_eq!(scales.text("BBRHPJR", &index), "23916.8");
+        assert_eq!(scales.unit("BBRHPJR"), None);
+        assert_eq!(scales.format("PACT_EST", 723.0), "0.723");
+        // Other labels are left as is
+        assert_eq!(scales.text("PAPP", &Value::Integer(803)), "803");
+        assert_eq!(scales.json("PAPP", &Value::Integer(803)), json!(803));
+        assert_eq!(scales.format("PAPP_AVG", 803.5), "803.5");
+
+        let invalid = BTreeMap::from([(String::from("PAPP"), scale(0.0, None, None))]);
+        assert!(Scales::new(&invalid).is_err());
+    }
+}
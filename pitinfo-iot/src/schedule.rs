@@ -0,0 +1,326 @@
+//! Periodic jobs run by the daemon, instead of cron entries on the Pi.
+//!
+//! Each `[[schedule]]` entry runs a job every day or every month at a local
+//! time of the configured time zone: one of the exports of the stored
+//! history, the consumption report of the previous day or month sent by
+//! email, or a shell command. `{date}` in the path of an export is replaced
+//! with the date of the run, so that successive runs do not overwrite each
+//! other.
+
+use crate::config::{self, EmailConfig, Every, Job, Language, ScheduleConfig, StorageConfig};
+use crate::email::{self, Mailer};
+#[cfg(feature = "parquet")]
+use crate::export;
+use crate::hooks;
+use crate::storage::{Energy, Store};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::error::Error;
+use std::io;
+#[cfg(feature = "parquet")]
+use std::path::PathBuf;
+use tokio::task;
+use tokio::time;
+
+struct Entry {
+    config: ScheduleConfig,
+    at: NaiveTime,
+}
+
+pub struct Scheduler {
+    entries: Vec<Entry>,
+    storage: Option<StorageConfig>,
+    mailer: Option<Mailer>,
+    timezone: Tz,
+    language: Language,
+}
+
+impl Scheduler {
+    pub fn new(
+        schedule: &[ScheduleConfig],
+        storage: Option<&StorageConfig>,
+        email: Option<&EmailConfig>,
+        timezone: Tz,
+        language: Language,
+    ) -> Result<Scheduler, io::Error> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut entries = Vec::new();
+        for config in schedule {
+            let at = NaiveTime::parse_from_str(&config.at, "%H:%M")
+                .map_err(|_| invalid(format!("invalid schedule time '{}'", config.at)))?;
+            match config.job {
+                Job::Command if config.command.is_none() => {
+                    return Err(invalid(String::from(
+                        "scheduled commands require a command",
+                    )))
+                }
+                Job::Command => (),
+                Job::EmailReport if email.is_none() => {
+                    return Err(invalid(String::from(
+                        "scheduled reports require an [email] section",
+                    )))
+                }
+                _ if storage.is_none() => {
+                    return Err(invalid(String::from(
+                        "scheduled exports and reports require a [storage] section",
+                    )))
+                }
+                Job::EmailReport => (),
+                _ if !cfg!(feature = "parquet") => {
+                    return Err(invalid(config::without_feature(
+                        "scheduled exports",
+                        "parquet",
+                    )))
+                }
+                _ if config.path.is_none() => {
+                    return Err(invalid(String::from("scheduled exports require a path")))
+                }
+                _ => (),
+            }
+            if !(1..=28).contains(&config.day) {
+                return Err(invalid(format!(
+                    "invalid schedule day {}, expected from 1 to 28",
+                    config.day
+                )));
+            }
+            entries.push(Entry {
+                config: config.clone(),
+                at,
+            });
+        }
+        Ok(Scheduler {
+            entries,
+            storage: storage.cloned(),
+            mailer: email.map(Mailer::new).transpose()?,
+            timezone,
+            language,
+        })
+    }
+
+    /// Runs the jobs when due, forever.
+    pub async fn run(self) {
+        loop {
+            let now = Utc::now();
+            let next = self
+                .entries
+                .iter()
+                .map(|entry| (next_run(entry, now, self.timezone), entry))
+                .min_by_key(|(next, _)| *next);
+            let (next, entry) = match next {
+                Some(next) => next,
+                None => return,
+            };
+            let wait = (next - now).to_std().unwrap_or_default();
+            time::sleep(wait).await;
+            let date = next.with_timezone(&self.timezone).date_naive();
+            let job = entry.config.clone();
+            let storage = self.storage.clone();
+            let timezone = self.timezone;
+            match job.job {
+                Job::Command => {
+                    let command = job.command.as_deref().unwrap_or_default();
+                    hooks::run(command, &[("PITINFO_DATE", &date.to_string())]).await;
+                }
+                Job::EmailReport => {
+                    let (first, last) = reported_days(job.every, date);
+                    let result =
+                        task::spawn_blocking(move || load_energy(storage, timezone, first, last))
+                            .await;
+                    match (result, &self.mailer) {
+                        (Ok(Ok(energy)), Some(mailer)) => {
+                            let (subject, text) =
+                                email::report(first, last, &energy, self.language);
+                            mailer.send(&subject, text).await;
+                        }
+                        (Ok(Err(e)), _) => eprintln!("Scheduled report failed: {}", e),
+                        (Err(e), _) => eprintln!("Scheduled report failed: {}", e),
+                        (Ok(Ok(_)), None) => (),
+                    }
+                }
+                _ => {
+                    let result =
+                        task::spawn_blocking(move || run_export(&job, storage, timezone, date))
+                            .await;
+                    match result {
+                        Ok(Ok(message)) => println!("Scheduled {}", message),
+                        Ok(Err(e)) => eprintln!("Scheduled export failed: {}", e),
+                        Err(e) => eprintln!("Scheduled export failed: {}", e),
+                    }
+                }
+            }
+            // Not twice within the same second
+            time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+fn run_export(
+    job: &ScheduleConfig,
+    storage: Option<StorageConfig>,
+    timezone: Tz,
+    date: NaiveDate,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let storage = storage.ok_or("no [storage] section")?;
+    let path = PathBuf::from(
+        job.path
+            .as_deref()
+            .unwrap_or_default()
+            .replace("{date}", &date.to_string()),
+    );
+    let store = Store::load(&storage, timezone)?;
+    let done = match job.job {
+        Job::Export => format!(
+            "export of {} days to {}",
+            export::export(&store, &path).map_err(|e| e.to_string())?,
+            path.display()
+        ),
+        Job::ExportAnonymized => format!(
+            "export of {} periods to {}",
+            export::export_anonymized(&store, &path).map_err(|e| e.to_string())?,
+            path.display()
+        ),
+        Job::ExportHeatmap => format!(
+            "export of {} hours to {}",
+            export::export_heatmap(&store, &path, timezone).map_err(|e| e.to_string())?,
+            path.display()
+        ),
+        Job::EmailReport | Job::Command => return Err("not an export".into()),
+    };
+    Ok(done)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn run_export(
+    _: &ScheduleConfig,
+    _: Option<StorageConfig>,
+    _: Tz,
+    _: NaiveDate,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Err(config::without_feature("scheduled exports", "parquet").into())
+}
+
+fn load_energy(
+    storage: Option<StorageConfig>,
+    timezone: Tz,
+    first: NaiveDate,
+    last: NaiveDate,
+) -> Result<Vec<Energy>, Box<dyn Error + Send + Sync>> {
+    let storage = storage.ok_or("no [storage] section")?;
+    let store = Store::load(&storage, timezone)?;
+    Ok(store.energy(first, last)?)
+}
+
+/// Days reported by a run on `date`: the day before, or the month before.
+fn reported_days(every: Every, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+    match every {
+        Every::Day => {
+            let day = date - Duration::days(1);
+            (day, day)
+        }
+        Every::Month => {
+            let last = date.with_day(1).unwrap_or(date) - Duration::days(1);
+            (last.with_day(1).unwrap_or(last), last)
+        }
+    }
+}
+
+/// First time after `now` the entry is due.
+fn next_run(entry: &Entry, now: DateTime<Utc>, timezone: Tz) -> DateTime<Utc> {
+    let today = now.with_timezone(&timezone).date_naive();
+    let mut date = match entry.config.every {
+        Every::Day => today,
+        Every::Month => today.with_day(entry.config.day).unwrap_or(today),
+    };
+    loop {
+        // Skipped or repeated by the daylight saving time changes
+        let local = date.and_time(entry.at);
+        let time = timezone.from_local_datetime(&local).earliest().or_else(|| {
+            timezone
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        });
+        if let Some(time) = time.map(|time| time.with_timezone(&Utc)) {
+            if time > now {
+                return time;
+            }
+        }
+        date = match entry.config.every {
+            Every::Day => date + Duration::days(1),
+            Every::Month => {
+                let (year, month) = match date.month() {
+                    12 => (date.year() + 1, 1),
+                    month => (date.year(), month + 1),
+                };
+                NaiveDate::from_ymd_opt(year, month, entry.config.day).unwrap_or(date)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_runs() {
+        let paris = chrono_tz::Europe::Paris;
+        let entry = |every, day| Entry {
+            config: ScheduleConfig {
+                at: String::from("07:00"),
+                every,
+                day,
+                job: Job::Command,
+                path: None,
+                command: Some(String::from("true")),
+            },
+            at: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+        };
+        let at = |y, m, d, h| Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap();
+
+        let daily = entry(Every::Day, 1);
+        // 07:00 in Paris is 06:00 UTC in winter
+        assert_eq!(
+            next_run(&daily, at(2024, 1, 16, 5), paris),
+            at(2024, 1, 16, 6)
+        );
+        assert_eq!(
+            next_run(&daily, at(2024, 1, 16, 6), paris),
+            at(2024, 1, 17, 6)
+        );
+        // 05:00 UTC in summer
+        assert_eq!(
+            next_run(&daily, at(2024, 3, 30, 12), paris),
+            at(2024, 3, 31, 5)
+        );
+
+        let monthly = entry(Every::Month, 1);
+        assert_eq!(
+            next_run(&monthly, at(2024, 1, 16, 5), paris),
+            at(2024, 2, 1, 6)
+        );
+        assert_eq!(
+            next_run(&monthly, at(2024, 12, 2, 5), paris),
+            at(2025, 1, 1, 6)
+        );
+
+        let day = |month, day| NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+        assert_eq!(
+            reported_days(Every::Day, day(3, 1)),
+            (day(2, 29), day(2, 29))
+        );
+        assert_eq!(
+            reported_days(Every::Month, day(3, 1)),
+            (day(2, 1), day(2, 29))
+        );
+
+        assert!(Scheduler::new(
+            &[entry(Every::Month, 30).config],
+            None,
+            None,
+            paris,
+            Language::En
+        )
+        .is_err());
+    }
+}
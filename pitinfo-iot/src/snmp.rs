@@ -0,0 +1,560 @@
+//! Meter values for SNMP monitoring, as an AgentX subagent.
+//!
+//! Facility monitoring often only speaks SNMP. Rather than running an agent
+//! of its own, the daemon connects to the master agent of the machine, e.g.
+//! Net-SNMP's snmpd with `master agentx`, registers the subtree of the
+//! PITINFO-MIB and answers the requests the master forwards, with the values
+//! of the last frame:
+//!
+//! - `.1.0` meter address, `.2.0` tariff option, `.3.0` current period, as
+//!   strings, and `.4.0` apparent power in VA, a gauge;
+//! - `.5.1.2.i` label and `.5.1.3.i` value in Wh, a 64-bit counter, of the
+//!   indexes, `i` being the tariff period from 1 to 6;
+//! - `.6.1.2.p` instantaneous and `.6.1.3.p` maximum current in A, gauges,
+//!   of the phases `p`, from 1 to 3.
+//!
+//! Objects not received yet are missing. The AgentX protocol (RFC 2741) is
+//! binary and simple enough to be implemented here: only the PDUs a
+//! read-only subagent needs are handled, set requests are refused.
+
+use crate::config::SnmpConfig;
+use crate::meter::MeterInfo;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{index_period, index_slot, is_index, MeterState, Update, Value};
+use pitinfo_parser::Message;
+use std::convert::TryInto;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time;
+
+/// Time between two attempts to connect to the master agent.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Largest payload accepted from the master agent.
+const MAX_PAYLOAD: usize = 65536;
+
+const HEADER_LENGTH: usize = 20;
+const VERSION: u8 = 1;
+const NON_DEFAULT_CONTEXT: u8 = 0x08;
+const NETWORK_BYTE_ORDER: u8 = 0x10;
+
+const OPEN: u8 = 1;
+const CLOSE: u8 = 2;
+const REGISTER: u8 = 3;
+const GET: u8 = 5;
+const GET_NEXT: u8 = 6;
+const GET_BULK: u8 = 7;
+const TEST_SET: u8 = 8;
+const COMMIT_SET: u8 = 9;
+const UNDO_SET: u8 = 10;
+const CLEANUP_SET: u8 = 11;
+const RESPONSE: u8 = 18;
+
+const NOT_WRITABLE: u16 = 17;
+const UNSUPPORTED: u16 = 268;
+
+type Oid = Vec<u32>;
+
+/// Value of an object, with its AgentX type.
+#[derive(Debug, Clone, PartialEq)]
+enum Data {
+    OctetString(String),
+    Gauge32(u32),
+    Counter64(u64),
+    NoSuchObject,
+    EndOfMibView,
+}
+
+impl Data {
+    fn tag(&self) -> u16 {
+        match self {
+            Data::OctetString(_) => 4,
+            Data::Gauge32(_) => 66,
+            Data::Counter64(_) => 70,
+            Data::NoSuchObject => 128,
+            Data::EndOfMibView => 130,
+        }
+    }
+}
+
+/// Objects of the subtree, sorted by OID.
+type Mib = Vec<(Oid, Data)>;
+
+/// Starts the subagent. Messages sent to the returned sink update the
+/// objects once per frame.
+pub fn spawn(
+    config: &SnmpConfig,
+    meter: watch::Receiver<Option<MeterInfo>>,
+) -> Result<Sink, io::Error> {
+    let base = parse_oid(&config.base_oid).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid SNMP base OID '{}'", config.base_oid),
+        )
+    })?;
+    let master = config.master.clone();
+    let mib: Arc<Mutex<Mib>> = Arc::default();
+    let agent = tokio::spawn(serve(master, base.clone(), Arc::clone(&mib)));
+    Ok(pipeline::spawn_sink(
+        "snmp",
+        move |mut receiver: Inbox<Update>| async move {
+            let mut state = MeterState::default();
+            while let Some(item) = receiver.recv().await {
+                // Frames start with ADCO: the state of the previous frame is complete
+                if matches!(item, Update::Meter(Message::MeterAddress(_))) {
+                    *mib.lock().unwrap() = objects(
+                        &base,
+                        meter.borrow().as_ref().map(|meter| meter.address.as_str()),
+                        &state,
+                    );
+                }
+                state.record(&item);
+            }
+            agent.abort();
+        },
+    ))
+}
+
+/// Connects to the master agent, again after each disconnection.
+async fn serve(master: String, base: Oid, mib: Arc<Mutex<Mib>>) {
+    loop {
+        let result = if master.starts_with('/') {
+            connect_unix(&master, &base, &mib).await
+        } else {
+            let address = master.strip_prefix("tcp:").unwrap_or(&master);
+            match TcpStream::connect(address).await {
+                Ok(stream) => session(stream, &base, &mib).await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("AgentX session with {} ended: {}", master, e);
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str, base: &[u32], mib: &Mutex<Mib>) -> Result<(), io::Error> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    session(stream, base, mib).await
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &str, _: &[u32], _: &Mutex<Mib>) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("no Unix sockets for {}, use host:port", path),
+    ))
+}
+
+/// Opens a session, registers the subtree and answers the requests.
+async fn session<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    base: &[u32],
+    mib: &Mutex<Mib>,
+) -> Result<(), io::Error> {
+    let mut open = vec![0, 0, 0, 0];
+    write_oid(&mut open, &[], false);
+    write_octets(&mut open, b"pitinfo-iot");
+    stream.write_all(&packet(OPEN, 0, 0, 1, &open)).await?;
+    let (header, payload) = read_packet(&mut stream).await?;
+    check_response(&header, &payload, "open")?;
+    let session_id = header.session_id;
+
+    let mut register = vec![0, 127, 0, 0];
+    write_oid(&mut register, base, false);
+    stream
+        .write_all(&packet(REGISTER, session_id, 0, 2, &register))
+        .await?;
+    let (header, payload) = read_packet(&mut stream).await?;
+    check_response(&header, &payload, "registration")?;
+    println!("Registered the SNMP subtree {}", format_oid(base));
+
+    loop {
+        let (header, payload) = read_packet(&mut stream).await?;
+        if header.kind == CLOSE {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "closed by the master agent",
+            ));
+        }
+        let response = {
+            let mib = mib.lock().unwrap();
+            answer(&header, &payload, &mib)
+        };
+        if let Some(response) = response {
+            stream.write_all(&response).await?;
+        }
+    }
+}
+
+fn check_response(header: &Header, payload: &[u8], what: &str) -> Result<(), io::Error> {
+    let mut reader = Reader::new(payload, header.big_endian);
+    let error = reader.u32().and_then(|_| reader.u16());
+    match (header.kind, error) {
+        (RESPONSE, Some(0)) => Ok(()),
+        (_, error) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} refused by the master agent, error {:?}", what, error),
+        )),
+    }
+}
+
+/// Objects of the state of a frame, under `base`.
+fn objects(base: &[u32], meter: Option<&str>, state: &MeterState) -> Mib {
+    let oid = |suffix: &[u32]| -> Oid { base.iter().chain(suffix).copied().collect() };
+    let mut mib = Vec::new();
+    if let Some(meter) = meter {
+        mib.push((oid(&[1, 0]), Data::OctetString(meter.to_string())));
+    }
+    for (label, value) in state.values() {
+        let phase = |prefix: &str| label.strip_prefix(prefix)?.parse::<u32>().ok();
+        match (label.as_str(), value) {
+            ("OPTARIF", Value::Text(text)) => mib.push((oid(&[2, 0]), Data::OctetString(text))),
+            ("PTEC", Value::Text(text)) => mib.push((oid(&[3, 0]), Data::OctetString(text))),
+            ("PAPP", Value::Integer(value)) => {
+                mib.push((oid(&[4, 0]), Data::Gauge32(value as u32)))
+            }
+            (label, Value::Integer(value)) if is_index(label) => {
+                if let Some(slot) = index_period(label).and_then(|period| index_slot(&period)) {
+                    let row = slot as u32 + 1;
+                    mib.push((oid(&[5, 1, 2, row]), Data::OctetString(label.to_string())));
+                    mib.push((oid(&[5, 1, 3, row]), Data::Counter64(value)));
+                }
+            }
+            (_, Value::Integer(value)) => {
+                if let Some(phase) = phase("IINST") {
+                    mib.push((oid(&[6, 1, 2, phase]), Data::Gauge32(value as u32)));
+                } else if let Some(phase) = phase("IMAX") {
+                    mib.push((oid(&[6, 1, 3, phase]), Data::Gauge32(value as u32)));
+                }
+            }
+            _ => (),
+        }
+    }
+    mib.sort_by(|(a, _), (b, _)| a.cmp(b));
+    mib
+}
+
+#[derive(Debug)]
+struct Header {
+    kind: u8,
+    flags: u8,
+    big_endian: bool,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+}
+
+async fn read_packet<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Header, Vec<u8>), io::Error> {
+    let mut bytes = [0; HEADER_LENGTH];
+    stream.read_exact(&mut bytes).await?;
+    let flags = bytes[2];
+    let big_endian = flags & NETWORK_BYTE_ORDER != 0;
+    let mut reader = Reader::new(&bytes[4..], big_endian);
+    let header = Header {
+        kind: bytes[1],
+        flags,
+        big_endian,
+        session_id: reader.u32().unwrap_or_default(),
+        transaction_id: reader.u32().unwrap_or_default(),
+        packet_id: reader.u32().unwrap_or_default(),
+    };
+    let length = reader.u32().unwrap_or_default() as usize;
+    if bytes[0] != VERSION || length > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an AgentX packet",
+        ));
+    }
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).await?;
+    Ok((header, payload))
+}
+
+/// Packet in network byte order.
+fn packet(
+    kind: u8,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut packet = vec![VERSION, kind, NETWORK_BYTE_ORDER, 0];
+    for field in [session_id, transaction_id, packet_id, payload.len() as u32] {
+        packet.extend(field.to_be_bytes());
+    }
+    packet.extend(payload);
+    packet
+}
+
+/// Response to a request of the master agent, `None` for PDUs that are not
+/// answered.
+fn answer(header: &Header, payload: &[u8], mib: &[(Oid, Data)]) -> Option<Vec<u8>> {
+    let mut reader = Reader::new(payload, header.big_endian);
+    if header.flags & NON_DEFAULT_CONTEXT != 0 {
+        reader.octets()?;
+    }
+    let (error, varbinds) = match header.kind {
+        GET => {
+            let varbinds = ranges(&mut reader)
+                .into_iter()
+                .map(|(start, _, _)| {
+                    let data = mib
+                        .iter()
+                        .find(|(oid, _)| *oid == start)
+                        .map_or(Data::NoSuchObject, |(_, data)| data.clone());
+                    (start, data)
+                })
+                .collect();
+            (0, varbinds)
+        }
+        GET_NEXT => {
+            let varbinds = ranges(&mut reader)
+                .into_iter()
+                .map(|(start, include, end)| next(mib, start, include, &end))
+                .collect();
+            (0, varbinds)
+        }
+        GET_BULK => {
+            let non_repeaters = reader.u16()? as usize;
+            let repetitions = reader.u16()? as usize;
+            let ranges = ranges(&mut reader);
+            let mut varbinds = Vec::new();
+            for (start, include, end) in ranges.iter().take(non_repeaters) {
+                varbinds.push(next(mib, start.clone(), *include, end));
+            }
+            let mut repeated: Vec<(Oid, bool, Oid)> =
+                ranges.into_iter().skip(non_repeaters).collect();
+            for _ in 0..repetitions {
+                if repeated.is_empty() {
+                    break;
+                }
+                let mut done = true;
+                for (start, include, end) in &mut repeated {
+                    let (oid, data) = next(mib, start.clone(), *include, end);
+                    done &= data == Data::EndOfMibView;
+                    *start = oid.clone();
+                    *include = false;
+                    varbinds.push((oid, data));
+                }
+                if done {
+                    break;
+                }
+            }
+            (0, varbinds)
+        }
+        TEST_SET => (NOT_WRITABLE, Vec::new()),
+        COMMIT_SET | UNDO_SET | CLEANUP_SET => (0, Vec::new()),
+        RESPONSE => return None,
+        _ => (UNSUPPORTED, Vec::new()),
+    };
+    let mut response = Vec::new();
+    response.extend(0u32.to_be_bytes());
+    response.extend(error.to_be_bytes());
+    response.extend(0u16.to_be_bytes());
+    for (oid, data) in &varbinds {
+        response.extend(data.tag().to_be_bytes());
+        response.extend([0, 0]);
+        write_oid(&mut response, oid, false);
+        match data {
+            Data::OctetString(text) => write_octets(&mut response, text.as_bytes()),
+            Data::Gauge32(value) => response.extend(value.to_be_bytes()),
+            Data::Counter64(value) => response.extend(value.to_be_bytes()),
+            Data::NoSuchObject | Data::EndOfMibView => (),
+        }
+    }
+    Some(packet(
+        RESPONSE,
+        header.session_id,
+        header.transaction_id,
+        header.packet_id,
+        &response,
+    ))
+}
+
+/// First object after `start`, or at it when included, before `end` unless
+/// null.
+fn next(mib: &[(Oid, Data)], start: Oid, include: bool, end: &[u32]) -> (Oid, Data) {
+    mib.iter()
+        .find(|(oid, _)| {
+            (*oid > start || (include && *oid == start)) && (end.is_empty() || oid.as_slice() < end)
+        })
+        .map_or((start, Data::EndOfMibView), |(oid, data)| {
+            (oid.clone(), data.clone())
+        })
+}
+
+/// Search ranges of a request: start, whether it is included, and end.
+fn ranges(reader: &mut Reader) -> Vec<(Oid, bool, Oid)> {
+    let mut ranges = Vec::new();
+    while let Some((start, include)) = reader.oid() {
+        let Some((end, _)) = reader.oid() else {
+            break;
+        };
+        ranges.push((start, include, end));
+    }
+    ranges
+}
+
+fn write_oid(bytes: &mut Vec<u8>, oid: &[u32], include: bool) {
+    bytes.extend([oid.len() as u8, 0, include as u8, 0]);
+    for subid in oid {
+        bytes.extend(subid.to_be_bytes());
+    }
+}
+
+fn write_octets(bytes: &mut Vec<u8>, octets: &[u8]) {
+    bytes.extend((octets.len() as u32).to_be_bytes());
+    bytes.extend(octets);
+    bytes.resize(bytes.len() + (4 - octets.len() % 4) % 4, 0);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], big_endian: bool) -> Reader<'a> {
+        Reader { bytes, big_endian }
+    }
+
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    /// OID and its include flag, expanding the `1.3.6.1.<prefix>` prefix.
+    fn oid(&mut self) -> Option<(Oid, bool)> {
+        let header = self.take(4)?;
+        let (count, prefix, include) = (header[0], header[1], header[2] != 0);
+        let mut oid = match prefix {
+            0 => Vec::new(),
+            prefix => vec![1, 3, 6, 1, u32::from(prefix)],
+        };
+        for _ in 0..count {
+            oid.push(self.u32()?);
+        }
+        Some((oid, include))
+    }
+
+    fn octets(&mut self) -> Option<&'a [u8]> {
+        let length = self.u32()? as usize;
+        let octets = self.take(length)?;
+        self.take((4 - length % 4) % 4)?;
+        Some(octets)
+    }
+}
+
+fn parse_oid(text: &str) -> Option<Oid> {
+    let oid: Option<Oid> = text
+        .trim_start_matches('.')
+        .split('.')
+        .map(|subid| subid.parse().ok())
+        .collect();
+    oid.filter(|oid| oid.len() >= 2 && oid.len() <= 100)
+}
+
+fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(|subid| format!(".{}", subid)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::{DayColor, HourlyTarifPeriod, TarifPeriod};
+
+    #[test]
+    fn agentx_requests() {
+        let base = parse_oid("1.3.6.1.4.1.8072.9999.9999.1").unwrap();
+        assert_eq!(format_oid(&base), ".1.3.6.1.4.1.8072.9999.9999.1");
+        let mut state = MeterState::default();
+        state.update(&Message::ApparentPower { value: 803 });
+        state.update(&Message::Index {
+            period: TarifPeriod {
+                hour: HourlyTarifPeriod::PeakHours,
+                day_color: Some(DayColor::Blue),
+            },
+            value: 23916830,
+        });
+        state.update(&Message::InstantaneousPower { phase: 1, value: 3 });
+        let mib = objects(&base, Some("031762120110"), &state);
+        let oid = |suffix: &[u32]| -> Oid { base.iter().chain(suffix).copied().collect() };
+        assert_eq!(
+            mib.iter().map(|(oid, _)| oid.clone()).collect::<Vec<_>>(),
+            vec![
+                oid(&[1, 0]),
+                oid(&[4, 0]),
+                oid(&[5, 1, 2, 2]),
+                oid(&[5, 1, 3, 2]),
+                oid(&[6, 1, 2, 1])
+            ]
+        );
+
+        // GetNext from the start of the subtree, with the compressed prefix
+        let mut request = Vec::new();
+        request.extend([5, 4, 0, 0]);
+        for subid in &base[5..] {
+            request.extend(subid.to_le_bytes());
+        }
+        request.extend([0, 0, 0, 0]);
+        let header = Header {
+            kind: GET_NEXT,
+            flags: 0,
+            big_endian: false,
+            session_id: 7,
+            transaction_id: 8,
+            packet_id: 9,
+        };
+        let response = answer(&header, &request, &mib).unwrap();
+        let mut reader = Reader::new(&response[4..], true);
+        assert_eq!(reader.u32(), Some(7));
+        assert_eq!(reader.u32(), Some(8));
+        assert_eq!(reader.u32(), Some(9));
+        reader.u32();
+        assert_eq!(reader.u32(), Some(0));
+        assert_eq!(reader.u16(), Some(0));
+        assert_eq!(reader.u16(), Some(0));
+        assert_eq!(reader.u16(), Some(4));
+        reader.u16();
+        assert_eq!(reader.oid(), Some((oid(&[1, 0]), false)));
+        assert_eq!(reader.octets(), Some(&b"031762120110"[..]));
+
+        assert_eq!(
+            next(&mib, oid(&[6, 1, 2, 1]), false, &[]),
+            (oid(&[6, 1, 2, 1]), Data::EndOfMibView)
+        );
+        assert_eq!(
+            next(&mib, oid(&[5]), false, &oid(&[5, 1, 3])),
+            (
+                oid(&[5, 1, 2, 2]),
+                Data::OctetString(String::from("BBRHPJB"))
+            )
+        );
+    }
+}
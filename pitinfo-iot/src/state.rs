@@ -0,0 +1,460 @@
+use pitinfo_parser::{
+    DayColor, HHPHCValue, Horodate, HourlyTarifPeriod, Message, MeterStatus, TarifPeriod,
+    TariffOptionValue,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Label of the derived value telling whether the previous frame had groups
+/// that could not be parsed, 1, or not, 0.
+pub const NON_NOMINAL_LABEL: &str = "NON_NOMINAL";
+
+/// Value of a group, as sent by the meter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(u64),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Item handed to the sinks: a message of the meter, or a value the daemon
+/// derived from the groups of a frame, which the meter never sends.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Update {
+    Meter(Message),
+    Derived { label: String, value: i64 },
+}
+
+impl Update {
+    /// Label and value of the reading, see `label_value`.
+    pub fn label_value(&self) -> Option<(String, Value)> {
+        match self {
+            Update::Meter(message) => label_value(message),
+            // Negative values are not expected from meter readings
+            Update::Derived { label, value } => {
+                Some((label.clone(), Value::Integer((*value).max(0) as u64)))
+            }
+        }
+    }
+}
+
+impl From<Message> for Update {
+    fn from(message: Message) -> Update {
+        Update::Meter(message)
+    }
+}
+
+/// Latest known value of every group received from the meter.
+#[derive(Debug, Default)]
+pub struct MeterState {
+    pub tariff_option: Option<TariffOptionValue>,
+    /// Current subscribed, in A
+    pub subscribed_current: Option<u8>,
+    pub current_period: Option<TarifPeriod>,
+    /// `Some(None)` when the meter announced that tomorrow's color is not known yet.
+    pub tomorrow: Option<Option<DayColor>>,
+    pub instantaneous_current: [Option<u8>; 3],
+    pub max_current: [Option<u16>; 3],
+    /// Highest three-phase power, in W
+    pub max_power: Option<u32>,
+    /// Presence of the voltage of each phase, three-phase meters only
+    pub phases: Option<[bool; 3]>,
+    /// Status word of the meter, from MOTDETAT
+    pub status: Option<MeterStatus>,
+    pub apparent_power: Option<u16>,
+    /// Tempo indexes in Wh, see `index_slot` for the ordering.
+    pub indexes: [Option<u32>; 6],
+    /// HCHC and HCHP indexes in Wh, of the off-peak hours option
+    pub off_peak_indexes: [Option<u32>; 2],
+    pub hhphc: Option<HHPHCValue>,
+    /// Notice of an EJP peak day in minutes, during the current frame only
+    pub ejp_notice: Option<u8>,
+    /// Time of the current frame, according to the meter
+    pub date: Option<Horodate>,
+    /// Groups of standard mode
+    pub voltage: [Option<u16>; 3],
+    pub supplied_energy: Option<u32>,
+    pub injected_energy: Option<u32>,
+    pub injected_power: Option<u16>,
+    /// Identifier of the delivery point, from PRM
+    pub delivery_point: Option<String>,
+    /// Whether the current frame had groups that could not be parsed, as
+    /// sent by meters in test or maintenance mode
+    pub unusual: bool,
+    /// Metrics derived from the groups, by label
+    pub derived: BTreeMap<String, i64>,
+}
+
+impl MeterState {
+    pub fn update(&mut self, message: &Message) {
+        match message {
+            // PEJP is only sent during the notice
+            Message::MeterAddress(_) => {
+                self.ejp_notice = None;
+                self.date = None;
+                self.unusual = false;
+            }
+            Message::DeliveryPoint(point) => self.delivery_point = Some(point.clone()),
+            Message::TariffOption(option) => self.tariff_option = Some(*option),
+            Message::SubscribedCurrent { value } => self.subscribed_current = Some(*value),
+            Message::Tomorrow(color) => self.tomorrow = Some(*color),
+            Message::InstantaneousPower { phase, value } => {
+                if (1..=3).contains(phase) {
+                    self.instantaneous_current[*phase as usize - 1] = Some(*value);
+                }
+            }
+            Message::MaxCurrent { phase, value } => {
+                if (1..=3).contains(phase) {
+                    self.max_current[*phase as usize - 1] = Some(*value);
+                }
+            }
+            Message::Index { period, value } => match index_slot(period) {
+                Some(slot) => self.indexes[slot] = Some(*value),
+                None => self.off_peak_indexes[hour_slot(period.hour)] = Some(*value),
+            },
+            Message::MaxPower { value } => self.max_power = Some(*value),
+            Message::MeterStatus(status) => self.status = Some(*status),
+            Message::PhasePotential {
+                phase1,
+                phase2,
+                phase3,
+            } => self.phases = Some([*phase1, *phase2, *phase3]),
+            Message::ApparentPower { value } => self.apparent_power = Some(*value),
+            Message::HHPHC(value) => self.hhphc = Some(*value),
+            Message::CurrentTariffPeriod(period) => self.current_period = Some(*period),
+            Message::EJPNotice { minutes } => self.ejp_notice = Some(*minutes),
+            Message::Date(horodate) => self.date = Some(*horodate),
+            Message::Voltage { phase, value } => {
+                if (1..=3).contains(phase) {
+                    self.voltage[*phase as usize - 1] = Some(*value);
+                }
+            }
+            Message::SuppliedEnergy { value } => self.supplied_energy = Some(*value),
+            Message::InjectedEnergy { value } => self.injected_energy = Some(*value),
+            Message::InjectedPower { value } => self.injected_power = Some(*value),
+            Message::SupplierIndex { index, value } => {
+                if let Some(period) = self.supplier_period(*index) {
+                    self.update(&Message::Index {
+                        period,
+                        value: *value,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Updates the state, keeping the derived values by label.
+    pub fn record(&mut self, update: &Update) {
+        match update {
+            Update::Meter(message) => self.update(message),
+            Update::Derived { label, value } => {
+                self.derived.insert(label.clone(), *value);
+            }
+        }
+    }
+
+    /// Period of an index of the supplier calendar, once the tariff option
+    /// is known.
+    pub fn supplier_period(&self, index: u8) -> Option<TarifPeriod> {
+        self.tariff_option?.supplier_period(index)
+    }
+
+    /// Flags the current frame as non-nominal.
+    pub fn flag_unusual(&mut self) {
+        self.unusual = true;
+    }
+
+    /// Label and value of every group received so far.
+    pub fn values(&self) -> Vec<(String, Value)> {
+        let mut messages = Vec::new();
+        if let Some(option) = self.tariff_option {
+            messages.push(Message::TariffOption(option));
+        }
+        if let Some(value) = self.subscribed_current {
+            messages.push(Message::SubscribedCurrent { value });
+        }
+        for (slot, index) in self.indexes.iter().enumerate() {
+            if let Some(value) = index {
+                messages.push(Message::Index {
+                    period: slot_period(slot),
+                    value: *value,
+                });
+            }
+        }
+        for (slot, index) in self.off_peak_indexes.iter().enumerate() {
+            if let Some(value) = index {
+                messages.push(Message::Index {
+                    period: TarifPeriod {
+                        hour: slot_period(slot).hour,
+                        day_color: None,
+                    },
+                    value: *value,
+                });
+            }
+        }
+        if let Some(period) = self.current_period {
+            messages.push(Message::CurrentTariffPeriod(period));
+        }
+        if let Some(color) = self.tomorrow {
+            messages.push(Message::Tomorrow(color));
+        }
+        for (phase, current) in self.instantaneous_current.iter().enumerate() {
+            if let Some(value) = current {
+                messages.push(Message::InstantaneousPower {
+                    phase: phase as u8 + 1,
+                    value: *value,
+                });
+            }
+        }
+        for (phase, current) in self.max_current.iter().enumerate() {
+            if let Some(value) = current {
+                messages.push(Message::MaxCurrent {
+                    phase: phase as u8 + 1,
+                    value: *value,
+                });
+            }
+        }
+        if let Some(value) = self.max_power {
+            messages.push(Message::MaxPower { value });
+        }
+        if let Some([phase1, phase2, phase3]) = self.phases {
+            messages.push(Message::PhasePotential {
+                phase1,
+                phase2,
+                phase3,
+            });
+        }
+        if let Some(status) = self.status {
+            messages.push(Message::MeterStatus(status));
+        }
+        if let Some(value) = self.apparent_power {
+            messages.push(Message::ApparentPower { value });
+        }
+        if let Some(value) = self.hhphc {
+            messages.push(Message::HHPHC(value));
+        }
+        if let Some(minutes) = self.ejp_notice {
+            messages.push(Message::EJPNotice { minutes });
+        }
+        for (phase, voltage) in self.voltage.iter().enumerate() {
+            if let Some(value) = voltage {
+                messages.push(Message::Voltage {
+                    phase: phase as u8 + 1,
+                    value: *value,
+                });
+            }
+        }
+        if let Some(value) = self.supplied_energy {
+            messages.push(Message::SuppliedEnergy { value });
+        }
+        if let Some(value) = self.injected_energy {
+            messages.push(Message::InjectedEnergy { value });
+        }
+        if let Some(value) = self.injected_power {
+            messages.push(Message::InjectedPower { value });
+        }
+        let derived = self.derived.iter().map(|(label, value)| Update::Derived {
+            label: label.clone(),
+            value: *value,
+        });
+
+        messages
+            .into_iter()
+            .map(Update::Meter)
+            .chain(derived)
+            .filter_map(|update| update.label_value())
+            .collect()
+    }
+}
+
+/// Label and value of the group a message was parsed from.
+pub fn label_value(message: &Message) -> Option<(String, Value)> {
+    match message {
+        // Frame metadata, not a measure
+        Message::MeterAddress(_) | Message::DeliveryPoint(_) | Message::Date(_) => None,
+        // Published as the index of its period, see `MeterState::supplier_period`
+        Message::SupplierIndex { .. } => None,
+        Message::TariffOption(option) => {
+            let value = match option {
+                TariffOptionValue::Base => "BASE",
+                TariffOptionValue::OffPeakHours => "HC..",
+                TariffOptionValue::EJP => "EJP.",
+                TariffOptionValue::Tempo => "BBR",
+            };
+            Some(("OPTARIF".into(), Value::Text(value.into())))
+        }
+        Message::SubscribedCurrent { value } => {
+            Some(("ISOUSC".into(), Value::Integer(*value as u64)))
+        }
+        Message::Tomorrow(color) => {
+            let value = match color {
+                None => "----",
+                Some(DayColor::Blue) => "BLEU",
+                Some(DayColor::White) => "BLAN",
+                Some(DayColor::Red) => "ROUG",
+            };
+            Some(("DEMAIN".into(), Value::Text(value.into())))
+        }
+        Message::InstantaneousPower { phase, value } => {
+            Some((format!("IINST{}", phase), Value::Integer(*value as u64)))
+        }
+        Message::MaxCurrent { phase, value } => {
+            Some((format!("IMAX{}", phase), Value::Integer(*value as u64)))
+        }
+        Message::MaxPower { value } => Some(("PMAX".into(), Value::Integer(*value as u64))),
+        // As sent, bits 1 to 3 set for the missing phases
+        Message::PhasePotential {
+            phase1,
+            phase2,
+            phase3,
+        } => {
+            let bits = (!phase1 as u64) << 1 | (!phase2 as u64) << 2 | (!phase3 as u64) << 3;
+            Some(("PPOT".into(), Value::Integer(bits)))
+        }
+        Message::MeterStatus(status) => Some(("MOTDETAT".into(), Value::Text(status.to_string()))),
+        Message::Index { period, value } => {
+            Some((index_label(period), Value::Integer(*value as u64)))
+        }
+        Message::ApparentPower { value } => Some(("PAPP".into(), Value::Integer(*value as u64))),
+        Message::HHPHC(value) => {
+            let value = match value {
+                HHPHCValue::A => "A",
+                HHPHCValue::C => "C",
+                HHPHCValue::D => "D",
+                HHPHCValue::E => "E",
+                HHPHCValue::Y => "Y",
+            };
+            Some(("HHPHC".into(), Value::Text(value.into())))
+        }
+        Message::CurrentTariffPeriod(period) => {
+            Some(("PTEC".into(), Value::Text(period_code(period))))
+        }
+        Message::EJPNotice { minutes } => Some(("PEJP".into(), Value::Integer(*minutes as u64))),
+        Message::Voltage { phase, value } => {
+            Some((format!("URMS{}", phase), Value::Integer(*value as u64)))
+        }
+        Message::SuppliedEnergy { value } => Some(("EAST".into(), Value::Integer(*value as u64))),
+        Message::InjectedEnergy { value } => Some(("EAIT".into(), Value::Integer(*value as u64))),
+        Message::InjectedPower { value } => Some(("SINSTI".into(), Value::Integer(*value as u64))),
+    }
+}
+
+/// Period as written in PTEC, e.g. `HPJR`, or `HC..` without day color.
+pub fn period_code(period: &TarifPeriod) -> String {
+    let hour = match period.hour {
+        HourlyTarifPeriod::OffPeakHours => 'C',
+        HourlyTarifPeriod::PeakHours => 'P',
+    };
+    match period.day_color {
+        Some(color) => format!("H{}J{}", hour, color_letter(color)),
+        None => format!("H{}..", hour),
+    }
+}
+
+/// Whether a label is an index, in Wh.
+pub fn is_index(label: &str) -> bool {
+    label.starts_with("BBR") || label.starts_with("HCH")
+}
+
+/// Label of the index group of a period, e.g. `BBRHCJB`.
+pub fn index_label(period: &TarifPeriod) -> String {
+    let code = period_code(period);
+    match period.day_color {
+        Some(_) => format!("BBR{}", code),
+        None => format!("HCH{}", &code[1..2]),
+    }
+}
+
+/// Tariff period of an index label, the reverse of `index_label`.
+pub fn index_period(label: &str) -> Option<TarifPeriod> {
+    let (hour, color) = match label.strip_prefix("BBRH") {
+        Some(code) => {
+            let mut chars = code.chars();
+            (chars.next()?, Some(chars.nth(1)?))
+        }
+        None => (label.strip_prefix("HCH")?.chars().next()?, None),
+    };
+    let hour = match hour {
+        'C' => HourlyTarifPeriod::OffPeakHours,
+        'P' => HourlyTarifPeriod::PeakHours,
+        _ => return None,
+    };
+    let day_color = match color {
+        Some('B') => Some(DayColor::Blue),
+        Some('W') => Some(DayColor::White),
+        Some('R') => Some(DayColor::Red),
+        Some(_) => return None,
+        None => None,
+    };
+    Some(TarifPeriod { hour, day_color })
+}
+
+fn color_letter(color: DayColor) -> char {
+    match color {
+        DayColor::Blue => 'B',
+        DayColor::White => 'W',
+        DayColor::Red => 'R',
+    }
+}
+
+fn slot_period(slot: usize) -> TarifPeriod {
+    TarifPeriod {
+        hour: if slot.is_multiple_of(2) {
+            HourlyTarifPeriod::OffPeakHours
+        } else {
+            HourlyTarifPeriod::PeakHours
+        },
+        day_color: Some(match slot / 2 {
+            0 => DayColor::Blue,
+            1 => DayColor::White,
+            _ => DayColor::Red,
+        }),
+    }
+}
+
+/// Position of a tariff period in `MeterState::indexes`:
+/// HCJB, HPJB, HCJW, HPJW, HCJR, HPJR.
+pub fn index_slot(period: &TarifPeriod) -> Option<usize> {
+    let color = match period.day_color? {
+        DayColor::Blue => 0,
+        DayColor::White => 1,
+        DayColor::Red => 2,
+    };
+    Some(color * 2 + hour_slot(period.hour))
+}
+
+/// Position of an hourly period in the indexes of a day color, off-peak
+/// hours first.
+fn hour_slot(hour: HourlyTarifPeriod) -> usize {
+    match hour {
+        HourlyTarifPeriod::OffPeakHours => 0,
+        HourlyTarifPeriod::PeakHours => 1,
+    }
+}
+
+/// Numeric code of a day color shared by the integrations:
+/// 0 none or not known yet, 1 blue, 2 white, 3 red.
+pub fn day_color_code(color: Option<DayColor>) -> u8 {
+    match color {
+        None => 0,
+        Some(DayColor::Blue) => 1,
+        Some(DayColor::White) => 2,
+        Some(DayColor::Red) => 3,
+    }
+}
+
+/// Numeric code of an hourly period: 1 off-peak, 2 peak.
+pub fn hour_code(hour: HourlyTarifPeriod) -> u8 {
+    match hour {
+        HourlyTarifPeriod::OffPeakHours => 1,
+        HourlyTarifPeriod::PeakHours => 2,
+    }
+}
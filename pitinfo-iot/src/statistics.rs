@@ -0,0 +1,260 @@
+//! Import of the stored history into the long-term statistics of Home
+//! Assistant.
+//!
+//! The Energy dashboard only knows the consumption recorded while Home
+//! Assistant received the indexes. After an outage of Home Assistant or of
+//! the broker, the hours missed are imported from the stored history through
+//! the `recorder/import_statistics` command of the WebSocket API, as external
+//! statistics, one per index, to select in the Energy dashboard.
+//!
+//! The statistics of an hour hold the last index of the hour, both as state
+//! and sum, so importing the same hours again overwrites them.
+
+use crate::config::HomeAssistantConfig;
+use crate::state::{is_index, Value};
+use crate::storage::{Resolution, Store};
+use chrono::{DateTime, DurationRound, NaiveDate, TimeDelta, Utc};
+use serde_json::{json, Value as Json};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::TcpStream;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+/// Source of the external statistics, the domain of their ids.
+const SOURCE: &str = "pitinfo";
+
+/// Last value of each index per hour, keyed by label then by start of hour.
+type HourlyIndexes = BTreeMap<String, BTreeMap<DateTime<Utc>, i64>>;
+
+/// Client of the WebSocket API, authenticated.
+pub struct Client {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    device_name: String,
+    /// Id of the last command sent
+    id: u64,
+}
+
+impl Client {
+    pub fn connect(config: &HomeAssistantConfig) -> Result<Client, Box<dyn Error>> {
+        let (socket, _) = tungstenite::connect(config.url.as_str())?;
+        let mut client = Client {
+            socket,
+            device_name: config.device_name.clone(),
+            id: 0,
+        };
+        client.expect("auth_required")?;
+        client.send(json!({"type": "auth", "access_token": config.token}))?;
+        let reply = client.receive()?;
+        match reply["type"].as_str() {
+            Some("auth_ok") => Ok(client),
+            _ => Err(format!(
+                "Home Assistant authentication failed: {}",
+                reply["message"].as_str().unwrap_or("unexpected reply")
+            )
+            .into()),
+        }
+    }
+
+    /// Imports the hourly statistics of an index.
+    pub fn import(
+        &mut self,
+        label: &str,
+        hours: &BTreeMap<DateTime<Utc>, i64>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.id += 1;
+        let command = json!({
+            "id": self.id,
+            "type": "recorder/import_statistics",
+            "metadata": metadata(&self.device_name, label),
+            "stats": stats(hours),
+        });
+        self.send(command)?;
+        loop {
+            let reply = self.receive()?;
+            if reply["id"].as_u64() != Some(self.id) {
+                continue;
+            }
+            return match reply["success"].as_bool() {
+                Some(true) => Ok(()),
+                _ => Err(format!(
+                    "Home Assistant refused the statistics of {}: {}",
+                    label, reply["error"]["message"]
+                )
+                .into()),
+            };
+        }
+    }
+
+    fn send(&mut self, message: Json) -> Result<(), Box<dyn Error>> {
+        self.socket.send(Message::Text(message.to_string()))?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Json, Box<dyn Error>> {
+        loop {
+            match self.socket.read()? {
+                Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+                Message::Close(_) => return Err("Home Assistant closed the connection".into()),
+                _ => (),
+            }
+        }
+    }
+
+    fn expect(&mut self, kind: &str) -> Result<(), Box<dyn Error>> {
+        let message = self.receive()?;
+        if message["type"].as_str() == Some(kind) {
+            Ok(())
+        } else {
+            Err(format!("expected {} from Home Assistant, got {}", kind, message).into())
+        }
+    }
+}
+
+/// Imports the days from `from`, or the whole history, returning the number
+/// of hourly statistics imported.
+pub fn backfill(
+    store: &Store,
+    client: &mut Client,
+    from: Option<NaiveDate>,
+) -> Result<usize, Box<dyn Error>> {
+    let mut days = store.history_days()?;
+    days.retain(|day| from.is_none_or(|from| *day >= from));
+    let mut total = 0;
+    for (done, day) in days.iter().enumerate() {
+        let readings = store.day_readings(*day)?;
+        let indexes = if readings.is_empty() {
+            let aggregates = store.aggregates(
+                Resolution::Minute,
+                store.day_start(*day).timestamp_millis(),
+                day_end(store, *day).timestamp_millis(),
+            )?;
+            hourly(
+                aggregates
+                    .iter()
+                    .filter_map(|(timestamp, label, aggregate)| {
+                        Some((*timestamp, label.as_str(), aggregate.last?))
+                    }),
+            )
+        } else {
+            hourly(readings.iter().filter_map(|reading| match reading.value {
+                Value::Integer(value) => {
+                    Some((reading.timestamp, reading.label.as_str(), value as i64))
+                }
+                Value::Text(_) => None,
+            }))
+        };
+        let mut count = 0;
+        for (label, hours) in &indexes {
+            client.import(label, hours)?;
+            count += hours.len();
+        }
+        println!(
+            "{}: {} hourly statistics ({}/{} days)",
+            day,
+            count,
+            done + 1,
+            days.len()
+        );
+        total += count;
+    }
+    Ok(total)
+}
+
+fn day_end(store: &Store, day: NaiveDate) -> DateTime<Utc> {
+    store.day_start(day.succ_opt().unwrap_or(day))
+}
+
+/// Keeps the last value of each index per hour, from values in chronological
+/// order.
+fn hourly<'a>(values: impl Iterator<Item = (DateTime<Utc>, &'a str, i64)>) -> HourlyIndexes {
+    let mut indexes = HourlyIndexes::new();
+    for (timestamp, label, value) in values {
+        if !is_index(label) {
+            continue;
+        }
+        let Ok(hour) = timestamp.duration_trunc(TimeDelta::hours(1)) else {
+            continue;
+        };
+        indexes
+            .entry(label.to_string())
+            .or_default()
+            .insert(hour, value);
+    }
+    indexes
+}
+
+/// Id of the statistic of an index, e.g. `pitinfo:teleinfo_bbrhcjb`.
+fn statistic_id(device_name: &str, label: &str) -> String {
+    let object_id: String = format!("{}_{}", device_name, label)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}:{}", SOURCE, object_id)
+}
+
+fn metadata(device_name: &str, label: &str) -> Json {
+    json!({
+        "statistic_id": statistic_id(device_name, label),
+        "source": SOURCE,
+        "name": format!("{} {}", device_name, label),
+        "unit_of_measurement": "Wh",
+        "has_mean": false,
+        "has_sum": true,
+    })
+}
+
+fn stats(hours: &BTreeMap<DateTime<Utc>, i64>) -> Vec<Json> {
+    hours
+        .iter()
+        .map(|(start, index)| {
+            json!({
+                "start": start.to_rfc3339(),
+                "state": index,
+                "sum": index,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn hourly_statistics() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 16, 12, 0, 0).unwrap();
+        let at = |minutes| start + Duration::minutes(minutes);
+        let indexes = hourly(
+            vec![
+                (at(0), "BBRHCJB", 1000),
+                (at(0), "PAPP", 800),
+                (at(30), "BBRHCJB", 1200),
+                (at(59), "BBRHCJB", 1400),
+                (at(60), "BBRHCJB", 1410),
+                (at(61), "BBRHPJB", 500),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(indexes.len(), 2);
+        assert_eq!(
+            indexes["BBRHCJB"].iter().collect::<Vec<_>>(),
+            vec![(&start, &1400), (&at(60), &1410)]
+        );
+        assert_eq!(
+            stats(&indexes["BBRHPJB"]),
+            vec![json!({"start": "2024-01-16T13:00:00+00:00", "state": 500, "sum": 500})]
+        );
+        assert_eq!(
+            statistic_id("Linky 1", "BBRHCJB"),
+            "pitinfo:linky_1_bbrhcjb"
+        );
+    }
+}
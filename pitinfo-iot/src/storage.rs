@@ -0,0 +1,974 @@
+//! Local history of the meter values in SQLite.
+//!
+//! A snapshot of every known value is stored at the start of a frame, at
+//! most once per configured interval. Timestamps are stored as milliseconds
+//! since the Unix epoch, UTC.
+//!
+//! Snapshots can be written in batches, and the whole database kept in memory
+//! and copied to its file periodically, to spare SD cards the constant small
+//! writes. The copies can be encrypted with age, the database is then only
+//! ever decrypted in memory.
+//!
+//! Raw readings are downsampled to 1-minute aggregates, themselves downsampled
+//! to daily aggregates, by a compaction job that also drops raw readings and
+//! minute aggregates past their retention. Daily aggregates are kept forever.
+//! Days run from midnight to midnight in the configured time zone, so they
+//! last 23 or 25 hours on daylight saving time changes.
+//!
+//! The energy of each index per day, with the day color and hourly period of
+//! the index, is accounted in the `daily_energy` table as days get aggregated,
+//! for costs and reports.
+
+use crate::clock::Stamper;
+use crate::config::{ClockConfig, RetentionConfig, StorageConfig, StorageSynchronous};
+use crate::pipeline::{self, Inbox, Sink};
+use crate::state::{index_period, period_code, MeterState, Update, Value};
+use age::x25519::Identity;
+use chrono::{DateTime, Duration as ChronoDuration, Months, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use pitinfo_parser::Message;
+use rusqlite::backup::Progress;
+use rusqlite::{params, Connection, OptionalExtension, MAIN_DB};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Time between two compactions of the database.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(3600);
+const MINUTE: i64 = 60_000;
+/// Start of the plain SQLite files.
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Values of the groups at a given time.
+pub type Snapshot = (DateTime<Utc>, Vec<(String, Value)>);
+
+/// Energy in Wh of a day color, if any, and period.
+pub type Energy = (Option<String>, String, i64);
+
+pub struct Store {
+    connection: Connection,
+    /// Time zone of the days
+    timezone: Tz,
+    /// Key of the encrypted copies
+    identity: Option<Identity>,
+}
+
+/// Resolution of aggregated readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Minute,
+    Day,
+}
+
+impl Resolution {
+    fn table(self) -> &'static str {
+        match self {
+            Resolution::Minute => "minute_readings",
+            Resolution::Day => "daily_readings",
+        }
+    }
+}
+
+/// Summary of the values of a group over a period. Numeric statistics are
+/// empty for text values.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Aggregate {
+    /// Number of raw readings
+    pub count: u32,
+    pub average: Option<f64>,
+    pub minimum: Option<i64>,
+    pub maximum: Option<i64>,
+    pub last: Option<i64>,
+    pub text: Option<String>,
+}
+
+impl Aggregate {
+    fn of(value: &Value) -> Aggregate {
+        match value {
+            Value::Integer(value) => Aggregate {
+                count: 1,
+                average: Some(*value as f64),
+                minimum: Some(*value as i64),
+                maximum: Some(*value as i64),
+                last: Some(*value as i64),
+                text: None,
+            },
+            Value::Text(text) => Aggregate {
+                count: 1,
+                text: Some(text.clone()),
+                ..Aggregate::default()
+            },
+        }
+    }
+
+    /// Adds the readings of a later period.
+    fn merge(&mut self, other: Aggregate) {
+        let count = self.count + other.count;
+        self.average = match (self.average, other.average) {
+            (Some(a), Some(b)) => {
+                Some((a * self.count as f64 + b * other.count as f64) / count as f64)
+            }
+            (a, b) => a.or(b),
+        };
+        self.minimum = self.minimum.into_iter().chain(other.minimum).min();
+        self.maximum = self.maximum.into_iter().chain(other.maximum).max();
+        self.last = other.last.or(self.last);
+        self.text = other.text.or(self.text.take());
+        self.count = count;
+    }
+}
+
+/// Value of a group at a given time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub timestamp: DateTime<Utc>,
+    pub label: String,
+    pub value: Value,
+}
+
+impl Store {
+    pub fn open(path: &Path, timezone: Tz) -> Result<Store, rusqlite::Error> {
+        Store::new(Connection::open(path)?, timezone)
+    }
+
+    /// Opens a copy in memory of the database, loaded from `path` when it
+    /// exists. Copies are encrypted when an identity is given, a plain
+    /// database is loaded as it is, to be encrypted by the next copy.
+    pub fn open_in_memory(
+        path: &Path,
+        timezone: Tz,
+        identity: Option<Identity>,
+    ) -> Result<Store, io::Error> {
+        let mut connection = Connection::open_in_memory().map_err(io::Error::other)?;
+        match &identity {
+            Some(identity) if path.exists() => {
+                let mut data = fs::read(path)?;
+                if !data.starts_with(SQLITE_HEADER) {
+                    data = age::decrypt(identity, &data).map_err(io::Error::other)?;
+                }
+                connection
+                    .deserialize_read_exact(MAIN_DB, &data[..], data.len(), false)
+                    .map_err(io::Error::other)?;
+            }
+            None if path.exists() => connection
+                .restore(MAIN_DB, path, None::<fn(Progress)>)
+                .map_err(io::Error::other)?,
+            _ => {}
+        }
+        let mut store = Store::new(connection, timezone).map_err(io::Error::other)?;
+        store.identity = identity;
+        Ok(store)
+    }
+
+    /// Opens the configured database, in memory when copied periodically.
+    pub fn load(config: &StorageConfig, timezone: Tz) -> Result<Store, io::Error> {
+        let identity = identity(config)?;
+        if config.snapshot_interval.is_some() {
+            Store::open_in_memory(&config.path, timezone, identity)
+        } else if identity.is_some() {
+            Err(io::Error::other(
+                "the encryption of the history requires a snapshot_interval",
+            ))
+        } else {
+            Store::open(&config.path, timezone).map_err(io::Error::other)
+        }
+    }
+
+    fn new(connection: Connection, timezone: Tz) -> Result<Store, rusqlite::Error> {
+        // Only effective on a new database, lets compactions give space back
+        connection.execute_batch("PRAGMA auto_vacuum = INCREMENTAL")?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS readings (
+                timestamp INTEGER NOT NULL,
+                label TEXT NOT NULL,
+                value INTEGER,
+                text TEXT
+            );
+            CREATE INDEX IF NOT EXISTS readings_timestamp ON readings (timestamp);",
+        )?;
+        for resolution in [Resolution::Minute, Resolution::Day] {
+            connection.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    timestamp INTEGER NOT NULL,
+                    label TEXT NOT NULL,
+                    count INTEGER NOT NULL,
+                    average REAL,
+                    minimum INTEGER,
+                    maximum INTEGER,
+                    last INTEGER,
+                    text TEXT,
+                    PRIMARY KEY (timestamp, label)
+                )",
+                resolution.table()
+            ))?;
+        }
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS daily_energy (
+                day TEXT NOT NULL,
+                label TEXT NOT NULL,
+                color TEXT,
+                period TEXT NOT NULL,
+                energy INTEGER NOT NULL,
+                PRIMARY KEY (day, label)
+            )",
+        )?;
+        Ok(Store {
+            connection,
+            timezone,
+            identity: None,
+        })
+    }
+
+    pub fn set_synchronous(&self, synchronous: StorageSynchronous) -> Result<(), rusqlite::Error> {
+        let value = match synchronous {
+            StorageSynchronous::Off => "OFF",
+            StorageSynchronous::Normal => "NORMAL",
+            StorageSynchronous::Full => "FULL",
+        };
+        self.connection.pragma_update(None, "synchronous", value)
+    }
+
+    /// Inserts snapshots in a single transaction.
+    pub fn insert_batch(&mut self, snapshots: &[Snapshot]) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO readings (timestamp, label, value, text) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (timestamp, values) in snapshots {
+                for (label, value) in values {
+                    let (integer, text) = match value {
+                        Value::Integer(value) => (Some(*value as i64), None),
+                        Value::Text(value) => (None, Some(value.as_str())),
+                    };
+                    statement.execute(params![
+                        timestamp.timestamp_millis(),
+                        label,
+                        integer,
+                        text
+                    ])?;
+                }
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Copies the database to `path`, encrypted when the store has an
+    /// identity. The copy is written aside then renamed, so a power cut never
+    /// leaves a truncated file.
+    pub fn snapshot(&self, path: &Path) -> Result<(), io::Error> {
+        let temporary = path.with_extension("tmp");
+        match &self.identity {
+            Some(identity) => {
+                let data = self
+                    .connection
+                    .serialize(MAIN_DB)
+                    .map_err(io::Error::other)?;
+                let encrypted =
+                    age::encrypt(&identity.to_public(), &data).map_err(io::Error::other)?;
+                fs::write(&temporary, encrypted)?;
+            }
+            None => self
+                .connection
+                .backup(MAIN_DB, &temporary, None)
+                .map_err(io::Error::other)?,
+        }
+        fs::rename(temporary, path)
+    }
+
+    /// Downsamples the readings and drops the ones past their retention.
+    pub fn compact(
+        &mut self,
+        now: DateTime<Utc>,
+        retention: &RetentionConfig,
+    ) -> Result<(), rusqlite::Error> {
+        let minute = now.timestamp_millis() / MINUTE * MINUTE;
+        let today = now.with_timezone(&self.timezone).date_naive();
+        self.aggregate_minutes(minute)?;
+        self.aggregate_days(today)?;
+        self.account_energy()?;
+
+        // Only aggregated readings are dropped
+        let raw_limit = now - ChronoDuration::days(retention.raw_days as i64);
+        let minute_limit = now
+            .checked_sub_months(Months::new(retention.minute_months))
+            .unwrap_or(now);
+        self.connection.execute(
+            "DELETE FROM readings WHERE timestamp < ?1",
+            [raw_limit.timestamp_millis().min(minute)],
+        )?;
+        self.connection.execute(
+            "DELETE FROM minute_readings WHERE timestamp < ?1",
+            [minute_limit.min(self.day_start(today)).timestamp_millis()],
+        )?;
+        self.connection.execute_batch("PRAGMA incremental_vacuum")
+    }
+
+    /// Aggregates the complete minutes not aggregated yet, before `until`.
+    fn aggregate_minutes(&mut self, until: i64) -> Result<(), rusqlite::Error> {
+        let mut from = match self.last_aggregate(Resolution::Minute)? {
+            Some(last) => last + MINUTE,
+            None => {
+                let first: Option<i64> = self.connection.query_row(
+                    "SELECT MIN(timestamp) FROM readings",
+                    [],
+                    |row| row.get(0),
+                )?;
+                match first {
+                    Some(first) => first / MINUTE * MINUTE,
+                    None => return Ok(()),
+                }
+            }
+        };
+        // One day at a time to bound the memory used after a long interruption
+        while from < until {
+            let to = until.min(from + 1440 * MINUTE);
+            let mut aggregates: BTreeMap<(i64, String), Aggregate> = BTreeMap::new();
+            for reading in self.readings(from, to)? {
+                let bucket = reading.timestamp.timestamp_millis() / MINUTE * MINUTE;
+                let aggregate = Aggregate::of(&reading.value);
+                match aggregates.get_mut(&(bucket, reading.label.clone())) {
+                    Some(existing) => existing.merge(aggregate),
+                    None => {
+                        aggregates.insert((bucket, reading.label), aggregate);
+                    }
+                }
+            }
+            self.insert_aggregates(Resolution::Minute, aggregates)?;
+            from = to;
+        }
+        Ok(())
+    }
+
+    /// Aggregates the minute aggregates of the days before `today`.
+    fn aggregate_days(&mut self, today: NaiveDate) -> Result<(), rusqlite::Error> {
+        let first = match self.last_aggregate(Resolution::Day)? {
+            Some(last) => self.local_day(last).and_then(|day| day.succ_opt()),
+            None => {
+                let first: Option<i64> = self.connection.query_row(
+                    "SELECT MIN(timestamp) FROM minute_readings",
+                    [],
+                    |row| row.get(0),
+                )?;
+                first.and_then(|first| self.local_day(first))
+            }
+        };
+        let first = match first {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        for day in first.iter_days().take_while(|day| *day < today) {
+            let end = day.succ_opt().unwrap_or(day);
+            let mut aggregates: BTreeMap<(i64, String), Aggregate> = BTreeMap::new();
+            let minutes = self.aggregates(
+                Resolution::Minute,
+                self.day_start(day).timestamp_millis(),
+                self.day_start(end).timestamp_millis(),
+            )?;
+            for (_, label, aggregate) in minutes {
+                let key = (self.day_start(day).timestamp_millis(), label);
+                match aggregates.get_mut(&key) {
+                    Some(existing) => existing.merge(aggregate),
+                    None => {
+                        aggregates.insert(key, aggregate);
+                    }
+                }
+            }
+            self.insert_aggregates(Resolution::Day, aggregates)?;
+        }
+        Ok(())
+    }
+
+    /// Accounts the energy of each index over the days aggregated since the
+    /// last accounted one, as the difference between its last values of the
+    /// day and of the day before. An index only moves during its period, so
+    /// the energy of a period is exact even when PTEC lags its changes. After
+    /// a gap in the history, the first value of the day is the baseline.
+    fn account_energy(&mut self) -> Result<(), rusqlite::Error> {
+        let last: Option<String> =
+            self.connection
+                .query_row("SELECT MAX(day) FROM daily_energy", [], |row| row.get(0))?;
+        let last = last.and_then(|day| day.parse::<NaiveDate>().ok());
+        // The last accounted day gives the baselines of the next one
+        let from = last.map_or(i64::MIN, |day| self.day_start(day).timestamp_millis());
+        let days = self.aggregates(Resolution::Day, from, i64::MAX)?;
+        let timezone = self.timezone;
+        let mut baselines: BTreeMap<String, (NaiveDate, i64)> = BTreeMap::new();
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO daily_energy (day, label, color, period, energy)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (timestamp, label, aggregate) in days {
+                let (Some(period), Some(first), Some(value)) =
+                    (index_period(&label), aggregate.minimum, aggregate.last)
+                else {
+                    continue;
+                };
+                let day = timestamp.with_timezone(&timezone).date_naive();
+                let baseline = match baselines.insert(label.clone(), (day, value)) {
+                    Some((previous, baseline)) if previous.succ_opt() == Some(day) => baseline,
+                    _ => first,
+                };
+                if last.is_some_and(|last| day <= last) {
+                    continue;
+                }
+                // e.g. HCJB, or HC.. without day color
+                let code = period_code(&period);
+                let color = Some(&code[3..]).filter(|color| *color != ".");
+                statement.execute(params![
+                    day.to_string(),
+                    label,
+                    color,
+                    &code[..2],
+                    (value - baseline).max(0)
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    fn last_aggregate(&self, resolution: Resolution) -> Result<Option<i64>, rusqlite::Error> {
+        self.connection
+            .query_row(
+                &format!("SELECT MAX(timestamp) FROM {}", resolution.table()),
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map(Option::flatten)
+    }
+
+    fn insert_aggregates(
+        &mut self,
+        resolution: Resolution,
+        aggregates: BTreeMap<(i64, String), Aggregate>,
+    ) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(&format!(
+                "INSERT OR REPLACE INTO {}
+                 (timestamp, label, count, average, minimum, maximum, last, text)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                resolution.table()
+            ))?;
+            for ((timestamp, label), aggregate) in aggregates {
+                statement.execute(params![
+                    timestamp,
+                    label,
+                    aggregate.count,
+                    aggregate.average,
+                    aggregate.minimum,
+                    aggregate.maximum,
+                    aggregate.last,
+                    aggregate.text
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Aggregates between two timestamps, in chronological order.
+    pub fn aggregates(
+        &self,
+        resolution: Resolution,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<(DateTime<Utc>, String, Aggregate)>, rusqlite::Error> {
+        let mut statement = self.connection.prepare_cached(&format!(
+            "SELECT timestamp, label, count, average, minimum, maximum, last, text FROM {}
+             WHERE timestamp >= ?1 AND timestamp < ?2 ORDER BY timestamp, label",
+            resolution.table()
+        ))?;
+        let rows = statement.query_map(params![from, to], |row| {
+            Ok((
+                utc(row.get(0)?),
+                row.get(1)?,
+                Aggregate {
+                    count: row.get(2)?,
+                    average: row.get(3)?,
+                    minimum: row.get(4)?,
+                    maximum: row.get(5)?,
+                    last: row.get(6)?,
+                    text: row.get(7)?,
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+
+    /// Local days with stored readings, oldest first.
+    #[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+    pub fn days(&self) -> Result<Vec<NaiveDate>, rusqlite::Error> {
+        self.days_between("SELECT MIN(timestamp), MAX(timestamp) FROM readings")
+    }
+
+    /// Local days with stored readings or minute aggregates, oldest first.
+    pub fn history_days(&self) -> Result<Vec<NaiveDate>, rusqlite::Error> {
+        self.days_between(
+            "SELECT MIN(timestamp), MAX(timestamp) FROM (
+                SELECT timestamp FROM readings UNION ALL SELECT timestamp FROM minute_readings
+            )",
+        )
+    }
+
+    fn days_between(&self, query: &str) -> Result<Vec<NaiveDate>, rusqlite::Error> {
+        let (first, last): (Option<i64>, Option<i64>) =
+            self.connection
+                .query_row(query, [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let (first, last) = match (
+            first.and_then(|first| self.local_day(first)),
+            last.and_then(|last| self.local_day(last)),
+        ) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(Vec::new()),
+        };
+        Ok(first.iter_days().take_while(|day| *day <= last).collect())
+    }
+
+    /// Readings of a local day, in chronological order.
+    pub fn day_readings(&self, day: NaiveDate) -> Result<Vec<Reading>, rusqlite::Error> {
+        let end = day.succ_opt().unwrap_or(day);
+        self.readings(
+            self.day_start(day).timestamp_millis(),
+            self.day_start(end).timestamp_millis(),
+        )
+    }
+
+    fn readings(&self, from: i64, to: i64) -> Result<Vec<Reading>, rusqlite::Error> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT timestamp, label, value, text FROM readings
+             WHERE timestamp >= ?1 AND timestamp < ?2 ORDER BY timestamp, rowid",
+        )?;
+        let rows = statement.query_map(params![from, to], |row| {
+            let integer: Option<i64> = row.get(2)?;
+            let text: Option<String> = row.get(3)?;
+            Ok(Reading {
+                timestamp: utc(row.get(0)?),
+                label: row.get(1)?,
+                value: match (integer, text) {
+                    (Some(value), _) => Value::Integer(value as u64),
+                    (None, text) => Value::Text(text.unwrap_or_default()),
+                },
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn local_day(&self, timestamp: i64) -> Option<NaiveDate> {
+        self.timezone
+            .timestamp_millis_opt(timestamp)
+            .single()
+            .map(|time| time.date_naive())
+    }
+
+    /// Energy in Wh of the local days from `first` to `last` included, per
+    /// day color and period, from the `daily_energy` table.
+    pub fn energy(
+        &self,
+        first: NaiveDate,
+        last: NaiveDate,
+    ) -> Result<Vec<Energy>, rusqlite::Error> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT color, period, SUM(energy) FROM daily_energy
+             WHERE day >= ?1 AND day <= ?2 GROUP BY color, period ORDER BY color, period",
+        )?;
+        let rows = statement.query_map(params![first.to_string(), last.to_string()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect()
+    }
+
+    /// Start of a local day.
+    pub fn day_start(&self, day: NaiveDate) -> DateTime<Utc> {
+        let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+        self.timezone
+            .from_local_datetime(&midnight)
+            .earliest()
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+    }
+}
+
+/// Secret key of the encrypted copies of the database, if configured.
+pub fn identity(config: &StorageConfig) -> Result<Option<Identity>, io::Error> {
+    let key = match (&config.identity, &config.identity_file) {
+        (Some(identity), _) => identity.clone(),
+        (None, Some(file)) => {
+            let file = match env::var_os("CREDENTIALS_DIRECTORY") {
+                Some(directory) if file.is_relative() => Path::new(&directory).join(file),
+                _ => file.clone(),
+            };
+            fs::read_to_string(file)?
+        }
+        (None, None) => return Ok(None),
+    };
+    // Keys generated by age-keygen follow comments
+    key.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .unwrap_or_default()
+        .parse()
+        .map(Some)
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid age identity: {}", e),
+            )
+        })
+}
+
+fn utc(timestamp: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(timestamp)
+        .single()
+        .unwrap_or_default()
+}
+
+/// Starts recording the meter values. Messages sent to the returned sink are
+/// stored in the configured database.
+pub fn spawn(config: &StorageConfig, clock: &ClockConfig) -> Result<Sink, io::Error> {
+    let store = Store::load(config, clock.timezone)?;
+    store
+        .set_synchronous(config.synchronous)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let config = config.clone();
+    let stamper = Stamper::new(clock.wait_for_sync);
+    Ok(pipeline::spawn_blocking_sink("storage", move |receiver| {
+        record(store, config, stamper, receiver)
+    }))
+}
+
+fn record(
+    mut store: Store,
+    config: StorageConfig,
+    mut stamper: Stamper<Vec<(String, Value)>>,
+    mut receiver: Inbox<Update>,
+) {
+    let interval = Duration::from_secs(config.interval);
+    let flush_interval = Duration::from_secs(config.flush_interval);
+    let snapshot_interval = config.snapshot_interval.map(Duration::from_secs);
+    let mut state = MeterState::default();
+    // Snapshots waiting to be written
+    let mut batch = Vec::new();
+    let mut last_insert: Option<Instant> = None;
+    let mut last_flush = Instant::now();
+    let mut last_snapshot = Instant::now();
+    let mut last_compaction: Option<Instant> = None;
+    while let Some(item) = receiver.blocking_recv() {
+        // A clock in the future would drop everything as past its retention,
+        // and values held back would miss their minute aggregates
+        if stamper.synchronized()
+            && !stamper.holding()
+            && last_compaction.is_none_or(|last| last.elapsed() >= COMPACTION_INTERVAL)
+        {
+            flush(&mut store, &mut batch);
+            if let Err(e) = store.compact(Utc::now(), &config.retention) {
+                eprintln!("Unable to compact the history: {}", e);
+            }
+            last_compaction = Some(Instant::now());
+        }
+        // Frames start with ADCO: the state of the previous frame is complete
+        if matches!(item, Update::Meter(Message::MeterAddress(_)))
+            && last_insert.is_none_or(|last| last.elapsed() >= interval)
+        {
+            let values = state.values();
+            if !values.is_empty() {
+                batch.extend(stamper.stamp(values));
+                last_insert = Some(Instant::now());
+            }
+        }
+        state.record(&item);
+
+        if last_flush.elapsed() >= flush_interval {
+            flush(&mut store, &mut batch);
+            last_flush = Instant::now();
+        }
+        if snapshot_interval
+            .is_some_and(|snapshot_interval| last_snapshot.elapsed() >= snapshot_interval)
+        {
+            save(&store, &config.path);
+            last_snapshot = Instant::now();
+        }
+    }
+    // Stopping, nothing waits anymore
+    flush(&mut store, &mut batch);
+    if snapshot_interval.is_some() {
+        save(&store, &config.path);
+    }
+}
+
+fn flush(store: &mut Store, batch: &mut Vec<Snapshot>) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = store.insert_batch(batch) {
+        eprintln!("Unable to store the meter values: {}", e);
+    }
+    batch.clear();
+}
+
+fn save(store: &Store, path: &Path) {
+    if let Err(e) = store.snapshot(path) {
+        eprintln!("Unable to copy the history to {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Paris;
+
+    #[test]
+    fn store_readings() {
+        let mut store = Store::open(Path::new(":memory:"), Paris).unwrap();
+        assert_eq!(store.days().unwrap(), Vec::new());
+
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        let timestamp = store.day_start(day) + chrono::Duration::hours(12);
+        store
+            .insert_batch(&[(
+                timestamp,
+                vec![
+                    (String::from("PAPP"), Value::Integer(803)),
+                    (String::from("PTEC"), Value::Text(String::from("HCJB"))),
+                ],
+            )])
+            .unwrap();
+
+        assert_eq!(store.days().unwrap(), vec![day]);
+        let readings = store.day_readings(day).unwrap();
+        assert_eq!(readings.len(), 2);
+        assert_eq!(
+            readings[1],
+            Reading {
+                timestamp,
+                label: String::from("PTEC"),
+                value: Value::Text(String::from("HCJB")),
+            }
+        );
+        assert!(store
+            .day_readings(day.succ_opt().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn compaction() {
+        let mut store = Store::open(Path::new(":memory:"), Paris).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        let start = store.day_start(day) + chrono::Duration::hours(12);
+        for (seconds, power) in [(0, 800), (10, 1000), (20, 1200), (60, 500)] {
+            store
+                .insert_batch(&[(
+                    start + chrono::Duration::seconds(seconds),
+                    vec![
+                        (String::from("PAPP"), Value::Integer(power)),
+                        (String::from("PTEC"), Value::Text(String::from("HCJB"))),
+                    ],
+                )])
+                .unwrap();
+        }
+        let retention = RetentionConfig {
+            raw_days: 1,
+            minute_months: 1,
+        };
+
+        // Only complete minutes are aggregated
+        store
+            .compact(start + chrono::Duration::seconds(90), &retention)
+            .unwrap();
+        let minutes = store.aggregates(Resolution::Minute, 0, i64::MAX).unwrap();
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(
+            minutes[0],
+            (
+                start,
+                String::from("PAPP"),
+                Aggregate {
+                    count: 3,
+                    average: Some(1000.0),
+                    minimum: Some(800),
+                    maximum: Some(1200),
+                    last: Some(1200),
+                    text: None,
+                }
+            )
+        );
+
+        store
+            .compact(start + chrono::Duration::days(2), &retention)
+            .unwrap();
+        assert!(store.day_readings(day).unwrap().is_empty());
+        let days = store.aggregates(Resolution::Day, 0, i64::MAX).unwrap();
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].0, store.day_start(day));
+        assert_eq!(days[0].2.count, 4);
+        assert_eq!(days[0].2.average, Some(875.0));
+        assert_eq!(days[0].2.last, Some(500));
+        assert_eq!(days[1].2.text.as_deref(), Some("HCJB"));
+    }
+
+    #[test]
+    fn energy_accounting() {
+        let mut store = Store::open(Path::new(":memory:"), Paris).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        let start = store.day_start(day);
+        let at = |hours| start + chrono::Duration::hours(hours);
+        let snapshots = [
+            (1, 1000, 5000),
+            (12, 1500, 5200),
+            (27, 1800, 5200),
+            (44, 1800, 6000),
+        ];
+        for (hours, off_peak, peak) in snapshots {
+            store
+                .insert_batch(&[(
+                    at(hours),
+                    vec![
+                        (String::from("BBRHCJB"), Value::Integer(off_peak)),
+                        (String::from("BBRHPJB"), Value::Integer(peak)),
+                        (String::from("PAPP"), Value::Integer(800)),
+                    ],
+                )])
+                .unwrap();
+        }
+        let retention = RetentionConfig {
+            raw_days: 30,
+            minute_months: 1,
+        };
+        let energy = |store: &Store| {
+            let mut statement = store
+                .connection
+                .prepare("SELECT day, color, period, energy FROM daily_energy ORDER BY day, label")
+                .unwrap();
+            statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)?,
+                    ))
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let row = |day: &str, period: &str, energy| {
+            (
+                day.to_string(),
+                Some(String::from("B")),
+                period.to_string(),
+                energy,
+            )
+        };
+
+        // The first day starts at its first value
+        store.compact(at(30), &retention).unwrap();
+        assert_eq!(
+            energy(&store),
+            vec![row("2024-01-16", "HC", 500), row("2024-01-16", "HP", 200)]
+        );
+        // The next day starts at the last value of the day before
+        store.compact(at(72), &retention).unwrap();
+        assert_eq!(
+            energy(&store)[2..],
+            [row("2024-01-17", "HC", 300), row("2024-01-17", "HP", 800)]
+        );
+        let day = |day| NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        assert_eq!(
+            store.energy(day(16), day(17)).unwrap(),
+            vec![
+                (Some(String::from("B")), String::from("HC"), 800),
+                (Some(String::from("B")), String::from("HP"), 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_snapshots() {
+        let path = std::env::temp_dir().join(format!("pitinfo-snapshot-{}.db", std::process::id()));
+        let mut store = Store::open_in_memory(&path, Paris, None).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        let power = vec![(String::from("PAPP"), Value::Integer(803))];
+        store
+            .insert_batch(&[
+                (store.day_start(day), power.clone()),
+                (store.day_start(day) + chrono::Duration::hours(1), power),
+            ])
+            .unwrap();
+        store.snapshot(&path).unwrap();
+
+        let copy = Store::open_in_memory(&path, Paris, None).unwrap();
+        assert_eq!(copy.day_readings(day).unwrap().len(), 2);
+        assert_eq!(
+            Store::open(&path, Paris).unwrap().days().unwrap(),
+            vec![day]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn encrypted_snapshots() {
+        let path =
+            std::env::temp_dir().join(format!("pitinfo-encrypted-{}.db", std::process::id()));
+        let identity = Identity::generate();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        // A plain database is encrypted by the first copy
+        let mut plain = Store::open_in_memory(&path, Paris, None).unwrap();
+        plain
+            .insert_batch(&[(
+                plain.day_start(day),
+                vec![(String::from("PAPP"), Value::Integer(803))],
+            )])
+            .unwrap();
+        plain.snapshot(&path).unwrap();
+        let store = Store::open_in_memory(&path, Paris, Some(identity.clone())).unwrap();
+        store.snapshot(&path).unwrap();
+        assert!(!fs::read(&path).unwrap().starts_with(SQLITE_HEADER));
+
+        let copy = Store::open_in_memory(&path, Paris, Some(identity)).unwrap();
+        assert_eq!(copy.days().unwrap(), vec![day]);
+        assert!(Store::open_in_memory(&path, Paris, Some(Identity::generate())).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn daylight_saving_days() {
+        let mut store = Store::open(Path::new(":memory:"), Paris).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let next = day.succ_opt().unwrap();
+        // Clocks go forward at 2:00
+        assert_eq!(
+            store.day_start(next) - store.day_start(day),
+            chrono::Duration::hours(23)
+        );
+
+        // Still March 31st in UTC
+        let timestamp = Paris.with_ymd_and_hms(2024, 4, 1, 0, 30, 0).unwrap();
+        store
+            .insert_batch(&[(
+                timestamp.with_timezone(&Utc),
+                vec![(String::from("PAPP"), Value::Integer(803))],
+            )])
+            .unwrap();
+        assert_eq!(store.days().unwrap(), vec![next]);
+        let retention = RetentionConfig {
+            raw_days: 1,
+            minute_months: 1,
+        };
+        store
+            .compact(
+                store.day_start(next) + chrono::Duration::days(2),
+                &retention,
+            )
+            .unwrap();
+        let days = store.aggregates(Resolution::Day, 0, i64::MAX).unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].0, store.day_start(next));
+    }
+}
@@ -0,0 +1,133 @@
+//! Sync of the stored history to the remote sinks, on demand.
+//!
+//! Installations offline most of the time, e.g. behind a metered mobile
+//! link, keep the history in the local store and send it in one go when
+//! they connect. Each sink taking timestamped data, InfluxDB and the Home
+//! Assistant statistics, gets the days from the last one it was synced up to
+//! the last stored day, the last one synced being sent again since it was
+//! incomplete. The last day synced of each sink is kept next to the
+//! database, in `<path>.sync`, and only moves when the sink succeeded, so a
+//! failed sync starts again from the same day.
+
+use crate::backfill;
+use crate::config::Config;
+use crate::influxdb;
+#[cfg(feature = "statistics")]
+use crate::statistics;
+use crate::storage::Store;
+use chrono::NaiveDate;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Result of the sync of a sink.
+#[derive(Debug)]
+pub struct Synced {
+    pub sink: &'static str,
+    /// First day sent, the whole history when never synced
+    pub from: Option<NaiveDate>,
+    /// Number of points or statistics sent
+    pub result: Result<usize, String>,
+}
+
+/// Sends the stored history to each configured sink, from its last sync.
+pub fn sync(config: &Config) -> Result<Vec<Synced>, Box<dyn Error>> {
+    let storage = config
+        .storage
+        .as_ref()
+        .ok_or("the sync requires a [storage] section in the configuration")?;
+    let store = Store::load(storage, config.clock.timezone)?;
+    let path = cursors_path(&storage.path);
+    let mut cursors = read_cursors(&path)?;
+    let last = store.history_days()?.last().copied();
+    let mut synced = Vec::new();
+    let mut run =
+        |sink: &'static str, send: &dyn Fn(Option<NaiveDate>) -> Result<usize, Box<dyn Error>>| {
+            let from = cursor(&cursors, sink);
+            println!("Syncing {} from {}", sink, describe(from));
+            let result = send(from).map_err(|e| e.to_string());
+            if let (Ok(_), Some(last)) = (&result, last) {
+                cursors.insert(String::from(sink), Value::String(last.to_string()));
+            }
+            synced.push(Synced { sink, from, result });
+        };
+    if let Some(influxdb) = &config.influxdb {
+        run("influxdb", &|from| {
+            let client = influxdb::Client::new(influxdb)?;
+            backfill::backfill(&store, &client, from)
+        });
+    }
+    #[cfg(feature = "statistics")]
+    if let Some(home_assistant) = &config.home_assistant {
+        run("homeassistant", &|from| {
+            let mut client = statistics::Client::connect(home_assistant)?;
+            statistics::backfill(&store, &mut client, from)
+        });
+    }
+    if synced.is_empty() {
+        return Err(
+            "the sync requires an [influxdb] or [home_assistant] section in the configuration"
+                .into(),
+        );
+    }
+    fs::write(&path, toml::to_string(&cursors)?)?;
+    Ok(synced)
+}
+
+/// Logs the result of each sink, returning whether they all succeeded.
+pub fn report(synced: &[Synced]) -> bool {
+    for Synced { sink, from, result } in synced {
+        match result {
+            Ok(count) => println!("Synced {} from {}: {} sent", sink, describe(*from), count),
+            Err(e) => eprintln!("Sync of {} from {} failed: {}", sink, describe(*from), e),
+        }
+    }
+    synced.iter().all(|synced| synced.result.is_ok())
+}
+
+fn describe(from: Option<NaiveDate>) -> String {
+    from.map_or_else(|| String::from("the start"), |from| from.to_string())
+}
+
+fn cursors_path(database: &Path) -> PathBuf {
+    let mut path = database.as_os_str().to_owned();
+    path.push(".sync");
+    PathBuf::from(path)
+}
+
+/// Last day synced of each sink, none before the first sync.
+fn read_cursors(path: &Path) -> Result<Table, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text.parse()?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Table::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn cursor(cursors: &Table, sink: &str) -> Option<NaiveDate> {
+    cursors.get(sink)?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors() {
+        assert_eq!(
+            cursors_path(Path::new("/var/lib/pitinfo/history.db")),
+            PathBuf::from("/var/lib/pitinfo/history.db.sync")
+        );
+        let cursors: Table = "influxdb = \"2024-01-16\"\nhomeassistant = \"?\""
+            .parse()
+            .unwrap();
+        assert_eq!(
+            cursor(&cursors, "influxdb"),
+            NaiveDate::from_ymd_opt(2024, 1, 16)
+        );
+        assert_eq!(cursor(&cursors, "homeassistant"), None);
+        assert_eq!(cursor(&cursors, "pvoutput"), None);
+    }
+}
@@ -0,0 +1,181 @@
+//! Tomorrow's Tempo color from the RTE calendar API.
+//!
+//! The meter only announces tomorrow's color (DEMAIN) in the evening while RTE
+//! publishes it around 11:00. Until the meter knows it, the color from the
+//! calendar is used; once it does, the meter value wins and disagreements are
+//! reported.
+
+use crate::config::TempoConfig;
+use crate::rte;
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use pitinfo_parser::{DayColor, Message};
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{task, time};
+
+#[derive(Deserialize)]
+struct CalendarResponse {
+    tempo_like_calendars: Calendar,
+}
+
+#[derive(Deserialize)]
+struct Calendar {
+    values: Vec<CalendarValue>,
+}
+
+#[derive(Deserialize)]
+struct CalendarValue {
+    start_date: String,
+    value: String,
+}
+
+#[derive(Clone)]
+pub struct TempoCalendar {
+    /// Color published by RTE for the next Tempo day
+    tomorrow: Arc<Mutex<Option<(NaiveDate, DayColor)>>>,
+    /// Last day for which a disagreement with the meter was reported
+    reported: Arc<Mutex<Option<NaiveDate>>>,
+    timezone: Tz,
+}
+
+/// Starts polling the calendar API. Tempo days follow `timezone`.
+pub fn spawn(config: &TempoConfig, timezone: Tz) -> TempoCalendar {
+    let calendar = TempoCalendar {
+        tomorrow: Arc::new(Mutex::new(None)),
+        reported: Arc::new(Mutex::new(None)),
+        timezone,
+    };
+    let config = config.clone();
+    let tomorrow = Arc::clone(&calendar.tomorrow);
+    tokio::spawn(async move {
+        loop {
+            let next_day = tempo_day(Utc::now().with_timezone(&timezone)) + ChronoDuration::days(1);
+            let fetch_config = config.clone();
+            // The HTTP client is blocking
+            let result = task::spawn_blocking(move || {
+                fetch_calendar(&fetch_config).map_err(|e| e.to_string())
+            })
+            .await;
+            match result {
+                Ok(Ok(values)) => {
+                    if let Some(color) = color_of(&values, next_day) {
+                        *tomorrow.lock().unwrap() = Some((next_day, color));
+                    }
+                }
+                Ok(Err(e)) => eprintln!("Unable to fetch the Tempo calendar: {}", e),
+                Err(e) => eprintln!("Unable to fetch the Tempo calendar: {}", e),
+            }
+            time::sleep(Duration::from_secs(config.poll_interval)).await;
+        }
+    });
+    calendar
+}
+
+impl TempoCalendar {
+    /// Completes the DEMAIN value of the meter with the calendar while it is
+    /// not known yet.
+    pub fn reconcile(&self, message: Message) -> Message {
+        let next_day =
+            tempo_day(Utc::now().with_timezone(&self.timezone)) + ChronoDuration::days(1);
+        let published = match *self.tomorrow.lock().unwrap() {
+            Some((day, color)) if day == next_day => Some(color),
+            _ => None,
+        };
+
+        match (message, published) {
+            (Message::Tomorrow(None), Some(color)) => Message::Tomorrow(Some(color)),
+            (Message::Tomorrow(Some(color)), Some(published)) if color != published => {
+                let mut reported = self.reported.lock().unwrap();
+                if *reported != Some(next_day) {
+                    *reported = Some(next_day);
+                    eprintln!(
+                        "Tomorrow's color from the meter ({:?}) differs from RTE ({:?})",
+                        color, published
+                    );
+                }
+                Message::Tomorrow(Some(color))
+            }
+            (message, _) => message,
+        }
+    }
+}
+
+/// Tempo days run from 6:00 to 6:00 the next day, local time: they last 23
+/// or 25 hours on daylight saving time changes.
+pub fn tempo_day<T: TimeZone>(now: DateTime<T>) -> NaiveDate {
+    (now.naive_local() - ChronoDuration::hours(6)).date()
+}
+
+fn fetch_calendar(config: &TempoConfig) -> Result<Vec<CalendarValue>, Box<dyn Error>> {
+    let token = rte::access_token(&config.token_url, &config.client_id, &config.client_secret)?;
+    let response = ureq::get(&config.calendar_url)
+        .set("Authorization", &format!("Bearer {}", token))
+        .call()?
+        .into_string()?;
+    parse_calendar(&response)
+}
+
+fn parse_calendar(response: &str) -> Result<Vec<CalendarValue>, Box<dyn Error>> {
+    let response: CalendarResponse = serde_json::from_str(response)?;
+    Ok(response.tempo_like_calendars.values)
+}
+
+fn color_of(values: &[CalendarValue], day: NaiveDate) -> Option<DayColor> {
+    let day = day.format("%Y-%m-%d").to_string();
+    values
+        .iter()
+        .find(|value| value.start_date.starts_with(&day))
+        .and_then(|value| match value.value.as_str() {
+            "BLUE" => Some(DayColor::Blue),
+            "WHITE" => Some(DayColor::White),
+            "RED" => Some(DayColor::Red),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Paris;
+
+    #[test]
+    fn calendar_colors() {
+        let values = parse_calendar(
+            r#"{"tempo_like_calendars":{"start_date":"2024-01-15T00:00:00+01:00",
+            "end_date":"2024-01-17T00:00:00+01:00","values":[
+            {"start_date":"2024-01-16T00:00:00+01:00","end_date":"2024-01-17T00:00:00+01:00",
+             "value":"RED","updated_date":"2024-01-15T10:20:00+01:00"},
+            {"start_date":"2024-01-15T00:00:00+01:00","end_date":"2024-01-16T00:00:00+01:00",
+             "value":"WHITE","updated_date":"2024-01-14T10:20:00+01:00"}]}}"#,
+        )
+        .unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 16).unwrap();
+        assert_eq!(color_of(&values, day), Some(DayColor::Red));
+        let day = NaiveDate::from_ymd_opt(2024, 1, 17).unwrap();
+        assert_eq!(color_of(&values, day), None);
+    }
+
+    #[test]
+    fn tempo_days() {
+        let before = Paris.with_ymd_and_hms(2024, 1, 16, 5, 59, 0).unwrap();
+        let after = Paris.with_ymd_and_hms(2024, 1, 16, 6, 0, 0).unwrap();
+        assert_eq!(
+            tempo_day(before),
+            NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()
+        );
+        assert_eq!(
+            tempo_day(after),
+            NaiveDate::from_ymd_opt(2024, 1, 16).unwrap()
+        );
+
+        // Clocks go forward at 2:00, 6:00 is only 5 hours after midnight
+        let after = Paris.with_ymd_and_hms(2024, 3, 31, 6, 0, 0).unwrap();
+        assert_eq!(
+            tempo_day(after),
+            NaiveDate::from_ymd_opt(2024, 3, 31).unwrap()
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Sends meter values to ThingsBoard.
+//!
+//! ThingsBoard devices connect to its MQTT broker with their access token
+//! as user name, then publish their time series on the telemetry topic and
+//! their properties on the attributes topic, which dashboards and rule
+//! chains build on. The state of a frame is sent as telemetry at most once
+//! per configured interval, numbers staying numbers, and the address of the
+//! meter, its tariff option and the daemon version as client attributes, on
+//! each connection and when they change.
+
+use crate::config::ThingsBoardConfig;
+use crate::meter::MeterInfo;
+use crate::mqtt;
+use crate::pipeline::{self, Inbox, Sink};
+use crate::scale::Scales;
+use crate::state::{MeterState, Update};
+use pitinfo_parser::Message;
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time;
+
+const TELEMETRY_TOPIC: &str = "v1/devices/me/telemetry";
+const ATTRIBUTES_TOPIC: &str = "v1/devices/me/attributes";
+
+/// Starts the publisher. Messages sent to the returned sink are published as
+/// telemetry once per interval.
+pub fn spawn(
+    config: &ThingsBoardConfig,
+    meter: watch::Receiver<Option<MeterInfo>>,
+) -> Result<Sink, io::Error> {
+    let scales = Scales::new(&config.scale)?;
+    if config.delivery.retries > 0 {
+        eprintln!(
+            "WARNING: thingsboard.delivery.retries is ignored, the client reconnects by itself"
+        );
+    }
+    let mut options = MqttOptions::new("pitinfo", &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive));
+    options.set_credentials(&config.access_token, "");
+    if let Some(tls) = &config.tls {
+        options.set_transport(mqtt::transport(tls)?);
+    }
+    let (client, mut event_loop) = AsyncClient::new(options, mqtt::REQUEST_CAPACITY);
+
+    // Attributes last published, for the next connections
+    let attributes: Arc<Mutex<Option<String>>> = Arc::default();
+    let connection_client = client.clone();
+    let connection_attributes = Arc::clone(&attributes);
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    let attributes = connection_attributes.lock().unwrap().clone();
+                    if let Some(attributes) = attributes {
+                        publish(&connection_client, ATTRIBUTES_TOPIC, attributes);
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("ThingsBoard connection error: {}", e);
+                    time::sleep(mqtt::RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    let publisher = Publisher {
+        client,
+        meter,
+        attributes,
+        scales,
+        interval: Duration::from_secs(config.interval),
+        state: MeterState::default(),
+    };
+    Ok(pipeline::spawn_sink_with(
+        "thingsboard",
+        &config.delivery,
+        |receiver| publisher.run(receiver),
+    ))
+}
+
+struct Publisher {
+    client: AsyncClient,
+    /// Address of the meter read
+    meter: watch::Receiver<Option<MeterInfo>>,
+    attributes: Arc<Mutex<Option<String>>>,
+    scales: Scales,
+    interval: Duration,
+    state: MeterState,
+}
+
+impl Publisher {
+    async fn run(mut self, mut receiver: Inbox<Update>) {
+        let mut last_publish: Option<Instant> = None;
+        if self.meter.borrow_and_update().is_some() {
+            self.publish_attributes();
+        }
+        loop {
+            tokio::select! {
+                item = receiver.recv() => match item {
+                    Some(item) => {
+                        if let Update::Meter(message) = &item {
+                            if mqtt::changes_info(&self.state, message) {
+                                self.state.update(message);
+                                self.publish_attributes();
+                            }
+                        }
+                        // Frames start with ADCO: the state of the previous frame is complete
+                        if matches!(item, Update::Meter(Message::MeterAddress(_)))
+                            && last_publish.is_none_or(|last| last.elapsed() >= self.interval)
+                        {
+                            let values = self.state.values();
+                            if !values.is_empty() {
+                                let telemetry = mqtt::json_state(&values, &[], &self.scales);
+                                publish(&self.client, TELEMETRY_TOPIC, telemetry);
+                                last_publish = Some(Instant::now());
+                            }
+                        }
+                        self.state.record(&item);
+                    }
+                    None => break,
+                },
+                Ok(()) = self.meter.changed() => {
+                    self.meter.borrow_and_update();
+                    self.publish_attributes();
+                }
+            }
+        }
+        if let Err(e) = self.client.try_disconnect() {
+            eprintln!("Unable to disconnect from ThingsBoard: {}", e);
+        }
+    }
+
+    /// Publishes the client attributes, and keeps them for the next
+    /// connections.
+    fn publish_attributes(&self) {
+        let payload = attributes(self.meter.borrow().as_ref(), &self.state);
+        *self.attributes.lock().unwrap() = Some(payload.clone());
+        publish(&self.client, ATTRIBUTES_TOPIC, payload);
+    }
+}
+
+fn publish(client: &AsyncClient, topic: &str, payload: String) {
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, false, payload) {
+        eprintln!("Unable to publish to ThingsBoard: {}", e);
+    }
+}
+
+/// Client attributes, those not received yet left out: ThingsBoard rejects
+/// `null` values.
+fn attributes(meter: Option<&MeterInfo>, state: &MeterState) -> String {
+    let info: serde_json::Value =
+        serde_json::from_str(&mqtt::device_info(meter, state)).unwrap_or(serde_json::Value::Null);
+    let attributes: serde_json::Map<String, serde_json::Value> = info
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    serde_json::Value::Object(attributes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitinfo_parser::TariffOptionValue;
+
+    #[test]
+    fn client_attributes() {
+        let mut state = MeterState::default();
+        state.update(&Message::TariffOption(TariffOptionValue::Base));
+        let attributes: serde_json::Value =
+            serde_json::from_str(&attributes(None, &state)).unwrap();
+        assert_eq!(attributes["tariff_option"], "BASE");
+        assert_eq!(attributes["version"], env!("CARGO_PKG_VERSION"));
+        assert!(attributes.get("meter").is_none());
+    }
+}
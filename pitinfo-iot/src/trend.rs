@@ -0,0 +1,106 @@
+//! Smoothing and rate of change of the apparent power.
+//!
+//! Both are computed over a sliding window: the smoothed power is the mean of
+//! the samples and the rate of change the slope of their least squares line,
+//! which is far less noisy than the difference between two samples.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const AVERAGE_LABEL: &str = "PAPP_AVG";
+pub const RATE_LABEL: &str = "PAPP_RATE";
+
+pub struct PowerTrend {
+    window: Duration,
+    samples: VecDeque<(Instant, u16)>,
+}
+
+impl PowerTrend {
+    pub fn new(window: Duration) -> PowerTrend {
+        PowerTrend {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn update(&mut self, power: u16, now: Instant) {
+        self.samples.push_back((now, power));
+        while let Some((time, _)) = self.samples.front() {
+            if now.duration_since(*time) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Mean apparent power over the window, in VA.
+    pub fn average(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let sum: f64 = self.samples.iter().map(|(_, power)| *power as f64).sum();
+        Some(sum / self.samples.len() as f64)
+    }
+
+    /// Rate of change of the apparent power over the window, in VA per minute.
+    pub fn rate(&self) -> Option<f64> {
+        let (origin, _) = self.samples.front()?;
+        let points: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .map(|(time, power)| {
+                let minutes = time.duration_since(*origin).as_secs_f64() / 60.0;
+                (minutes, *power as f64)
+            })
+            .collect();
+        let count = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+        let covariance: f64 = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        if variance == 0.0 {
+            None
+        } else {
+            Some(covariance / variance)
+        }
+    }
+
+    /// Smoothed power and rate of change, rounded for publishing.
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        let mut values = Vec::new();
+        if let Some(average) = self.average() {
+            values.push((AVERAGE_LABEL, average.round()));
+        }
+        if let Some(rate) = self.rate() {
+            values.push((RATE_LABEL, (rate * 10.0).round() / 10.0));
+        }
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rising_power() {
+        let mut trend = PowerTrend::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(trend.values(), vec![]);
+
+        // 10 VA per second for two minutes, only the last minute is kept
+        for second in 0..=120 {
+            trend.update(
+                1000 + 10 * second,
+                start + Duration::from_secs(second as u64),
+            );
+        }
+        assert_eq!(
+            trend.values(),
+            vec![(AVERAGE_LABEL, 1900.0), (RATE_LABEL, 600.0)]
+        );
+    }
+}
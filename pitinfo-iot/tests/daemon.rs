@@ -0,0 +1,212 @@
+//! End-to-end tests of the daemon: captured frames go through the real read
+//! loop and the sinks write their outputs to a temporary directory.
+
+use rusqlite::Connection;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Three frames of a three-phase Tempo meter, with 803, 1250 and 2430 VA.
+const CAPTURE: &[u8] = include_bytes!("data/tempo.tic");
+
+fn temp_dir(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("pitinfo-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Configuration with the sinks writing files, whether the clock of the
+/// machine running the tests is synchronized or not.
+fn write_config(directory: &Path, port: &str) -> PathBuf {
+    let config = directory.join("pitinfo.toml");
+    fs::write(
+        &config,
+        format!(
+            "[serial]\nport = \"{}\"\n\n\
+             [clock]\nwait_for_sync = false\n\n\
+             [storage]\npath = \"{}\"\ninterval = 0\n\n\
+             [nilm]\ndirectory = \"{}\"\n",
+            port,
+            directory.join("history.db").display(),
+            directory.join("nilm").display()
+        ),
+    )
+    .unwrap();
+    config
+}
+
+fn daemon(config: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_pitinfo-iot"));
+    command
+        .arg(config)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+fn assert_sinks(directory: &Path, output: &Output) {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("PAPP 02430 *         -> ApparentPower { value: 2430 }"),
+        "{}\n{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let capture = fs::read_to_string(directory.join("nilm/channel_1.dat")).unwrap();
+    let powers: Vec<&str> = capture
+        .lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .collect();
+    assert_eq!(powers, vec!["803", "1250", "2430"]);
+
+    // Frames are stored once complete, on the next ADCO
+    let history = Connection::open(directory.join("history.db")).unwrap();
+    let mut statement = history
+        .prepare("SELECT value FROM readings WHERE label = 'PAPP' ORDER BY timestamp")
+        .unwrap();
+    let stored: Vec<i64> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(stored.last(), Some(&1250));
+}
+
+#[test]
+fn standard_input() {
+    let directory = temp_dir("stdin");
+    let mut child = daemon(&write_config(&directory, "-"))
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(CAPTURE).unwrap();
+    // The daemon stops at the end of the stream
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    assert_sinks(&directory, &output);
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn serial_port() {
+    use serialport::{SerialPort, TTYPort};
+    use std::thread;
+    use std::time::Duration;
+
+    let directory = temp_dir("serial");
+    let (mut meter, port) = TTYPort::pair().unwrap();
+    let child = daemon(&write_config(&directory, &port.name().unwrap()))
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    meter.write_all(CAPTURE).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    // Graceful shutdown, as by systemd
+    let status = Command::new("kill")
+        .arg("-TERM")
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    assert_sinks(&directory, &output);
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_frames() {
+    let directory = temp_dir("test-frames");
+    // The first frame has a current as sent in test mode, with its checksum
+    let capture = String::from_utf8_lossy(CAPTURE).replacen("IINST1 001 I", "IINST1 0X1 1", 1);
+    let mut child = daemon(&write_config(&directory, "-"))
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(capture.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("WARNING: unusual group 'IINST1 0X1 1'"),
+        "{}",
+        stderr
+    );
+
+    // The flag of each frame is stored with it, on the next ADCO
+    let history = Connection::open(directory.join("history.db")).unwrap();
+    let mut statement = history
+        .prepare("SELECT value FROM readings WHERE label = 'NON_NOMINAL' ORDER BY timestamp")
+        .unwrap();
+    let stored: Vec<i64> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(stored, vec![1, 0]);
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn interrupted_frame() {
+    let directory = temp_dir("interrupted");
+    // The meter interrupts the second frame after its apparent power
+    let capture =
+        String::from_utf8_lossy(CAPTURE).replacen("PAPP 01250 )\r\n", "PAPP 01250 )\r\n\x04", 1);
+    let mut child = daemon(&write_config(&directory, "-"))
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(capture.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("WARNING: Truncated frame"), "{}", stderr);
+    assert!(
+        !stdout.contains("ApparentPower { value: 1250 }"),
+        "{}",
+        stdout
+    );
+
+    // Nothing of the interrupted frame reaches the sinks
+    let capture = fs::read_to_string(directory.join("nilm/channel_1.dat")).unwrap();
+    let powers: Vec<&str> = capture
+        .lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .collect();
+    assert_eq!(powers, vec!["803", "2430"]);
+    let history = Connection::open(directory.join("history.db")).unwrap();
+    let mut statement = history
+        .prepare("SELECT value FROM readings WHERE label IN ('PAPP', 'BBRHCJB') ORDER BY timestamp")
+        .unwrap();
+    let stored: Vec<i64> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    // The first frame is stored on the ADCO of the third one
+    assert!(stored.contains(&803), "{:?}", stored);
+    assert!(
+        !stored.contains(&1250) && !stored.contains(&23916831),
+        "{:?}",
+        stored
+    );
+    fs::remove_dir_all(directory).unwrap();
+}
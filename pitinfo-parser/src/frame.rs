@@ -0,0 +1,276 @@
+//! Frames of the stream, delimited by their control characters.
+//!
+//! A frame starts with STX (0x02) and ends with ETX (0x03), each of its groups
+//! between LF (0x0A) and CR (0x0D). The meter interrupts a frame with EOT
+//! (0x04), and a frame is also cut short when the reading starts in the
+//! middle of it, or when bytes are lost on the line. Such frames are reported
+//! instead of mixing their groups with those of the next frame.
+//!
+//! The stream is split by a `Splitter`, which scans it with `memchr`.
+//!
+//! The parser is sized like its splitter: groups longer than `GROUP` bytes and
+//! the groups of a frame beyond the first `GROUPS` are dropped. The messages
+//! of the frame being read are kept in a buffer of `GROUPS` messages,
+//! allocated with the parser, reused from frame to frame and never grown.
+//! The parser still needs `alloc`: each frame returned, and the text of some
+//! messages, e.g. the meter address, are allocated.
+
+use crate::{Frame, Message, ParseError, Splitter, TicMode, Token};
+
+/// Bytes of a group beyond which the stream is taken for noise.
+const MAX_GROUP_LENGTH: usize = 512;
+/// Groups of a frame beyond which the stream is taken for noise, standard
+/// frames have about 70.
+const MAX_FRAME_GROUPS: usize = 128;
+
+/// Parses the frames of a stream, fed by bytes or by lines.
+#[derive(Debug)]
+pub struct FrameParser<
+    const GROUP: usize = MAX_GROUP_LENGTH,
+    const GROUPS: usize = MAX_FRAME_GROUPS,
+> {
+    mode: TicMode,
+    checked: bool,
+    splitter: Splitter<GROUP, GROUPS>,
+    /// Whether a frame is being read
+    reading: bool,
+    /// Messages of the frame being read
+    messages: Vec<Message>,
+    /// Groups of the frame being read handed to the parsing function
+    parsed: usize,
+    /// First error of the frame being read
+    error: Option<ParseError>,
+}
+
+impl FrameParser {
+    /// Parser of the frames of a mode, checking the checksum of their groups
+    /// when `checked`, with groups of up to 512 bytes and frames of up to
+    /// 128 groups.
+    pub fn new(mode: TicMode, checked: bool) -> FrameParser {
+        FrameParser::sized(mode, checked)
+    }
+}
+
+impl<const GROUP: usize, const GROUPS: usize> FrameParser<GROUP, GROUPS> {
+    /// Parser like `new`, with groups of up to `GROUP` bytes and frames of up
+    /// to `GROUPS` groups.
+    pub fn sized(mode: TicMode, checked: bool) -> Self {
+        FrameParser {
+            mode,
+            checked,
+            splitter: Splitter::new(),
+            reading: false,
+            messages: Vec::with_capacity(GROUPS),
+            parsed: 0,
+            error: None,
+        }
+    }
+
+    /// Reads a byte, returning the frame it completes, if any. A frame with a
+    /// group in error fails with the first error.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, ParseError>> {
+        self.push_bytes(&[byte]).pop()
+    }
+
+    /// Reads bytes, returning the frames they complete.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Vec<Result<Frame, ParseError>> {
+        let (mode, checked) = (self.mode, self.checked);
+        self.push_bytes_with(bytes, |group, _| mode.parse_group(group, checked))
+    }
+
+    /// Reads bytes like `push_bytes`, the groups of the frames being parsed
+    /// by `parse`, with the position of their delimiter in the bytes, e.g. to
+    /// follow a change of mode or to report the groups in error.
+    pub fn push_bytes_with<F>(&mut self, bytes: &[u8], parse: F) -> Vec<Result<Frame, ParseError>>
+    where
+        F: FnMut(&str, usize) -> Result<Option<Message>, ParseError>,
+    {
+        self.push_bytes_counted(bytes, parse)
+            .into_iter()
+            .map(|(frame, _)| frame)
+            .collect()
+    }
+
+    /// Reads bytes like `push_bytes_with`, returning with each frame the
+    /// number of its groups handed to `parse`, in the order they were, e.g.
+    /// to drop the groups of the frames cut short. The groups handed last
+    /// and not counted yet are those of a frame not ended yet.
+    pub fn push_bytes_counted<F>(
+        &mut self,
+        bytes: &[u8],
+        mut parse: F,
+    ) -> Vec<(Result<Frame, ParseError>, usize)>
+    where
+        F: FnMut(&str, usize) -> Result<Option<Message>, ParseError>,
+    {
+        let FrameParser {
+            splitter,
+            reading,
+            messages,
+            parsed,
+            error,
+            ..
+        } = self;
+        let mut frames = Vec::new();
+        splitter.split(bytes, |token| match token {
+            Token::Start => {
+                *error = None;
+                messages.clear();
+                if std::mem::replace(reading, true) {
+                    frames.push((Err(ParseError::TruncatedFrame), *parsed));
+                }
+                *parsed = 0;
+            }
+            Token::End => {
+                if std::mem::take(reading) {
+                    let frame = match error.take() {
+                        Some(error) => Err(error),
+                        None => Ok(messages.drain(..).collect()),
+                    };
+                    frames.push((frame, *parsed));
+                }
+                messages.clear();
+                *parsed = 0;
+            }
+            Token::Interrupted => {
+                *error = None;
+                messages.clear();
+                if std::mem::take(reading) {
+                    frames.push((Err(ParseError::TruncatedFrame), *parsed));
+                }
+                *parsed = 0;
+            }
+            Token::Group { bytes, end } => {
+                if *reading {
+                    *parsed += 1;
+                    match parse(&String::from_utf8_lossy(bytes), end) {
+                        Ok(Some(message)) => messages.push(message),
+                        Ok(None) => (),
+                        Err(e) => {
+                            error.get_or_insert(e);
+                        }
+                    }
+                }
+            }
+        });
+        frames
+    }
+
+    /// Reads a line, without its line feed, returning the frames it
+    /// completes. The end of the line ends its group, whether its carriage
+    /// return was kept or not.
+    pub fn push_line(&mut self, line: &str) -> Vec<Result<Frame, ParseError>> {
+        let mut frames = self.push_bytes(b"\n");
+        frames.extend(self.push_bytes(line.as_bytes()));
+        frames.extend(self.push_bytes(b"\n"));
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageKind;
+
+    #[test]
+    fn frames() {
+        let mut parser = FrameParser::new(TicMode::Historic, true);
+        // Read from the middle of a frame
+        let frames = parser.push_bytes(
+            b"0 @\r\nPAPP 00803 ,\r\x03\x02\nADCO 031762120110 /\r\n\
+              ISOUSC 30 9\r\nPAPP 00803 ,\r\x03\x02\nADCO 031762120110 /\r\n",
+        );
+        assert_eq!(
+            frames,
+            vec![Ok(vec![
+                Message::MeterAddress(String::from("031762120110")),
+                Message::SubscribedCurrent { value: 30 },
+                Message::ApparentPower { value: 803 }
+            ]
+            .into_iter()
+            .collect::<Frame>())]
+        );
+        // Cut short by a new frame, then by the meter
+        let frames = parser.push_bytes(b"PAPP\x02\nPAPP 00803 ,\r\x04\x03");
+        assert_eq!(
+            frames,
+            vec![
+                Err(ParseError::TruncatedFrame),
+                Err(ParseError::TruncatedFrame)
+            ]
+        );
+    }
+
+    #[test]
+    fn lines() {
+        let mut parser = FrameParser::new(TicMode::Historic, true);
+        let mut frames = Vec::new();
+        for line in [
+            "\x02",
+            "ADCO 031762120110 /",
+            "PAPP 00803 .",
+            "PPOT 00 #\r\x03\x02",
+        ] {
+            frames.extend(parser.push_line(line));
+        }
+        assert_eq!(
+            frames,
+            vec![Err(ParseError::ChecksumError {
+                expected: ',',
+                actual: '.'
+            })]
+        );
+        for line in ["ADCO 031762120110 /", "PAPP 00803 ,", "PPOT 00 #\r\x03\x02"] {
+            frames.extend(parser.push_line(line));
+        }
+        let frame = frames.pop().unwrap().unwrap();
+        assert_eq!(frame.messages().len(), 3);
+        assert!(frame.get(MessageKind::ApparentPower).is_some());
+    }
+
+    #[test]
+    fn sized() {
+        let mut parser = FrameParser::<24, 2>::sized(TicMode::Historic, true);
+        let frames = parser.push_bytes(
+            b"\x02\nADCO 031762120110 /\r\nMOTDETAT 000000000000000000000 B\r\n\
+              PAPP 00803 ,\r\nPPOT 00 #\r\x03",
+        );
+        // The status word is too long, the groups past the first two dropped
+        assert_eq!(
+            frames,
+            vec![Ok(vec![
+                Message::MeterAddress(String::from("031762120110")),
+                Message::ApparentPower { value: 803 }
+            ]
+            .into_iter()
+            .collect::<Frame>())]
+        );
+        assert_eq!(parser.messages.capacity(), 2);
+    }
+
+    #[test]
+    fn counted() {
+        let mut parser = FrameParser::new(TicMode::Historic, true);
+        let mut groups = Vec::new();
+        let mut parse = |group: &str, _| {
+            groups.push(group.to_string());
+            TicMode::Historic.parse_group(group, true)
+        };
+        // The frame is interrupted, the next one ends in the next bytes
+        let mut frames = parser.push_bytes_counted(
+            b"\x02\nADCO 031762120110 /\r\nPA\x04PP 00803 ,\r\x03\x02\nADCO 031762120110 /\r\n",
+            &mut parse,
+        );
+        frames.extend(parser.push_bytes_counted(b"PPOT 00 #\r\x03", &mut parse));
+        assert_eq!(
+            frames.iter().map(|(_, count)| *count).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(frames[0].0, Err(ParseError::TruncatedFrame));
+        assert_eq!(frames[1].0.as_ref().unwrap().messages().len(), 2);
+        assert_eq!(
+            groups,
+            ["ADCO 031762120110 /", "ADCO 031762120110 /", "PPOT 00 #"]
+        );
+    }
+}
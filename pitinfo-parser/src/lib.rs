@@ -1,15 +1,78 @@
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::convert::TryFrom;
 use std::fmt;
+use std::iter::FromIterator;
+use thiserror::Error;
+
+mod frame;
+mod rate;
+mod schedule;
+mod splitter;
+mod standard;
+mod status;
+mod teleinfo;
+
+pub use frame::FrameParser;
+pub use rate::IndexRate;
+pub use schedule::OffPeakWindow;
+pub use splitter::{Splitter, Token};
+pub use status::MeterStatus;
+pub use teleinfo::TeleinfoFrame;
+
+/// Format of the TIC, set on the meter: the historic mode at 1200 bauds,
+/// with space separators, or the standard mode of the Linky at 9600 bauds,
+/// with tab separators and more groups.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TicMode {
+    Historic,
+    Standard,
+}
+
+impl TicMode {
+    /// Mode usually sent at a speed: standard mode at 9600 bauds.
+    pub fn from_baud_rate(baud_rate: u32) -> TicMode {
+        if baud_rate == 9600 {
+            TicMode::Standard
+        } else {
+            TicMode::Historic
+        }
+    }
+
+    pub fn other(&self) -> TicMode {
+        match self {
+            TicMode::Historic => TicMode::Standard,
+            TicMode::Standard => TicMode::Historic,
+        }
+    }
 
-#[derive(PartialEq, Debug)]
+    /// Parses a group sent in this mode, checking its checksum when
+    /// `checked`.
+    pub fn parse_group(&self, group: &str, checked: bool) -> Result<Option<Message>, ParseError> {
+        match self {
+            TicMode::Historic => parse(group, checked),
+            TicMode::Standard => standard::parse_group(group, checked),
+        }
+    }
+}
+
+impl fmt::Display for TicMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TicMode::Historic => write!(f, "historic"),
+            TicMode::Standard => write!(f, "standard"),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum DayColor {
     Blue,
     White,
     Red,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum TariffOptionValue {
     Base,
     OffPeakHours,
@@ -17,7 +80,7 @@ pub enum TariffOptionValue {
     Tempo,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum HHPHCValue {
     A,
     C,
@@ -26,91 +89,371 @@ pub enum HHPHCValue {
     Y,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum HourlyTarifPeriod {
     OffPeakHours,
     PeakHours,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub struct TarifPeriod {
-    hour: HourlyTarifPeriod,
-    day_color: Option<DayColor>,
+    pub hour: HourlyTarifPeriod,
+    pub day_color: Option<DayColor>,
+}
+
+/// Season of the clock of the meter, which shifts its local time.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Season {
+    Summer,
+    Winter,
+}
+
+/// Local time of the meter, as sent in DATE.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Horodate {
+    /// `None` when the clock of the meter is failing
+    pub season: Option<Season>,
+    /// Whether the clock of the meter runs in degraded mode, e.g. after a
+    /// power outage
+    pub degraded: bool,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum Message {
-    ADCO,
+    /// Address of the meter, sent in ADCO, or ADSC in standard mode
+    MeterAddress(String),
     TariffOption(TariffOptionValue),
+    /// Current subscribed, in A, the breaker trips above it
+    SubscribedCurrent { value: u8 },
     Tomorrow(Option<DayColor>),
     InstantaneousPower { phase: u8, value: u8 },
     Index { period: TarifPeriod, value: u32 },
     ApparentPower { value: u16 },
     HHPHC(HHPHCValue),
-    CurrentTariffPeriod(TarifPeriod)
+    CurrentTariffPeriod(TarifPeriod),
+    /// Highest current reached on a phase since the meter last reset it, in A
+    MaxCurrent { phase: u8, value: u16 },
+    /// Highest three-phase power reached since the meter last reset it, in W
+    MaxPower { value: u32 },
+    /// Presence of the voltage of each phase, sent by three-phase meters
+    PhasePotential { phase1: bool, phase2: bool, phase3: bool },
+    /// Status word of the meter, sent in MOTDETAT
+    MeterStatus(MeterStatus),
+    /// Notice of an EJP peak day, in minutes, only sent before it starts
+    EJPNotice { minutes: u8 },
+    /// Time of the frame, sent by meters in standard mode
+    Date(Horodate),
+    /// RMS voltage of a phase, in V, sent in standard mode
+    Voltage { phase: u8, value: u16 },
+    /// Active energy drawn from the grid since the meter was installed, in
+    /// Wh, sent in standard mode
+    SuppliedEnergy { value: u32 },
+    /// Active energy injected into the grid, in Wh, sent in standard mode
+    InjectedEnergy { value: u32 },
+    /// Apparent power injected into the grid, in VA, sent in standard mode
+    InjectedPower { value: u16 },
+    /// Index of a period of the supplier calendar, in Wh, sent in EASF01 to
+    /// EASF10 in standard mode, numbered like the current period in NTARF.
+    /// See `TariffOptionValue::supplier_period` for the period of each one.
+    SupplierIndex { index: u8, value: u32 },
+    /// Identifier of the delivery point, the PRM of the contract, sent in
+    /// standard mode
+    DeliveryPoint(String),
 }
 
-#[derive(PartialEq, Debug, Clone)]
+/// Kind of a message, whatever its values.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum MessageKind {
+    MeterAddress,
+    TariffOption,
+    SubscribedCurrent,
+    Tomorrow,
+    InstantaneousPower,
+    Index,
+    ApparentPower,
+    HHPHC,
+    CurrentTariffPeriod,
+    MaxCurrent,
+    MaxPower,
+    PhasePotential,
+    MeterStatus,
+    EJPNotice,
+    Date,
+    Voltage,
+    SuppliedEnergy,
+    InjectedEnergy,
+    InjectedPower,
+    SupplierIndex,
+    DeliveryPoint,
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::MeterAddress(_) => MessageKind::MeterAddress,
+            Message::TariffOption(_) => MessageKind::TariffOption,
+            Message::SubscribedCurrent { .. } => MessageKind::SubscribedCurrent,
+            Message::Tomorrow(_) => MessageKind::Tomorrow,
+            Message::InstantaneousPower { .. } => MessageKind::InstantaneousPower,
+            Message::Index { .. } => MessageKind::Index,
+            Message::ApparentPower { .. } => MessageKind::ApparentPower,
+            Message::HHPHC(_) => MessageKind::HHPHC,
+            Message::CurrentTariffPeriod(_) => MessageKind::CurrentTariffPeriod,
+            Message::MaxCurrent { .. } => MessageKind::MaxCurrent,
+            Message::MaxPower { .. } => MessageKind::MaxPower,
+            Message::PhasePotential { .. } => MessageKind::PhasePotential,
+            Message::MeterStatus(_) => MessageKind::MeterStatus,
+            Message::EJPNotice { .. } => MessageKind::EJPNotice,
+            Message::Date(_) => MessageKind::Date,
+            Message::Voltage { .. } => MessageKind::Voltage,
+            Message::SuppliedEnergy { .. } => MessageKind::SuppliedEnergy,
+            Message::InjectedEnergy { .. } => MessageKind::InjectedEnergy,
+            Message::InjectedPower { .. } => MessageKind::InjectedPower,
+            Message::SupplierIndex { .. } => MessageKind::SupplierIndex,
+            Message::DeliveryPoint(_) => MessageKind::DeliveryPoint,
+        }
+    }
+}
+
+/// Messages of a frame, in the order of their groups.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Frame {
+    messages: Vec<Message>,
+}
+
+impl Frame {
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// First message of a kind.
+    pub fn get(&self, kind: MessageKind) -> Option<&Message> {
+        self.messages.iter().find(|message| message.kind() == kind)
+    }
+
+    /// Every message of a kind, e.g. the indexes or the currents of the
+    /// phases.
+    pub fn get_all(&self, kind: MessageKind) -> impl Iterator<Item = &Message> {
+        self.messages.iter().filter(move |message| message.kind() == kind)
+    }
+}
+
+impl FromIterator<Message> for Frame {
+    fn from_iter<I: IntoIterator<Item = Message>>(messages: I) -> Frame {
+        Frame {
+            messages: messages.into_iter().collect(),
+        }
+    }
+}
+
+/// Parses a frame as sent on the line, or its groups one per line. Ignored
+/// groups are left out, the first group in error fails the whole frame.
+impl TryFrom<&str> for Frame {
+    type Error = ParseError;
+
+    fn try_from(frame: &str) -> Result<Frame, ParseError> {
+        frame
+            .split(['\n', '\r'])
+            .map(|group| group.trim_matches(['\x02', '\x03']))
+            .filter(|group| !group.is_empty())
+            .filter_map(|group| parse_group(group).transpose())
+            .collect()
+    }
+}
+
+/// Category of a parse error, telling how a reader should react to it.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ErrorKind {
+    /// Group damaged on the line, e.g. by electromagnetic noise: the next
+    /// ones are likely fine
+    Corruption,
+    /// Group that the meter should never send
+    Protocol,
+    /// Groups not read the way they are sent, e.g. in the wrong TIC mode
+    Configuration,
+}
+
+#[derive(PartialEq, Debug, Clone, Error)]
 pub enum ParseError {
+    #[error("Unable to parse group: '{0}'")]
     GroupError(String),
+    #[error("Unable to parse {0} with data: '{1}'")]
     FieldError(String, String),
+    #[error("Unable to parse day color period from {0}")]
     DayColorError(String),
+    #[error("Unable to parse hourly period from {0}")]
     OffPeakHoursError(String),
+    #[error("Control character error")]
     ControlCharacterError,
-
+    #[error("Checksum error: expected '{expected}', got '{actual}'")]
+    ChecksumError { expected: char, actual: char },
+    /// Group of standard mode, with tab separators, while reading historic
+    /// mode
+    #[error("Group of standard mode: '{0}'")]
+    ModeError(String),
+    /// Group of historic mode, with space separators, while reading standard
+    /// mode
+    #[error("Group of historic mode: '{0}'")]
+    HistoricModeError(String),
+    /// Frame interrupted, or missing its start or its end
+    #[error("Truncated frame")]
+    TruncatedFrame,
 }
 
-// Generation of an error is completely separate from how it is displayed.
-// There's no need to be concerned about cluttering complex logic with the display style.
-//
-// Note that we don't store any extra info about the errors. This means we can't state
-// which string failed to parse without modifying our types to carry that information.
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl ParseError {
+    pub fn kind(&self) -> ErrorKind {
         match self {
-            ParseError::GroupError(group) =>
-                write!(f, "Unable to parse group: '{}'", group),
-            // The wrapped error contains additional information and is available
-            // via the source() method.
-            ParseError::ControlCharacterError =>
-                write!(f, "Control character error"),
-            ParseError::DayColorError(code) =>
-                write!(f, "Unable to parse day color period from {}", code),
-            ParseError::OffPeakHoursError
-            (code) =>
-                write!(f, "Unable to parse hourly period from {}", code),
-            ParseError::FieldError(field_name, data) =>
-                write!(f, "Unable to parse {} with data: '{}'", field_name, data),
+            ParseError::GroupError(_)
+            | ParseError::FieldError(_, _)
+            | ParseError::ControlCharacterError
+            | ParseError::ChecksumError { .. }
+            | ParseError::TruncatedFrame => ErrorKind::Corruption,
+            ParseError::DayColorError(_) | ParseError::OffPeakHoursError(_) => ErrorKind::Protocol,
+            ParseError::ModeError(_) | ParseError::HistoricModeError(_) => {
+                ErrorKind::Configuration
+            }
         }
     }
+
+    /// Whether skipping the group is enough, or the port needs to be read
+    /// differently.
+    pub fn is_recoverable(&self) -> bool {
+        self.kind() != ErrorKind::Configuration
+    }
+}
+
+/// Labels to parse: the groups of other labels are skipped by comparing
+/// their label only, without parsing their data.
+#[derive(PartialEq, Debug, Clone)]
+pub struct LabelFilter {
+    labels: Vec<String>,
 }
 
+impl LabelFilter {
+    pub fn new<I, S>(labels: I) -> LabelFilter
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        LabelFilter {
+            labels: labels.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Whether the label of a group is wanted.
+    pub fn wants(&self, group: &str) -> bool {
+        let label = match group.find([' ', '\t']) {
+            Some(end) => &group[..end],
+            None => group,
+        };
+        self.labels.iter().any(|wanted| wanted == label)
+    }
+}
+
+/// Parses a group when its label passes the filter, other groups are
+/// ignored.
+pub fn parse_filtered_group(group: &str, filter: &LabelFilter) -> Result<Option<Message>, ParseError> {
+    if filter.wants(group) {
+        parse_group(group)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses a group of historic mode, checking its checksum.
 pub fn parse_group(group: &str) -> Result<Option<Message>, ParseError> {
+    parse(group, true)
+}
+
+/// Parses a group without checking its checksum, e.g. a group rebuilt from
+/// its value or read through a link that alters it.
+pub fn parse_group_unchecked(group: &str) -> Result<Option<Message>, ParseError> {
+    parse(group, false)
+}
+
+/// Checksum of a group in historic mode: the sum of the label, separator and
+/// data characters, on 6 bits, shifted to a printable character.
+pub fn checksum(span: &str) -> char {
+    let sum: u32 = span.bytes().map(u32::from).sum();
+    ((sum & 0x3F) + 0x20) as u8 as char
+}
+
+fn parse(group: &str, checked: bool) -> Result<Option<Message>, ParseError> {
     lazy_static! {
         static ref RE: Regex = Regex::new(
-            "^(ADCO|OPTARIF|ISOUSC|BBRH[CP]J[BWR]|IMAX[123]|PTEC|DEMAIN|IINST[123]|IMAX[123]|PMAX|PAPP|HHPHC|MOTDETAT|PPOT)\
+            "^(ADCO|OPTARIF|ISOUSC|HCH[CP]|BBRH[CP]J[BWR]|IMAX[123]|PTEC|DEMAIN|IINST[123]|IMAX[123]|PMAX|PAPP|HHPHC|MOTDETAT|PPOT|PEJP)\
         [ U+0009](.+)[ U+0009](.)$"
         )
         .unwrap();
     }
+    // DATE has an empty data field after the horodate, and its checksum
+    // covers the separator before it, as in standard mode
+    if let Some(fields) = group.strip_prefix("DATE") {
+        let separator = |position: &usize| matches!(fields.as_bytes()[*position], b' ' | b'\t');
+        // Truncated or garbled
+        if fields.len() != 17 || !fields.is_ascii() || ![0, 14, 15].iter().all(separator) {
+            return Err(ParseError::GroupError(group.into()));
+        }
+        let (span, control) = group.split_at(group.len() - 1);
+        if checked {
+            let expected = checksum(span);
+            let actual = control.chars().next().unwrap();
+            if actual != expected {
+                return Err(ParseError::ChecksumError { expected, actual });
+            }
+        }
+        let data = &fields[1..14];
+        return match parse_horodate(data) {
+            Some(horodate) => Ok(Some(Message::Date(horodate))),
+            None => Err(ParseError::FieldError("DATE".into(), data.into())),
+        };
+    }
     let captures = RE.captures(group);
 
     if let Some(captures) = captures {
         let code = captures.get(1).unwrap().as_str();
-        let data = captures.get(2).unwrap().as_str();
-        //let control = captures.get(3).unwrap().as_str();
+        let data = captures.get(2).unwrap();
+        let control = captures.get(3).unwrap().as_str();
+        if checked {
+            let expected = checksum(&group[..data.end()]);
+            let actual = control.chars().next().unwrap();
+            if actual != expected {
+                return Err(ParseError::ChecksumError { expected, actual });
+            }
+        }
+        let data = data.as_str();
 
         return match code {
-            "ADCO" => Ok(Some(Message::ADCO)),
+            "ADCO" => Ok(Some(Message::MeterAddress(data.into()))),
             "BBRHCJB" | "BBRHCJW" | "BBRHCJR" | "BBRHPJB" | "BBRHPJW" | "BBRHPJR" => {
                 match data.parse::<u32>() {
                     Ok(value) => Ok(Some(Message::Index {
                         period: parse_period(&code[3..])?,
-                        value: value
+                        value,
                     })),
                     Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
                 }
             },
+            "HCHC" | "HCHP" => match data.parse::<u32>() {
+                Ok(value) => Ok(Some(Message::Index {
+                    period: TarifPeriod {
+                        hour: if code == "HCHC" {
+                            HourlyTarifPeriod::OffPeakHours
+                        } else {
+                            HourlyTarifPeriod::PeakHours
+                        },
+                        day_color: None,
+                    },
+                    value,
+                })),
+                Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
+            },
             "PTEC" => {
                 match data {
                     "HCJB" => Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
@@ -143,6 +486,14 @@ pub fn parse_group(group: &str) -> Result<Option<Message>, ParseError> {
                         hour: HourlyTarifPeriod::PeakHours,
                         day_color: Some(DayColor::Red)
                     } ))),
+                    "HC.." => Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
+                        hour: HourlyTarifPeriod::OffPeakHours,
+                        day_color: None
+                    } ))),
+                    "HP.." => Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
+                        hour: HourlyTarifPeriod::PeakHours,
+                        day_color: None
+                    } ))),
                     _ => Err(ParseError::FieldError("PTEC".into(), data.into())),
 
                 }
@@ -154,6 +505,13 @@ pub fn parse_group(group: &str) -> Result<Option<Message>, ParseError> {
                 })),
                 Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
             },
+            "IMAX1" | "IMAX2" | "IMAX3" => match data.parse::<u16>() {
+                Ok(value) => Ok(Some(Message::MaxCurrent {
+                    phase: code.chars().nth(4).unwrap().to_digit(10).unwrap() as u8,
+                    value,
+                })),
+                Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
+            },
             "OPTARIF" => match data {
                 "BASE" => Ok(Some(Message::TariffOption(TariffOptionValue::Base))),
                 "HC.." => Ok(Some(Message::TariffOption(TariffOptionValue::OffPeakHours
@@ -176,7 +534,7 @@ pub fn parse_group(group: &str) -> Result<Option<Message>, ParseError> {
                 _ => Err(ParseError::FieldError("DEMAIN".into(), data.into())),
             },
             "PAPP" => match data.parse::<u16>() {
-                Ok(value) => Ok(Some(Message::ApparentPower { value: value })),
+                Ok(value) => Ok(Some(Message::ApparentPower { value })),
                 Err(_) => Err(ParseError::FieldError("PAPP".into(), data.into())),
             },
             "HHPHC" => match data {
@@ -187,14 +545,73 @@ pub fn parse_group(group: &str) -> Result<Option<Message>, ParseError> {
                 "Y" => Ok(Some(Message::HHPHC(HHPHCValue::Y))),
                 _ => Err(ParseError::FieldError("HHPHC".into(), data.into())),
             },
-            // The following codes are ignored
-            "MOTDETAT" | "IMAX1" | "IMAX2" | "IMAX3" | "PPOT" | "PMAX" | "ISOUSC" => Ok(None),
+            "PEJP" => match data.parse::<u8>() {
+                Ok(minutes) => Ok(Some(Message::EJPNotice { minutes })),
+                Err(_) => Err(ParseError::FieldError("PEJP".into(), data.into())),
+            },
+            "ISOUSC" => match data.parse::<u8>() {
+                Ok(value) => Ok(Some(Message::SubscribedCurrent { value })),
+                Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
+            },
+            "PMAX" => match data.parse::<u32>() {
+                Ok(value) => Ok(Some(Message::MaxPower { value })),
+                Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
+            },
+            // Bits 1 to 3 are set when the voltage of phases 1 to 3 is missing
+            "PPOT" => match u8::from_str_radix(data, 16) {
+                Ok(bits) => Ok(Some(Message::PhasePotential {
+                    phase1: bits & 0x02 == 0,
+                    phase2: bits & 0x04 == 0,
+                    phase3: bits & 0x08 == 0,
+                })),
+                Err(_e) => Err(ParseError::FieldError(code.into(), data.into()))
+            },
+            "MOTDETAT" => match MeterStatus::from_hex(data) {
+                Some(status) => Ok(Some(Message::MeterStatus(status))),
+                None => Err(ParseError::FieldError(code.into(), data.into()))
+            },
             _ => panic!("Matching a code that is not recognized should never happen"),
         };
     }
+    if group.contains('\t') {
+        return Err(ParseError::ModeError(group.into()));
+    }
     Err(ParseError::GroupError(group.into()))
 }
 
+/// Parses a horodate: a season letter, then YYMMDDhhmmss. The letter is
+/// lower case while the clock is degraded and blank when it is failing.
+fn parse_horodate(horodate: &str) -> Option<Horodate> {
+    let season = horodate.chars().next()?;
+    let (season, degraded) = match season {
+        'E' | 'e' => (Some(Season::Summer), season == 'e'),
+        'H' | 'h' => (Some(Season::Winter), season == 'h'),
+        ' ' => (None, false),
+        _ => return None,
+    };
+    let digits = &horodate[1..];
+    if digits.len() != 12 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |start: usize| digits[start..start + 2].parse::<u8>().unwrap();
+    let horodate = Horodate {
+        season,
+        degraded,
+        year: 2000 + field(0) as u16,
+        month: field(2),
+        day: field(4),
+        hour: field(6),
+        minute: field(8),
+        second: field(10),
+    };
+    let valid = (1..=12).contains(&horodate.month)
+        && (1..=31).contains(&horodate.day)
+        && horodate.hour < 24
+        && horodate.minute < 60
+        && horodate.second < 60;
+    valid.then_some(horodate)
+}
+
 fn parse_period(code: &str) -> Result<TarifPeriod, ParseError> {
     // HCJB
 
@@ -217,7 +634,7 @@ fn parse_period(code: &str) -> Result<TarifPeriod, ParseError> {
     };
 
     Ok(TarifPeriod {
-        hour: hour,
+        hour,
         day_color: Some(day),
     })
 }
@@ -228,7 +645,10 @@ mod tests {
 
     #[test]
     fn parse_adco() {
-        assert_eq!(parse_group("ADCO 020830022493 8"), Ok(Some(Message::ADCO)));
+        assert_eq!(
+            parse_group("ADCO 020830022493 8"),
+            Ok(Some(Message::MeterAddress(String::from("020830022493"))))
+        );
     }
 
     #[test]
@@ -241,18 +661,16 @@ mod tests {
 
     #[test]
     fn parse_tomorrow_blue() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("DEMAIN BLEU +"),
+            parse_group("DEMAIN BLEU V"),
             Ok(Some(Message::Tomorrow(Some(DayColor::Blue))))
         );
     }
 
     #[test]
     fn parse_tomorrow_white() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("DEMAIN BLAN +"),
+            parse_group("DEMAIN BLAN K"),
             Ok(Some(Message::Tomorrow(Some(DayColor::White))))
         );
     }
@@ -267,18 +685,16 @@ mod tests {
 
     #[test]
     fn parse_opttarif_base() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("OPTARIF BASE S"),
+            parse_group("OPTARIF BASE 0"),
             Ok(Some(Message::TariffOption(TariffOptionValue::Base)))
         );
     }
 
     #[test]
     fn parse_opttarif_heures_creuses() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("OPTARIF HC.. S"),
+            parse_group("OPTARIF HC.. <"),
             Ok(Some(Message::TariffOption(TariffOptionValue::OffPeakHours
             )))
         );
@@ -286,9 +702,8 @@ mod tests {
 
     #[test]
     fn parse_opttarif_ejp() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("OPTARIF EJP. S"),
+            parse_group("OPTARIF EJP. \""),
             Ok(Some(Message::TariffOption(TariffOptionValue::EJP)))
         );
     }
@@ -303,75 +718,260 @@ mod tests {
 
     #[test]
     fn parse_opttarif_bad_data() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("OPTARIF ABCD S"),
+            parse_group("OPTARIF ABCD _"),
             Err(ParseError::FieldError("OPTARIF".into(), "ABCD".into()))
         );
     }
 
     #[test]
     fn parse_iinstx() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("IINST1 0 S"),
+            parse_group("IINST1 0 ("),
             Ok(Some(Message::InstantaneousPower { phase: 1, value: 0 }))
         );
         assert_eq!(
-            parse_group("IINST2 0 S"),
+            parse_group("IINST2 0 )"),
             Ok(Some(Message::InstantaneousPower { phase: 2, value: 0 }))
         );
         assert_eq!(
-            parse_group("IINST3 0 S"),
+            parse_group("IINST3 0 *"),
             Ok(Some(Message::InstantaneousPower { phase: 3, value: 0 }))
         );
         assert_eq!(
-            parse_group("IINST1 1 S"),
+            parse_group("IINST1 1 )"),
             Ok(Some(Message::InstantaneousPower { phase: 1, value: 1 }))
         );
         assert_eq!(
-            parse_group("IINST2 1 S"),
+            parse_group("IINST2 1 *"),
             Ok(Some(Message::InstantaneousPower { phase: 2, value: 1 }))
         );
         assert_eq!(
-            parse_group("IINST3 1 S"),
+            parse_group("IINST3 1 +"),
             Ok(Some(Message::InstantaneousPower { phase: 3, value: 1 }))
         );
         assert_eq!(
-            parse_group("IINST1 33 S"),
+            parse_group("IINST1 33 ^"),
             Ok(Some(Message::InstantaneousPower {
                 phase: 1,
                 value: 33
             }))
         );
         assert_eq!(
-            parse_group("IINST2 33 S"),
+            parse_group("IINST2 33 _"),
             Ok(Some(Message::InstantaneousPower {
                 phase: 2,
                 value: 33
             }))
         );
         assert_eq!(
-            parse_group("IINST3 33 S"),
+            parse_group("IINST3 33  "),
             Ok(Some(Message::InstantaneousPower {
                 phase: 3,
                 value: 33
             }))
         );
         assert_eq!(
-            parse_group("IINST1 A S"),
+            parse_group("IINST1 A 9"),
             Err(ParseError::FieldError("IINST1".into(), "A".into()))
         );
         assert_eq!(
-            parse_group("IINST2 A S"),
+            parse_group("IINST2 A :"),
             Err(ParseError::FieldError("IINST2".into(), "A".into()))
         );
         assert_eq!(
-            parse_group("IINST3 A S"),
+            parse_group("IINST3 A ;"),
             Err(ParseError::FieldError("IINST3".into(), "A".into()))
         );
     }
 
+    #[test]
+    fn parse_imaxx() {
+        assert_eq!(
+            parse_group("IMAX1 031 4"),
+            Ok(Some(Message::MaxCurrent { phase: 1, value: 31 }))
+        );
+        assert_eq!(
+            parse_group("IMAX3 029 ="),
+            Ok(Some(Message::MaxCurrent { phase: 3, value: 29 }))
+        );
+        assert_eq!(
+            parse_group("IMAX2 A \""),
+            Err(ParseError::FieldError("IMAX2".into(), "A".into()))
+        );
+    }
+
+    #[test]
+    fn parse_isousc() {
+        assert_eq!(
+            parse_group("ISOUSC 30 9"),
+            Ok(Some(Message::SubscribedCurrent { value: 30 }))
+        );
+        assert_eq!(
+            parse_group("ISOUSC A W"),
+            Err(ParseError::FieldError("ISOUSC".into(), "A".into()))
+        );
+    }
+
+    #[test]
+    fn parse_pmax() {
+        assert_eq!(
+            parse_group("PMAX 13190 4"),
+            Ok(Some(Message::MaxPower { value: 13190 }))
+        );
+        assert_eq!(
+            parse_group("PMAX A 7"),
+            Err(ParseError::FieldError("PMAX".into(), "A".into()))
+        );
+    }
+
+    #[test]
+    fn parse_ppot() {
+        assert_eq!(
+            parse_group("PPOT 00 #"),
+            Ok(Some(Message::PhasePotential {
+                phase1: true,
+                phase2: true,
+                phase3: true
+            }))
+        );
+        assert_eq!(
+            parse_group("PPOT 04 '"),
+            Ok(Some(Message::PhasePotential {
+                phase1: true,
+                phase2: false,
+                phase3: true
+            }))
+        );
+        assert_eq!(
+            parse_group("PPOT 0E 8"),
+            Ok(Some(Message::PhasePotential {
+                phase1: false,
+                phase2: false,
+                phase3: false
+            }))
+        );
+        assert_eq!(
+            parse_group("PPOT XY 4"),
+            Err(ParseError::FieldError("PPOT".into(), "XY".into()))
+        );
+    }
+
+    #[test]
+    fn parse_motdetat() {
+        assert_eq!(
+            parse_group("MOTDETAT 000000 B"),
+            Ok(Some(Message::MeterStatus(MeterStatus::empty())))
+        );
+        assert_eq!(
+            parse_group("MOTDETAT 000100 C"),
+            Ok(Some(Message::MeterStatus(MeterStatus::from_bits_retain(0x100))))
+        );
+        assert_eq!(
+            parse_group("MOTDETAT 00000G Y"),
+            Err(ParseError::FieldError("MOTDETAT".into(), "00000G".into()))
+        );
+    }
+
+    #[test]
+    fn parse_pejp() {
+        assert_eq!(
+            parse_group("PEJP 30 R"),
+            Ok(Some(Message::EJPNotice { minutes: 30 }))
+        );
+        assert_eq!(
+            parse_group("PEJP A 0"),
+            Err(ParseError::FieldError("PEJP".into(), "A".into()))
+        );
+    }
+
+    #[test]
+    fn parse_date() {
+        assert_eq!(
+            parse_group("DATE\tE240716123005\t\t="),
+            Ok(Some(Message::Date(Horodate {
+                season: Some(Season::Summer),
+                degraded: false,
+                year: 2024,
+                month: 7,
+                day: 16,
+                hour: 12,
+                minute: 30,
+                second: 5,
+            })))
+        );
+        match parse_group("DATE h240116083000  _") {
+            Ok(Some(Message::Date(horodate))) => {
+                assert_eq!(horodate.season, Some(Season::Winter));
+                assert!(horodate.degraded);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_group("DATE\t 240116083000\t\tR") {
+            Ok(Some(Message::Date(horodate))) => assert_eq!(horodate.season, None),
+            other => panic!("unexpected {:?}", other),
+        }
+        // Test frames may carry a reset clock
+        assert_eq!(
+            parse_group("DATE\tE000000000000\t\t^"),
+            Err(ParseError::FieldError("DATE".into(), "E000000000000".into()))
+        );
+        assert_eq!(
+            parse_group("DATE\tE240716123005\t\t>"),
+            Err(ParseError::ChecksumError {
+                expected: '=',
+                actual: '>'
+            })
+        );
+        assert_eq!(
+            parse_group("DATE\tE24\t\tD"),
+            Err(ParseError::GroupError("DATE\tE24\t\tD".into()))
+        );
+    }
+
+    #[test]
+    fn filter_labels() {
+        let filter = LabelFilter::new(vec!["ADCO", "PAPP", "PTEC"]);
+        assert_eq!(
+            parse_filtered_group("PAPP 00803 ,", &filter),
+            Ok(Some(Message::ApparentPower { value: 803 }))
+        );
+        assert_eq!(parse_filtered_group("IINST1 003 K", &filter), Ok(None));
+        // Skipped before parsing
+        assert_eq!(parse_filtered_group("IINST1 ABC >", &filter), Ok(None));
+        assert_eq!(parse_filtered_group("PAP 00803 .", &filter), Ok(None));
+        assert!(filter.wants("PTEC\tHPJB\t9"));
+        assert!(parse_filtered_group("PAPP ABCDE @", &filter).is_err());
+    }
+
+    #[test]
+    fn parse_hchc() {
+        assert_eq!(
+            parse_group("HCHC 001234567 \""),
+            Ok(Some(Message::Index {
+                period: TarifPeriod {
+                    hour: HourlyTarifPeriod::OffPeakHours,
+                    day_color: None
+                },
+                value: 1234567
+            }))
+        );
+        assert_eq!(
+            parse_group("HCHP 007654321 /"),
+            Ok(Some(Message::Index {
+                period: TarifPeriod {
+                    hour: HourlyTarifPeriod::PeakHours,
+                    day_color: None
+                },
+                value: 7654321
+            }))
+        );
+        assert_eq!(
+            parse_group("HCHP 00765432A ?"),
+            Err(ParseError::FieldError("HCHP".into(), "00765432A".into()))
+        );
+    }
+
     #[test]
     fn parse_bbrhcjc() {
         assert_eq!(
@@ -385,7 +985,7 @@ mod tests {
             }))
         );
         assert_eq!(
-            parse_group("BBRHCJB a -"),
+            parse_group("BBRHCJB a N"),
             Err(ParseError::FieldError("BBRHCJB".into(), "a".into()))
         );
     }
@@ -393,7 +993,7 @@ mod tests {
     #[test]
     fn parse_bbrhcjw() {
         assert_eq!(
-            parse_group("BBRHCJW 023916830 ="), // control OK
+            parse_group("BBRHCJW 023916830 R"), // control OK
             Ok(Some(Message::Index {
                 period: TarifPeriod {
                     hour: HourlyTarifPeriod::OffPeakHours
@@ -405,7 +1005,7 @@ mod tests {
             }))
         );
         assert_eq!(
-            parse_group("BBRHCJW a -"),
+            parse_group("BBRHCJW a #"),
             Err(ParseError::FieldError("BBRHCJW".into(), "a".into()))
         );
     }
@@ -413,7 +1013,7 @@ mod tests {
     #[test]
     fn parse_bbrhcjr() {
         assert_eq!(
-            parse_group("BBRHCJR 023916830 ="), // control OK
+            parse_group("BBRHCJR 023916830 M"), // control OK
             Ok(Some(Message::Index {
                 period: TarifPeriod {
                     hour: HourlyTarifPeriod::OffPeakHours
@@ -425,7 +1025,7 @@ mod tests {
             }))
         );
         assert_eq!(
-            parse_group("BBRHCJR a -"),
+            parse_group("BBRHCJR a ^"),
             Err(ParseError::FieldError("BBRHCJR".into(), "a".into()))
         );
     }
@@ -433,7 +1033,7 @@ mod tests {
     #[test]
     fn parse_bbrhpjb() {
         assert_eq!(
-            parse_group("BBRHPJB 023916830 ="), // control OK
+            parse_group("BBRHPJB 023916830 J"), // control OK
             Ok(Some(Message::Index {
                 period: TarifPeriod {
                     hour: HourlyTarifPeriod::PeakHours,
@@ -443,7 +1043,7 @@ mod tests {
             }))
         );
         assert_eq!(
-            parse_group("BBRHPJB a -"),
+            parse_group("BBRHPJB a ["),
             Err(ParseError::FieldError("BBRHPJB".into(), "a".into()))
         );
     }
@@ -451,7 +1051,7 @@ mod tests {
     #[test]
     fn parse_bbrhpjw() {
         assert_eq!(
-            parse_group("BBRHPJW 023916830 ="), // control OK
+            parse_group("BBRHPJW 023916830 _"), // control OK
             Ok(Some(Message::Index {
                 period: TarifPeriod {
                     hour: HourlyTarifPeriod::PeakHours,
@@ -461,7 +1061,7 @@ mod tests {
             }))
         );
         assert_eq!(
-            parse_group("BBRHPJW a -"),
+            parse_group("BBRHPJW a 0"),
             Err(ParseError::FieldError("BBRHPJW".into(), "a".into()))
         );
     }
@@ -469,7 +1069,7 @@ mod tests {
     #[test]
     fn parse_bbrhpjr() {
         assert_eq!(
-            parse_group("BBRHPJR 023916830 ="), // control OK
+            parse_group("BBRHPJR 023916830 Z"), // control OK
             Ok(Some(Message::Index {
                 period: TarifPeriod {
                     hour: HourlyTarifPeriod::PeakHours,
@@ -479,7 +1079,7 @@ mod tests {
             }))
         );
         assert_eq!(
-            parse_group("BBRHPJR a -"),
+            parse_group("BBRHPJR a +"),
             Err(ParseError::FieldError("BBRHPJR".into(), "a".into()))
         );
     }
@@ -495,28 +1095,27 @@ mod tests {
             Ok(Some(Message::ApparentPower { value: 813 }))
         );
         assert_eq!(
-            parse_group("PAPP a -"),
+            parse_group("PAPP a R"),
             Err(ParseError::FieldError("PAPP".into(), "a".into()))
         );
     }
 
     #[test]
     fn parse_hhphc() {
-        // TODO: correct control char
         assert_eq!(
-            parse_group("HHPHC A D"),
+            parse_group("HHPHC A ,"),
             Ok(Some(Message::HHPHC(HHPHCValue::A)))
         );
         assert_eq!(
-            parse_group("HHPHC C D"),
+            parse_group("HHPHC C ."),
             Ok(Some(Message::HHPHC(HHPHCValue::C)))
         );
         assert_eq!(
-            parse_group("HHPHC D D"),
+            parse_group("HHPHC D /"),
             Ok(Some(Message::HHPHC(HHPHCValue::D)))
         );
         assert_eq!(
-            parse_group("HHPHC E D"),
+            parse_group("HHPHC E 0"),
             Ok(Some(Message::HHPHC(HHPHCValue::E)))
         );
         assert_eq!(
@@ -524,7 +1123,7 @@ mod tests {
             Ok(Some(Message::HHPHC(HHPHCValue::Y)))
         );
         assert_eq!(
-            parse_group("HHPHC X D"),
+            parse_group("HHPHC X C"),
             Err(ParseError::FieldError("HHPHC".into(), "X".into()))
         );
     }
@@ -551,7 +1150,7 @@ mod tests {
             })))
         );
         assert_eq!(
-            parse_group("PTEC HCJB S"), // control is OK
+            parse_group("PTEC HCJB C"), // control is OK
             Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
                 hour: HourlyTarifPeriod::OffPeakHours
 
@@ -560,7 +1159,7 @@ mod tests {
             })))
         );
         assert_eq!(
-            parse_group("PTEC HCJW S"), // control is OK
+            parse_group("PTEC HCJW X"), // control is OK
             Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
                 hour: HourlyTarifPeriod::OffPeakHours
 
@@ -578,28 +1177,28 @@ mod tests {
             })))
         );
         assert_eq!(
-            parse_group("PTEC HPJB S"), // control is OK
+            parse_group("PTEC HPJB P"), // control is OK
             Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
                 hour: HourlyTarifPeriod::PeakHours,
                 day_color: Some(DayColor::Blue)
             })))
         );
         assert_eq!(
-            parse_group("PTEC HPJW S"), // control is OK
+            parse_group("PTEC HPJW %"), // control is OK
             Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
                 hour: HourlyTarifPeriod::PeakHours,
                 day_color: Some(DayColor::White)
             })))
         );
         assert_eq!(
-            parse_group("PTEC HPJR S"), // control is OK
+            parse_group("PTEC HPJR  "), // control is OK
             Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
                 hour: HourlyTarifPeriod::PeakHours,
                 day_color: Some(DayColor::Red)
             })))
         );
         assert_eq!(
-            parse_group("PTEC XXXX S"),
+            parse_group("PTEC XXXX L"),
             Err(ParseError::FieldError("PTEC".into(), "XXXX".into()))
         );
     }
@@ -610,13 +1209,82 @@ mod tests {
 
     #[test]
     fn parse_iinst4() {
-        // TODO: correct control char
         assert_eq!(
             parse_group("IINST4 3 S"),
             Err(ParseError::GroupError(String::from("IINST4 3 S")))
         );
     }
 
+    #[test]
+    fn error_kinds() {
+        let error = parse_group("PAPP 0080\u{1}3 .").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Corruption);
+        assert!(error.is_recoverable());
+        let error = parse_group("SINSTS\t00803\t.").unwrap_err();
+        assert_eq!(error, ParseError::ModeError("SINSTS\t00803\t.".into()));
+        assert_eq!(error.kind(), ErrorKind::Configuration);
+        assert!(!error.is_recoverable());
+        assert_eq!(
+            error.to_string(),
+            "Group of standard mode: 'SINSTS\t00803\t.'"
+        );
+        assert_eq!(
+            ParseError::FieldError("PAPP".into(), "A".into()).to_string(),
+            "Unable to parse PAPP with data: 'A'"
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum("PAPP 00803"), ',');
+        assert_eq!(checksum("PTEC\tHPJB"), '9');
+        let error = parse_group("PAPP 00803 .").unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::ChecksumError {
+                expected: ',',
+                actual: '.'
+            }
+        );
+        assert_eq!(error.kind(), ErrorKind::Corruption);
+        assert_eq!(error.to_string(), "Checksum error: expected ',', got '.'");
+        assert_eq!(
+            parse_group_unchecked("PAPP 00803 ."),
+            Ok(Some(Message::ApparentPower { value: 803 }))
+        );
+        // A space is a valid checksum
+        assert_eq!(
+            parse_group("IINST3 33  "),
+            Ok(Some(Message::InstantaneousPower { phase: 3, value: 33 }))
+        );
+    }
+
+    #[test]
+    fn frames() {
+        let frame = Frame::try_from(
+            "\x02\nADCO 031762120110 /\r\nIINST1 003 K\r\nIINST2 002 K\r\nPAPP 00803 ,\r\nMOTDETAT 000000 B\r\x03",
+        )
+        .unwrap();
+        assert_eq!(frame.messages().len(), 5);
+        assert_eq!(
+            frame.get(MessageKind::ApparentPower),
+            Some(&Message::ApparentPower { value: 803 })
+        );
+        assert_eq!(frame.get(MessageKind::Tomorrow), None);
+        assert_eq!(frame.get_all(MessageKind::InstantaneousPower).count(), 2);
+        assert_eq!(
+            Frame::try_from("ADCO 031762120110 /\nPAPP ABCDE @"),
+            Err(ParseError::FieldError("PAPP".into(), "ABCDE".into()))
+        );
+
+        let address = Message::MeterAddress(String::from("031762120110"));
+        let built: Frame = vec![address.clone(), Message::ApparentPower { value: 803 }]
+            .into_iter()
+            .collect();
+        assert_eq!(built.get(MessageKind::MeterAddress), Some(&address));
+        assert_eq!(address.kind(), MessageKind::MeterAddress);
+    }
+
     #[test]
     fn parse_unknown_code() {
         assert_eq!(
@@ -0,0 +1,134 @@
+//! Average active power from the increase of the indexes.
+//!
+//! PAPP is an apparent power, in VA, rounded and only sampled once per
+//! frame: integrating it never quite matches the bill. The indexes count the
+//! active energy the bill is made of, in Wh, so their increase over an
+//! interval gives its average active power, in W. Only the index of the
+//! current tariff period counts, and the energy of an interval spanning a
+//! switch, e.g. from peak to off-peak hours or to the next Tempo day, is
+//! spread over several of them: the increases of all the indexes are summed.
+//!
+//! Indexes only count whole Wh, so the interval has to be long enough for
+//! the power to be precise, about a minute at a few hundred W.
+
+use crate::{Frame, Message, MessageKind, TarifPeriod};
+use std::time::{Duration, Instant};
+
+/// Average active power over successive intervals of a minimum duration,
+/// from the indexes of the frames.
+#[derive(Debug, Clone)]
+pub struct IndexRate {
+    interval: Duration,
+    /// Indexes at the start of the interval, with the time they were read
+    start: Option<(Instant, Vec<(TarifPeriod, u32)>)>,
+}
+
+impl IndexRate {
+    pub fn new(interval: Duration) -> IndexRate {
+        IndexRate {
+            interval,
+            start: None,
+        }
+    }
+
+    /// Records the indexes of a frame read at `now`, returning the average
+    /// power in W since the start of the interval once it lasted long enough,
+    /// the next interval starting then. Indexes missing from the frame are
+    /// taken as unchanged. An index going backwards, e.g. after the meter was
+    /// replaced, or showing up during the interval gives no power.
+    pub fn update(&mut self, frame: &Frame, now: Instant) -> Option<f64> {
+        let indexes: Vec<(TarifPeriod, u32)> = frame
+            .get_all(MessageKind::Index)
+            .filter_map(|message| match message {
+                Message::Index { period, value } => Some((*period, *value)),
+                _ => None,
+            })
+            .collect();
+        if indexes.is_empty() {
+            return None;
+        }
+        let (start, before) = match &self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some((now, indexes));
+                return None;
+            }
+        };
+        let elapsed = now.saturating_duration_since(*start);
+        if elapsed < self.interval || elapsed.is_zero() {
+            return None;
+        }
+        let energy = energy(before, &indexes);
+        let mut current = before.clone();
+        for (period, value) in indexes {
+            match current.iter_mut().find(|(known, _)| *known == period) {
+                Some(index) => index.1 = value,
+                None => current.push((period, value)),
+            }
+        }
+        self.start = Some((now, current));
+        Some(energy? as f64 * 3600.0 / elapsed.as_secs_f64())
+    }
+}
+
+/// Energy in Wh counted by the indexes between two readings, none when an
+/// index cannot be compared.
+fn energy(before: &[(TarifPeriod, u32)], after: &[(TarifPeriod, u32)]) -> Option<u64> {
+    after.iter().try_fold(0, |total, (period, value)| {
+        let (_, previous) = before.iter().find(|(known, _)| known == period)?;
+        Some(total + u64::from(value.checked_sub(*previous)?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DayColor, HourlyTarifPeriod};
+
+    fn index(hour: HourlyTarifPeriod, day_color: DayColor, value: u32) -> Message {
+        Message::Index {
+            period: TarifPeriod {
+                hour,
+                day_color: Some(day_color),
+            },
+            value,
+        }
+    }
+
+    #[test]
+    fn average_power() {
+        use DayColor::*;
+        use HourlyTarifPeriod::*;
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut rate = IndexRate::new(Duration::from_secs(60));
+        let frame = |hc_blue, hp_blue, hc_white| -> Frame {
+            vec![
+                index(OffPeakHours, Blue, hc_blue),
+                index(PeakHours, Blue, hp_blue),
+                index(OffPeakHours, White, hc_white),
+            ]
+            .into_iter()
+            .collect()
+        };
+        assert_eq!(rate.update(&frame(1000, 2000, 0), start), None);
+        assert_eq!(rate.update(&frame(1000, 2010, 0), at(30)), None);
+        // 36 Wh in 72 s
+        assert_eq!(rate.update(&frame(1000, 2036, 0), at(72)), Some(1800.0));
+
+        // Switching from a blue peak day to a white night, meanwhile
+        assert_eq!(rate.update(&frame(1000, 2040, 20), at(144)), Some(1200.0));
+
+        // Frames without indexes are ignored, missing indexes unchanged
+        let papp: Frame = vec![Message::ApparentPower { value: 800 }]
+            .into_iter()
+            .collect();
+        assert_eq!(rate.update(&papp, at(200)), None);
+        let partial: Frame = vec![index(OffPeakHours, White, 30)].into_iter().collect();
+        assert_eq!(rate.update(&partial, at(216)), Some(500.0));
+
+        // Replaced meter
+        assert_eq!(rate.update(&frame(0, 0, 0), at(300)), None);
+        assert_eq!(rate.update(&frame(0, 0, 10), at(372)), Some(500.0));
+    }
+}
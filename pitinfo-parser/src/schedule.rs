@@ -0,0 +1,96 @@
+//! Off-peak windows of the HHPHC schedules.
+//!
+//! HHPHC names the schedule programmed in the meter for the switch between
+//! peak and off-peak hours. The times of most schedules are not national:
+//! the local distribution network sets them, within about 8 hours a day, and
+//! they differ from one town to the next. Only the Tempo schedule, Y, has
+//! contractual off-peak hours, the same everywhere, from 22:00 to 06:00.
+
+use crate::HHPHCValue;
+
+/// Minutes in a day.
+const DAY: u16 = 24 * 60;
+
+/// Off-peak window of a day, in local time, as minutes from midnight. A
+/// window ending before its start runs over midnight.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct OffPeakWindow {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl OffPeakWindow {
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Minutes from a time until the window starts, 0 within it.
+    pub fn minutes_until(&self, minute: u16) -> u16 {
+        if self.contains(minute) {
+            0
+        } else {
+            (self.start + DAY - minute % DAY) % DAY
+        }
+    }
+}
+
+const TEMPO_WINDOWS: &[OffPeakWindow] = &[OffPeakWindow {
+    start: 22 * 60,
+    end: 6 * 60,
+}];
+
+impl HHPHCValue {
+    /// Off-peak windows of the schedule, none when they are set locally.
+    pub fn off_peak_windows(&self) -> Option<&'static [OffPeakWindow]> {
+        match self {
+            HHPHCValue::Y => Some(TEMPO_WINDOWS),
+            HHPHCValue::A | HHPHCValue::C | HHPHCValue::D | HHPHCValue::E => None,
+        }
+    }
+
+    /// Minutes from a local time, in minutes from midnight, until the next
+    /// off-peak hours, 0 during them, none when the windows are set locally.
+    pub fn minutes_until_off_peak(&self, minute: u16) -> Option<u16> {
+        self.off_peak_windows()?
+            .iter()
+            .map(|window| window.minutes_until(minute))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            HHPHCValue::Y.off_peak_windows(),
+            Some(
+                &[OffPeakWindow {
+                    start: 1320,
+                    end: 360
+                }][..]
+            )
+        );
+        assert_eq!(HHPHCValue::C.off_peak_windows(), None);
+
+        // 21:15, 23:00 and 06:00
+        assert_eq!(HHPHCValue::Y.minutes_until_off_peak(1275), Some(45));
+        assert_eq!(HHPHCValue::Y.minutes_until_off_peak(1380), Some(0));
+        assert_eq!(HHPHCValue::Y.minutes_until_off_peak(360), Some(960));
+        assert_eq!(HHPHCValue::D.minutes_until_off_peak(360), None);
+
+        let noon = OffPeakWindow {
+            start: 750,
+            end: 870,
+        };
+        assert!(noon.contains(750));
+        assert!(!noon.contains(870));
+        assert_eq!(noon.minutes_until(1380), 810);
+    }
+}
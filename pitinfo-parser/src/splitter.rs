@@ -0,0 +1,199 @@
+//! Groups and frame delimiters of a stream of bytes, split without
+//! allocating.
+//!
+//! The delimiters are located with `memchr`, which scans a whole chunk of
+//! the stream at once instead of looking at each byte. Groups are handed out
+//! as slices of the bytes split, or of a buffer whose size is set at compile
+//! time, so that embedded targets bound the memory used statically: the
+//! splitter only needs `core`.
+
+use memchr::{memchr3, memrchr};
+
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+const EOT: u8 = 0x04;
+const LF: u8 = b'\n';
+const CR: u8 = b'\r';
+
+/// Piece of a stream found by a `Splitter`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Token<'a> {
+    /// Group, without its line feed and carriage return, and the position in
+    /// the bytes split of the delimiter ending it
+    Group { bytes: &'a [u8], end: usize },
+    /// Start of a frame, STX
+    Start,
+    /// End of a frame, ETX
+    End,
+    /// Frame interrupted by the meter, EOT
+    Interrupted,
+}
+
+/// Splits a stream of bytes into groups, ending at line feeds and frame
+/// delimiters, without the carriage returns. The bytes before the first
+/// delimiter are dropped: reading usually starts in the middle of a group.
+///
+/// Groups longer than `GROUP` bytes and the groups of a frame beyond the
+/// first `GROUPS` are taken for noise and dropped, so that the buffer has a
+/// fixed size, known at compile time, and never grows.
+#[derive(Debug, Clone)]
+pub struct Splitter<const GROUP: usize, const GROUPS: usize> {
+    /// Start of a group not ended yet
+    pending: [u8; GROUP],
+    length: usize,
+    /// Whether the group not ended yet is too long
+    overflow: bool,
+    /// Groups of the current frame so far
+    groups: usize,
+    /// Whether a delimiter was seen
+    aligned: bool,
+}
+
+impl<const GROUP: usize, const GROUPS: usize> Splitter<GROUP, GROUPS> {
+    pub const fn new() -> Self {
+        Splitter {
+            pending: [0; GROUP],
+            length: 0,
+            overflow: false,
+            groups: 0,
+            aligned: false,
+        }
+    }
+
+    /// Hands the groups ended by the bytes and the frame delimiters to
+    /// `token`, in the order of the stream, keeping the start of the last
+    /// group.
+    pub fn split(&mut self, mut bytes: &[u8], mut token: impl FnMut(Token<'_>)) {
+        let mut offset = 0;
+        while let Some(end) = memchr3(LF, STX, ETX, bytes) {
+            let mut line = if self.length == 0 {
+                &bytes[..end]
+            } else {
+                self.append(&bytes[..end]);
+                &self.pending[..self.length]
+            };
+            // The rest of an interrupted frame is dropped
+            if let Some(interrupted) = memrchr(EOT, line) {
+                line = &line[interrupted + 1..];
+                self.groups = 0;
+                token(Token::Interrupted);
+            }
+            let line = line.strip_suffix(&[CR]).unwrap_or(line);
+            let noise = self.overflow || line.len() > GROUP || self.groups >= GROUPS;
+            if self.aligned && !noise && !line.is_empty() {
+                token(Token::Group {
+                    bytes: line,
+                    end: offset + end,
+                });
+                self.groups += 1;
+            }
+            match bytes[end] {
+                STX => token(Token::Start),
+                ETX => token(Token::End),
+                _ => (),
+            }
+            if bytes[end] != LF {
+                self.groups = 0;
+            }
+            self.length = 0;
+            self.overflow = false;
+            self.aligned = true;
+            bytes = &bytes[end + 1..];
+            offset += end + 1;
+        }
+        self.append(bytes);
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        let length = self.length + bytes.len();
+        if self.overflow || length > GROUP {
+            self.overflow = true;
+            self.length = 0;
+        } else {
+            self.pending[self.length..length].copy_from_slice(bytes);
+            self.length = length;
+        }
+    }
+}
+
+impl<const GROUP: usize, const GROUPS: usize> Default for Splitter<GROUP, GROUPS> {
+    fn default() -> Self {
+        Splitter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split<const GROUP: usize, const GROUPS: usize>(
+        splitter: &mut Splitter<GROUP, GROUPS>,
+        bytes: &[u8],
+    ) -> Vec<String> {
+        let mut tokens = Vec::new();
+        splitter.split(bytes, |token| {
+            tokens.push(match token {
+                Token::Group { bytes, .. } => String::from_utf8_lossy(bytes).into_owned(),
+                token => format!("{:?}", token),
+            })
+        });
+        tokens
+    }
+
+    #[test]
+    fn groups() {
+        let mut splitter = Splitter::<512, 128>::new();
+        assert_eq!(
+            split(
+                &mut splitter,
+                b"DCO 031762120110 @\r\x03\x02\nADCO 031762120110 @\r\nPA"
+            ),
+            vec!["End", "Start", "ADCO 031762120110 @"]
+        );
+        assert_eq!(
+            split(&mut splitter, b"PP 00803 .\r\x03\x02\nPTEC\tHPJB\tP\r"),
+            vec!["PAPP 00803 .", "End", "Start"]
+        );
+        assert_eq!(split(&mut splitter, b"\n"), vec!["PTEC\tHPJB\tP"]);
+        assert_eq!(split(&mut splitter, &[b'x'; 513]), Vec::<String>::new());
+        assert_eq!(
+            split(&mut splitter, b"x\nIINST1 003 J\n"),
+            vec!["IINST1 003 J"]
+        );
+        assert_eq!(
+            split(&mut splitter, b"PAPP 0\x04\x02\nPAPP 00803 .\r\n"),
+            vec!["Interrupted", "Start", "PAPP 00803 ."]
+        );
+    }
+
+    #[test]
+    fn positions() {
+        let mut splitter = Splitter::<512, 128>::new();
+        let mut ends = Vec::new();
+        splitter.split(b"\x02\nADCO 0 @\r\nPAPP 00803 .\r\x03", |token| {
+            if let Token::Group { end, .. } = token {
+                ends.push(end);
+            }
+        });
+        assert_eq!(ends, vec![11, 25]);
+    }
+
+    #[test]
+    fn bounded_frames() {
+        let mut splitter = Splitter::<12, 2>::new();
+        assert_eq!(
+            split(
+                &mut splitter,
+                b"\x02\nADCO 0 @\r\nPAPP 00803 .\r\nIINST1 3 J\r\x03"
+            ),
+            vec!["Start", "ADCO 0 @", "PAPP 00803 .", "End"]
+        );
+        assert_eq!(
+            split(
+                &mut splitter,
+                b"\x02\nBBRHCJB 000012345 ,\r\nPAPP 00803 .\r\n"
+            ),
+            vec!["Start", "PAPP 00803 ."]
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! Groups of the standard mode of the Linky.
+//!
+//! Standard mode separates the fields with tabs, since data may hold spaces,
+//! and some groups carry a horodate before their data, e.g. the time of the
+//! maximum power of the day. The checksum also covers the separator before
+//! it. The groups with a historic equivalent give the same messages, e.g.
+//! SINSTS the apparent power like PAPP and ADSC the address like ADCO, so
+//! that the integrations work the same in both modes. The indexes of the
+//! supplier calendar only get their period from the tariff option, sent in
+//! another group, and are left to the reader of the frame to map.
+
+use crate::{
+    checksum, parse_horodate, DayColor, HourlyTarifPeriod, Message, ParseError, TarifPeriod,
+    TariffOptionValue,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref LABELS: Regex = Regex::new(
+        "^(ADSC|VTIC|DATE|NGTF|LTARF|EAST|EASF(0[1-9]|10)|EASD0[1-4]|EAIT|ERQ[1-4]|IRMS[1-3]\
+         |URMS[1-3]|PREF|PCOUP|SINSTS[1-3]?|SMAXSN[1-3]?(-1)?|SINSTI|SMAXIN(-1)?|CCASN(-1)?\
+         |CCAIN(-1)?|UMOY[1-3]|STGE|DPM[1-3]|FPM[1-3]|MSG[12]|PRM|RELAIS|NTARF|NJOURF(\\+1)?\
+         |PJOURF\\+1|PPOINTE)$"
+    )
+    .unwrap();
+}
+
+/// Parses a group of standard mode, checking its checksum when `checked`.
+pub fn parse_group(group: &str, checked: bool) -> Result<Option<Message>, ParseError> {
+    let (span, control) = match group.rfind('\t') {
+        Some(end) => group.split_at(end + 1),
+        None if group.contains(' ') => return Err(ParseError::HistoricModeError(group.into())),
+        None => return Err(ParseError::GroupError(group.into())),
+    };
+    let mut fields = span[..span.len() - 1].split('\t');
+    let label = fields.next().unwrap_or_default();
+    // The horodate, if any, then the data
+    let fields: Vec<&str> = fields.collect();
+    let mut control = control.chars();
+    let actual = match (control.next(), control.next()) {
+        (Some(actual), None) if LABELS.is_match(label) && (1..=2).contains(&fields.len()) => actual,
+        _ => return Err(ParseError::GroupError(group.into())),
+    };
+    if checked {
+        let expected = checksum(span);
+        if actual != expected {
+            return Err(ParseError::ChecksumError { expected, actual });
+        }
+    }
+    let data = fields[fields.len() - 1];
+    let error = || ParseError::FieldError(label.into(), data.into());
+    let phase = || label[label.len() - 1..].parse::<u8>().map_err(|_| error());
+    match label {
+        "ADSC" => Ok(Some(Message::MeterAddress(data.into()))),
+        "PRM" => Ok(Some(Message::DeliveryPoint(data.into()))),
+        "DATE" => match parse_horodate(fields[0]) {
+            Some(horodate) => Ok(Some(Message::Date(horodate))),
+            None => Err(ParseError::FieldError(label.into(), fields[0].into())),
+        },
+        "NGTF" => Ok(tariff_option(data).map(Message::TariffOption)),
+        "LTARF" => Ok(current_period(data).map(Message::CurrentTariffPeriod)),
+        "EAST" => Ok(Some(Message::SuppliedEnergy {
+            value: data.parse().map_err(|_| error())?,
+        })),
+        "EAIT" => Ok(Some(Message::InjectedEnergy {
+            value: data.parse().map_err(|_| error())?,
+        })),
+        "IRMS1" | "IRMS2" | "IRMS3" => Ok(Some(Message::InstantaneousPower {
+            phase: phase()?,
+            value: data.parse().map_err(|_| error())?,
+        })),
+        "URMS1" | "URMS2" | "URMS3" => Ok(Some(Message::Voltage {
+            phase: phase()?,
+            value: data.parse().map_err(|_| error())?,
+        })),
+        "SINSTS" => Ok(Some(Message::ApparentPower {
+            value: data.parse().map_err(|_| error())?,
+        })),
+        "SINSTI" => Ok(Some(Message::InjectedPower {
+            value: data.parse().map_err(|_| error())?,
+        })),
+        label if label.starts_with("EASF") => Ok(Some(Message::SupplierIndex {
+            index: label[4..].parse().map_err(|_| error())?,
+            value: data.parse().map_err(|_| error())?,
+        })),
+        // The other groups are ignored
+        _ => Ok(None),
+    }
+}
+
+impl TariffOptionValue {
+    /// Period of an index of the supplier calendar, as numbered by the
+    /// regulated offers: off-peak then peak hours, and for Tempo blue, white
+    /// then red days. None for the other options, whose indexes have no
+    /// historic equivalent.
+    pub fn supplier_period(self, index: u8) -> Option<TarifPeriod> {
+        let hour = if index % 2 == 1 {
+            HourlyTarifPeriod::OffPeakHours
+        } else {
+            HourlyTarifPeriod::PeakHours
+        };
+        let day_color = match (self, index) {
+            (TariffOptionValue::OffPeakHours, 1..=2) => None,
+            (TariffOptionValue::Tempo, 1..=2) => Some(DayColor::Blue),
+            (TariffOptionValue::Tempo, 3..=4) => Some(DayColor::White),
+            (TariffOptionValue::Tempo, 5..=6) => Some(DayColor::Red),
+            _ => return None,
+        };
+        Some(TarifPeriod { hour, day_color })
+    }
+}
+
+/// Tariff option of the name of the supplier calendar, none for the names
+/// of other offers.
+fn tariff_option(name: &str) -> Option<TariffOptionValue> {
+    let name = name.trim();
+    if name.contains("TEMPO") {
+        Some(TariffOptionValue::Tempo)
+    } else if name.contains("EJP") {
+        Some(TariffOptionValue::EJP)
+    } else if name.contains("CREUSE") || name.contains("HC") {
+        Some(TariffOptionValue::OffPeakHours)
+    } else if name.contains("BASE") {
+        Some(TariffOptionValue::Base)
+    } else {
+        None
+    }
+}
+
+/// Period of the label of the current tariff, e.g. `HEURE  CREUSE` or
+/// `HP  BLEU`, none for a single period.
+fn current_period(label: &str) -> Option<TarifPeriod> {
+    let words: Vec<&str> = label.split_whitespace().collect();
+    let has = |word: &str| words.contains(&word);
+    let hour = if has("CREUSE") || has("CREUSES") || has("HC") {
+        HourlyTarifPeriod::OffPeakHours
+    } else if has("PLEINE") || has("PLEINES") || has("HP") {
+        HourlyTarifPeriod::PeakHours
+    } else {
+        return None;
+    };
+    let day_color = if has("BLEU") {
+        Some(DayColor::Blue)
+    } else if has("BLANC") {
+        Some(DayColor::White)
+    } else if has("ROUGE") {
+        Some(DayColor::Red)
+    } else {
+        None
+    };
+    Some(TarifPeriod { hour, day_color })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_groups() {
+        assert_eq!(
+            parse_group("ADSC\t041876097437\tE", true),
+            Ok(Some(Message::MeterAddress(String::from("041876097437"))))
+        );
+        assert_eq!(
+            parse_group("PRM\t30001610071843\t#", true),
+            Ok(Some(Message::DeliveryPoint(String::from("30001610071843"))))
+        );
+        assert_eq!(
+            parse_group("EAST\t012345678\t3", true),
+            Ok(Some(Message::SuppliedEnergy { value: 12345678 }))
+        );
+        assert_eq!(
+            parse_group("EAIT\t000001234\tO", true),
+            Ok(Some(Message::InjectedEnergy { value: 1234 }))
+        );
+        assert_eq!(
+            parse_group("IRMS1\t003\t1", true),
+            Ok(Some(Message::InstantaneousPower { phase: 1, value: 3 }))
+        );
+        assert_eq!(
+            parse_group("URMS1\t232\tA", true),
+            Ok(Some(Message::Voltage {
+                phase: 1,
+                value: 232
+            }))
+        );
+        assert_eq!(
+            parse_group("SINSTS\t00803\tQ", true),
+            Ok(Some(Message::ApparentPower { value: 803 }))
+        );
+        assert_eq!(
+            parse_group("SINSTI\t01250\tD", true),
+            Ok(Some(Message::InjectedPower { value: 1250 }))
+        );
+        assert!(matches!(
+            parse_group("DATE\tE240116120000\t\t/", true),
+            Ok(Some(Message::Date(_)))
+        ));
+        // Horodated, ignored
+        assert_eq!(
+            parse_group("SMAXSN\tE240116083012\t05424\t5", true),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_group("MSG1\tPAS DE          MESSAGE         \t<", true),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn tariffs() {
+        assert_eq!(
+            parse_group("NGTF\t     TEMPO      \tF", true),
+            Ok(Some(Message::TariffOption(TariffOptionValue::Tempo)))
+        );
+        assert_eq!(
+            parse_group("NGTF\t      BASE      \t<", true),
+            Ok(Some(Message::TariffOption(TariffOptionValue::Base)))
+        );
+        assert_eq!(
+            parse_group("LTARF\t    HP  BLEU    \t+", true),
+            Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
+                hour: HourlyTarifPeriod::PeakHours,
+                day_color: Some(DayColor::Blue)
+            })))
+        );
+        assert_eq!(
+            parse_group("LTARF\t HEURE  CREUSE  \tK", true),
+            Ok(Some(Message::CurrentTariffPeriod(TarifPeriod {
+                hour: HourlyTarifPeriod::OffPeakHours,
+                day_color: None
+            })))
+        );
+    }
+
+    #[test]
+    fn supplier_indexes() {
+        assert_eq!(
+            parse_group("EASF02\t000123456\t8", true),
+            Ok(Some(Message::SupplierIndex {
+                index: 2,
+                value: 123456
+            }))
+        );
+        // Periods of the distributor calendar
+        assert_eq!(parse_group("EASD01\t000123456\t5", true), Ok(None));
+
+        assert_eq!(
+            TariffOptionValue::Tempo.supplier_period(4),
+            Some(TarifPeriod {
+                hour: HourlyTarifPeriod::PeakHours,
+                day_color: Some(DayColor::White)
+            })
+        );
+        assert_eq!(
+            TariffOptionValue::OffPeakHours.supplier_period(1),
+            Some(TarifPeriod {
+                hour: HourlyTarifPeriod::OffPeakHours,
+                day_color: None
+            })
+        );
+        assert_eq!(TariffOptionValue::OffPeakHours.supplier_period(3), None);
+        assert_eq!(TariffOptionValue::Tempo.supplier_period(7), None);
+        assert_eq!(TariffOptionValue::Base.supplier_period(1), None);
+    }
+
+    #[test]
+    fn standard_errors() {
+        assert_eq!(
+            parse_group("SINSTS\t00803\t.", true),
+            Err(ParseError::ChecksumError {
+                expected: 'Q',
+                actual: '.'
+            })
+        );
+        assert_eq!(
+            parse_group("SINSTS\t00803\t.", false),
+            Ok(Some(Message::ApparentPower { value: 803 }))
+        );
+        assert_eq!(
+            parse_group("IRMS1\tABC\t$", true),
+            Err(ParseError::FieldError("IRMS1".into(), "ABC".into()))
+        );
+        assert_eq!(
+            parse_group("XYZ\t1\t.", true),
+            Err(ParseError::GroupError("XYZ\t1\t.".into()))
+        );
+        assert_eq!(
+            parse_group("PAPP 00803 ,", true),
+            Err(ParseError::HistoricModeError("PAPP 00803 ,".into()))
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Status word of the meter, sent in MOTDETAT.
+//!
+//! The six hexadecimal digits of MOTDETAT report the state of the meter.
+//! Enedis leaves the meaning of each bit to the meter, for its internal use:
+//! all of them are clear on a meter working normally, and any bit set is a
+//! fault condition to look into, e.g. with the meter manufacturer.
+
+use bitflags::bitflags;
+use std::fmt;
+
+bitflags! {
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct MeterStatus: u32 {
+        // The 24 bits of the word, kept as sent
+        const _ = 0x00FF_FFFF;
+    }
+}
+
+impl MeterStatus {
+    /// Status word of the hexadecimal digits of MOTDETAT.
+    pub fn from_hex(data: &str) -> Option<MeterStatus> {
+        if data.len() != 6 {
+            return None;
+        }
+        u32::from_str_radix(data, 16)
+            .ok()
+            .map(MeterStatus::from_bits_retain)
+    }
+
+    /// Whether the meter reports no fault.
+    pub fn is_nominal(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+/// The status word as sent, e.g. `000000`.
+impl fmt::Display for MeterStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:06X}", self.bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_word() {
+        let status = MeterStatus::from_hex("000100").unwrap();
+        assert_eq!(status.bits(), 0x100);
+        assert!(!status.is_nominal());
+        assert_eq!(status.to_string(), "000100");
+        assert!(MeterStatus::from_hex("000000").unwrap().is_nominal());
+        assert_eq!(MeterStatus::from_hex("00000G"), None);
+        assert_eq!(MeterStatus::from_hex("0100"), None);
+    }
+}
@@ -0,0 +1,213 @@
+//! Groups of a frame gathered by field, for the consumers that read a frame
+//! as a whole rather than as a stream of messages.
+
+use crate::{
+    DayColor, Frame, HHPHCValue, Horodate, HourlyTarifPeriod, Message, MeterStatus, TarifPeriod,
+    TariffOptionValue,
+};
+use std::iter::FromIterator;
+
+/// Values of the groups of a frame, `None` for the groups it did not have.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct TeleinfoFrame {
+    /// Address of the meter
+    pub address: Option<String>,
+    /// Identifier of the delivery point, standard mode only
+    pub delivery_point: Option<String>,
+    pub tariff_option: Option<TariffOptionValue>,
+    /// Current subscribed, in A
+    pub subscribed_current: Option<u8>,
+    pub current_period: Option<TarifPeriod>,
+    /// `Some(None)` when the meter announced that tomorrow's color is not
+    /// known yet
+    pub tomorrow: Option<Option<DayColor>>,
+    /// Current of each phase, in A, only the first one for single phase
+    pub currents: [Option<u8>; 3],
+    pub max_currents: [Option<u16>; 3],
+    /// Highest three-phase power, in W
+    pub max_power: Option<u32>,
+    /// Presence of the voltage of each phase, three-phase meters only
+    pub phases: Option<[bool; 3]>,
+    pub status: Option<MeterStatus>,
+    /// Apparent power, in VA
+    pub apparent_power: Option<u16>,
+    /// Tempo indexes, in Wh, see `TeleinfoFrame::index` for the ordering
+    pub indexes: [Option<u32>; 6],
+    /// HCHC and HCHP indexes, in Wh, of the off-peak hours option
+    pub off_peak_indexes: [Option<u32>; 2],
+    pub hhphc: Option<HHPHCValue>,
+    /// Notice of an EJP peak day, in minutes
+    pub ejp_notice: Option<u8>,
+    pub date: Option<Horodate>,
+    pub voltages: [Option<u16>; 3],
+    pub supplied_energy: Option<u32>,
+    pub injected_energy: Option<u32>,
+    pub injected_power: Option<u16>,
+}
+
+impl TeleinfoFrame {
+    /// Sets the field of a message. The indexes of the supplier calendar are
+    /// set once the tariff option, sent before them, is known.
+    pub fn update(&mut self, message: &Message) {
+        let phase = |phase: u8| (1..=3).contains(&phase).then(|| phase as usize - 1);
+        match message {
+            Message::MeterAddress(address) => self.address = Some(address.clone()),
+            Message::DeliveryPoint(point) => self.delivery_point = Some(point.clone()),
+            Message::TariffOption(option) => self.tariff_option = Some(*option),
+            Message::SubscribedCurrent { value } => self.subscribed_current = Some(*value),
+            Message::Tomorrow(color) => self.tomorrow = Some(*color),
+            Message::InstantaneousPower { phase: p, value } => {
+                if let Some(slot) = phase(*p) {
+                    self.currents[slot] = Some(*value);
+                }
+            }
+            Message::MaxCurrent { phase: p, value } => {
+                if let Some(slot) = phase(*p) {
+                    self.max_currents[slot] = Some(*value);
+                }
+            }
+            Message::Index { period, value } => match index_slot(period) {
+                Some(slot) => self.indexes[slot] = Some(*value),
+                None => self.off_peak_indexes[hour_slot(period.hour)] = Some(*value),
+            },
+            Message::MaxPower { value } => self.max_power = Some(*value),
+            Message::MeterStatus(status) => self.status = Some(*status),
+            Message::PhasePotential {
+                phase1,
+                phase2,
+                phase3,
+            } => self.phases = Some([*phase1, *phase2, *phase3]),
+            Message::ApparentPower { value } => self.apparent_power = Some(*value),
+            Message::HHPHC(value) => self.hhphc = Some(*value),
+            Message::CurrentTariffPeriod(period) => self.current_period = Some(*period),
+            Message::EJPNotice { minutes } => self.ejp_notice = Some(*minutes),
+            Message::Date(horodate) => self.date = Some(*horodate),
+            Message::Voltage { phase: p, value } => {
+                if let Some(slot) = phase(*p) {
+                    self.voltages[slot] = Some(*value);
+                }
+            }
+            Message::SuppliedEnergy { value } => self.supplied_energy = Some(*value),
+            Message::InjectedEnergy { value } => self.injected_energy = Some(*value),
+            Message::InjectedPower { value } => self.injected_power = Some(*value),
+            Message::SupplierIndex { index, value } => {
+                let period = self
+                    .tariff_option
+                    .and_then(|option| option.supplier_period(*index));
+                if let Some(period) = period {
+                    self.update(&Message::Index {
+                        period,
+                        value: *value,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Tempo index of a period, the indexes being ordered by day color, blue,
+    /// white then red, and off-peak before peak hours.
+    pub fn index(&self, period: &TarifPeriod) -> Option<u32> {
+        self.indexes[index_slot(period)?]
+    }
+
+    /// Whether the frame has the groups a meter in historic mode always
+    /// sends: the address of the meter, the tariff option, the current
+    /// period, the current of the
+    /// first phase and the apparent power, for Tempo the six indexes and
+    /// tomorrow's color, and for the off-peak hours option its two indexes.
+    pub fn is_complete(&self) -> bool {
+        let tariff_indexes = match self.tariff_option {
+            Some(TariffOptionValue::Tempo) => {
+                self.indexes.iter().all(Option::is_some) && self.tomorrow.is_some()
+            }
+            Some(TariffOptionValue::OffPeakHours) => {
+                self.off_peak_indexes.iter().all(Option::is_some)
+            }
+            _ => true,
+        };
+        self.address.is_some()
+            && self.tariff_option.is_some()
+            && self.current_period.is_some()
+            && self.currents[0].is_some()
+            && self.apparent_power.is_some()
+            && tariff_indexes
+    }
+}
+
+impl FromIterator<Message> for TeleinfoFrame {
+    fn from_iter<I: IntoIterator<Item = Message>>(messages: I) -> TeleinfoFrame {
+        let mut frame = TeleinfoFrame::default();
+        for message in messages {
+            frame.update(&message);
+        }
+        frame
+    }
+}
+
+impl From<&Frame> for TeleinfoFrame {
+    fn from(frame: &Frame) -> TeleinfoFrame {
+        frame.messages().iter().cloned().collect()
+    }
+}
+
+fn index_slot(period: &TarifPeriod) -> Option<usize> {
+    let color = match period.day_color? {
+        DayColor::Blue => 0,
+        DayColor::White => 1,
+        DayColor::Red => 2,
+    };
+    Some(color * 2 + hour_slot(period.hour))
+}
+
+fn hour_slot(hour: HourlyTarifPeriod) -> usize {
+    match hour {
+        HourlyTarifPeriod::OffPeakHours => 0,
+        HourlyTarifPeriod::PeakHours => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn tempo_frame() {
+        let groups = "ADCO 031762120110 /\nOPTARIF BBR( S\nBBRHCJB 023916830 =\n\
+                      BBRHPJB 012567412 F\nBBRHCJW 001234567 N\nBBRHPJW 000987654 &\n\
+                      BBRHCJR 000345678 N\nBBRHPJR 000456789 !\nPTEC HCJB C\n\
+                      DEMAIN ---- \"\nIINST1 001 I";
+        let mut frame = TeleinfoFrame::from(&Frame::try_from(groups).unwrap());
+        assert_eq!(frame.address.as_deref(), Some("031762120110"));
+        assert_eq!(frame.tariff_option, Some(TariffOptionValue::Tempo));
+        assert_eq!(frame.tomorrow, Some(None));
+        assert_eq!(frame.currents, [Some(1), None, None]);
+        let white_peak = TarifPeriod {
+            hour: HourlyTarifPeriod::PeakHours,
+            day_color: Some(DayColor::White),
+        };
+        assert_eq!(frame.index(&white_peak), Some(987654));
+        assert!(!frame.is_complete());
+
+        frame.update(&Message::ApparentPower { value: 803 });
+        assert!(frame.is_complete());
+        frame.indexes[5] = None;
+        assert!(!frame.is_complete());
+    }
+
+    #[test]
+    fn off_peak_frame() {
+        let groups = "ADCO 031762120110 /\nOPTARIF HC.. <\nHCHC 001234567 \"\n\
+                      PTEC HC.. S\nIINST1 001 I\nPAPP 00803 ,";
+        let mut frame = TeleinfoFrame::from(&Frame::try_from(groups).unwrap());
+        assert_eq!(frame.off_peak_indexes, [Some(1234567), None]);
+        assert!(!frame.is_complete());
+        // As sent in EASF02 in standard mode
+        frame.update(&Message::SupplierIndex {
+            index: 2,
+            value: 7654321,
+        });
+        assert_eq!(frame.off_peak_indexes, [Some(1234567), Some(7654321)]);
+        assert!(frame.is_complete());
+    }
+}